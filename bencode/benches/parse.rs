@@ -0,0 +1,61 @@
+//! Parsing throughput on torrent-shaped input. Run with `cargo bench -p bencode`.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use bencode::{BencodeValue, BencodeValueRef, SpannedValue};
+
+/// Metainfo with `pieces` hashes of 20 bytes and as many files.
+fn torrent(pieces: usize) -> Vec<u8> {
+    let mut files = Vec::new();
+    for i in 0..pieces {
+        let path = format!("file-{i}.bin");
+        files.extend_from_slice(
+            format!("d6:lengthi{}e4:pathl{}:{path}ee", i * 1024, path.len()).as_bytes(),
+        );
+    }
+
+    [
+        &b"d8:announce31:http://tracker.example/announce4:infod5:filesl"[..],
+        &files,
+        b"e4:name6:sample12:piece lengthi262144e6:pieces",
+        format!("{}:", pieces * 20).as_bytes(),
+        &vec![0xab; pieces * 20],
+        b"ee",
+    ]
+    .concat()
+}
+
+fn bench(name: &str, input: &[u8], parse: impl Fn(&[u8])) {
+    const TARGET: Duration = Duration::from_secs(1);
+
+    let mut iterations = 0u32;
+    let start = Instant::now();
+    while start.elapsed() < TARGET {
+        parse(black_box(input));
+        iterations += 1;
+    }
+
+    let per_iteration = start.elapsed() / iterations;
+    let throughput = input.len() as f64 / per_iteration.as_secs_f64() / (1 << 20) as f64;
+    println!("{name:<24} {per_iteration:>12.2?}/iter {throughput:>10.1} MiB/s");
+}
+
+fn main() {
+    for pieces in [1_000, 50_000] {
+        let input = torrent(pieces);
+        println!("{pieces} pieces ({} bytes)", input.len());
+
+        bench("BencodeValueRef", &input, |input| {
+            black_box(BencodeValueRef::try_from_bytes(input).unwrap());
+        });
+        bench("SpannedValue", &input, |input| {
+            black_box(SpannedValue::try_from_bytes(input).unwrap());
+        });
+        bench("BencodeValue", &input, |input| {
+            black_box(BencodeValue::try_from_bytes(input).unwrap());
+        });
+    }
+}
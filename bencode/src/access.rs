@@ -0,0 +1,433 @@
+use std::{
+    collections::BTreeMap,
+    ops::{Index, IndexMut},
+};
+
+use bstr::{BStr, BString, ByteSlice};
+
+use super::{error::TryFromValueError, BencodeValue, PathSegment};
+
+impl BencodeValue {
+    /// Looks up a key when this value is a dict.
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Option<&BencodeValue> {
+        match self {
+            BencodeValue::Dict(d) => d.get(BStr::new(key.as_ref())),
+            _ => None,
+        }
+    }
+
+    /// Looks up an element when this value is a list.
+    pub fn get_index(&self, index: usize) -> Option<&BencodeValue> {
+        match self {
+            BencodeValue::List(l) => l.get(index),
+            _ => None,
+        }
+    }
+
+    /// Follows a sequence of dict keys and list indices (e.g. `["info", "pieces"]`).
+    pub fn get_path<I>(&self, path: I) -> Option<&BencodeValue>
+    where
+        I: IntoIterator,
+        I::Item: Into<PathSegment>,
+    {
+        path.into_iter()
+            .try_fold(self, |value, segment| match segment.into() {
+                PathSegment::Key(key) => value.get(key),
+                PathSegment::Index(index) => value.get_index(index),
+            })
+    }
+
+    /// Mutable variant of [`BencodeValue::get`].
+    pub fn get_mut(&mut self, key: impl AsRef<[u8]>) -> Option<&mut BencodeValue> {
+        match self {
+            BencodeValue::Dict(d) => d.get_mut(BStr::new(key.as_ref())),
+            _ => None,
+        }
+    }
+
+    /// Mutable variant of [`BencodeValue::get_index`].
+    pub fn get_index_mut(&mut self, index: usize) -> Option<&mut BencodeValue> {
+        match self {
+            BencodeValue::List(l) => l.get_mut(index),
+            _ => None,
+        }
+    }
+
+    /// Mutable variant of [`BencodeValue::get_path`].
+    pub fn get_path_mut<I>(&mut self, path: I) -> Option<&mut BencodeValue>
+    where
+        I: IntoIterator,
+        I::Item: Into<PathSegment>,
+    {
+        path.into_iter()
+            .try_fold(self, |value, segment| match segment.into() {
+                PathSegment::Key(key) => value.get_mut(key),
+                PathSegment::Index(index) => value.get_index_mut(index),
+            })
+    }
+
+    /// Returns the string when this value is a utf-8 encoded string.
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes().and_then(|s| s.to_str().ok())
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            BencodeValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Returns the integer when this value is an integer fitting in an `i64`.
+    pub fn as_int(&self) -> Option<i64> {
+        self.as_i128().and_then(|i| i64::try_from(i).ok())
+    }
+
+    /// Returns the integer when this value is an integer fitting in a `u64`.
+    pub fn as_u64(&self) -> Option<u64> {
+        self.as_i128().and_then(|i| u64::try_from(i).ok())
+    }
+
+    pub fn as_i128(&self) -> Option<i128> {
+        match self {
+            BencodeValue::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[BencodeValue]> {
+        match self {
+            BencodeValue::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<BString, BencodeValue>> {
+        match self {
+            BencodeValue::Dict(d) => Some(d),
+            _ => None,
+        }
+    }
+
+    /// Returns the string when this value is a utf-8 encoded string.
+    pub fn into_string(self) -> Option<String> {
+        self.into_bytes()
+            .and_then(|s| String::from_utf8(s.into()).ok())
+    }
+
+    pub fn into_bytes(self) -> Option<BString> {
+        match self {
+            BencodeValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn into_int(self) -> Option<i64> {
+        self.as_int()
+    }
+
+    pub fn into_list(self) -> Option<Vec<BencodeValue>> {
+        match self {
+            BencodeValue::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn into_dict(self) -> Option<BTreeMap<BString, BencodeValue>> {
+        match self {
+            BencodeValue::Dict(d) => Some(d),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! impl_try_from_integer {
+    ($($ty:ty),*) => {
+        $(
+            impl TryFrom<BencodeValue> for $ty {
+                type Error = TryFromValueError;
+
+                fn try_from(value: BencodeValue) -> Result<Self, Self::Error> {
+                    value
+                        .as_i128()
+                        .and_then(|i| <$ty>::try_from(i).ok())
+                        .ok_or_else(|| TryFromValueError::new(stringify!($ty), value))
+                }
+            }
+        )*
+    };
+}
+
+impl_try_from_integer!(i8, i16, i32, i64, i128, u8, u16, u32, u64);
+
+impl TryFrom<BencodeValue> for bool {
+    type Error = TryFromValueError;
+
+    fn try_from(value: BencodeValue) -> Result<Self, Self::Error> {
+        match value {
+            BencodeValue::Integer(0) => Ok(false),
+            BencodeValue::Integer(1) => Ok(true),
+            value => Err(TryFromValueError::new("0 or 1", value)),
+        }
+    }
+}
+
+impl TryFrom<BencodeValue> for String {
+    type Error = TryFromValueError;
+
+    fn try_from(value: BencodeValue) -> Result<Self, Self::Error> {
+        match value {
+            BencodeValue::String(s) => String::from_utf8(s.into()).map_err(|e| {
+                TryFromValueError::new("utf-8 string", BencodeValue::String(e.into_bytes().into()))
+            }),
+            value => Err(TryFromValueError::new("utf-8 string", value)),
+        }
+    }
+}
+
+impl TryFrom<BencodeValue> for BString {
+    type Error = TryFromValueError;
+
+    fn try_from(value: BencodeValue) -> Result<Self, Self::Error> {
+        match value {
+            BencodeValue::String(s) => Ok(s),
+            value => Err(TryFromValueError::new("string", value)),
+        }
+    }
+}
+
+impl TryFrom<BencodeValue> for Vec<u8> {
+    type Error = TryFromValueError;
+
+    fn try_from(value: BencodeValue) -> Result<Self, Self::Error> {
+        BString::try_from(value).map(Vec::from)
+    }
+}
+
+impl TryFrom<BencodeValue> for Vec<BencodeValue> {
+    type Error = TryFromValueError;
+
+    fn try_from(value: BencodeValue) -> Result<Self, Self::Error> {
+        match value {
+            BencodeValue::List(l) => Ok(l),
+            value => Err(TryFromValueError::new("list", value)),
+        }
+    }
+}
+
+impl TryFrom<BencodeValue> for BTreeMap<BString, BencodeValue> {
+    type Error = TryFromValueError;
+
+    fn try_from(value: BencodeValue) -> Result<Self, Self::Error> {
+        match value {
+            BencodeValue::Dict(d) => Ok(d),
+            value => Err(TryFromValueError::new("dict", value)),
+        }
+    }
+}
+
+/// Looks up a dict key, like [`BencodeValue::get`].
+///
+/// # Panics
+///
+/// Panics when this value is not a dict or does not contain the key.
+impl Index<&str> for BencodeValue {
+    type Output = BencodeValue;
+
+    fn index(&self, key: &str) -> &Self::Output {
+        self.get(key)
+            .unwrap_or_else(|| panic!("key `{key}` not found in bencode value"))
+    }
+}
+
+impl IndexMut<&str> for BencodeValue {
+    fn index_mut(&mut self, key: &str) -> &mut Self::Output {
+        self.get_mut(key)
+            .unwrap_or_else(|| panic!("key `{key}` not found in bencode value"))
+    }
+}
+
+/// Looks up a list element, like [`BencodeValue::get_index`].
+///
+/// # Panics
+///
+/// Panics when this value is not a list or the index is out of bounds.
+impl Index<usize> for BencodeValue {
+    type Output = BencodeValue;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        self.get_index(index)
+            .unwrap_or_else(|| panic!("index {index} not found in bencode value"))
+    }
+}
+
+impl IndexMut<usize> for BencodeValue {
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        self.get_index_mut(index)
+            .unwrap_or_else(|| panic!("index {index} not found in bencode value"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> BencodeValue {
+        BencodeValue::try_from_bytes(
+            b"d8:announce3:url4:infod5:filesld6:lengthi1e4:pathl1:aeee6:pieces3:abcee",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn get() {
+        let value = sample();
+
+        assert_eq!(
+            value.get("announce"),
+            Some(&BencodeValue::String("url".into()))
+        );
+        assert_eq!(value.get("missing"), None);
+        assert_eq!(value.get("announce").unwrap().get("url"), None);
+        assert_eq!(value.get_index(0), None);
+    }
+
+    #[test]
+    fn get_path() {
+        let value = sample();
+
+        assert_eq!(
+            value.get_path(["info", "pieces"]),
+            Some(&BencodeValue::String("abc".into()))
+        );
+        assert_eq!(
+            value.get_path([
+                PathSegment::from("info"),
+                "files".into(),
+                0.into(),
+                "length".into()
+            ]),
+            Some(&BencodeValue::Integer(1))
+        );
+        assert_eq!(value.get_path(["info", "files", "length"]), None);
+        assert_eq!(value.get_path::<[&str; 0]>([]), Some(&value));
+    }
+
+    #[test]
+    fn get_path_mut() {
+        let mut value = sample();
+
+        *value.get_path_mut(["info", "pieces"]).unwrap() = BencodeValue::Integer(0);
+        assert_eq!(
+            value.get_path(["info", "pieces"]),
+            Some(&BencodeValue::Integer(0))
+        );
+    }
+
+    #[test]
+    fn typed() {
+        let value = sample();
+        let info = value.get("info").unwrap();
+
+        assert_eq!(value.get("announce").unwrap().as_str(), Some("url"));
+        assert_eq!(info.get("pieces").unwrap().as_bytes(), Some(&b"abc"[..]));
+        assert_eq!(info.get_path(["files", "0", "length"]), None);
+        assert_eq!(
+            info.get("files").unwrap().as_list().map(<[_]>::len),
+            Some(1)
+        );
+        assert_eq!(info.as_dict().map(BTreeMap::len), Some(2));
+        assert_eq!(info.as_int(), None);
+        assert_eq!(BencodeValue::String(b"\xff"[..].into()).as_str(), None);
+
+        assert_eq!(
+            value
+                .get_path([
+                    PathSegment::from("info"),
+                    "files".into(),
+                    0.into(),
+                    "length".into()
+                ])
+                .cloned()
+                .and_then(BencodeValue::into_int),
+            Some(1)
+        );
+        assert_eq!(
+            value
+                .clone()
+                .into_dict()
+                .unwrap()
+                .into_keys()
+                .collect::<Vec<_>>(),
+            ["announce", "info"]
+        );
+        assert_eq!(
+            BencodeValue::String("foo".into()).into_string().as_deref(),
+            Some("foo")
+        );
+        assert_eq!(value.into_list(), None);
+    }
+
+    #[test]
+    fn index() {
+        let mut value = sample();
+
+        assert_eq!(
+            value["info"]["files"][0]["length"],
+            BencodeValue::Integer(1)
+        );
+        assert_eq!(value["info"]["files"][0]["path"][0].as_str(), Some("a"));
+
+        value["info"]["files"][0]["length"] = BencodeValue::Integer(2);
+        assert_eq!(value["info"]["files"][0]["length"].as_int(), Some(2));
+    }
+
+    #[test]
+    #[should_panic = "key `missing` not found"]
+    fn index_missing_key() {
+        let _ = &sample()["missing"];
+    }
+
+    #[test]
+    #[should_panic = "index 0 not found"]
+    fn index_not_a_list() {
+        let _ = &sample()[0];
+    }
+
+    #[test]
+    fn try_from() {
+        assert_eq!(i64::try_from(BencodeValue::from(-3)), Ok(-3));
+        assert_eq!(u64::try_from(BencodeValue::from(u64::MAX)), Ok(u64::MAX));
+        assert_eq!(bool::try_from(BencodeValue::from(true)), Ok(true));
+        assert_eq!(
+            String::try_from(BencodeValue::from("spam")).as_deref(),
+            Ok("spam")
+        );
+        assert_eq!(
+            Vec::<u8>::try_from(BencodeValue::from(vec![0xff, 0x00])),
+            Ok(vec![0xff, 0x00])
+        );
+        assert_eq!(
+            Vec::<BencodeValue>::try_from(BencodeValue::from(vec![
+                BencodeValue::from(1),
+                "a".into()
+            ])),
+            Ok(vec![BencodeValue::Integer(1), BencodeValue::from("a")])
+        );
+        assert_eq!(
+            BTreeMap::try_from(BencodeValue::from(BTreeMap::from([("a".into(), 1.into())]))),
+            Ok(BTreeMap::from([(
+                BString::from("a"),
+                BencodeValue::Integer(1)
+            )]))
+        );
+
+        let err = u8::try_from(BencodeValue::from(256)).unwrap_err();
+        assert_eq!(err.to_string(), "expected u8, found integer");
+        assert_eq!(err.into_value(), BencodeValue::Integer(256));
+        let err = String::try_from(BencodeValue::from(vec![0xff])).unwrap_err();
+        assert_eq!(err.to_string(), "expected utf-8 string, found string");
+        assert_eq!(err.into_value(), BencodeValue::from(vec![0xff]));
+        assert!(BString::try_from(BencodeValue::from(1)).is_err());
+    }
+}
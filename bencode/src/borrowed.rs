@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+
+use bstr::{BStr, BString};
+
+use super::{error::ParseError, options::ParseOptions, parser::Parser, BencodeValue};
+
+/// Bencode value borrowing its strings from the parsed input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BencodeValueRef<'a> {
+    String(&'a BStr),
+    Integer(i128),
+    List(Vec<BencodeValueRef<'a>>),
+    Dict(BTreeMap<&'a BStr, BencodeValueRef<'a>>),
+}
+
+impl<'a> BencodeValueRef<'a> {
+    /// Attempts to parse the bytes into a [`BencodeValueRef`] without copying any strings.
+    pub fn try_from_bytes(bytes: &'a [u8]) -> Result<Self, ParseError> {
+        Self::try_from_bytes_with(bytes, &ParseOptions::default())
+    }
+
+    /// Attempts to parse the bytes into a [`BencodeValueRef`] using the given options.
+    pub fn try_from_bytes_with(
+        bytes: &'a [u8],
+        options: &ParseOptions,
+    ) -> Result<Self, ParseError> {
+        Parser::new(bytes, options).parse()
+    }
+
+    /// Parses the first value in the bytes, returning it together with the amount of bytes
+    /// consumed. Trailing bytes are ignored.
+    pub fn parse_prefix(bytes: &'a [u8]) -> Result<(Self, usize), ParseError> {
+        Parser::new(bytes, &ParseOptions::default()).parse_prefix()
+    }
+
+    /// Copies the borrowed data into an owned [`BencodeValue`].
+    pub fn to_owned(&self) -> BencodeValue {
+        match self {
+            BencodeValueRef::String(s) => BencodeValue::String(BString::from(*s)),
+            BencodeValueRef::Integer(i) => BencodeValue::Integer(*i),
+            BencodeValueRef::List(l) => {
+                BencodeValue::List(l.iter().map(BencodeValueRef::to_owned).collect())
+            }
+            BencodeValueRef::Dict(d) => BencodeValue::Dict(
+                d.iter()
+                    .map(|(k, v)| (BString::from(*k), v.to_owned()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl From<BencodeValueRef<'_>> for BencodeValue {
+    fn from(value: BencodeValueRef<'_>) -> Self {
+        value.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn borrows_from_input() {
+        let input = b"d4:spaml3:fooi42ee4:eggs0:e".to_vec();
+        let value = BencodeValueRef::try_from_bytes(&input).unwrap();
+
+        let BencodeValueRef::Dict(d) = &value else {
+            panic!("expected dict");
+        };
+        let BencodeValueRef::List(l) = &d[BStr::new("spam")] else {
+            panic!("expected list");
+        };
+        let BencodeValueRef::String(s) = l[0] else {
+            panic!("expected string");
+        };
+        assert_eq!(s, "foo");
+        assert!(input.as_ptr_range().contains(&s.as_ptr()));
+    }
+
+    #[test]
+    fn to_owned() {
+        let input = b"d4:spaml3:fooi42ee4:eggs0:e";
+
+        assert_eq!(
+            BencodeValueRef::try_from_bytes(input).unwrap().to_owned(),
+            BencodeValue::try_from_bytes(input).unwrap()
+        );
+        assert_eq!(
+            BencodeValue::from(BencodeValueRef::try_from_bytes(b"i-7e").unwrap()),
+            BencodeValue::Integer(-7)
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::BTreeMap;
+
+use bstr::{BStr, BString};
+use derive_more::{Deref, DerefMut, IntoIterator};
+
+use super::BencodeValue;
+
+impl BencodeValue {
+    pub fn dict_mut(&mut self) -> Option<&mut BTreeMap<BString, BencodeValue>> {
+        match self {
+            BencodeValue::Dict(d) => Some(d),
+            _ => None,
+        }
+    }
+
+    pub fn list_mut(&mut self) -> Option<&mut [BencodeValue]> {
+        match self {
+            BencodeValue::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    /// Inserts a key into a dict, returning the value previously stored under it.
+    ///
+    /// # Panics
+    ///
+    /// Panics when this value is not a dict.
+    pub fn insert(
+        &mut self,
+        key: impl Into<BString>,
+        value: impl Into<BencodeValue>,
+    ) -> Option<BencodeValue> {
+        self.dict_mut()
+            .expect("insert called on a bencode value that is not a dict")
+            .insert(key.into(), value.into())
+    }
+
+    /// Removes a key from a dict, returning its value.
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> Option<BencodeValue> {
+        self.dict_mut()?.remove(BStr::new(key.as_ref()))
+    }
+
+    /// Appends an element to a list.
+    ///
+    /// # Panics
+    ///
+    /// Panics when this value is not a list.
+    pub fn push(&mut self, value: impl Into<BencodeValue>) {
+        let BencodeValue::List(l) = self else {
+            panic!("push called on a bencode value that is not a list");
+        };
+        l.push(value.into());
+    }
+}
+
+/// Builder for bencode dicts (e.g. `BencodeDict::new().with("announce", url)`).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deref, DerefMut, IntoIterator)]
+pub struct BencodeDict(BTreeMap<BString, BencodeValue>);
+
+impl BencodeDict {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, key: impl Into<BString>, value: impl Into<BencodeValue>) -> Self {
+        self.0.insert(key.into(), value.into());
+        self
+    }
+
+    /// Like [`BencodeDict::with`], but skips the key when there is no value.
+    pub fn with_opt(self, key: impl Into<BString>, value: Option<impl Into<BencodeValue>>) -> Self {
+        match value {
+            Some(value) => self.with(key, value),
+            None => self,
+        }
+    }
+
+    pub fn build(self) -> BencodeValue {
+        BencodeValue::Dict(self.0)
+    }
+}
+
+impl From<BencodeDict> for BencodeValue {
+    fn from(value: BencodeDict) -> Self {
+        value.build()
+    }
+}
+
+impl From<BTreeMap<BString, BencodeValue>> for BencodeDict {
+    fn from(value: BTreeMap<BString, BencodeValue>) -> Self {
+        Self(value)
+    }
+}
+
+macro_rules! impl_from_integer {
+    ($($ty:ty),*) => {
+        $(
+            impl From<$ty> for BencodeValue {
+                fn from(value: $ty) -> Self {
+                    BencodeValue::Integer(value.into())
+                }
+            }
+        )*
+    };
+}
+
+impl_from_integer!(i8, i16, i32, i64, i128, u8, u16, u32, u64);
+
+impl From<bool> for BencodeValue {
+    fn from(value: bool) -> Self {
+        BencodeValue::Integer(value.into())
+    }
+}
+
+impl From<&str> for BencodeValue {
+    fn from(value: &str) -> Self {
+        BencodeValue::String(value.into())
+    }
+}
+
+impl From<&[u8]> for BencodeValue {
+    fn from(value: &[u8]) -> Self {
+        BencodeValue::String(value.into())
+    }
+}
+
+impl From<String> for BencodeValue {
+    fn from(value: String) -> Self {
+        BencodeValue::String(value.into())
+    }
+}
+
+impl From<Vec<u8>> for BencodeValue {
+    fn from(value: Vec<u8>) -> Self {
+        BencodeValue::String(value.into())
+    }
+}
+
+impl From<BString> for BencodeValue {
+    fn from(value: BString) -> Self {
+        BencodeValue::String(value)
+    }
+}
+
+impl From<Vec<BencodeValue>> for BencodeValue {
+    fn from(value: Vec<BencodeValue>) -> Self {
+        BencodeValue::List(value)
+    }
+}
+
+impl From<BTreeMap<BString, BencodeValue>> for BencodeValue {
+    fn from(value: BTreeMap<BString, BencodeValue>) -> Self {
+        BencodeValue::Dict(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder() {
+        let value = BencodeDict::new()
+            .with("announce", "http://tracker")
+            .with(
+                "info",
+                BencodeDict::new()
+                    .with("length", 3)
+                    .with("pieces", &b"\xff\x00"[..])
+                    .with_opt("private", None::<i64>),
+            )
+            .with("url-list", vec![BencodeValue::from("http://seed")])
+            .build();
+
+        assert_eq!(
+            value.to_byte_string().unwrap(),
+            &b"d8:announce14:http://tracker4:infod6:lengthi3e6:pieces2:\xff\x00e8:url-listl11:http://seedee"[..]
+        );
+    }
+
+    #[test]
+    fn mutate() {
+        let mut value =
+            BencodeValue::try_from_bytes(b"d8:announce3:url13:announce-listll3:urleee").unwrap();
+
+        assert_eq!(
+            value.remove("announce-list"),
+            Some(BencodeValue::List(vec![BencodeValue::List(vec![
+                BencodeValue::from("url")
+            ])]))
+        );
+        assert_eq!(value.remove("announce-list"), None);
+        assert_eq!(value.insert("announce", "other"), Some("url".into()));
+
+        value.insert("url-list", Vec::<BencodeValue>::new());
+        value.get_mut("url-list").unwrap().push("http://seed");
+        value.get_mut("url-list").unwrap().push("http://mirror");
+
+        assert_eq!(
+            value.to_byte_string().unwrap(),
+            "d8:announce5:other8:url-listl11:http://seed13:http://mirroree".as_bytes()
+        );
+        assert_eq!(value.dict_mut().map(|d| d.len()), Some(2));
+        assert_eq!(value.list_mut(), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn push_to_dict() {
+        BencodeDict::new().build().push(1);
+    }
+}
@@ -1,10 +1,15 @@
-use std::collections::BTreeMap;
+use std::collections::{btree_map, BTreeMap};
 
 use bstr::BString;
 use serde::de::{self, value::MapDeserializer, Error as DeError, IntoDeserializer};
 
 use super::{error::Error, BencodeValue};
 
+pub use self::slice::Deserializer;
+
+mod slice;
+mod value_ref;
+
 impl<'de> de::Deserialize<'de> for BencodeValue {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -30,43 +35,31 @@ impl<'de> de::Deserialize<'de> for BencodeValue {
             where
                 E: de::Error,
             {
-                Ok(BencodeValue::Integer(v))
+                self.visit_i128(v.into())
             }
 
             fn visit_i128<E>(self, v: i128) -> Result<Self::Value, E>
             where
                 E: de::Error,
             {
-                match i64::try_from(v).ok() {
-                    Some(v) => self.visit_i64(v),
-                    None => Err(de::Error::invalid_value(
-                        de::Unexpected::Other("i128 not storable in an i64"),
-                        &self,
-                    )),
-                }
+                Ok(BencodeValue::Integer(v))
             }
 
             fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
             where
                 E: de::Error,
             {
-                match i64::try_from(v).ok() {
-                    Some(v) => self.visit_i64(v),
-                    None => Err(de::Error::invalid_value(
-                        de::Unexpected::Other("u64 not storable in an i64"),
-                        &self,
-                    )),
-                }
+                self.visit_i128(v.into())
             }
 
             fn visit_u128<E>(self, v: u128) -> Result<Self::Value, E>
             where
                 E: de::Error,
             {
-                match i64::try_from(v).ok() {
-                    Some(v) => self.visit_i64(v),
+                match i128::try_from(v).ok() {
+                    Some(v) => self.visit_i128(v),
                     None => Err(de::Error::invalid_value(
-                        de::Unexpected::Other("u128 not storable in an i64"),
+                        de::Unexpected::Other("u128 not storable in an i128"),
                         &self,
                     )),
                 }
@@ -108,7 +101,7 @@ impl<'de> de::Deserialize<'de> for BencodeValue {
                 while let Some(elem) = seq.next_element()? {
                     vec.push(elem);
                 }
-                Ok(BencodeValue::List(vec.into_boxed_slice()))
+                Ok(BencodeValue::List(vec))
             }
 
             fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
@@ -135,10 +128,13 @@ impl<'de> de::Deserializer<'de> for BencodeValue {
         V: de::Visitor<'de>,
     {
         match self {
-            BencodeValue::String(s) => visitor.visit_bytes(&s),
-            BencodeValue::Integer(i) => visitor.visit_i64(i),
+            BencodeValue::String(s) => match String::from_utf8(s.into()) {
+                Ok(s) => visitor.visit_string(s),
+                Err(e) => visitor.visit_byte_buf(e.into_bytes()),
+            },
+            BencodeValue::Integer(i) => visit_integer(i, visitor),
             BencodeValue::List(l) => visitor.visit_seq(l.to_vec().into_deserializer()),
-            BencodeValue::Dict(d) => visitor.visit_map(d.into_deserializer()),
+            BencodeValue::Dict(d) => visitor.visit_map(dict_deserializer(d)),
         }
     }
 
@@ -161,7 +157,7 @@ impl<'de> de::Deserializer<'de> for BencodeValue {
         };
         match i8::try_from(i).ok() {
             Some(i) => visitor.visit_i8(i),
-            None => Err(Error::invalid_value(self.unexpected(), &visitor)),
+            None => Err(Error::integer_overflow(i, &visitor)),
         }
     }
 
@@ -174,7 +170,7 @@ impl<'de> de::Deserializer<'de> for BencodeValue {
         };
         match i16::try_from(i).ok() {
             Some(i) => visitor.visit_i16(i),
-            None => Err(Error::invalid_value(self.unexpected(), &visitor)),
+            None => Err(Error::integer_overflow(i, &visitor)),
         }
     }
 
@@ -187,16 +183,29 @@ impl<'de> de::Deserializer<'de> for BencodeValue {
         };
         match i32::try_from(i).ok() {
             Some(i) => visitor.visit_i32(i),
-            None => Err(Error::invalid_value(self.unexpected(), &visitor)),
+            None => Err(Error::integer_overflow(i, &visitor)),
         }
     }
 
     fn deserialize_i64<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let BencodeValue::Integer(i) = self else {
+            return Err(Error::invalid_type(self.unexpected(), &visitor));
+        };
+        match i64::try_from(i).ok() {
+            Some(i) => visitor.visit_i64(i),
+            None => Err(Error::integer_overflow(i, &visitor)),
+        }
+    }
+
+    fn deserialize_i128<V>(self, visitor: V) -> Result<V::Value, Self::Error>
     where
         V: de::Visitor<'de>,
     {
         match self {
-            BencodeValue::Integer(i) => visitor.visit_i64(i),
+            BencodeValue::Integer(i) => visitor.visit_i128(i),
             _ => Err(Error::invalid_type(self.unexpected(), &visitor)),
         }
     }
@@ -210,7 +219,7 @@ impl<'de> de::Deserializer<'de> for BencodeValue {
         };
         match u8::try_from(i).ok() {
             Some(i) => visitor.visit_u8(i),
-            None => Err(Error::invalid_value(self.unexpected(), &visitor)),
+            None => Err(Error::integer_overflow(i, &visitor)),
         }
     }
 
@@ -223,7 +232,7 @@ impl<'de> de::Deserializer<'de> for BencodeValue {
         };
         match u16::try_from(i).ok() {
             Some(i) => visitor.visit_u16(i),
-            None => Err(Error::invalid_value(self.unexpected(), &visitor)),
+            None => Err(Error::integer_overflow(i, &visitor)),
         }
     }
 
@@ -236,7 +245,7 @@ impl<'de> de::Deserializer<'de> for BencodeValue {
         };
         match u32::try_from(i).ok() {
             Some(i) => visitor.visit_u32(i),
-            None => Err(Error::invalid_value(self.unexpected(), &visitor)),
+            None => Err(Error::integer_overflow(i, &visitor)),
         }
     }
 
@@ -249,7 +258,20 @@ impl<'de> de::Deserializer<'de> for BencodeValue {
         };
         match u64::try_from(i).ok() {
             Some(i) => visitor.visit_u64(i),
-            None => Err(Error::invalid_value(self.unexpected(), &visitor)),
+            None => Err(Error::integer_overflow(i, &visitor)),
+        }
+    }
+
+    fn deserialize_u128<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let BencodeValue::Integer(i) = self else {
+            return Err(Error::invalid_type(self.unexpected(), &visitor));
+        };
+        match u128::try_from(i).ok() {
+            Some(i) => visitor.visit_u128(i),
+            None => Err(Error::integer_overflow(i, &visitor)),
         }
     }
 
@@ -385,7 +407,7 @@ impl<'de> de::Deserializer<'de> for BencodeValue {
         let BencodeValue::Dict(d) = self else {
             return Err(Error::invalid_type(self.unexpected(), &visitor));
         };
-        let mut d = d.into_deserializer();
+        let mut d = dict_deserializer(d);
         let result = visitor.visit_map(&mut d)?;
         d.end().map(|_| result)
     }
@@ -415,7 +437,7 @@ impl<'de> de::Deserializer<'de> for BencodeValue {
 
         let deserializer: EnumDeserializer = match self {
             BencodeValue::Dict(d) => {
-                de::value::MapAccessDeserializer::new(d.into_deserializer()).into()
+                de::value::MapAccessDeserializer::new(dict_deserializer(d)).into()
             }
             BencodeValue::String(s) => Vec::from(s)
                 .into_string()
@@ -450,23 +472,31 @@ impl<'de> de::Deserializer<'de> for BencodeValue {
     }
 }
 
+type DictDeserializer<'de> = MapDeserializer<
+    'de,
+    std::iter::Map<
+        btree_map::IntoIter<BString, BencodeValue>,
+        fn((BString, BencodeValue)) -> (BencodeValue, BencodeValue),
+    >,
+    Error,
+>;
+
+/// Deserializes a dictionary, handing out its (possibly non utf-8) keys as bencode strings.
+fn dict_deserializer<'de>(d: BTreeMap<BString, BencodeValue>) -> DictDeserializer<'de> {
+    let entries: fn(_) -> _ = |(k, v)| (BencodeValue::String(k), v);
+    MapDeserializer::new(d.into_iter().map(entries))
+}
+
 #[derive(derive_more::From)]
 enum EnumDeserializer<'de> {
     String(de::value::StringDeserializer<Error>),
-    Struct(
-        de::value::MapAccessDeserializer<
-            MapDeserializer<'de, <BTreeMap<String, BencodeValue> as IntoIterator>::IntoIter, Error>,
-        >,
-    ),
+    Struct(de::value::MapAccessDeserializer<DictDeserializer<'de>>),
 }
 
-#[allow(clippy::type_complexity)]
 enum EnumVariantKind<'de> {
     Unit(<de::value::StringDeserializer<Error> as de::EnumAccess<'de>>::Variant),
     Struct(
-        <de::value::MapAccessDeserializer<
-            MapDeserializer<'de, <BTreeMap<String, BencodeValue> as IntoIterator>::IntoIter, Error>,
-        > as de::EnumAccess<'de>>::Variant,
+        <de::value::MapAccessDeserializer<DictDeserializer<'de>> as de::EnumAccess<'de>>::Variant,
     ),
 }
 
@@ -535,16 +565,45 @@ impl<'de> de::VariantAccess<'de> for EnumVariantKind<'de> {
 }
 
 impl BencodeValue {
-    fn unexpected(&self) -> de::Unexpected {
+    fn unexpected(&self) -> de::Unexpected<'_> {
         match self {
             BencodeValue::String(s) => de::Unexpected::Bytes(s),
-            BencodeValue::Integer(i) => de::Unexpected::Signed(*i),
+            BencodeValue::Integer(i) => unexpected_integer(*i),
             BencodeValue::List(_) => de::Unexpected::Seq,
             BencodeValue::Dict(_) => de::Unexpected::Map,
         }
     }
 }
 
+/// Visits an integer using the narrowest serde integer type able to hold it.
+fn visit_integer<'de, V: de::Visitor<'de>>(i: i128, visitor: V) -> Result<V::Value, Error> {
+    match (i64::try_from(i), u64::try_from(i)) {
+        (Ok(i), _) => visitor.visit_i64(i),
+        (_, Ok(i)) => visitor.visit_u64(i),
+        _ => visitor.visit_i128(i),
+    }
+}
+
+/// Visits a string as `str` when it is valid utf-8, so self-describing consumers such as untagged
+/// enums see text rather than bytes.
+fn visit_borrowed_string<'de, V: de::Visitor<'de>>(
+    s: &'de [u8],
+    visitor: V,
+) -> Result<V::Value, Error> {
+    match std::str::from_utf8(s) {
+        Ok(s) => visitor.visit_borrowed_str(s),
+        Err(_) => visitor.visit_borrowed_bytes(s),
+    }
+}
+
+fn unexpected_integer(i: i128) -> de::Unexpected<'static> {
+    match (i64::try_from(i), u64::try_from(i)) {
+        (Ok(i), _) => de::Unexpected::Signed(i),
+        (_, Ok(i)) => de::Unexpected::Unsigned(i),
+        _ => de::Unexpected::Other("128 bit integer"),
+    }
+}
+
 impl<'de> IntoDeserializer<'de, Error> for BencodeValue {
     type Deserializer = Self;
 
@@ -552,3 +611,171 @@ impl<'de> IntoDeserializer<'de, Error> for BencodeValue {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bstr::BString;
+    use serde::{Deserialize, Serialize};
+
+    use crate::{from_bytes, to_bytes, BencodeValue};
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(untagged)]
+    enum TrackerResponse {
+        Failure {
+            #[serde(rename = "failure reason")]
+            reason: String,
+        },
+        Peers {
+            interval: u64,
+            peers: BString,
+            // Serde buffers untagged content, which cannot turn integers into bools.
+            private: Option<u8>,
+        },
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(untagged)]
+    enum Scalar {
+        Int(i64),
+        Text(String),
+        List(Vec<Scalar>),
+        Binary(BString),
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(tag = "t", content = "c")]
+    enum Message {
+        Ping,
+        Have(u32),
+        Request { index: u32, begin: u32 },
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(tag = "type")]
+    enum Internal {
+        File { length: u64 },
+        Dir { name: String, mode: Mode },
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    enum Mode {
+        Read,
+        Write,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Info {
+        length: u64,
+        name: BString,
+        #[serde(flatten)]
+        extra: HashMap<BString, BencodeValue>,
+    }
+
+    /// Deserializes through every deserializer, checking they agree.
+    fn parse<T>(input: &[u8]) -> Result<T, String>
+    where
+        T: for<'de> Deserialize<'de> + PartialEq + std::fmt::Debug,
+    {
+        let value = BencodeValue::try_from_bytes(input).unwrap();
+        let from_slice = from_bytes::<T>(input).map_err(|e| format!("{e:#}"));
+        let from_ref = T::deserialize(&value).map_err(|e| e.to_string());
+        let from_value = value.into_deserialize::<T>().map_err(|e| format!("{e:#}"));
+        assert_eq!(from_slice.is_ok(), from_ref.is_ok());
+        assert_eq!(from_slice.is_ok(), from_value.is_ok());
+        let from_slice = from_slice?;
+        assert_eq!(Ok(&from_slice), from_ref.as_ref());
+        assert_eq!(Ok(&from_slice), from_value.as_ref());
+        Ok(from_slice)
+    }
+
+    #[test]
+    fn untagged() {
+        assert_eq!(
+            parse(b"d14:failure reason4:oopse"),
+            Ok(TrackerResponse::Failure {
+                reason: "oops".into()
+            })
+        );
+        assert_eq!(
+            parse(b"d8:intervali1800e5:peers6:\xff\x00\x01\x02\x1a\xe1e"),
+            Ok(TrackerResponse::Peers {
+                interval: 1800,
+                peers: b"\xff\x00\x01\x02\x1a\xe1"[..].into(),
+                private: None,
+            })
+        );
+        assert_eq!(
+            parse(b"d8:intervali1800e5:peers0:7:privatei1ee"),
+            Ok(TrackerResponse::Peers {
+                interval: 1800,
+                peers: "".into(),
+                private: Some(1),
+            })
+        );
+        assert!(parse::<TrackerResponse>(b"d8:intervali1ee").is_err());
+        assert_eq!(
+            parse(b"li-1e4:spam2:\xff\xfeli1eee"),
+            Ok(Scalar::List(vec![
+                Scalar::Int(-1),
+                Scalar::Text("spam".into()),
+                Scalar::Binary(b"\xff\xfe"[..].into()),
+                Scalar::List(vec![Scalar::Int(1)]),
+            ]))
+        );
+    }
+
+    #[test]
+    fn adjacently_tagged() {
+        assert_eq!(parse(b"d1:t4:Pinge"), Ok(Message::Ping));
+        assert_eq!(parse(b"d1:ci3e1:t4:Havee"), Ok(Message::Have(3)));
+        assert_eq!(
+            parse(b"d1:cd5:begini16384e5:indexi1ee1:t7:Requeste"),
+            Ok(Message::Request {
+                index: 1,
+                begin: 16384
+            })
+        );
+        assert!(parse::<Message>(b"d1:t5:Othere").is_err());
+    }
+
+    #[test]
+    fn internally_tagged() {
+        assert_eq!(
+            parse(b"d6:lengthi3e4:type4:Filee"),
+            Ok(Internal::File { length: 3 })
+        );
+        assert_eq!(
+            parse(b"d4:mode4:Read4:name1:a4:type3:Dire"),
+            Ok(Internal::Dir {
+                name: "a".into(),
+                mode: Mode::Read
+            })
+        );
+    }
+
+    #[test]
+    fn flatten() {
+        let input = b"d6:lengthi3e4:name1:a6:pieces3:\xff\x00\x017:privatei1e1:\xffld1:ai1eeee";
+        let info: Info = parse(input).unwrap();
+
+        assert_eq!(
+            info.extra,
+            HashMap::from([
+                ("pieces".into(), b"\xff\x00\x01"[..].into()),
+                ("private".into(), 1.into()),
+                (
+                    b"\xff"[..].into(),
+                    vec![BencodeValue::try_from_bytes(b"d1:ai1ee").unwrap()].into()
+                ),
+            ])
+        );
+        assert_eq!(to_bytes(&info).unwrap(), input);
+        assert_eq!(
+            BencodeValue::from_serialize(&info).unwrap(),
+            BencodeValue::try_from_bytes(input).unwrap()
+        );
+    }
+}
@@ -0,0 +1,736 @@
+use serde::de::{self, Error as DeError, IntoDeserializer};
+
+use super::{visit_borrowed_string, visit_integer};
+use crate::{error::Error, ParseError, ParseOptions};
+
+/// Deserializer driving the visitor straight from bencoded bytes.
+pub struct Deserializer<'de> {
+    input: &'de [u8],
+    pos: usize,
+    /// Lists and dicts that may still be entered before exceeding the depth limit.
+    remaining_depth: usize,
+    max_string_length: usize,
+}
+
+impl<'de> Deserializer<'de> {
+    pub fn from_bytes(input: &'de [u8]) -> Self {
+        Self::from_bytes_with(input, &ParseOptions::default())
+    }
+
+    /// Creates a deserializer enforcing the depth and string length limits of `options`.
+    pub fn from_bytes_with(input: &'de [u8], options: &ParseOptions) -> Self {
+        Self {
+            input,
+            pos: 0,
+            remaining_depth: options.max_depth,
+            max_string_length: options.max_string_length,
+        }
+    }
+
+    /// Checks that the whole input has been consumed.
+    pub fn end(&self) -> Result<(), Error> {
+        if self.pos != self.input.len() {
+            return Err(Error::TrailingBytes { offset: self.pos });
+        }
+        Ok(())
+    }
+
+    fn error(&self, expected: &'static str) -> Error {
+        ParseError::new(self.input, self.pos, vec![expected]).into()
+    }
+
+    fn enter(&mut self) -> Result<(), Error> {
+        self.remaining_depth = self
+            .remaining_depth
+            .checked_sub(1)
+            .ok_or_else(|| self.error("nesting depth within limit"))?;
+        Ok(())
+    }
+
+    fn leave(&mut self) {
+        self.remaining_depth += 1;
+    }
+
+    fn peek(&self) -> Result<u8, Error> {
+        self.input
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| self.error("more input"))
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), Error> {
+        if self.peek()? != byte {
+            return Err(self.error(match byte {
+                b'i' => "\"i\"",
+                b'e' => "\"e\"",
+                b':' => "\":\"",
+                _ => unreachable!("unexpected delimiter `{}`", char::from(byte)),
+            }));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Takes an unsigned natural number without leading zeros.
+    fn take_digits(&mut self) -> Result<&'de str, Error> {
+        let start = self.pos;
+        let length = self.input[start..]
+            .iter()
+            .take_while(|b| b.is_ascii_digit())
+            .count();
+
+        if length == 0 {
+            return Err(self.error("ascii digit"));
+        }
+        if length > 1 && self.input[start] == b'0' {
+            return Err(self.error("number without leading zeros"));
+        }
+
+        self.pos += length;
+        Ok(std::str::from_utf8(&self.input[start..self.pos]).expect("ascii digits are utf-8"))
+    }
+
+    /// Parses a binary encoded integer (`i<some-whole-number>e`).
+    fn parse_integer(&mut self) -> Result<i128, Error> {
+        self.expect(b'i')?;
+        let negative = self.peek()? == b'-';
+        if negative {
+            self.pos += 1;
+        }
+        let n = self
+            .take_digits()?
+            .parse::<u128>()
+            .ok()
+            .and_then(|n| i128::try_from(n).ok())
+            .ok_or_else(|| self.error("integer within 128 bit range"))?;
+        self.expect(b'e')?;
+
+        Ok(if negative { -n } else { n })
+    }
+
+    /// Parses a binary encoded string (`n:<some-content>`).
+    fn parse_bytes(&mut self) -> Result<&'de [u8], Error> {
+        let length = self
+            .take_digits()?
+            .parse::<usize>()
+            .ok()
+            .filter(|length| *length <= self.max_string_length)
+            .ok_or_else(|| self.error("string length within limit"))?;
+        self.expect(b':')?;
+
+        let end = self
+            .pos
+            .checked_add(length)
+            .filter(|end| *end <= self.input.len())
+            .ok_or_else(|| self.error("string contents"))?;
+        let bytes = &self.input[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn parse_integer_as<T: TryFrom<i128>>(
+        &mut self,
+        visitor: &impl de::Visitor<'de>,
+    ) -> Result<T, Error> {
+        let i = self.parse_integer()?;
+        T::try_from(i).map_err(|_| Error::integer_overflow(i, visitor))
+    }
+
+    fn unexpected(&self) -> de::Unexpected<'static> {
+        match self.input.get(self.pos) {
+            Some(b'i') => de::Unexpected::Other("integer"),
+            Some(b'l') => de::Unexpected::Seq,
+            Some(b'd') => de::Unexpected::Map,
+            Some(b'0'..=b'9') => de::Unexpected::Other("byte string"),
+            _ => de::Unexpected::Other("invalid bencode"),
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        match self.peek()? {
+            b'0'..=b'9' => visit_borrowed_string(self.parse_bytes()?, visitor),
+            b'i' => visit_integer(self.parse_integer()?, visitor),
+            b'l' => self.deserialize_seq(visitor),
+            b'd' => self.deserialize_map(visitor),
+            _ => Err(self.error("bencode value")),
+        }
+    }
+
+    fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        match self.peek()? {
+            b'i' => visitor.visit_bool(self.parse_integer()? != 0),
+            _ => Err(Error::invalid_type(self.unexpected(), &visitor)),
+        }
+    }
+
+    fn deserialize_i8<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let i = self.parse_integer_as(&visitor)?;
+        visitor.visit_i8(i)
+    }
+
+    fn deserialize_i16<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let i = self.parse_integer_as(&visitor)?;
+        visitor.visit_i16(i)
+    }
+
+    fn deserialize_i32<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let i = self.parse_integer_as(&visitor)?;
+        visitor.visit_i32(i)
+    }
+
+    fn deserialize_i64<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let i = self.parse_integer_as(&visitor)?;
+        visitor.visit_i64(i)
+    }
+
+    fn deserialize_i128<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let i = self.parse_integer()?;
+        visitor.visit_i128(i)
+    }
+
+    fn deserialize_u8<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let i = self.parse_integer_as(&visitor)?;
+        visitor.visit_u8(i)
+    }
+
+    fn deserialize_u16<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let i = self.parse_integer_as(&visitor)?;
+        visitor.visit_u16(i)
+    }
+
+    fn deserialize_u32<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let i = self.parse_integer_as(&visitor)?;
+        visitor.visit_u32(i)
+    }
+
+    fn deserialize_u64<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let i = self.parse_integer_as(&visitor)?;
+        visitor.visit_u64(i)
+    }
+
+    fn deserialize_u128<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let i = self.parse_integer_as(&visitor)?;
+        visitor.visit_u128(i)
+    }
+
+    fn deserialize_f32<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        visitor.visit_f32(self.parse_integer()? as f32)
+    }
+
+    fn deserialize_f64<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        visitor.visit_f64(self.parse_integer()? as f64)
+    }
+
+    fn deserialize_char<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_str<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        if !self.peek()?.is_ascii_digit() {
+            return self.deserialize_byte_buf(visitor);
+        }
+        let bytes = self.parse_bytes()?;
+        match std::str::from_utf8(bytes) {
+            Ok(s) => visitor.visit_borrowed_str(s),
+            Err(_) => visitor.visit_borrowed_bytes(bytes),
+        }
+    }
+
+    fn deserialize_string<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        Err(Error::invalid_type(self.unexpected(), &visitor))
+    }
+
+    fn deserialize_unit_struct<V>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        match self.peek()? {
+            b'0'..=b'9' => visitor.visit_borrowed_bytes(self.parse_bytes()?),
+            b'l' => {
+                self.pos += 1;
+                self.enter()?;
+                let mut access = Access::new(self);
+                let result = visitor.visit_seq(&mut access)?;
+                access.end()?;
+                self.leave();
+                Ok(result)
+            }
+            _ => Err(Error::invalid_type(self.unexpected(), &visitor)),
+        }
+    }
+
+    fn deserialize_tuple<V>(self, _len: usize, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        if self.peek()? != b'd' {
+            return Err(Error::invalid_type(self.unexpected(), &visitor));
+        }
+        self.pos += 1;
+        self.enter()?;
+
+        let mut access = Access::new(self);
+        let result = visitor.visit_map(&mut access)?;
+        access.end()?;
+        self.leave();
+        Ok(result)
+    }
+
+    fn deserialize_struct<V>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        match self.peek()? {
+            b'd' => {
+                self.pos += 1;
+                self.enter()?;
+                let result = visitor.visit_enum(Enum { de: &mut *self })?;
+                self.leave();
+                Ok(result)
+            }
+            b'0'..=b'9' => {
+                let variant = self.parse_bytes()?;
+                let variant = std::str::from_utf8(variant).map_err(|_| {
+                    Error::invalid_value(de::Unexpected::Bytes(variant), &"valid utf-8 string")
+                })?;
+                visitor.visit_enum(variant.into_deserializer())
+            }
+            _ => Err(Error::invalid_value(
+                self.unexpected(),
+                &"map with a single key or a string",
+            )),
+        }
+    }
+
+    fn deserialize_identifier<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_any(de::IgnoredAny)?;
+        visitor.visit_unit()
+    }
+}
+
+/// Access to the elements of a list or the entries of a dict, up to the closing `e`.
+struct Access<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+    finished: bool,
+}
+
+impl<'a, 'de> Access<'a, 'de> {
+    fn new(de: &'a mut Deserializer<'de>) -> Self {
+        Self {
+            de,
+            finished: false,
+        }
+    }
+
+    fn at_end(&mut self) -> Result<bool, Error> {
+        if !self.finished && self.de.peek()? == b'e' {
+            self.de.pos += 1;
+            self.finished = true;
+        }
+        Ok(self.finished)
+    }
+
+    fn end(mut self) -> Result<(), Error> {
+        if !self.at_end()? {
+            return Err(self.de.error("expected end of container"));
+        }
+        Ok(())
+    }
+}
+
+impl<'de> de::SeqAccess<'de> for Access<'_, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, Self::Error>
+    where
+        T: de::DeserializeSeed<'de>,
+    {
+        if self.at_end()? {
+            return Ok(None);
+        }
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+}
+
+impl<'de> de::MapAccess<'de> for Access<'_, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error>
+    where
+        K: de::DeserializeSeed<'de>,
+    {
+        if self.at_end()? {
+            return Ok(None);
+        }
+        if !self.de.peek()?.is_ascii_digit() {
+            return Err(self.de.error("expected byte string dict key"));
+        }
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::DeserializeSeed<'de>,
+    {
+        seed.deserialize(&mut *self.de)
+    }
+}
+
+/// Externally tagged enum encoded as a dict with a single key.
+struct Enum<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+}
+
+impl<'a, 'de> de::EnumAccess<'de> for Enum<'a, 'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self::Variant), Self::Error>
+    where
+        V: de::DeserializeSeed<'de>,
+    {
+        let variant = seed.deserialize(&mut *self.de)?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Enum<'_, 'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Self::Error> {
+        Err(Error::invalid_type(
+            self.de.unexpected(),
+            &"unit variant encoded as a string",
+        ))
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value, Self::Error>
+    where
+        T: de::DeserializeSeed<'de>,
+    {
+        let value = seed.deserialize(&mut *self.de)?;
+        self.de.expect(b'e').map(|_| value)
+    }
+
+    fn tuple_variant<V>(self, _len: usize, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let value = de::Deserializer::deserialize_seq(&mut *self.de, visitor)?;
+        self.de.expect(b'e').map(|_| value)
+    }
+
+    fn struct_variant<V>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let value = de::Deserializer::deserialize_map(&mut *self.de, visitor)?;
+        self.de.expect(b'e').map(|_| value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use bstr::BString;
+    use serde::Deserialize;
+
+    use crate::{from_bytes, BencodeValue, Error};
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Info {
+        name: String,
+        length: u64,
+        #[serde(rename = "piece length")]
+        piece_length: u32,
+        pieces: BString,
+        private: Option<bool>,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    enum Event {
+        Started,
+        Peer { port: u16 },
+        Tags(Vec<String>),
+    }
+
+    #[test]
+    fn deserialize_struct() {
+        let info: Info =
+            from_bytes(b"d6:lengthi92063e4:name10:sample.txt12:piece lengthi32768e6:pieces3:abce")
+                .unwrap();
+
+        assert_eq!(
+            info,
+            Info {
+                name: "sample.txt".into(),
+                length: 92063,
+                piece_length: 32768,
+                pieces: "abc".into(),
+                private: None,
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_collections() {
+        let value0: Vec<i64> = from_bytes(b"li1ei-2ei3ee").unwrap();
+        let value1: BTreeMap<String, Vec<String>> = from_bytes(b"d3:fool3:bare4:spamlee").unwrap();
+        let value2: (u8, String) = from_bytes(b"li7e3:fooe").unwrap();
+
+        assert_eq!(value0, vec![1, -2, 3]);
+        assert_eq!(
+            value1,
+            BTreeMap::from([("foo".into(), vec!["bar".into()]), ("spam".into(), vec![])])
+        );
+        assert_eq!(value2, (7, "foo".into()));
+    }
+
+    #[test]
+    fn deserialize_enum() {
+        let value0: Event = from_bytes(b"7:Started").unwrap();
+        let value1: Event = from_bytes(b"d4:Peerd4:porti6881eee").unwrap();
+        let value2: Event = from_bytes(b"d4:Tagsl1:a1:bee").unwrap();
+
+        assert_eq!(value0, Event::Started);
+        assert_eq!(value1, Event::Peer { port: 6881 });
+        assert_eq!(value2, Event::Tags(vec!["a".into(), "b".into()]));
+    }
+
+    #[test]
+    fn deserialize_ignores_unknown_keys() {
+        let value: BTreeMap<String, i64> = from_bytes(b"d1:ai1e1:bi2ee").unwrap();
+        let info: Info = from_bytes(
+            b"d6:lengthi1e4:name1:a12:piece lengthi1e6:pieces0:7:privatei1e5:extrald1:xleeee",
+        )
+        .unwrap();
+
+        assert_eq!(value, BTreeMap::from([("a".into(), 1), ("b".into(), 2)]));
+        assert_eq!(info.private, Some(true));
+    }
+
+    #[test]
+    fn borrowed_fields() {
+        #[derive(Debug, Deserialize)]
+        struct Borrowed<'a> {
+            name: &'a str,
+            pieces: &'a [u8],
+            #[serde(borrow)]
+            url_list: Vec<&'a bstr::BStr>,
+        }
+
+        let input = b"d4:name3:foo6:pieces2:\xff\x008:url_listl4:http0:ee".to_vec();
+        let value: Borrowed = from_bytes(&input).unwrap();
+
+        assert_eq!(value.name, "foo");
+        assert_eq!(value.pieces, b"\xff\x00");
+        assert_eq!(value.url_list, ["http", ""]);
+        assert!(input.as_ptr_range().contains(&value.pieces.as_ptr()));
+        assert!(from_bytes::<&str>(b"2:\xff\x00").is_err());
+    }
+
+    #[test]
+    fn matches_value_deserializer() {
+        let input = b"d4:spaml3:fooi42ee4:eggsd1:xi-1eee";
+
+        assert_eq!(
+            from_bytes::<BencodeValue>(input).unwrap(),
+            BencodeValue::try_from_bytes(input).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        assert!(from_bytes::<i64>(b"i42etrailing").is_err());
+        assert!(from_bytes::<i64>(b"i042e").is_err());
+        assert!(from_bytes::<u8>(b"i256e").is_err());
+        assert!(from_bytes::<String>(b"10:short").is_err());
+        assert!(from_bytes::<Vec<i64>>(b"li1e").is_err());
+        assert!(from_bytes::<BTreeMap<String, i64>>(b"di1ei1ee").is_err());
+    }
+
+    #[test]
+    fn error_variants() {
+        assert!(matches!(
+            from_bytes::<u8>(b"i300e"),
+            Err(Error::IntegerOverflow { .. })
+        ));
+        assert!(matches!(
+            from_bytes::<String>(b"i1e"),
+            Err(Error::UnexpectedType { .. })
+        ));
+        assert!(matches!(
+            from_bytes::<i64>(b"i1ei2e"),
+            Err(Error::TrailingBytes { offset: 3 })
+        ));
+        assert!(matches!(
+            from_bytes::<i64>(b"i1"),
+            Err(Error::Parse(e)) if e.offset == 2
+        ));
+    }
+
+    #[test]
+    fn depth_limit() {
+        let nested = [&[b'l'; 65][..], &[b'e'; 65][..]].concat();
+
+        assert!(from_bytes::<BencodeValue>(&nested).is_err());
+        assert!(from_bytes::<BencodeValue>(&nested[1..nested.len() - 1]).is_ok());
+        assert!(from_bytes::<serde::de::IgnoredAny>(&nested).is_err());
+    }
+}
@@ -0,0 +1,501 @@
+use bstr::BString;
+use serde::de::{
+    self,
+    value::{MapDeserializer, SeqDeserializer},
+    Error as DeError, IntoDeserializer,
+};
+
+use super::{visit_borrowed_string, visit_integer};
+use crate::{error::Error, BencodeValue};
+
+impl<'de> de::Deserializer<'de> for &'de BencodeValue {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        match self {
+            BencodeValue::String(s) => visit_borrowed_string(s, visitor),
+            BencodeValue::Integer(i) => visit_integer(*i, visitor),
+            BencodeValue::List(l) => visit_list(l, visitor),
+            BencodeValue::Dict(_) => self.deserialize_map(visitor),
+        }
+    }
+
+    fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        match self {
+            BencodeValue::Integer(i) => visitor.visit_bool(*i != 0),
+            _ => Err(Error::invalid_type(self.unexpected(), &visitor)),
+        }
+    }
+
+    fn deserialize_i8<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let i = self.integer_as(&visitor)?;
+        visitor.visit_i8(i)
+    }
+
+    fn deserialize_i16<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let i = self.integer_as(&visitor)?;
+        visitor.visit_i16(i)
+    }
+
+    fn deserialize_i32<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let i = self.integer_as(&visitor)?;
+        visitor.visit_i32(i)
+    }
+
+    fn deserialize_i64<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let i = self.integer_as(&visitor)?;
+        visitor.visit_i64(i)
+    }
+
+    fn deserialize_i128<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let i = self.integer_as(&visitor)?;
+        visitor.visit_i128(i)
+    }
+
+    fn deserialize_u8<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let i = self.integer_as(&visitor)?;
+        visitor.visit_u8(i)
+    }
+
+    fn deserialize_u16<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let i = self.integer_as(&visitor)?;
+        visitor.visit_u16(i)
+    }
+
+    fn deserialize_u32<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let i = self.integer_as(&visitor)?;
+        visitor.visit_u32(i)
+    }
+
+    fn deserialize_u64<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let i = self.integer_as(&visitor)?;
+        visitor.visit_u64(i)
+    }
+
+    fn deserialize_u128<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let i = self.integer_as(&visitor)?;
+        visitor.visit_u128(i)
+    }
+
+    fn deserialize_f32<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        match self {
+            BencodeValue::Integer(i) => visitor.visit_f32(*i as f32),
+            _ => Err(Error::invalid_type(self.unexpected(), &visitor)),
+        }
+    }
+
+    fn deserialize_f64<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        match self {
+            BencodeValue::Integer(i) => visitor.visit_f64(*i as f64),
+            _ => Err(Error::invalid_type(self.unexpected(), &visitor)),
+        }
+    }
+
+    fn deserialize_char<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_str<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        match self {
+            BencodeValue::String(s) => match std::str::from_utf8(s) {
+                Ok(s) => visitor.visit_borrowed_str(s),
+                Err(_) => visitor.visit_borrowed_bytes(s),
+            },
+            _ => self.deserialize_byte_buf(visitor),
+        }
+    }
+
+    fn deserialize_string<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        Err(Error::invalid_type(self.unexpected(), &visitor))
+    }
+
+    fn deserialize_unit_struct<V>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        match self {
+            BencodeValue::String(s) => visitor.visit_borrowed_bytes(s),
+            BencodeValue::List(l) => visit_list(l, visitor),
+            _ => Err(Error::invalid_type(self.unexpected(), &visitor)),
+        }
+    }
+
+    fn deserialize_tuple<V>(self, _len: usize, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let BencodeValue::Dict(d) = self else {
+            return Err(Error::invalid_type(self.unexpected(), &visitor));
+        };
+        let mut d = MapDeserializer::new(d.iter().map(|(k, v)| (Key(k), v)));
+        let result = visitor.visit_map(&mut d)?;
+        d.end().map(|_| result)
+    }
+
+    fn deserialize_struct<V>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        let (variant, value) = match self {
+            BencodeValue::String(s) => (s, None),
+            BencodeValue::Dict(d) if d.len() == 1 => {
+                let (k, v) = d.iter().next().expect("dict has a single entry");
+                (k, Some(v))
+            }
+            other => {
+                return Err(Error::invalid_value(
+                    other.unexpected(),
+                    &"map with a single key or a string",
+                ))
+            }
+        };
+        visitor.visit_enum(Enum { variant, value })
+    }
+
+    fn deserialize_identifier<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        visitor.visit_unit()
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for &'de BencodeValue {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self::Deserializer {
+        self
+    }
+}
+
+impl BencodeValue {
+    fn integer_as<'de, T: TryFrom<i128>>(
+        &self,
+        visitor: &impl de::Visitor<'de>,
+    ) -> Result<T, Error> {
+        let BencodeValue::Integer(i) = self else {
+            return Err(Error::invalid_type(self.unexpected(), visitor));
+        };
+        T::try_from(*i).map_err(|_| Error::integer_overflow(i, visitor))
+    }
+}
+
+fn visit_list<'de, V>(list: &'de [BencodeValue], visitor: V) -> Result<V::Value, Error>
+where
+    V: de::Visitor<'de>,
+{
+    let mut l = SeqDeserializer::new(list.iter());
+    let result = visitor.visit_seq(&mut l)?;
+    l.end().map(|_| result)
+}
+
+/// Dict key handed to visitors as bytes borrowed from the value.
+struct Key<'de>(&'de BString);
+
+impl<'de> de::Deserializer<'de> for Key<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        visit_borrowed_string(self.0, visitor)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for Key<'de> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self::Deserializer {
+        self
+    }
+}
+
+/// Externally tagged enum, given as its variant name and the value it wraps (if any).
+struct Enum<'de> {
+    variant: &'de BString,
+    value: Option<&'de BencodeValue>,
+}
+
+impl<'de> de::EnumAccess<'de> for Enum<'de> {
+    type Error = Error;
+    type Variant = Variant<'de>;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self::Variant), Self::Error>
+    where
+        V: de::DeserializeSeed<'de>,
+    {
+        let variant = seed.deserialize(Key(self.variant))?;
+        Ok((variant, Variant(self.value)))
+    }
+}
+
+struct Variant<'de>(Option<&'de BencodeValue>);
+
+impl<'de> de::VariantAccess<'de> for Variant<'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Self::Error> {
+        match self.0 {
+            None => Ok(()),
+            Some(value) => Err(Error::invalid_type(value.unexpected(), &"unit variant")),
+        }
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value, Self::Error>
+    where
+        T: de::DeserializeSeed<'de>,
+    {
+        match self.0 {
+            Some(value) => seed.deserialize(value),
+            None => Err(Error::invalid_type(
+                de::Unexpected::UnitVariant,
+                &"newtype variant",
+            )),
+        }
+    }
+
+    fn tuple_variant<V>(self, _len: usize, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        match self.0 {
+            Some(value) => de::Deserializer::deserialize_seq(value, visitor),
+            None => Err(Error::invalid_type(
+                de::Unexpected::UnitVariant,
+                &"tuple variant",
+            )),
+        }
+    }
+
+    fn struct_variant<V>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        match self.0 {
+            Some(value) => de::Deserializer::deserialize_map(value, visitor),
+            None => Err(Error::invalid_type(
+                de::Unexpected::UnitVariant,
+                &"struct variant",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Info<'a> {
+        name: &'a str,
+        length: u64,
+        pieces: &'a [u8],
+        private: Option<bool>,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    enum Event {
+        Started,
+        Peer { port: u16 },
+        Tags(Vec<String>),
+    }
+
+    #[test]
+    fn deserialize_without_consuming() {
+        let value = BencodeValue::try_from_bytes(
+            b"d4:infod6:lengthi3e4:name3:foo6:pieces2:\xff\x00e5:otheri1ee",
+        )
+        .unwrap();
+
+        let info = Info::deserialize(value.get("info").unwrap()).unwrap();
+        assert_eq!(
+            info,
+            Info {
+                name: "foo",
+                length: 3,
+                pieces: b"\xff\x00",
+                private: None,
+            }
+        );
+        // The tree is still intact and matches the consuming deserializer.
+        assert_eq!(
+            BencodeValue::deserialize(&value).unwrap(),
+            value.clone().into_deserialize::<BencodeValue>().unwrap()
+        );
+    }
+
+    #[test]
+    fn deserialize_enum() {
+        let started = BencodeValue::String("Started".into());
+        let peer = BencodeValue::try_from_bytes(b"d4:Peerd4:porti6881eee").unwrap();
+        let tags = BencodeValue::try_from_bytes(b"d4:Tagsl1:a1:bee").unwrap();
+
+        assert_eq!(Event::deserialize(&started).unwrap(), Event::Started);
+        assert_eq!(
+            Event::deserialize(&peer).unwrap(),
+            Event::Peer { port: 6881 }
+        );
+        assert_eq!(
+            Event::deserialize(&tags).unwrap(),
+            Event::Tags(vec!["a".into(), "b".into()])
+        );
+        assert!(u8::deserialize(&BencodeValue::Integer(256)).is_err());
+    }
+}
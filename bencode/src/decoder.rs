@@ -0,0 +1,442 @@
+use std::cell::Cell;
+
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::{error::ParseError, BencodeValue, ParseOptions, Result};
+
+/// Bytes read from a stream at once, so the buffer only grows with input that actually arrives.
+#[cfg(feature = "tokio")]
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Incremental decoder that accepts input in arbitrary chunks and yields values once complete.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    buf: Vec<u8>,
+    options: ParseOptions,
+    /// Progress through the value at the start of the buffer.
+    scanner: Scanner,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Decoded {
+    /// A complete value was decoded and its bytes were removed from the buffer.
+    Value(BencodeValue),
+    /// At least this many more bytes are needed before a value can be decoded.
+    NeedMoreData(usize),
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a decoder that applies the given options, including their limits, to every value.
+    pub fn with_options(options: ParseOptions) -> Self {
+        Self {
+            options,
+            ..Self::default()
+        }
+    }
+
+    /// Appends bytes to the internal buffer.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Attempts to decode the next value from the buffered bytes.
+    ///
+    /// Bytes following a decoded value are kept for subsequent calls.
+    pub fn decode(&mut self) -> Result<Decoded> {
+        match self.scanner.scan(&self.buf, &self.options) {
+            Scan::Incomplete(needed) => Ok(Decoded::NeedMoreData(needed)),
+            Scan::Invalid(offset, expected) => {
+                Err(ParseError::new(&self.buf, offset, vec![expected]).into())
+            }
+            Scan::Complete(length) => {
+                self.scanner = Scanner::default();
+                let value = BencodeValue::try_from_bytes_with(&self.buf[..length], &self.options)?;
+                self.buf.drain(..length);
+                Ok(Decoded::Value(value))
+            }
+        }
+    }
+
+    /// Bytes that have been pushed but not yet decoded.
+    pub fn buffered(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Reads from `reader` until the next value is complete.
+    ///
+    /// Only as many bytes as are known to be needed are read, so the reader is never advanced past
+    /// the end of the value.
+    #[cfg(feature = "tokio")]
+    pub async fn read_value<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<BencodeValue> {
+        loop {
+            match self.decode()? {
+                Decoded::Value(value) => return Ok(value),
+                Decoded::NeedMoreData(needed) => {
+                    let limit = needed.min(READ_CHUNK_SIZE);
+                    self.buf.reserve(limit);
+                    let read = (&mut *reader)
+                        .take(limit as u64)
+                        .read_buf(&mut self.buf)
+                        .await?;
+                    if read == 0 {
+                        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl BencodeValue {
+    /// Reads exactly one bencoded value from the stream.
+    pub async fn from_async_reader(mut reader: impl AsyncRead + Unpin) -> Result<Self> {
+        Decoder::new().read_value(&mut reader).await
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Scan {
+    Complete(usize),
+    Incomplete(usize),
+    /// Offset of the error and what would have been accepted there.
+    Invalid(usize, &'static str),
+}
+
+/// Open container along with the offset of its opening byte.
+#[derive(Debug)]
+enum Frame {
+    List { start: usize },
+    Dict { start: usize, key_next: bool },
+}
+
+/// Walks the structure of the first value in the input without building it. Scanning stops at
+/// the first incomplete token and resumes there once more input arrived, so every byte is only
+/// walked once.
+#[derive(Debug, Default)]
+struct Scanner {
+    /// Start of the next token.
+    pos: usize,
+    stack: Vec<Frame>,
+    /// Values completed so far, checked against [`ParseOptions::max_elements`].
+    elements: Cell<usize>,
+}
+
+impl Scanner {
+    fn scan(&mut self, input: &[u8], options: &ParseOptions) -> Scan {
+        loop {
+            if options.skip_whitespace {
+                while input.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+                    self.pos += 1;
+                }
+            }
+            let pos = self.pos;
+            let Some(&byte) = input.get(pos) else {
+                return Scan::Incomplete(1);
+            };
+            let key_next = matches!(self.stack.last(), Some(Frame::Dict { key_next: true, .. }));
+
+            let start = match byte {
+                b'e' if key_next || matches!(self.stack.last(), Some(Frame::List { .. })) => {
+                    self.pos += 1;
+                    match self.stack.pop() {
+                        Some(Frame::List { start } | Frame::Dict { start, .. }) => start,
+                        None => unreachable!("container end outside of a container"),
+                    }
+                }
+                b'0'..=b'9' => match scan_string(&input[pos..], options) {
+                    Scan::Complete(length) => {
+                        self.pos += length;
+                        pos
+                    }
+                    Scan::Incomplete(needed) => return Scan::Incomplete(needed),
+                    Scan::Invalid(offset, expected) => {
+                        return Scan::Invalid(pos + offset, expected)
+                    }
+                },
+                _ if key_next => return Scan::Invalid(pos, "dict key"),
+                b'i' => match scan_integer(&input[pos..]) {
+                    Scan::Complete(length) => {
+                        self.pos += length;
+                        pos
+                    }
+                    Scan::Incomplete(needed) => return Scan::Incomplete(needed),
+                    Scan::Invalid(offset, expected) => {
+                        return Scan::Invalid(pos + offset, expected)
+                    }
+                },
+                b'l' | b'd' => {
+                    self.pos += 1;
+                    if let Err(expected) = options.check_depth(self.stack.len() + 1) {
+                        return Scan::Invalid(self.pos, expected);
+                    }
+                    self.stack.push(match byte {
+                        b'l' => Frame::List { start: pos },
+                        _ => Frame::Dict {
+                            start: pos,
+                            key_next: true,
+                        },
+                    });
+                    continue;
+                }
+                _ => return Scan::Invalid(pos, "valid bencode"),
+            };
+
+            // A complete value, or dict key, has been consumed.
+            if !key_next {
+                if let Err(expected) = options.count_element(&self.elements) {
+                    return Scan::Invalid(start, expected);
+                }
+            }
+            match self.stack.last_mut() {
+                None => return Scan::Complete(self.pos),
+                Some(Frame::Dict { key_next, .. }) => *key_next = !*key_next,
+                Some(Frame::List { .. }) => (),
+            }
+        }
+    }
+}
+
+/// Scans a binary string (`n:<some-content>`).
+fn scan_string(input: &[u8], options: &ParseOptions) -> Scan {
+    let digits = input.iter().take_while(|b| b.is_ascii_digit()).count();
+    // Checked before the colon arrived, so an endless length prefix is not buffered either.
+    let Some(length) = std::str::from_utf8(&input[..digits])
+        .ok()
+        .and_then(|n| n.parse::<u128>().ok())
+        .and_then(|n| options.check_string_length(n).ok())
+    else {
+        return Scan::Invalid(0, "string length within limit");
+    };
+    match input.get(digits) {
+        None => return Scan::Incomplete(1),
+        Some(b':') => (),
+        Some(_) => return Scan::Invalid(digits, "string colon"),
+    }
+
+    let Some(length) = length.checked_add(digits + 1) else {
+        return Scan::Invalid(0, "string length within limit");
+    };
+    match length.checked_sub(input.len()) {
+        Some(needed) if needed > 0 => Scan::Incomplete(needed),
+        _ => Scan::Complete(length),
+    }
+}
+
+/// Scans an integer (`i<some-whole-number>e`).
+fn scan_integer(input: &[u8]) -> Scan {
+    let digits_start = match input.get(1) {
+        Some(b'-') => 2,
+        Some(_) => 1,
+        None => return Scan::Incomplete(1),
+    };
+
+    let mut significant_digits = 0;
+    for (pos, byte) in input.iter().enumerate().skip(digits_start) {
+        match byte {
+            b'0' if significant_digits == 0 => (),
+            b'0'..=b'9' => {
+                significant_digits += 1;
+                // More digits than fit 128 bits are not buffered until the end arrives.
+                if significant_digits > 39 {
+                    return Scan::Invalid(pos, "integer within 128 bit range");
+                }
+            }
+            b'e' if pos > digits_start => return Scan::Complete(pos + 1),
+            _ => return Scan::Invalid(pos, "integer digit"),
+        }
+    }
+    Scan::Incomplete(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn scan(input: &[u8]) -> Scan {
+        Scanner::default().scan(input, &ParseOptions::default())
+    }
+
+    #[test]
+    fn scan_complete() {
+        assert_eq!(scan(b"6:foobar"), Scan::Complete(8));
+        assert_eq!(scan(b"i-42e"), Scan::Complete(5));
+        assert_eq!(scan(b"l4:spami42eetrailing"), Scan::Complete(12));
+        assert_eq!(scan(b"d1:ei-40012ee"), Scan::Complete(13));
+        assert_eq!(scan(b"d4:spamd4:spamleee"), Scan::Complete(18));
+    }
+
+    #[test]
+    fn scan_incomplete() {
+        assert_eq!(scan(b""), Scan::Incomplete(1));
+        assert_eq!(scan(b"6:foo"), Scan::Incomplete(3));
+        assert_eq!(scan(b"12"), Scan::Incomplete(1));
+        assert_eq!(scan(b"i42"), Scan::Incomplete(1));
+        assert_eq!(scan(b"l4:spam"), Scan::Incomplete(1));
+        assert_eq!(scan(b"d4:spam3:f"), Scan::Incomplete(2));
+    }
+
+    #[test]
+    fn scan_invalid() {
+        assert_eq!(scan(b"x"), Scan::Invalid(0, "valid bencode"));
+        assert_eq!(scan(b"3-foo"), Scan::Invalid(1, "string colon"));
+        assert_eq!(scan(b"ie"), Scan::Invalid(1, "integer digit"));
+        assert_eq!(scan(b"i4-2e"), Scan::Invalid(2, "integer digit"));
+        assert_eq!(scan(b"di42ei42ee"), Scan::Invalid(1, "dict key"));
+        assert_eq!(scan(b"d4:spame"), Scan::Invalid(7, "valid bencode"));
+    }
+
+    #[test]
+    fn scan_resumes() {
+        let mut scanner = Scanner::default();
+        let options = ParseOptions::default();
+
+        assert_eq!(scanner.scan(b"l4:spam", &options), Scan::Incomplete(1));
+        assert_eq!(scanner.pos, 7);
+        assert_eq!(scanner.scan(b"l4:spami4", &options), Scan::Incomplete(1));
+        assert_eq!(scanner.pos, 7);
+        assert_eq!(scanner.scan(b"l4:spami42ee", &options), Scan::Complete(12));
+    }
+
+    #[test]
+    fn decode_oversized_string() {
+        let mut decoder = Decoder::new();
+
+        decoder.push(b"99999999999999:");
+        assert!(decoder.decode().is_err());
+        assert_eq!(decoder.buffered().len(), 15);
+    }
+
+    #[test]
+    fn scan_depth_limit() {
+        let options = ParseOptions {
+            max_depth: 2,
+            ..ParseOptions::default()
+        };
+
+        assert_eq!(
+            Scanner::default().scan(b"lleei1e", &options),
+            Scan::Complete(4)
+        );
+        assert_eq!(
+            Scanner::default().scan(b"llle", &options),
+            Scan::Invalid(3, "nesting depth within limit")
+        );
+    }
+
+    #[test]
+    fn scan_string_length_limit() {
+        let options = ParseOptions {
+            max_string_length: 4,
+            ..ParseOptions::default()
+        };
+
+        assert_eq!(
+            Scanner::default().scan(b"4:spam", &options),
+            Scan::Complete(6)
+        );
+        assert_eq!(
+            Scanner::default().scan(b"l5", &options),
+            Scan::Invalid(1, "string length within limit")
+        );
+    }
+
+    #[test]
+    fn scan_element_limit() {
+        let options = ParseOptions {
+            max_elements: 3,
+            ..ParseOptions::default()
+        };
+
+        assert_eq!(
+            Scanner::default().scan(b"d1:ai1e1:bi2ee", &options),
+            Scan::Complete(14)
+        );
+        assert_eq!(
+            Scanner::default().scan(b"li1ei2ei3ee", &options),
+            Scan::Invalid(0, "element count within limit")
+        );
+    }
+
+    #[test]
+    fn decode_with_options() {
+        let mut decoder = Decoder::with_options(ParseOptions {
+            max_string_length: 4,
+            ..ParseOptions::default()
+        });
+
+        decoder.push(b"4:spam5:");
+        assert_eq!(
+            decoder.decode().unwrap(),
+            Decoded::Value(BencodeValue::String("spam".into()))
+        );
+        assert!(decoder.decode().is_err());
+    }
+
+    #[test]
+    fn decode_in_chunks() {
+        let mut decoder = Decoder::new();
+
+        decoder.push(b"d4:spa");
+        assert_eq!(decoder.decode().unwrap(), Decoded::NeedMoreData(1));
+        decoder.push(b"m3:foo");
+        assert_eq!(decoder.decode().unwrap(), Decoded::NeedMoreData(1));
+        decoder.push(b"ei42");
+        assert_eq!(
+            decoder.decode().unwrap(),
+            Decoded::Value(BencodeValue::Dict(BTreeMap::from([(
+                "spam".into(),
+                BencodeValue::String("foo".into())
+            )])))
+        );
+        assert_eq!(decoder.buffered(), b"i42");
+        assert_eq!(decoder.decode().unwrap(), Decoded::NeedMoreData(1));
+        decoder.push(b"e");
+        assert_eq!(
+            decoder.decode().unwrap(),
+            Decoded::Value(BencodeValue::Integer(42))
+        );
+        assert!(decoder.buffered().is_empty());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn from_async_reader() {
+        let mut input: &[u8] = b"d4:spaml3:fooi42eee4:tail";
+
+        let value = BencodeValue::from_async_reader(&mut input).await.unwrap();
+        assert_eq!(
+            value,
+            BencodeValue::try_from_bytes(b"d4:spaml3:fooi42eee").unwrap()
+        );
+        assert_eq!(input, b"4:tail");
+
+        let mut truncated: &[u8] = b"l4:sp";
+        assert!(BencodeValue::from_async_reader(&mut truncated)
+            .await
+            .is_err());
+
+        let mut oversized: &[u8] = b"99999999999999:foo";
+        assert!(BencodeValue::from_async_reader(&mut oversized)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn decode_invalid() {
+        let mut decoder = Decoder::new();
+
+        decoder.push(b"l4:spamx");
+        assert!(decoder.decode().is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use std::fmt;
+
+use bstr::BString;
+
+use super::{BencodeValue, Path, PathSegment};
+
+/// Single difference between two bencode values, located by its path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference<'a> {
+    /// Present only in the right value.
+    Added { path: Path, value: &'a BencodeValue },
+    /// Present only in the left value.
+    Removed { path: Path, value: &'a BencodeValue },
+    /// Present in both values, but with a different type or content.
+    Changed {
+        path: Path,
+        left: &'a BencodeValue,
+        right: &'a BencodeValue,
+    },
+}
+
+impl Difference<'_> {
+    pub fn path(&self) -> &Path {
+        match self {
+            Difference::Added { path, .. }
+            | Difference::Removed { path, .. }
+            | Difference::Changed { path, .. } => path,
+        }
+    }
+}
+
+impl fmt::Display for Difference<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Difference::Added { path, value } => write!(f, "+ {path}: {value}"),
+            Difference::Removed { path, value } => write!(f, "- {path}: {value}"),
+            Difference::Changed { path, left, right } => write!(f, "~ {path}: {left} -> {right}"),
+        }
+    }
+}
+
+/// Lists the differences between two values, ordered by path.
+///
+/// Lists are compared element by element, so an inserted element shows up as a change of every
+/// following element.
+pub fn diff<'a>(left: &'a BencodeValue, right: &'a BencodeValue) -> Vec<Difference<'a>> {
+    let mut differences = Vec::new();
+    diff_at(&mut Path::new(), left, right, &mut differences);
+    differences
+}
+
+fn diff_at<'a>(
+    path: &mut Path,
+    left: &'a BencodeValue,
+    right: &'a BencodeValue,
+    differences: &mut Vec<Difference<'a>>,
+) {
+    let mut nested = |path: &mut Path, segment, left: Option<_>, right: Option<_>| {
+        path.push(segment);
+        match (left, right) {
+            (Some(left), Some(right)) => diff_at(path, left, right, differences),
+            (Some(value), None) => differences.push(Difference::Removed {
+                path: path.clone(),
+                value,
+            }),
+            (None, Some(value)) => differences.push(Difference::Added {
+                path: path.clone(),
+                value,
+            }),
+            (None, None) => unreachable!("segment is taken from one of the values"),
+        }
+        path.pop();
+    };
+
+    match (left, right) {
+        (BencodeValue::List(l), BencodeValue::List(r)) => {
+            for index in 0..l.len().max(r.len()) {
+                nested(path, PathSegment::Index(index), l.get(index), r.get(index));
+            }
+        }
+        (BencodeValue::Dict(l), BencodeValue::Dict(r)) => {
+            let mut keys = l.keys().chain(r.keys()).collect::<Vec<&BString>>();
+            keys.sort_unstable();
+            keys.dedup();
+
+            for key in keys {
+                nested(path, PathSegment::Key(key.clone()), l.get(key), r.get(key));
+            }
+        }
+        (left, right) if left != right => differences.push(Difference::Changed {
+            path: path.clone(),
+            left,
+            right,
+        }),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn differences() {
+        let left = BencodeValue::try_from_bytes(
+            b"d8:announce3:url4:infod6:lengthi3e4:name1:a6:pieces3:abce4:listli1ei2eee",
+        )
+        .unwrap();
+        let right = BencodeValue::try_from_bytes(
+            b"d4:infod6:lengthi4e4:name1:a6:pieces3:abc7:privatei1ee4:listli1eli2eei3eee",
+        )
+        .unwrap();
+
+        let differences = diff(&left, &right);
+
+        assert_eq!(
+            differences
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            [
+                "- announce: \"url\"",
+                "~ info.length: 3 -> 4",
+                "+ info.private: 1",
+                "~ list[1]: 2 -> [\n  2,\n]",
+                "+ list[2]: 3",
+            ]
+        );
+        assert_eq!(differences[1].path().to_string(), "info.length");
+        assert!(diff(&left, &left).is_empty());
+    }
+
+    #[test]
+    fn root_changed() {
+        let left = BencodeValue::Integer(1);
+        let right = BencodeValue::String("1".into());
+
+        assert_eq!(
+            diff(&left, &right),
+            [Difference::Changed {
+                path: Path::new(),
+                left: &left,
+                right: &right
+            }]
+        );
+    }
+}
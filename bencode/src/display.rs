@@ -0,0 +1,119 @@
+use std::fmt::{self, Write};
+
+use bstr::ByteSlice;
+
+use super::BencodeValue;
+
+/// Binary strings up to this length are shown in full as hex.
+const MAX_HEX_BYTES: usize = 32;
+/// Amount of leading bytes shown for longer binary strings.
+const PREVIEW_BYTES: usize = 8;
+
+impl fmt::Display for BencodeValue {
+    /// Renders the value with indentation, showing binary strings as (truncated) hex.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+impl BencodeValue {
+    /// Human readable, indented rendering of the value.
+    pub fn pretty(&self) -> String {
+        self.to_string()
+    }
+
+    fn fmt_indented(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        const INDENT: &str = "  ";
+
+        match self {
+            BencodeValue::String(s) => fmt_bytes(f, s),
+            BencodeValue::Integer(i) => write!(f, "{i}"),
+            BencodeValue::List(l) if l.is_empty() => f.write_str("[]"),
+            BencodeValue::List(l) => {
+                f.write_str("[\n")?;
+                for v in l.iter() {
+                    f.write_str(&INDENT.repeat(depth + 1))?;
+                    v.fmt_indented(f, depth + 1)?;
+                    f.write_str(",\n")?;
+                }
+                write!(f, "{}]", INDENT.repeat(depth))
+            }
+            BencodeValue::Dict(d) if d.is_empty() => f.write_str("{}"),
+            BencodeValue::Dict(d) => {
+                f.write_str("{\n")?;
+                for (k, v) in d.iter() {
+                    f.write_str(&INDENT.repeat(depth + 1))?;
+                    match k.to_str() {
+                        Ok(k) => f.write_str(k)?,
+                        Err(_) => fmt_bytes(f, k)?,
+                    }
+                    f.write_str(": ")?;
+                    v.fmt_indented(f, depth + 1)?;
+                    f.write_str(",\n")?;
+                }
+                write!(f, "{}}}", INDENT.repeat(depth))
+            }
+        }
+    }
+}
+
+/// Writes utf-8 strings quoted and binary strings as hex, summarizing long ones.
+fn fmt_bytes(f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    if let Ok(s) = bytes.to_str() {
+        return write!(f, "{s:?}");
+    }
+
+    if bytes.len() <= MAX_HEX_BYTES {
+        f.write_str("<")?;
+        write_hex(f, bytes)?;
+        return f.write_str(">");
+    }
+
+    write!(f, "<{} bytes", bytes.len())?;
+    if bytes.len() % 20 == 0 {
+        // Most likely a concatenation of piece hashes.
+        f.write_str(", sha1 list")?;
+    } else {
+        f.write_str(": ")?;
+        write_hex(f, &bytes[..PREVIEW_BYTES])?;
+        f.write_char('…')?;
+    }
+    f.write_str(">")
+}
+
+fn write_hex(f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    bytes.iter().try_for_each(|b| write!(f, "{b:02x}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn indented() {
+        let value = BencodeValue::try_from_bytes(
+            b"d4:infod6:lengthi3e5:fileslee4:listl1:ad2:\xff\xffleeee",
+        )
+        .unwrap();
+
+        assert_eq!(
+            value.to_string(),
+            "{\n  info: {\n    files: [],\n    length: 3,\n  },\n  list: [\n    \"a\",\n    {\n      <ffff>: [],\n    },\n  ],\n}"
+        );
+    }
+
+    #[test]
+    fn binary_strings() {
+        let short = BencodeValue::String(b"\x00\xff\x10"[..].into());
+        let pieces = BencodeValue::String(vec![0xff; 60].into());
+        let long = BencodeValue::String(vec![0xab; 50].into());
+
+        assert_eq!(short.to_string(), "<00ff10>");
+        assert_eq!(pieces.to_string(), "<60 bytes, sha1 list>");
+        assert_eq!(long.to_string(), "<50 bytes: abababababababab…>");
+        assert_eq!(
+            BencodeValue::String("say \"hi\"".into()).pretty(),
+            r#""say \"hi\"""#
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    borrow::Cow,
+    collections::btree_map,
+    io::{self, Write},
+};
+
+use bstr::BString;
+use digest::{Digest, Output};
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use super::BencodeValue;
+
+/// Encoder writing bencode straight into a sink, without building the encoded value in memory.
+///
+/// Values are written in many small chunks, so unbuffered sinks should be wrapped in a buffered
+/// writer.
+#[derive(Debug)]
+pub struct Encoder<W> {
+    writer: W,
+}
+
+impl<W> Encoder<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn encode(&mut self, value: &BencodeValue) -> io::Result<()> {
+        Chunks::new(value).try_for_each(|chunk| self.writer.write_all(&chunk))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<W: AsyncWrite + Unpin> Encoder<W> {
+    pub async fn encode_async(&mut self, value: &BencodeValue) -> io::Result<()> {
+        for chunk in Chunks::new(value) {
+            self.writer.write_all(&chunk).await?;
+        }
+        Ok(())
+    }
+}
+
+impl BencodeValue {
+    /// Hashes the canonical encoding of the value, without materializing the encoded bytes.
+    pub fn hash_encoded<D: Digest>(&self) -> Output<D> {
+        let mut hasher = D::new();
+        Chunks::new(self).for_each(|chunk| hasher.update(chunk));
+        hasher.finalize()
+    }
+}
+
+enum Frame<'a> {
+    List(std::slice::Iter<'a, BencodeValue>),
+    Dict(btree_map::Iter<'a, BString, BencodeValue>),
+}
+
+/// Walks a value depth first, yielding its encoding piece by piece.
+///
+/// String contents are borrowed from the value, so no copies of (large) strings are made.
+struct Chunks<'a> {
+    stack: Vec<Frame<'a>>,
+    /// String contents to emit after their length prefix.
+    pending: Option<&'a [u8]>,
+    next: Option<&'a BencodeValue>,
+}
+
+impl<'a> Chunks<'a> {
+    fn new(value: &'a BencodeValue) -> Self {
+        Self {
+            stack: Vec::new(),
+            pending: None,
+            next: Some(value),
+        }
+    }
+
+    fn string(&mut self, s: &'a [u8]) -> Cow<'a, [u8]> {
+        self.pending = Some(s);
+        Cow::Owned(format!("{}:", s.len()).into_bytes())
+    }
+
+    fn end(&mut self) -> Option<Cow<'a, [u8]>> {
+        self.stack.pop();
+        Some(Cow::Borrowed(b"e"))
+    }
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = Cow<'a, [u8]>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(s) = self.pending.take() {
+            return Some(Cow::Borrowed(s));
+        }
+
+        let value = match self.next.take() {
+            Some(value) => value,
+            None => match self.stack.last_mut()? {
+                Frame::List(l) => match l.next() {
+                    Some(value) => value,
+                    None => return self.end(),
+                },
+                Frame::Dict(d) => match d.next() {
+                    Some((k, v)) => {
+                        self.next = Some(v);
+                        return Some(self.string(k));
+                    }
+                    None => return self.end(),
+                },
+            },
+        };
+
+        Some(match value {
+            BencodeValue::String(s) => self.string(s),
+            BencodeValue::Integer(i) => Cow::Owned(format!("i{i}e").into_bytes()),
+            BencodeValue::List(l) => {
+                self.stack.push(Frame::List(l.iter()));
+                Cow::Borrowed(b"l")
+            }
+            BencodeValue::Dict(d) => {
+                self.stack.push(Frame::Dict(d.iter()));
+                Cow::Borrowed(b"d")
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode() {
+        let input = b"d4:infod6:lengthi-3e6:pieces3:\xff\x00\x01e4:listl0:lei0eee";
+        let value = BencodeValue::try_from_bytes(input).unwrap();
+
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.encode(&value).unwrap();
+        assert_eq!(encoder.into_inner(), input);
+    }
+
+    #[test]
+    fn hash_encoded() {
+        use sha1::Sha1;
+
+        let value =
+            BencodeValue::try_from_bytes(b"d6:lengthi3e4:name1:a6:pieces3:\xff\x00\x01e").unwrap();
+
+        assert_eq!(
+            value.hash_encoded::<Sha1>(),
+            Sha1::digest(value.to_canonical_bytes())
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn encode_async() {
+        let input = b"l4:spamd3:fooi42eee";
+        let value = BencodeValue::try_from_bytes(input).unwrap();
+
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.encode_async(&value).await.unwrap();
+        assert_eq!(encoder.into_inner(), input);
+    }
+}
@@ -1,20 +1,66 @@
 use derive_more::{Display, From};
 use serde::{de, ser};
 
+use crate::{path::Path, BencodeValue};
+
+/// Error produced while converting between bencode and Rust values.
 #[derive(Debug, Display, From)]
 pub enum Error {
+    /// A value has a different type than the target expects, or the value has no bencode
+    /// representation.
+    #[display(fmt = "invalid type: {found}, expected {expected}")]
+    UnexpectedType { expected: String, found: String },
+    /// An integer does not fit in the target type.
+    #[display(fmt = "integer {value} out of range, expected {expected}")]
+    IntegerOverflow { value: String, expected: String },
+    /// Input continues after a complete value.
+    #[display(fmt = "trailing bytes after value at byte offset {offset}")]
+    TrailingBytes { offset: usize },
+    /// The input is not valid bencode.
+    #[from]
+    #[display(fmt = "{_0}")]
+    Parse(ParseError),
+    /// Reading or writing the underlying stream failed.
     #[from]
-    Generic(anyhow::Error),
+    #[display(fmt = "{_0}")]
+    Io(std::io::Error),
+    /// Message from a `Serialize` or `Deserialize` implementation.
+    #[display(fmt = "{_0}")]
+    Custom(String),
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Parse(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
-impl std::error::Error for Error {}
+impl Error {
+    pub(crate) fn unexpected_type(expected: impl ToString, found: impl ToString) -> Self {
+        Error::UnexpectedType {
+            expected: expected.to_string(),
+            found: found.to_string(),
+        }
+    }
+
+    pub(crate) fn integer_overflow(value: impl ToString, expected: &dyn de::Expected) -> Self {
+        Error::IntegerOverflow {
+            value: value.to_string(),
+            expected: expected.to_string(),
+        }
+    }
+}
 
 impl ser::Error for Error {
     fn custom<T>(msg: T) -> Self
     where
         T: std::fmt::Display,
     {
-        Self::from(anyhow::Error::msg(msg.to_string()))
+        Error::Custom(msg.to_string())
     }
 }
 
@@ -23,6 +69,80 @@ impl de::Error for Error {
     where
         T: std::fmt::Display,
     {
-        Self::from(anyhow::Error::msg(msg.to_string()))
+        Error::Custom(msg.to_string())
+    }
+
+    fn invalid_type(unexp: de::Unexpected, exp: &dyn de::Expected) -> Self {
+        Error::unexpected_type(exp, unexp)
+    }
+}
+
+/// Error produced when the input is not valid bencode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Byte offset in the input at which parsing failed.
+    pub offset: usize,
+    /// Tokens that would have been accepted at the offset.
+    pub expected: Vec<&'static str>,
+    /// Location of the value that failed to parse.
+    pub path: Path,
+}
+
+impl ParseError {
+    pub(crate) fn new(input: &[u8], offset: usize, expected: Vec<&'static str>) -> Self {
+        Self {
+            offset,
+            expected,
+            path: Path::at_offset(input, offset),
+        }
     }
 }
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.expected.as_slice() {
+            [] => write!(f, "invalid bencode")?,
+            [expected] => write!(f, "expected {expected}")?,
+            expected => write!(f, "expected one of {}", expected.join(", "))?,
+        }
+        write!(f, " at byte offset {}", self.offset)?;
+        if !self.path.is_empty() {
+            write!(f, " (in `{}`)", self.path)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Error returned when converting a [`BencodeValue`] into a type it does not hold.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TryFromValueError {
+    expected: &'static str,
+    value: BencodeValue,
+}
+
+impl TryFromValueError {
+    pub(crate) fn new(expected: &'static str, value: BencodeValue) -> Self {
+        Self { expected, value }
+    }
+
+    /// Gives back the value that failed to convert.
+    pub fn into_value(self) -> BencodeValue {
+        self.value
+    }
+}
+
+impl std::fmt::Display for TryFromValueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let found = match &self.value {
+            BencodeValue::String(_) => "string",
+            BencodeValue::Integer(_) => "integer",
+            BencodeValue::List(_) => "list",
+            BencodeValue::Dict(_) => "dict",
+        };
+        write!(f, "expected {}, found {found}", self.expected)
+    }
+}
+
+impl std::error::Error for TryFromValueError {}
@@ -0,0 +1,190 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bstr::{BString, ByteSlice};
+use serde_json::{Map, Number, Value};
+
+use super::{BencodeValue, Error, Result};
+
+const HEX_PREFIX: &str = "hex:";
+const BASE64_PREFIX: &str = "base64:";
+
+/// How strings that are not valid utf-8 are represented in json.
+///
+/// Encoded strings are prefixed with the encoding, so they can be told apart from textual strings
+/// when converting back. Textual strings starting with that prefix are encoded as well.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BinaryPolicy {
+    /// `hex:<lowercase hex digits>`
+    #[default]
+    Hex,
+    /// `base64:<standard padded base64>`
+    Base64,
+    /// Invalid sequences are replaced by `U+FFFD`, which cannot be converted back.
+    Lossy,
+}
+
+impl FromStr for BinaryPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "hex" => Ok(Self::Hex),
+            "base64" => Ok(Self::Base64),
+            "lossy" => Ok(Self::Lossy),
+            _ => Err(Error::Custom(format!(
+                "unknown binary policy `{s}`, expected one of hex, base64, lossy"
+            ))),
+        }
+    }
+}
+
+impl BinaryPolicy {
+    fn encode(self, bytes: &[u8]) -> String {
+        match bytes.to_str() {
+            // Text that looks encoded is encoded, so it is read back as is.
+            Ok(s) if !self.prefix().is_some_and(|prefix| s.starts_with(prefix)) => s.to_string(),
+            _ => match self {
+                BinaryPolicy::Hex => format!("{HEX_PREFIX}{}", hex::encode(bytes)),
+                BinaryPolicy::Base64 => format!("{BASE64_PREFIX}{}", BASE64.encode(bytes)),
+                BinaryPolicy::Lossy => bytes.to_str_lossy().into_owned(),
+            },
+        }
+    }
+
+    fn prefix(self) -> Option<&'static str> {
+        match self {
+            BinaryPolicy::Hex => Some(HEX_PREFIX),
+            BinaryPolicy::Base64 => Some(BASE64_PREFIX),
+            BinaryPolicy::Lossy => None,
+        }
+    }
+
+    fn decode(self, s: &str) -> Result<BString> {
+        let decoded = match self {
+            BinaryPolicy::Hex => s
+                .strip_prefix(HEX_PREFIX)
+                .map(|hex| hex::decode(hex).map_err(|_| invalid_string("hex", s))),
+            BinaryPolicy::Base64 => s
+                .strip_prefix(BASE64_PREFIX)
+                .map(|b64| BASE64.decode(b64).map_err(|_| invalid_string("base64", s))),
+            BinaryPolicy::Lossy => None,
+        };
+        Ok(decoded.transpose()?.unwrap_or_else(|| s.into()).into())
+    }
+}
+
+/// Converts a bencode value into json, encoding binary strings according to `policy`.
+pub fn to_json(value: &BencodeValue, policy: BinaryPolicy) -> Value {
+    match value {
+        BencodeValue::String(s) => Value::String(policy.encode(s)),
+        BencodeValue::Integer(i) => match (i64::try_from(*i), u64::try_from(*i)) {
+            (Ok(i), _) => Value::Number(i.into()),
+            (_, Ok(i)) => Value::Number(i.into()),
+            // Json numbers beyond 64 bits are not portable.
+            _ => Value::String(i.to_string()),
+        },
+        BencodeValue::List(l) => Value::Array(l.iter().map(|v| to_json(v, policy)).collect()),
+        BencodeValue::Dict(d) => Value::Object(
+            d.iter()
+                .map(|(k, v)| (policy.encode(k), to_json(v, policy)))
+                .collect::<Map<_, _>>(),
+        ),
+    }
+}
+
+/// Converts json into a bencode value, decoding strings encoded by [`to_json`] with the same
+/// `policy`.
+///
+/// Booleans become `0`/`1`. Floats and `null` have no bencode representation.
+pub fn from_json(value: &Value, policy: BinaryPolicy) -> Result<BencodeValue> {
+    Ok(match value {
+        Value::Null => return Err(Error::unexpected_type("bencode value", "null")),
+        Value::Bool(b) => BencodeValue::Integer(i128::from(*b)),
+        Value::Number(n) => BencodeValue::Integer(integer(n)?),
+        Value::String(s) => BencodeValue::String(policy.decode(s)?),
+        Value::Array(a) => BencodeValue::List(
+            a.iter()
+                .map(|v| from_json(v, policy))
+                .collect::<Result<_>>()?,
+        ),
+        Value::Object(o) => BencodeValue::Dict(
+            o.iter()
+                .map(|(k, v)| Ok((policy.decode(k)?, from_json(v, policy)?)))
+                .collect::<Result<BTreeMap<_, _>>>()?,
+        ),
+    })
+}
+
+fn integer(n: &Number) -> Result<i128> {
+    n.as_i64()
+        .map(i128::from)
+        .or_else(|| n.as_u64().map(i128::from))
+        .ok_or_else(|| Error::unexpected_type("integer", format!("number `{n}`")))
+}
+
+fn invalid_string(encoding: &str, s: &str) -> Error {
+    Error::unexpected_type(format!("{encoding} encoded string"), format!("`{s}`"))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn sample() -> BencodeValue {
+        BencodeValue::try_from_bytes(b"d4:name3:foo6:piecesl2:\xff\x00i-3eee").unwrap()
+    }
+
+    #[test]
+    fn to_json_policies() {
+        let value = sample();
+
+        assert_eq!(
+            to_json(&value, BinaryPolicy::Hex),
+            json!({ "name": "foo", "pieces": ["hex:ff00", -3] })
+        );
+        assert_eq!(
+            to_json(&value, BinaryPolicy::Base64),
+            json!({ "name": "foo", "pieces": ["base64:/wA=", -3] })
+        );
+        assert_eq!(
+            to_json(&value, BinaryPolicy::Lossy),
+            json!({ "name": "foo", "pieces": ["\u{fffd}\u{0}", -3] })
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let value = sample();
+
+        for policy in [BinaryPolicy::Hex, BinaryPolicy::Base64] {
+            assert_eq!(from_json(&to_json(&value, policy), policy).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn round_trip_text_with_prefix() {
+        let value = BencodeValue::try_from_bytes(b"l6:hex:0011:base64:AA==e").unwrap();
+
+        assert_eq!(
+            to_json(&value, BinaryPolicy::Hex),
+            json!(["hex:6865783a3030", "base64:AA=="])
+        );
+        for policy in [BinaryPolicy::Hex, BinaryPolicy::Base64] {
+            assert_eq!(from_json(&to_json(&value, policy), policy).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn from_json_errors() {
+        assert!(from_json(&json!([null]), BinaryPolicy::Hex).is_err());
+        assert!(from_json(&json!(1.5), BinaryPolicy::Hex).is_err());
+        assert!(from_json(&json!("hex:zz"), BinaryPolicy::Hex).is_err());
+        assert_eq!(
+            from_json(&json!(true), BinaryPolicy::Hex).unwrap(),
+            BencodeValue::Integer(1)
+        );
+    }
+}
@@ -1,71 +1,149 @@
 use std::collections::BTreeMap;
 
-use anyhow::{Context, Result};
-use bstr::BString;
-use format_bytes::write_bytes;
+use bstr::{BString, ByteSlice};
+use bytes::{BufMut, BytesMut};
 use serde::{
     ser::{SerializeMap, SerializeSeq},
     Deserialize, Serialize,
 };
 
-use self::ser::Serializer;
+use self::{
+    parser::Parser,
+    ser::{BytesSerializer, Serializer},
+};
+
+pub use self::{
+    borrowed::BencodeValueRef,
+    builder::BencodeDict,
+    de::Deserializer,
+    decoder::{Decoded, Decoder},
+    diff::{diff, Difference},
+    encoder::Encoder,
+    error::{Error, ParseError, TryFromValueError},
+    options::{DuplicateKeyPolicy, ParseOptions, SerializeOptions},
+    parser::token::{Token, Tokenizer},
+    path::{Path, PathSegment},
+    shared::SharedValue,
+    spanned::{SpannedKind, SpannedValue},
+    warning::{Warning, WarningKind},
+};
 
+mod access;
+mod borrowed;
+mod builder;
 mod de;
+mod decoder;
+mod diff;
+mod display;
+mod encoder;
 mod error;
+pub mod json;
+mod options;
+mod parser;
+mod path;
 mod ser;
+mod shared;
+mod spanned;
+mod warning;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum BencodeValue {
     String(BString),
-    Integer(i64),
-    List(Box<[BencodeValue]>),
-    Dict(BTreeMap<String, BencodeValue>),
+    /// Bencode integers are unbounded; this covers the full `i64` and `u64` ranges.
+    Integer(i128),
+    List(Vec<BencodeValue>),
+    Dict(BTreeMap<BString, BencodeValue>),
 }
 
 impl BencodeValue {
     /// Attempts to parse the bytes into a [`BencodeValue`].
-    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self> {
-        Ok(bencode_parser::value(bytes)?)
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        BencodeValueRef::try_from_bytes(bytes).map(|v| v.to_owned())
+    }
+
+    /// Attempts to parse the bytes into a [`BencodeValue`] using the given options.
+    pub fn try_from_bytes_with(bytes: &[u8], options: &ParseOptions) -> Result<Self, ParseError> {
+        BencodeValueRef::try_from_bytes_with(bytes, options).map(|v| v.to_owned())
+    }
+
+    /// Parses the bytes with [`ParseOptions::lenient`], returning the value together with the
+    /// deviations from the specification that were accepted.
+    pub fn try_from_bytes_lenient(bytes: &[u8]) -> Result<(Self, Vec<Warning>), ParseError> {
+        Parser::new(bytes, &ParseOptions::lenient())
+            .parse_with_warnings::<BencodeValueRef>()
+            .map(|(v, warnings)| (v.to_owned(), warnings))
+    }
+
+    /// Parses the first value in the bytes, returning it together with the amount of bytes
+    /// consumed. Trailing bytes are ignored.
+    pub fn parse_prefix(bytes: &[u8]) -> Result<(Self, usize), ParseError> {
+        BencodeValueRef::parse_prefix(bytes).map(|(v, consumed)| (v.to_owned(), consumed))
     }
 
     pub fn to_byte_string(&self) -> std::io::Result<BString> {
-        use std::io::Write;
+        Ok(BString::new(self.to_canonical_bytes()))
+    }
 
-        let mut buf = Vec::new();
-        match self {
-            BencodeValue::String(s) => write_bytes!(&mut buf, b"{}:{}", s.len(), **s)?,
-            BencodeValue::Integer(i) => write_bytes!(&mut buf, b"i{}e", i)?,
-            BencodeValue::List(l) => {
-                write!(&mut buf, "l")?;
-                for v in l.iter() {
-                    let v = v.to_byte_string()?;
-                    write_bytes!(&mut buf, b"{}", *v)?;
-                }
-                write!(&mut buf, "e")?;
-            }
-            BencodeValue::Dict(d) => {
-                write!(&mut buf, "d")?;
-                for (k, v) in d.iter() {
-                    let v = v.to_byte_string()?;
-                    write_bytes!(&mut buf, b"{}:{}{}", k.len(), k.as_bytes(), *v)?;
-                }
-                write!(&mut buf, "e")?;
-            }
-        };
-        Ok(BString::new(buf))
+    /// Encodes the value as spec-compliant bencode, with dict keys sorted by their raw bytes.
+    ///
+    /// Equal values always encode to the same bytes, so this is suitable for computing info
+    /// hashes.
+    pub fn to_canonical_bytes(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new(Vec::new());
+        encoder
+            .encode(self)
+            .expect("writing to a vec should not fail");
+        encoder.into_inner()
+    }
+
+    /// Appends the canonical encoding of the value to `buf`.
+    pub fn to_bytes_mut(&self, buf: &mut BytesMut) {
+        Encoder::new(buf.writer())
+            .encode(self)
+            .expect("writing to a bytes buffer should not fail");
     }
 
     pub fn from_serialize<T: Serialize>(value: T) -> Result<Self> {
-        value
-            .serialize(Serializer)
-            .context("failed to serialize value to bencode")
+        Self::from_serialize_with(value, &SerializeOptions::default())
+    }
+
+    /// Serializes `value` into a [`BencodeValue`] using the given options.
+    pub fn from_serialize_with<T: Serialize>(value: T, options: &SerializeOptions) -> Result<Self> {
+        value.serialize(Serializer::new(*options))
     }
 
     pub fn into_deserialize<T: for<'de> Deserialize<'de>>(self) -> Result<T> {
-        T::deserialize(self).context("failed to deserialize bencode value into requested type")
+        T::deserialize(self)
     }
 }
 
+/// Deserializes bencoded bytes directly into `T` without building a [`BencodeValue`] tree.
+///
+/// Strings and byte slices in `T` may borrow from `bytes`.
+pub fn from_bytes<'de, T: Deserialize<'de>>(bytes: &'de [u8]) -> Result<T> {
+    let mut deserializer = Deserializer::from_bytes(bytes);
+    let value = T::deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    Ok(value)
+}
+
+/// Serializes `value` directly into bencoded bytes without building a [`BencodeValue`] tree.
+pub fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    to_bytes_with(value, &SerializeOptions::default())
+}
+
+/// Like [`to_bytes`], using the given options.
+pub fn to_bytes_with<T: Serialize + ?Sized>(
+    value: &T,
+    options: &SerializeOptions,
+) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    value.serialize(BytesSerializer::new(&mut buf, *options))?;
+    Ok(buf)
+}
+
 impl Serialize for BencodeValue {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -73,7 +151,7 @@ impl Serialize for BencodeValue {
     {
         match self {
             BencodeValue::String(value) => value.serialize(serializer),
-            BencodeValue::Integer(n) => serializer.serialize_i64(*n),
+            BencodeValue::Integer(n) => ser::serialize_integer(*n, serializer),
             BencodeValue::List(l) => {
                 let mut s = serializer.serialize_seq(Some(l.len()))?;
                 for e in l.iter() {
@@ -84,7 +162,7 @@ impl Serialize for BencodeValue {
             BencodeValue::Dict(d) => {
                 let mut s = serializer.serialize_map(Some(d.len()))?;
                 for (k, v) in d.iter() {
-                    s.serialize_entry(k, v)?;
+                    s.serialize_entry(&DictKey(k), v)?;
                 }
                 s.end()
             }
@@ -92,40 +170,19 @@ impl Serialize for BencodeValue {
     }
 }
 
-peg::parser! {
-    grammar bencode_parser() for [u8] {
-        use bstr::ByteVec;
-
-        pub rule value() -> BencodeValue
-            = s:bstring() { BencodeValue::String(s) }
-            / n:binteger() { BencodeValue::Integer(n) }
-            / l:blist() { BencodeValue::List(l) }
-            / d:bdict() { BencodeValue::Dict(d) }
-
-        /// Binary encoded string (`n:<some-content>`).
-        rule bstring() -> BString = n:integer() ":" value:$([_]*<{n as usize}>) { BString::from(value) }
-        /// Binary encoded integer (`d:<some-whole-number>e`).
-        rule binteger() -> i64 = "i" sign:[b'-']? n:integer() "e" { sign.map(|_| -(n as i64)).unwrap_or(n as i64)}
-        /// Binary encoded list of bencode values (`l<values-without-separators>e`).
-        rule blist() -> Box<[BencodeValue]> = "l" l:value()* "e" { Box::from(l) }
-        /// Binary encoded dictionary (`d<key-value-pairs>e`)
-        rule bdict() -> BTreeMap<String, BencodeValue> = "d" kvs:(
-            k:bstring() v:value() {?
-                Vec::from(k).into_string().map(|k| (k, v)).or(Err("valid utf-8 dict key"))
-            }
-        )* "e" {
-            BTreeMap::from_iter(kvs)
-        }
+/// Dict key serialized as text when it is valid UTF-8, so formats requiring string keys (e.g.
+/// json) keep working, and as bytes otherwise.
+struct DictKey<'a>(&'a BString);
 
-        /// Unsigned natural number.
-        rule integer() -> u64 = n:$((non_zero_digit() digit()*) / digit()) {?
-            std::str::from_utf8(n).map_err(|_| ())
-                .and_then(|n| n.parse().map_err(|_| ()))
-                .or(Err("unsigned 64 bit integer"))
+impl Serialize for DictKey<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self.0.to_str() {
+            Ok(key) => serializer.serialize_str(key),
+            Err(_) => serializer.serialize_bytes(self.0),
         }
-
-        rule non_zero_digit() -> u8 = quiet! { [c if c.is_ascii_digit() && c != b'0'] } / expected!("non-zero ascii digit")
-        rule digit() -> u8 = quiet! { [c if c.is_ascii_digit()] } / expected!("ascii digit")
     }
 }
 
@@ -177,32 +234,29 @@ mod tests {
 
             assert_eq!(
                 value0,
-                BencodeValue::List(Box::from([
+                BencodeValue::List(vec![
                     BencodeValue::String("spam".into()),
                     BencodeValue::Integer(42)
-                ]))
+                ])
             );
             assert_eq!(
                 value1,
-                BencodeValue::List(Box::from([BencodeValue::String("spam".into()),]))
-            );
-            assert_eq!(
-                value2,
-                BencodeValue::List(Box::from([BencodeValue::Integer(42),]))
+                BencodeValue::List(vec![BencodeValue::String("spam".into()),])
             );
-            assert_eq!(value3, BencodeValue::List(Box::from([])));
+            assert_eq!(value2, BencodeValue::List(vec![BencodeValue::Integer(42),]));
+            assert_eq!(value3, BencodeValue::List(Vec::new()));
             assert_eq!(
                 value4,
-                BencodeValue::List(Box::from([BencodeValue::List(Box::from([
-                    BencodeValue::List(Box::from([BencodeValue::List(Box::from([]))]))
-                ]))]))
+                BencodeValue::List(vec![BencodeValue::List(vec![BencodeValue::List(vec![
+                    BencodeValue::List(Vec::new())
+                ])])])
             );
             assert_eq!(
                 value5,
-                BencodeValue::List(Box::from([BencodeValue::List(Box::from([
+                BencodeValue::List(vec![BencodeValue::List(vec![
                     BencodeValue::Integer(-42),
-                    BencodeValue::List(Box::from([BencodeValue::List(Box::from([]))]))
-                ]))]))
+                    BencodeValue::List(vec![BencodeValue::List(Vec::new())])
+                ])])
             );
         }
 
@@ -262,19 +316,294 @@ mod tests {
                 value5,
                 BencodeValue::Dict(BTreeMap::from([(
                     "e".into(),
-                    BencodeValue::List(Box::from([
+                    BencodeValue::List(vec![
                         BencodeValue::String("bar".into()),
                         BencodeValue::Dict(BTreeMap::from([(
                             "e".into(),
                             BencodeValue::Integer(-1008)
                         )]))
-                    ]))
+                    ])
                 ),]))
             );
         }
+
+        #[test]
+        fn bdict_binary_keys() {
+            let input = b"d4:spami2e2:\xff\xfei1ee";
+            let value = BencodeValue::try_from_bytes(input).unwrap();
+
+            assert_eq!(
+                value,
+                BencodeValue::Dict(BTreeMap::from([
+                    (BString::from(&b"\xff\xfe"[..]), BencodeValue::Integer(1)),
+                    ("spam".into(), BencodeValue::Integer(2))
+                ]))
+            );
+            assert_eq!(value.to_byte_string().unwrap(), input.as_slice());
+            assert_eq!(to_bytes(&value).unwrap(), input);
+            assert_eq!(from_bytes::<BencodeValue>(input).unwrap(), value);
+        }
+    }
+
+    mod large_integers {
+        use super::*;
+
+        #[test]
+        fn beyond_i64() {
+            let input = b"li18446744073709551615ei-18446744073709551615ei9223372036854775808ee";
+            let value = BencodeValue::try_from_bytes(input).unwrap();
+
+            assert_eq!(
+                value,
+                BencodeValue::List(vec![
+                    BencodeValue::Integer(u64::MAX.into()),
+                    BencodeValue::Integer(-i128::from(u64::MAX)),
+                    BencodeValue::Integer(i128::from(i64::MAX) + 1),
+                ])
+            );
+            assert_eq!(value.to_byte_string().unwrap(), input.as_slice());
+            assert_eq!(to_bytes(&value).unwrap(), input);
+            assert_eq!(value.get_index(0).unwrap().as_u64(), Some(u64::MAX));
+            assert_eq!(value.get_index(0).unwrap().as_int(), None);
+
+            let (max, _, _): (u64, i128, u64) = value.clone().into_deserialize().unwrap();
+            assert_eq!(max, u64::MAX);
+            assert_eq!(from_bytes::<(u64, i128, u64)>(input).unwrap().0, u64::MAX);
+            assert!(from_bytes::<(i64, i128, u64)>(input).is_err());
+            assert_eq!(from_bytes::<BencodeValue>(input).unwrap(), value);
+            assert_eq!(
+                BencodeValue::from_serialize(u64::MAX).unwrap(),
+                value.as_list().unwrap()[0]
+            );
+        }
+
+        #[test]
+        fn beyond_i128() {
+            assert!(
+                BencodeValue::try_from_bytes(b"i170141183460469231731687303715884105728e").is_err()
+            );
+        }
+    }
+
+    mod parse_prefix {
+        use super::*;
+
+        #[test]
+        fn trailing_bytes() {
+            let value0 = BencodeValue::parse_prefix(b"6:foobar").unwrap();
+            let value1 = BencodeValue::parse_prefix(b"i42etrailing").unwrap();
+            let value2 = BencodeValue::parse_prefix(b"l4:spamei1e").unwrap();
+            let value3 = BencodeValue::parse_prefix(b"d4:spam3:fooe\x00\xff\x13").unwrap();
+
+            assert_eq!(value0, (BencodeValue::String("foobar".into()), 8));
+            assert_eq!(value1, (BencodeValue::Integer(42), 4));
+            assert_eq!(
+                value2,
+                (
+                    BencodeValue::List(vec![BencodeValue::String("spam".into())]),
+                    8
+                )
+            );
+            assert_eq!(
+                value3,
+                (
+                    BencodeValue::Dict(BTreeMap::from([(
+                        "spam".into(),
+                        BencodeValue::String("foo".into())
+                    )])),
+                    13
+                )
+            );
+        }
+
+        #[test]
+        fn rejects_invalid_prefix() {
+            assert!(BencodeValue::parse_prefix(b"").is_err());
+            assert!(BencodeValue::parse_prefix(b"x6:foobar").is_err());
+            assert!(BencodeValue::parse_prefix(b"l4:spam").is_err());
+        }
+    }
+
+    mod parse_options {
+        use super::*;
+
+        #[test]
+        fn default_options() {
+            let value0 = BencodeValue::try_from_bytes(b"i-0e").unwrap();
+            let value1 = BencodeValue::try_from_bytes(b"d1:bi1e1:ai2ee").unwrap();
+            let value2 = BencodeValue::try_from_bytes(b"d1:ai1e1:ai2ee").unwrap();
+
+            assert_eq!(value0, BencodeValue::Integer(0));
+            assert_eq!(
+                value1,
+                BencodeValue::Dict(BTreeMap::from([
+                    ("a".into(), BencodeValue::Integer(2)),
+                    ("b".into(), BencodeValue::Integer(1))
+                ]))
+            );
+            assert_eq!(
+                value2,
+                BencodeValue::Dict(BTreeMap::from([("a".into(), BencodeValue::Integer(2))]))
+            );
+            assert!(BencodeValue::try_from_bytes(b"i042e").is_err());
+            assert!(BencodeValue::try_from_bytes(b"03:foo").is_err());
+        }
+
+        #[test]
+        fn strict_options() {
+            let strict = ParseOptions::strict();
+
+            assert!(BencodeValue::try_from_bytes_with(b"i-0e", &strict).is_err());
+            assert!(BencodeValue::try_from_bytes_with(b"i042e", &strict).is_err());
+            assert!(BencodeValue::try_from_bytes_with(b"d1:bi1e1:ai2ee", &strict).is_err());
+            assert!(BencodeValue::try_from_bytes_with(b"d1:ai1e1:ai2ee", &strict).is_err());
+            assert!(BencodeValue::try_from_bytes_with(b"ld1:bi1e1:ai2eee", &strict).is_err());
+            assert!(BencodeValue::try_from_bytes_with(b"d1:ai1e1:bi2ee", &strict).is_ok());
+            assert!(BencodeValue::try_from_bytes_with(b"i0e", &strict).is_ok());
+        }
+
+        #[test]
+        fn duplicate_keys() {
+            let input = b"d1:ai1e1:bi2e1:ai3ee";
+            let parse = |duplicate_keys| {
+                let options = ParseOptions {
+                    duplicate_keys,
+                    ..Default::default()
+                };
+                BencodeValue::try_from_bytes_with(input, &options)
+                    .map(|v| v.get("a").and_then(BencodeValue::as_int))
+            };
+
+            assert_eq!(parse(DuplicateKeyPolicy::FirstWins), Ok(Some(1)));
+            assert_eq!(parse(DuplicateKeyPolicy::LastWins), Ok(Some(3)));
+            let err = parse(DuplicateKeyPolicy::Error).unwrap_err();
+            assert_eq!(err.offset, 13);
+            assert_eq!(err.expected, ["unique dict keys"]);
+        }
+
+        #[test]
+        fn leading_zeros_allowed() {
+            let options = ParseOptions {
+                reject_leading_zeros: false,
+                ..Default::default()
+            };
+
+            assert_eq!(
+                BencodeValue::try_from_bytes_with(b"i042e", &options).unwrap(),
+                BencodeValue::Integer(42)
+            );
+            assert_eq!(
+                BencodeValue::try_from_bytes_with(b"03:foo", &options).unwrap(),
+                BencodeValue::String("foo".into())
+            );
+        }
+
+        #[test]
+        fn lenient() {
+            let input = b"d 8:intervali01800e5:peers0:4:minii-0e8:completei1e8:completei2e\r\n e\n";
+            let (value, warnings) = BencodeValue::try_from_bytes_lenient(input).unwrap();
+
+            assert_eq!(
+                value,
+                BencodeValue::try_from_bytes(b"d8:completei2e8:intervali1800e4:minii0e5:peers0:e")
+                    .unwrap()
+            );
+            assert_eq!(
+                warnings.iter().map(|w| w.kind).collect::<Vec<_>>(),
+                [
+                    WarningKind::Whitespace,
+                    WarningKind::LeadingZeros,
+                    WarningKind::UnsortedKey,
+                    WarningKind::NegativeZero,
+                    WarningKind::UnsortedKey,
+                    WarningKind::DuplicateKey,
+                    WarningKind::Whitespace,
+                    WarningKind::Whitespace,
+                ]
+            );
+            assert_eq!(
+                warnings[0].to_string(),
+                "whitespace between values at byte offset 1"
+            );
+            assert!(BencodeValue::try_from_bytes(input).is_err());
+            assert!(BencodeValue::try_from_bytes_lenient(b"i1 2e").is_err());
+            assert!(BencodeValue::try_from_bytes_lenient(b"1 :a").is_err());
+        }
+    }
+
+    mod parse_limits {
+        use super::*;
+
+        #[test]
+        fn depth() {
+            let nested = [&[b'l'; 65][..], &[b'e'; 65][..]].concat();
+
+            let err = BencodeValue::try_from_bytes(&nested).unwrap_err();
+            assert_eq!(err.offset, 65);
+            assert_eq!(err.expected, ["nesting depth within limit"]);
+            assert!(BencodeValue::try_from_bytes(&nested[1..nested.len() - 1]).is_ok());
+            assert!(BencodeValue::try_from_bytes_with(&nested, &ParseOptions::unlimited()).is_ok());
+        }
+
+        #[test]
+        fn string_length() {
+            let options = ParseOptions {
+                max_string_length: 3,
+                ..Default::default()
+            };
+
+            assert!(BencodeValue::try_from_bytes_with(b"3:foo", &options).is_ok());
+            let err = BencodeValue::try_from_bytes_with(b"l4:spame", &options).unwrap_err();
+            assert!(err.expected.contains(&"string length within limit"));
+        }
+
+        #[test]
+        fn elements() {
+            let options = ParseOptions {
+                max_elements: 3,
+                ..Default::default()
+            };
+
+            assert!(BencodeValue::try_from_bytes_with(b"li1ei2ee", &options).is_ok());
+            assert!(BencodeValue::try_from_bytes_with(b"li1ei2ei3ee", &options).is_err());
+            assert!(SpannedValue::try_from_bytes_with(b"d1:ai1e1:bi2e1:ci3ee", &options).is_err());
+        }
+    }
+
+    mod parse_error {
+        use super::*;
+
+        #[test]
+        fn offset_and_expected() {
+            let err0 = BencodeValue::try_from_bytes(b"i42").unwrap_err();
+            let err1 = BencodeValue::try_from_bytes(b"x").unwrap_err();
+
+            assert_eq!(err0.offset, 3);
+            assert_eq!(err0.expected, ["\"e\"", "ascii digit"]);
+            assert!(err0.path.is_empty());
+            assert_eq!(err1.offset, 0);
+            assert!(err1.expected.contains(&"\"i\""));
+        }
+
+        #[test]
+        fn context_path() {
+            let err = BencodeValue::try_from_bytes(
+                b"d4:infod5:filesld6:lengthi1eed6:lengthi2eed4:pathl1:xi3xeeeee",
+            )
+            .unwrap_err();
+
+            assert_eq!(err.offset, 55);
+            assert_eq!(err.path.to_string(), "info.files[2].path[1]");
+            assert_eq!(
+                err.to_string(),
+                "expected one of \"e\", ascii digit at byte offset 55 (in `info.files[2].path[1]`)"
+            );
+        }
     }
 
     mod to_byte_string {
+        use std::collections::HashMap;
+
         use super::*;
         use bstr::B;
 
@@ -310,20 +639,21 @@ mod tests {
 
         #[test]
         fn blist() {
-            let value0 = BencodeValue::List(Box::from([
+            let value0 = BencodeValue::List(vec![
                 BencodeValue::String("spam".into()),
                 BencodeValue::Integer(42),
-            ]));
-            let value1 = BencodeValue::List(Box::from([BencodeValue::String("spam".into())]));
-            let value2 = BencodeValue::List(Box::from([BencodeValue::Integer(42)]));
-            let value3 = BencodeValue::List(Box::from([]));
-            let value4 = BencodeValue::List(Box::from([BencodeValue::List(Box::from([
-                BencodeValue::List(Box::from([BencodeValue::List(Box::from([]))])),
-            ]))]));
-            let value5 = BencodeValue::List(Box::from([BencodeValue::List(Box::from([
+            ]);
+            let value1 = BencodeValue::List(vec![BencodeValue::String("spam".into())]);
+            let value2 = BencodeValue::List(vec![BencodeValue::Integer(42)]);
+            let value3 = BencodeValue::List(Vec::new());
+            let value4 =
+                BencodeValue::List(vec![BencodeValue::List(vec![BencodeValue::List(vec![
+                    BencodeValue::List(Vec::new()),
+                ])])]);
+            let value5 = BencodeValue::List(vec![BencodeValue::List(vec![
                 BencodeValue::Integer(-42),
-                BencodeValue::List(Box::from([BencodeValue::List(Box::from([]))])),
-            ]))]));
+                BencodeValue::List(vec![BencodeValue::List(Vec::new())]),
+            ])]);
 
             assert_eq!(value0.to_byte_string().unwrap(), B(b"l4:spami42ee"));
             assert_eq!(value1.to_byte_string().unwrap(), B(b"l4:spame"));
@@ -366,13 +696,13 @@ mod tests {
             )]));
             let value5 = BencodeValue::Dict(BTreeMap::from([(
                 "e".into(),
-                BencodeValue::List(Box::from([
+                BencodeValue::List(vec![
                     BencodeValue::String("bar".into()),
                     BencodeValue::Dict(BTreeMap::from([(
                         "e".into(),
                         BencodeValue::Integer(-1008),
                     )])),
-                ])),
+                ]),
             )]));
 
             assert_eq!(value0.to_byte_string().unwrap(), B(b"d4:spam3:fooe"));
@@ -388,5 +718,26 @@ mod tests {
                 B(b"d1:el3:bard1:ei-1008eeee")
             );
         }
+
+        #[test]
+        fn canonical() {
+            let options = ParseOptions {
+                reject_leading_zeros: false,
+                ..Default::default()
+            };
+            let value = BencodeValue::try_from_bytes_with(
+                b"d1:zi1e2:\xc3\xa91:x1:\xffi-0e1:Ai042ee",
+                &options,
+            )
+            .unwrap();
+            let expected = b"d1:Ai42e1:zi1e2:\xc3\xa91:x1:\xffi0ee";
+
+            assert_eq!(B(&value.to_canonical_bytes()), B(expected));
+            assert_eq!(B(&to_bytes(&value).unwrap()), B(expected));
+            assert_eq!(
+                B(&to_bytes(&HashMap::from([("z", 1), ("A", 42), ("é", 0)])).unwrap()),
+                B(b"d1:Ai42e1:zi1e2:\xc3\xa9i0ee")
+            );
+        }
     }
 }
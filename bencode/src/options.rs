@@ -0,0 +1,185 @@
+use std::{
+    cell::Cell,
+    collections::{btree_map::Entry, BTreeMap},
+};
+
+/// Controls which deviations from the bencode specification the parser accepts, and how much
+/// input it is willing to process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Reject integers and string lengths with leading zeros (`i042e`, `03:foo`).
+    pub reject_leading_zeros: bool,
+    /// Reject negative zero (`i-0e`).
+    pub reject_negative_zero: bool,
+    /// Reject dicts whose keys are not sorted by their raw bytes.
+    pub reject_unsorted_keys: bool,
+    /// Skip ASCII whitespace between values, as sent by some buggy trackers.
+    pub skip_whitespace: bool,
+    /// How a dict containing the same key more than once is handled.
+    pub duplicate_keys: DuplicateKeyPolicy,
+    /// Maximum nesting of lists and dicts.
+    pub max_depth: usize,
+    /// Maximum length of a single string in bytes.
+    pub max_string_length: usize,
+    /// Maximum amount of values in the whole document.
+    pub max_elements: usize,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            reject_leading_zeros: true,
+            reject_negative_zero: false,
+            reject_unsorted_keys: false,
+            skip_whitespace: false,
+            duplicate_keys: DuplicateKeyPolicy::LastWins,
+            max_depth: 64,
+            max_string_length: 64 << 20,
+            max_elements: 1 << 22,
+        }
+    }
+}
+
+impl ParseOptions {
+    /// Options rejecting every encoding that is not spec-compliant.
+    pub fn strict() -> Self {
+        Self {
+            reject_leading_zeros: true,
+            reject_negative_zero: true,
+            reject_unsorted_keys: true,
+            duplicate_keys: DuplicateKeyPolicy::Error,
+            ..Self::default()
+        }
+    }
+
+    /// Options accepting common real-world deviations from the specification. Use
+    /// [`BencodeValue::try_from_bytes_lenient`](crate::BencodeValue::try_from_bytes_lenient) to
+    /// find out which ones were encountered.
+    pub fn lenient() -> Self {
+        Self {
+            reject_leading_zeros: false,
+            reject_negative_zero: false,
+            reject_unsorted_keys: false,
+            skip_whitespace: true,
+            duplicate_keys: DuplicateKeyPolicy::LastWins,
+            ..Self::default()
+        }
+    }
+
+    /// Options without any limits, for trusted input.
+    pub fn unlimited() -> Self {
+        Self {
+            max_depth: usize::MAX,
+            max_string_length: usize::MAX,
+            max_elements: usize::MAX,
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn check_number(&self, digits: &[u8]) -> Result<(), &'static str> {
+        if self.reject_leading_zeros && digits.len() > 1 && digits[0] == b'0' {
+            return Err("number without leading zeros");
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_integer(&self, negative: bool, n: u128) -> Result<(), &'static str> {
+        if self.reject_negative_zero && negative && n == 0 {
+            return Err("non-negative zero");
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_depth(&self, depth: usize) -> Result<(), &'static str> {
+        if depth > self.max_depth {
+            return Err("nesting depth within limit");
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_string_length(&self, length: u128) -> Result<usize, &'static str> {
+        usize::try_from(length)
+            .ok()
+            .filter(|length| *length <= self.max_string_length)
+            .ok_or("string length within limit")
+    }
+
+    /// Accounts for one more parsed value in `elements`.
+    pub(crate) fn count_element(&self, elements: &Cell<usize>) -> Result<(), &'static str> {
+        let count = elements.get() + 1;
+        if count > self.max_elements {
+            return Err("element count within limit");
+        }
+        elements.set(count);
+        Ok(())
+    }
+
+    pub(crate) fn check_dict_keys<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Result<(), &'static str> {
+        let mut previous = None;
+        for key in keys {
+            match previous.map(|p: &[u8]| p.cmp(key)) {
+                Some(std::cmp::Ordering::Greater) if self.reject_unsorted_keys => {
+                    return Err("sorted dict keys");
+                }
+                _ => (),
+            }
+            previous = Some(key);
+        }
+        Ok(())
+    }
+}
+
+/// Which value is kept when a dict contains the same key more than once.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateKeyPolicy {
+    /// Keep the first value, as libtorrent does.
+    FirstWins,
+    /// Keep the last value.
+    #[default]
+    LastWins,
+    /// Reject the dict.
+    Error,
+}
+
+impl DuplicateKeyPolicy {
+    /// Inserts an entry into a dict being parsed, resolving a repeated key according to the
+    /// policy.
+    pub(crate) fn insert<K: Ord, V>(
+        self,
+        dict: &mut BTreeMap<K, V>,
+        key: K,
+        value: V,
+    ) -> Result<(), &'static str> {
+        match dict.entry(key) {
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+            }
+            Entry::Occupied(mut entry) => match self {
+                DuplicateKeyPolicy::FirstWins => (),
+                DuplicateKeyPolicy::LastWins => {
+                    entry.insert(value);
+                }
+                DuplicateKeyPolicy::Error => return Err("unique dict keys"),
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Controls how values are serialized into bencode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SerializeOptions {
+    /// Leave out dict entries and struct fields that are `None`, instead of failing. Bencode has
+    /// no null value.
+    pub skip_none: bool,
+}
+
+impl SerializeOptions {
+    /// Options leaving out `None` fields, matching `#[serde(skip_serializing_if = "Option::is_none")]`.
+    pub fn skip_none() -> Self {
+        Self { skip_none: true }
+    }
+}
@@ -0,0 +1,440 @@
+use std::{cell::Cell, collections::BTreeMap, ops::Range};
+
+use bstr::BStr;
+
+use super::{
+    error::ParseError, options::ParseOptions, BencodeValueRef, SpannedKind, SpannedValue, Warning,
+    WarningKind,
+};
+
+#[cfg(test)]
+pub(crate) mod reference;
+pub(crate) mod token;
+
+const VALUE: &[&str] = &["\"d\"", "\"i\"", "\"l\"", "ascii digit"];
+const LIST_ITEM: &[&str] = &["\"d\"", "\"e\"", "\"i\"", "\"l\"", "ascii digit"];
+const DICT_KEY: &[&str] = &["\"e\"", "ascii digit"];
+const STRING_COLON: &[&str] = &["\":\"", "ascii digit"];
+const INTEGER_END: &[&str] = &["\"e\"", "ascii digit"];
+const INTEGER_START: &[&str] = &["\"-\"", "ascii digit"];
+
+/// Tree the parser builds from the parsed values.
+pub(crate) trait Node<'a>: Sized {
+    fn string(s: &'a BStr, span: Range<usize>) -> Self;
+    fn integer(n: i128, span: Range<usize>) -> Self;
+    fn list(l: Vec<Self>, span: Range<usize>) -> Self;
+    fn dict(d: BTreeMap<&'a BStr, Self>, span: Range<usize>) -> Self;
+}
+
+impl<'a> Node<'a> for BencodeValueRef<'a> {
+    fn string(s: &'a BStr, _span: Range<usize>) -> Self {
+        BencodeValueRef::String(s)
+    }
+
+    fn integer(n: i128, _span: Range<usize>) -> Self {
+        BencodeValueRef::Integer(n)
+    }
+
+    fn list(l: Vec<Self>, _span: Range<usize>) -> Self {
+        BencodeValueRef::List(l)
+    }
+
+    fn dict(d: BTreeMap<&'a BStr, Self>, _span: Range<usize>) -> Self {
+        BencodeValueRef::Dict(d)
+    }
+}
+
+impl<'a> Node<'a> for SpannedValue<'a> {
+    fn string(s: &'a BStr, span: Range<usize>) -> Self {
+        SpannedValue {
+            span,
+            kind: SpannedKind::String(s),
+        }
+    }
+
+    fn integer(n: i128, span: Range<usize>) -> Self {
+        SpannedValue {
+            span,
+            kind: SpannedKind::Integer(n),
+        }
+    }
+
+    fn list(l: Vec<Self>, span: Range<usize>) -> Self {
+        SpannedValue {
+            span,
+            kind: SpannedKind::List(l),
+        }
+    }
+
+    fn dict(d: BTreeMap<&'a BStr, Self>, span: Range<usize>) -> Self {
+        SpannedValue {
+            span,
+            kind: SpannedKind::Dict(d),
+        }
+    }
+}
+
+/// Recursive descent parser borrowing strings from its input.
+pub(crate) struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+    options: ParseOptions,
+    elements: Cell<usize>,
+    warnings: Vec<Warning>,
+}
+
+impl<'a> Parser<'a> {
+    pub(crate) fn new(input: &'a [u8], options: &ParseOptions) -> Self {
+        Self {
+            input,
+            pos: 0,
+            options: *options,
+            elements: Cell::new(0),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Parses a single value spanning the whole input.
+    pub(crate) fn parse<T: Node<'a>>(self) -> Result<T, ParseError> {
+        self.parse_with_warnings().map(|(value, _)| value)
+    }
+
+    /// Like [`Parser::parse`], also returning the deviations from the specification the options
+    /// allowed.
+    pub(crate) fn parse_with_warnings<T: Node<'a>>(
+        mut self,
+    ) -> Result<(T, Vec<Warning>), ParseError> {
+        let value = self.value(0, VALUE)?;
+        if self.peek_token().is_some() {
+            return Err(self.expected(&["EOF"]));
+        }
+        Ok((value, self.warnings))
+    }
+
+    /// Parses the first value in the input, returning it with the amount of bytes consumed.
+    pub(crate) fn parse_prefix<T: Node<'a>>(mut self) -> Result<(T, usize), ParseError> {
+        let value = self.value(0, VALUE)?;
+        Ok((value, self.pos))
+    }
+
+    fn value<T: Node<'a>>(
+        &mut self,
+        depth: usize,
+        expected: &'static [&'static str],
+    ) -> Result<T, ParseError> {
+        let next = self.peek_token();
+        let start = self.pos;
+        let value = match next {
+            Some(b'0'..=b'9') => {
+                let s = self.string()?;
+                T::string(s, start..self.pos)
+            }
+            Some(b'i') => {
+                let n = self.integer()?;
+                T::integer(n, start..self.pos)
+            }
+            Some(b'l') => {
+                self.enter(depth + 1)?;
+                let mut list = Vec::new();
+                while self.peek_token() != Some(b'e') {
+                    list.push(self.value(depth + 1, LIST_ITEM)?);
+                }
+                self.pos += 1;
+                T::list(list, start..self.pos)
+            }
+            Some(b'd') => {
+                self.enter(depth + 1)?;
+                let dict = self.dict_entries(depth + 1)?;
+                T::dict(dict, start..self.pos)
+            }
+            _ => return Err(self.expected(expected)),
+        };
+        self.options
+            .count_element(&self.elements)
+            .map_err(|e| self.error_at(start, e))?;
+        Ok(value)
+    }
+
+    /// Consumes the opening byte of a container nested at `depth`.
+    fn enter(&mut self, depth: usize) -> Result<(), ParseError> {
+        self.pos += 1;
+        self.options.check_depth(depth).map_err(|e| self.error(e))
+    }
+
+    fn dict_entries<T: Node<'a>>(
+        &mut self,
+        depth: usize,
+    ) -> Result<BTreeMap<&'a BStr, T>, ParseError> {
+        let mut dict = BTreeMap::new();
+        let mut previous: Option<&'a BStr> = None;
+        loop {
+            match self.peek_token() {
+                Some(b'e') => break,
+                Some(b'0'..=b'9') => (),
+                _ => return Err(self.expected(DICT_KEY)),
+            }
+
+            let key_start = self.pos;
+            let key = self.string()?;
+            if let Some(previous) = previous {
+                self.options
+                    .check_dict_keys([previous.as_ref(), key.as_ref()])
+                    .map_err(|e| self.error_at(key_start, e))?;
+                if previous > key {
+                    self.warn(key_start, WarningKind::UnsortedKey);
+                }
+            }
+            previous = Some(key);
+
+            let value = self.value(depth, VALUE)?;
+            if dict.contains_key(key) {
+                self.warn(key_start, WarningKind::DuplicateKey);
+            }
+            self.options
+                .duplicate_keys
+                .insert(&mut dict, key, value)
+                .map_err(|e| self.error_at(key_start, e))?;
+        }
+        self.pos += 1;
+        Ok(dict)
+    }
+
+    /// Binary encoded string (`n:<some-content>`).
+    fn string(&mut self) -> Result<&'a BStr, ParseError> {
+        let n = self.number()?;
+        let len = self
+            .options
+            .check_string_length(n)
+            .map_err(|e| self.error(e))?;
+        self.consume(b':', STRING_COLON)?;
+
+        let start = self.pos;
+        match start
+            .checked_add(len)
+            .filter(|end| *end <= self.input.len())
+        {
+            Some(end) => {
+                self.pos = end;
+                Ok(BStr::new(&self.input[start..end]))
+            }
+            None => Err(self.error_at(self.input.len(), "string contents")),
+        }
+    }
+
+    /// Binary encoded integer (`i<some-whole-number>e`).
+    fn integer(&mut self) -> Result<i128, ParseError> {
+        let start = self.pos;
+        self.pos += 1;
+        let negative = self.peek() == Some(b'-');
+        if negative {
+            self.pos += 1;
+        } else if !self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            return Err(self.expected(INTEGER_START));
+        }
+        let n = self.number()?;
+        self.consume(b'e', INTEGER_END)?;
+
+        self.options
+            .check_integer(negative, n)
+            .map_err(|e| self.error(e))?;
+        if negative && n == 0 {
+            self.warn(start, WarningKind::NegativeZero);
+        }
+        let n = i128::try_from(n).map_err(|_| self.error("integer within 128 bit range"))?;
+        Ok(if negative { -n } else { n })
+    }
+
+    /// Unsigned natural number.
+    fn number(&mut self) -> Result<u128, ParseError> {
+        let start = self.pos;
+        let len = self.input[start..]
+            .iter()
+            .take_while(|b| b.is_ascii_digit())
+            .count();
+        if len == 0 {
+            return Err(self.expected(&["ascii digit"]));
+        }
+        self.pos += len;
+
+        let digits = &self.input[start..self.pos];
+        self.options
+            .check_number(digits)
+            .map_err(|e| self.error(e))?;
+        if digits.len() > 1 && digits[0] == b'0' {
+            self.warn(start, WarningKind::LeadingZeros);
+        }
+        digits
+            .iter()
+            .try_fold(0u128, |n, d| {
+                n.checked_mul(10)?.checked_add(u128::from(d - b'0'))
+            })
+            .ok_or_else(|| self.error("unsigned 128 bit integer"))
+    }
+
+    fn consume(&mut self, byte: u8, expected: &'static [&'static str]) -> Result<(), ParseError> {
+        if self.peek() != Some(byte) {
+            return Err(self.expected(expected));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    /// Peeks at the start of the next value or container end, skipping whitespace when allowed.
+    fn peek_token(&mut self) -> Option<u8> {
+        if self.options.skip_whitespace && self.peek().is_some_and(|b| b.is_ascii_whitespace()) {
+            self.warn(self.pos, WarningKind::Whitespace);
+            while self.peek().is_some_and(|b| b.is_ascii_whitespace()) {
+                self.pos += 1;
+            }
+        }
+        self.peek()
+    }
+
+    fn warn(&mut self, offset: usize, kind: WarningKind) {
+        self.warnings.push(Warning { offset, kind });
+    }
+
+    fn error(&self, expected: &'static str) -> ParseError {
+        self.error_at(self.pos, expected)
+    }
+
+    fn error_at(&self, offset: usize, expected: &'static str) -> ParseError {
+        ParseError::new(self.input, offset, vec![expected])
+    }
+
+    fn expected(&self, expected: &'static [&'static str]) -> ParseError {
+        ParseError::new(self.input, self.pos, expected.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Inputs on which the parser and the reference grammar must agree.
+    const CORPUS: &[&[u8]] = &[
+        b"0:",
+        b"4:spam",
+        b"i0e",
+        b"i-42e",
+        b"i170141183460469231731687303715884105727e",
+        b"le",
+        b"l4:spami42ee",
+        b"lli-42elleeee",
+        b"de",
+        b"d1:ai1e1:bl1:xee",
+        b"d1:bi1e1:ai2ee",
+        b"d1:ai1e1:ai2ee",
+        b"d1:ai1e1:bi2e1:ai3ee",
+        b"d4:infod6:lengthi3e6:pieces3:\xff\x00\x01ee",
+        b"",
+        b"x",
+        b"i42",
+        b"i-e",
+        b"ie",
+        b"i042e",
+        b"i-0e",
+        b"i170141183460469231731687303715884105728e",
+        b"i999999999999999999999999999999999999999999e",
+        b"03:foo",
+        b"4:spa",
+        b"4spam",
+        b"l4:spam",
+        b"l4:spamx",
+        b"d4:spame",
+        b"di1ei2ee",
+        b"d1:a",
+        b"i1ei2e",
+        b"d4:infod5:filesld6:lengthi1eed6:lengthi2eed4:pathl1:xi3xeeeee",
+    ];
+
+    fn options() -> [ParseOptions; 3] {
+        [
+            ParseOptions::default(),
+            ParseOptions::strict(),
+            ParseOptions {
+                max_depth: 1,
+                max_string_length: 2,
+                max_elements: 3,
+                ..ParseOptions::unlimited()
+            },
+        ]
+    }
+
+    #[test]
+    fn matches_reference() {
+        for options in options() {
+            for input in CORPUS {
+                let parsed = Parser::new(input, &options).parse::<SpannedValue>();
+                let expected = reference::spanned(input, &options);
+
+                match (parsed, expected) {
+                    (Ok(parsed), Ok(expected)) => assert_eq!(parsed, expected),
+                    (Err(parsed), Err(expected)) => {
+                        assert_eq!(parsed.path, expected.path, "{:?}", bstr::BStr::new(input));
+                    }
+                    (parsed, expected) => panic!(
+                        "{:?}: parsed {parsed:?}, reference {expected:?}",
+                        bstr::BStr::new(input)
+                    ),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn syntax_errors_match_reference() {
+        let options = ParseOptions::default();
+        for input in CORPUS {
+            let parsed = Parser::new(input, &options).parse::<BencodeValueRef>();
+            let expected = reference::value(input, &options);
+
+            if let (Err(parsed), Err(expected)) = (parsed, expected) {
+                assert_eq!(
+                    (parsed.offset, parsed.path),
+                    (expected.offset, expected.path),
+                    "{:?}",
+                    bstr::BStr::new(input)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn prefix() {
+        let options = ParseOptions::default();
+        let (value, consumed) = Parser::new(b"l4:spamei1e", &options)
+            .parse_prefix::<BencodeValueRef>()
+            .unwrap();
+
+        assert_eq!(
+            value,
+            BencodeValueRef::List(vec![BencodeValueRef::String(BStr::new("spam"))])
+        );
+        assert_eq!(consumed, 8);
+    }
+
+    #[test]
+    fn large_piece_list() {
+        let pieces = vec![0xab; 20 * 50_000];
+        let input = [
+            format!("d6:pieces{}:", pieces.len()).as_bytes(),
+            &pieces,
+            b"e",
+        ]
+        .concat();
+
+        let value = BencodeValueRef::try_from_bytes(&input).unwrap();
+        assert_eq!(
+            value,
+            BencodeValueRef::Dict(BTreeMap::from([(
+                BStr::new("pieces"),
+                BencodeValueRef::String(BStr::new(&pieces))
+            )]))
+        );
+    }
+}
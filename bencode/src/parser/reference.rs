@@ -0,0 +1,114 @@
+//! The original peg grammar, kept as a reference to test the hand-written parser against.
+
+use std::{cell::Cell, collections::BTreeMap};
+
+use bstr::ByteSlice;
+
+use crate::{
+    error::ParseError, options::ParseOptions, path::Path, BencodeValueRef, SpannedKind,
+    SpannedValue,
+};
+
+pub(crate) fn value<'a>(
+    input: &'a [u8],
+    options: &ParseOptions,
+) -> Result<BencodeValueRef<'a>, ParseError> {
+    bencode_parser::value(input, options, &Cell::new(0)).map_err(|err| from_peg(input, err))
+}
+
+pub(crate) fn spanned<'a>(
+    input: &'a [u8],
+    options: &ParseOptions,
+) -> Result<SpannedValue<'a>, ParseError> {
+    bencode_parser::spanned(input, options, &Cell::new(0)).map_err(|err| from_peg(input, err))
+}
+
+fn dict<K: Ord, V>(
+    options: &ParseOptions,
+    kvs: Vec<(K, V)>,
+) -> Result<BTreeMap<K, V>, &'static str> {
+    kvs.into_iter()
+        .try_fold(BTreeMap::new(), |mut dict, (k, v)| {
+            options.duplicate_keys.insert(&mut dict, k, v)?;
+            Ok(dict)
+        })
+}
+
+fn from_peg(input: &[u8], err: peg::error::ParseError<usize>) -> ParseError {
+    let mut expected = err.expected.tokens().collect::<Vec<_>>();
+    expected.sort_unstable();
+    expected.dedup();
+
+    ParseError {
+        offset: err.location,
+        expected,
+        path: Path::at_offset(input, err.location),
+    }
+}
+
+peg::parser! {
+    grammar bencode_parser(options: &ParseOptions, elements: &Cell<usize>) for [u8] {
+        use bstr::BStr;
+
+        pub rule value() -> BencodeValueRef<'input> = nested_value(0)
+        rule nested_value(depth: usize) -> BencodeValueRef<'input> = v:(
+            s:bstring() { BencodeValueRef::String(s) }
+            / n:binteger() { BencodeValueRef::Integer(n) }
+            / l:blist(depth) { BencodeValueRef::List(l) }
+            / d:bdict(depth) { BencodeValueRef::Dict(d) }
+        ) {? options.count_element(elements).map(|_| v) }
+
+        /// Value annotated with the byte range it spans.
+        pub rule spanned() -> SpannedValue<'input> = nested_spanned(0)
+        rule nested_spanned(depth: usize) -> SpannedValue<'input> =
+            start:position!() kind:spanned_kind(depth) end:position!() {?
+                options.count_element(elements).map(|_| SpannedValue { span: start..end, kind })
+            }
+        rule spanned_kind(depth: usize) -> SpannedKind<'input>
+            = s:bstring() { SpannedKind::String(s) }
+            / n:binteger() { SpannedKind::Integer(n) }
+            / "l" within_depth(depth + 1) l:nested_spanned(depth + 1)* "e" { SpannedKind::List(l) }
+            / "d" within_depth(depth + 1) kvs:(
+                k:bstring() v:nested_spanned(depth + 1) { (k, v) }
+            )* "e" {?
+                options.check_dict_keys(kvs.iter().map(|(k, _)| k.as_bytes()))
+                    .and_then(|_| dict(options, kvs))
+                    .map(SpannedKind::Dict)
+            }
+
+        /// Binary encoded string (`n:<some-content>`).
+        rule bstring() -> &'input BStr = n:string_length() ":" value:$([_]*<{n}>) { BStr::new(value) }
+        rule string_length() -> usize = n:integer() {? options.check_string_length(n) }
+        /// Binary encoded integer (`d:<some-whole-number>e`).
+        rule binteger() -> i128 = "i" sign:[b'-']? n:integer() "e" {?
+            options.check_integer(sign.is_some(), n).and_then(|_| {
+                let n = i128::try_from(n).or(Err("integer within 128 bit range"))?;
+                Ok(if sign.is_some() { -n } else { n })
+            })
+        }
+        /// Binary encoded list of bencode values (`l<values-without-separators>e`).
+        rule blist(depth: usize) -> Vec<BencodeValueRef<'input>> =
+            "l" within_depth(depth + 1) l:nested_value(depth + 1)* "e" { l }
+        /// Binary encoded dictionary (`d<key-value-pairs>e`)
+        rule bdict(depth: usize) -> BTreeMap<&'input BStr, BencodeValueRef<'input>> =
+            "d" within_depth(depth + 1) kvs:(
+                k:bstring() v:nested_value(depth + 1) { (k, v) }
+            )* "e" {?
+                options.check_dict_keys(kvs.iter().map(|(k, _)| k.as_bytes()))
+                    .and_then(|_| dict(options, kvs))
+            }
+        /// Fails before descending into a container nested deeper than allowed.
+        rule within_depth(depth: usize) = {? options.check_depth(depth) }
+
+        /// Unsigned natural number.
+        rule integer() -> u128 = n:$(digit()+) {?
+            options.check_number(n).and_then(|_| {
+                std::str::from_utf8(n).map_err(|_| ())
+                    .and_then(|n| n.parse().map_err(|_| ()))
+                    .or(Err("unsigned 128 bit integer"))
+            })
+        }
+
+        rule digit() -> u8 = quiet! { [c if c.is_ascii_digit()] } / expected!("ascii digit")
+    }
+}
@@ -0,0 +1,228 @@
+use std::ops::Range;
+
+use super::{Parser, DICT_KEY, LIST_ITEM, VALUE};
+use crate::{error::ParseError, options::ParseOptions};
+
+/// Event produced by the [`Tokenizer`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Token<'a> {
+    Str(&'a [u8]),
+    Int(i128),
+    ListStart,
+    DictStart,
+    /// End of the innermost list or dict.
+    End,
+}
+
+enum Frame {
+    List,
+    Dict { expect_key: bool },
+}
+
+/// Pull-based parser yielding a stream of [`Token`]s without building a tree.
+///
+/// Dict keys are yielded in input order; the key ordering and duplicate key options are not
+/// applied.
+pub struct Tokenizer<'a> {
+    parser: Parser<'a>,
+    stack: Vec<Frame>,
+    started: bool,
+    finished: bool,
+}
+
+impl<'a> Tokenizer<'a> {
+    pub fn new(input: &'a [u8]) -> Self {
+        Self::with_options(input, &ParseOptions::default())
+    }
+
+    pub fn with_options(input: &'a [u8], options: &ParseOptions) -> Self {
+        Self {
+            parser: Parser::new(input, options),
+            stack: Vec::new(),
+            started: false,
+            finished: false,
+        }
+    }
+
+    /// Byte offset of the next token in the input.
+    pub fn offset(&self) -> usize {
+        self.parser.pos
+    }
+
+    /// Skips over the next value, returning the byte range it spans.
+    ///
+    /// This is used to extract raw values, e.g. the `info` dict of a torrent for hashing.
+    pub fn skip_value(&mut self) -> Result<Range<usize>, ParseError> {
+        let start = self.offset();
+        let mut depth = 0usize;
+        loop {
+            match self.next() {
+                Some(Ok(Token::ListStart | Token::DictStart)) => depth += 1,
+                Some(Ok(Token::End)) if depth == 0 => {
+                    return Err(ParseError::new(self.parser.input, start, VALUE.to_vec()));
+                }
+                Some(Ok(Token::End)) => depth -= 1,
+                Some(Ok(Token::Str(_) | Token::Int(_))) => (),
+                Some(Err(err)) => return Err(err),
+                None => return Err(self.parser.expected(VALUE)),
+            }
+            if depth == 0 {
+                return Ok(start..self.offset());
+            }
+        }
+    }
+
+    fn token(&mut self) -> Result<Option<Token<'a>>, ParseError> {
+        let expected = match self.stack.last_mut() {
+            None if self.started => {
+                if self.parser.peek_token().is_some() {
+                    return Err(self.parser.expected(&["EOF"]));
+                }
+                return Ok(None);
+            }
+            None => VALUE,
+            Some(Frame::List) => LIST_ITEM,
+            Some(Frame::Dict { expect_key }) if *expect_key => {
+                return match self.parser.peek_token() {
+                    Some(b'e') => Ok(Some(self.end())),
+                    Some(b'0'..=b'9') => {
+                        let key = self.parser.string()?;
+                        *expect_key = false;
+                        Ok(Some(Token::Str(key)))
+                    }
+                    _ => Err(self.parser.expected(DICT_KEY)),
+                };
+            }
+            Some(Frame::Dict { expect_key }) => {
+                *expect_key = true;
+                VALUE
+            }
+        };
+        self.started = true;
+
+        let next = self.parser.peek_token();
+        let start = self.parser.pos;
+        let token = match next {
+            Some(b'e') if expected == LIST_ITEM => return Ok(Some(self.end())),
+            Some(b'0'..=b'9') => Token::Str(self.parser.string()?),
+            Some(b'i') => Token::Int(self.parser.integer()?),
+            Some(b'l') => {
+                self.parser.enter(self.stack.len() + 1)?;
+                self.stack.push(Frame::List);
+                Token::ListStart
+            }
+            Some(b'd') => {
+                self.parser.enter(self.stack.len() + 1)?;
+                self.stack.push(Frame::Dict { expect_key: true });
+                Token::DictStart
+            }
+            _ => return Err(self.parser.expected(expected)),
+        };
+        self.parser
+            .options
+            .count_element(&self.parser.elements)
+            .map_err(|e| self.parser.error_at(start, e))?;
+        Ok(Some(token))
+    }
+
+    fn end(&mut self) -> Token<'a> {
+        self.parser.pos += 1;
+        self.stack.pop();
+        Token::End
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = Result<Token<'a>, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        let token = self.token().transpose();
+        if !matches!(token, Some(Ok(_))) {
+            self.finished = true;
+        }
+        token
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens() {
+        let tokens = Tokenizer::new(b"d4:infod6:lengthi3ee4:listl0:i-1eee")
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(
+            tokens,
+            [
+                Token::DictStart,
+                Token::Str(b"info"),
+                Token::DictStart,
+                Token::Str(b"length"),
+                Token::Int(3),
+                Token::End,
+                Token::Str(b"list"),
+                Token::ListStart,
+                Token::Str(b""),
+                Token::Int(-1),
+                Token::End,
+                Token::End,
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let errors = [&b"di1ei2ee"[..], b"l4:spam", b"i1ei2e", b"e", b"d1:ae"]
+            .map(|input| Tokenizer::new(input).find_map(Result::err));
+
+        assert_eq!(errors[0].as_ref().map(|e| e.offset), Some(1));
+        assert_eq!(errors[1].as_ref().map(|e| e.offset), Some(7));
+        assert_eq!(
+            errors[2].as_ref().map(|e| e.expected.clone()),
+            Some(vec!["EOF"])
+        );
+        assert_eq!(errors[3].as_ref().map(|e| e.offset), Some(0));
+        assert_eq!(errors[4].as_ref().map(|e| e.offset), Some(4));
+    }
+
+    #[test]
+    fn limits() {
+        let options = ParseOptions {
+            max_depth: 1,
+            ..Default::default()
+        };
+
+        assert!(Tokenizer::with_options(b"ll", &options).any(|t| t.is_err()));
+        assert!(Tokenizer::with_options(b"li1ee", &options).all(|t| t.is_ok()));
+    }
+
+    #[test]
+    fn extract_info() {
+        let input = b"d8:announce3:url4:infod6:lengthi3e6:pieces3:abce3:zzzi1ee";
+        let mut tokenizer = Tokenizer::new(input);
+
+        let mut info = None;
+        assert_eq!(tokenizer.next(), Some(Ok(Token::DictStart)));
+        while let Some(token) = tokenizer.next() {
+            match token.unwrap() {
+                Token::Str(b"info") => info = Some(tokenizer.skip_value().unwrap()),
+                Token::Str(_) => {
+                    tokenizer.skip_value().unwrap();
+                }
+                _ => (),
+            }
+        }
+
+        assert_eq!(
+            info.map(|span| &input[span]),
+            Some(&b"d6:lengthi3e6:pieces3:abce"[..])
+        );
+    }
+}
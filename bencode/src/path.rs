@@ -0,0 +1,178 @@
+use bstr::BString;
+use derive_more::{Deref, From};
+
+/// Single step into a nested bencode value.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PathSegment {
+    Key(BString),
+    Index(usize),
+}
+
+/// Location of a value inside a bencode document (e.g. `info.files[3].path`).
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Deref, From)]
+pub struct Path(Vec<PathSegment>);
+
+impl Path {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, segment: PathSegment) {
+        self.0.push(segment);
+    }
+
+    pub fn pop(&mut self) -> Option<PathSegment> {
+        self.0.pop()
+    }
+
+    /// Returns a copy of this path extended with `segment`.
+    pub fn join(&self, segment: PathSegment) -> Self {
+        let mut path = self.clone();
+        path.push(segment);
+        path
+    }
+
+    /// Determines the path of the value being parsed at `offset` in the bencoded `input`.
+    ///
+    /// Only the structure preceding `offset` is inspected, so the input may be malformed at or
+    /// after the offset.
+    pub(crate) fn at_offset(input: &[u8], offset: usize) -> Self {
+        enum Frame {
+            List(usize),
+            Dict(Option<BString>),
+        }
+
+        fn value_done(stack: &mut [Frame]) {
+            match stack.last_mut() {
+                Some(Frame::List(index)) => *index += 1,
+                Some(Frame::Dict(key)) => *key = None,
+                None => (),
+            }
+        }
+
+        let mut stack = Vec::new();
+        let mut pos = 0;
+        while pos < offset {
+            let Some(&byte) = input.get(pos) else {
+                break;
+            };
+
+            match byte {
+                b'l' => stack.push(Frame::List(0)),
+                b'd' => stack.push(Frame::Dict(None)),
+                b'e' => {
+                    stack.pop();
+                    value_done(&mut stack);
+                }
+                b'i' => {
+                    let Some(end) = input[pos..].iter().position(|b| *b == b'e') else {
+                        break;
+                    };
+                    if pos + end >= offset {
+                        break;
+                    }
+                    pos += end;
+                    value_done(&mut stack);
+                }
+                b'0'..=b'9' => {
+                    let Some(colon) = input[pos..].iter().position(|b| *b == b':') else {
+                        break;
+                    };
+                    let Some(end) = std::str::from_utf8(&input[pos..pos + colon])
+                        .ok()
+                        .and_then(|n| n.parse::<usize>().ok())
+                        .and_then(|n| (pos + colon + 1).checked_add(n))
+                        .filter(|end| *end <= offset)
+                    else {
+                        break;
+                    };
+
+                    match stack.last_mut() {
+                        Some(Frame::Dict(key @ None)) => {
+                            *key = Some(BString::from(&input[pos + colon + 1..end]));
+                        }
+                        _ => value_done(&mut stack),
+                    }
+                    pos = end;
+                    continue;
+                }
+                _ => break,
+            }
+            pos += 1;
+        }
+
+        Self(
+            stack
+                .into_iter()
+                .filter_map(|frame| match frame {
+                    Frame::List(index) => Some(PathSegment::Index(index)),
+                    Frame::Dict(key) => key.map(PathSegment::Key),
+                })
+                .collect(),
+        )
+    }
+}
+
+impl std::fmt::Display for Path {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, segment) in self.0.iter().enumerate() {
+            match segment {
+                PathSegment::Key(key) if i == 0 => write!(f, "{key}")?,
+                PathSegment::Key(key) => write!(f, ".{key}")?,
+                PathSegment::Index(index) => write!(f, "[{index}]")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromIterator<PathSegment> for Path {
+    fn from_iter<T: IntoIterator<Item = PathSegment>>(iter: T) -> Self {
+        Self(Vec::from_iter(iter))
+    }
+}
+
+impl From<&str> for PathSegment {
+    fn from(value: &str) -> Self {
+        Self::Key(value.into())
+    }
+}
+
+impl From<usize> for PathSegment {
+    fn from(value: usize) -> Self {
+        Self::Index(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let path = Path::from(vec!["info".into(), "files".into(), 3.into(), "path".into()]);
+
+        assert_eq!(path.to_string(), "info.files[3].path");
+        assert_eq!(Path::from(vec![1.into(), "a".into()]).to_string(), "[1].a");
+        assert_eq!(Path::new().to_string(), "");
+    }
+
+    #[test]
+    fn at_offset() {
+        let input = b"d4:infod5:filesld6:lengthi1eed6:lengthi2eed4:pathl1:xi3eeeee";
+
+        assert_eq!(Path::at_offset(input, 0), Path::new());
+        assert_eq!(Path::at_offset(input, 7).to_string(), "info");
+        assert_eq!(Path::at_offset(input, 15).to_string(), "info.files");
+        assert_eq!(Path::at_offset(input, 16).to_string(), "info.files[0]");
+        assert_eq!(
+            Path::at_offset(input, 26).to_string(),
+            "info.files[0].length"
+        );
+        assert_eq!(Path::at_offset(input, 49).to_string(), "info.files[2].path");
+        assert_eq!(
+            Path::at_offset(input, 54).to_string(),
+            "info.files[2].path[1]"
+        );
+    }
+}
@@ -1,14 +1,65 @@
-use std::collections::BTreeMap;
+use std::{cell::Cell, collections::BTreeMap};
 
-use anyhow::anyhow;
 use bstr::BString;
 use serde::{ser, Serialize};
 
-use super::{error::Error, BencodeValue};
+use super::{error::Error, BencodeValue, SerializeOptions};
 
-pub(super) struct Serializer;
+pub(crate) use self::bytes::BytesSerializer;
 
-impl ser::Serializer for Serializer {
+mod bytes;
+
+#[derive(Clone, Copy)]
+pub(super) struct Serializer<'a> {
+    options: SerializeOptions,
+    /// Set when the value is a `None` that is left out of its dict.
+    skipped: Option<&'a Cell<bool>>,
+}
+
+impl Serializer<'_> {
+    pub(super) fn new(options: SerializeOptions) -> Self {
+        Self {
+            options,
+            skipped: None,
+        }
+    }
+}
+
+/// Inserts a dict entry, leaving it out when the value is `None` and
+/// [`SerializeOptions::skip_none`] is set.
+fn insert_entry<T>(
+    dict: &mut BTreeMap<BString, BencodeValue>,
+    options: SerializeOptions,
+    key: BString,
+    value: &T,
+) -> Result<(), Error>
+where
+    T: ?Sized + serde::Serialize,
+{
+    let skipped = Cell::new(false);
+    let value = value.serialize(Serializer {
+        options,
+        skipped: options.skip_none.then_some(&skipped),
+    })?;
+    if !skipped.get() {
+        dict.insert(key, value);
+    }
+    Ok(())
+}
+
+/// Serializes an integer using the narrowest serde integer type able to hold it.
+pub(crate) fn serialize_integer<S: ser::Serializer>(
+    n: i128,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match (i64::try_from(n), u64::try_from(n)) {
+        (Ok(n), _) => serializer.serialize_i64(n),
+        (_, Ok(n)) => serializer.serialize_u64(n),
+        _ => serializer.serialize_i128(n),
+    }
+}
+
+impl ser::Serializer for Serializer<'_> {
     type Ok = BencodeValue;
     type Error = Error;
 
@@ -37,6 +88,10 @@ impl ser::Serializer for Serializer {
     }
 
     fn serialize_i64(self, v: i64) -> Result<Self::Ok, Self::Error> {
+        self.serialize_i128(v.into())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<Self::Ok, Self::Error> {
         Ok(BencodeValue::Integer(v))
     }
 
@@ -53,18 +108,22 @@ impl ser::Serializer for Serializer {
     }
 
     fn serialize_u64(self, v: u64) -> Result<Self::Ok, Self::Error> {
-        match i64::try_from(v).ok() {
-            Some(v) => self.serialize_i64(v),
-            None => Err(anyhow!("invalid value: value in u64 cannot be stored in i64").into()),
+        self.serialize_i128(v.into())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<Self::Ok, Self::Error> {
+        match i128::try_from(v).ok() {
+            Some(v) => self.serialize_i128(v),
+            None => Err(Error::integer_overflow(v, &"i128")),
         }
     }
 
     fn serialize_f32(self, _v: f32) -> Result<Self::Ok, Self::Error> {
-        Err(anyhow!("unsupported type: f32").into())
+        Err(Error::unexpected_type("bencode value", "f32"))
     }
 
     fn serialize_f64(self, _v: f64) -> Result<Self::Ok, Self::Error> {
-        Err(anyhow!("unsupported type: f64").into())
+        Err(Error::unexpected_type("bencode value", "f64"))
     }
 
     fn serialize_char(self, v: char) -> Result<Self::Ok, Self::Error> {
@@ -80,22 +139,29 @@ impl ser::Serializer for Serializer {
     }
 
     fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
-        Err(anyhow!("unsupported type: none").into())
+        match self.skipped {
+            Some(skipped) => {
+                skipped.set(true);
+                // Placeholder, the dict drops it.
+                Ok(BencodeValue::List(Vec::new()))
+            }
+            None => Err(Error::unexpected_type("bencode value", "none")),
+        }
     }
 
-    fn serialize_some<T: ?Sized>(self, value: &T) -> Result<Self::Ok, Self::Error>
+    fn serialize_some<T>(self, value: &T) -> Result<Self::Ok, Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
         value.serialize(self)
     }
 
     fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
-        Err(anyhow!("unsupported type: unit").into())
+        Err(Error::unexpected_type("bencode value", "unit"))
     }
 
     fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Self::Error> {
-        Err(anyhow!("unsupported type: unit struct").into())
+        Err(Error::unexpected_type("bencode value", "unit struct"))
     }
 
     fn serialize_unit_variant(
@@ -107,18 +173,18 @@ impl ser::Serializer for Serializer {
         self.serialize_str(variant)
     }
 
-    fn serialize_newtype_struct<T: ?Sized>(
+    fn serialize_newtype_struct<T>(
         self,
         _name: &'static str,
         value: &T,
     ) -> Result<Self::Ok, Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
         value.serialize(self)
     }
 
-    fn serialize_newtype_variant<T: ?Sized>(
+    fn serialize_newtype_variant<T>(
         self,
         _name: &'static str,
         _variant_index: u32,
@@ -126,15 +192,19 @@ impl ser::Serializer for Serializer {
         value: &T,
     ) -> Result<Self::Ok, Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
         let mut dict = BTreeMap::new();
-        dict.insert(variant.to_string(), value.serialize(self)?);
+        dict.insert(
+            variant.into(),
+            value.serialize(Serializer::new(self.options))?,
+        );
         Ok(BencodeValue::Dict(dict))
     }
 
     fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
         Ok(SeqSerializer {
+            options: self.options,
             result: match len {
                 Some(len) => Vec::with_capacity(len),
                 None => Vec::new(),
@@ -144,6 +214,7 @@ impl ser::Serializer for Serializer {
 
     fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Self::Error> {
         Ok(SeqSerializer {
+            options: self.options,
             result: Vec::with_capacity(len),
         })
     }
@@ -154,6 +225,7 @@ impl ser::Serializer for Serializer {
         len: usize,
     ) -> Result<Self::SerializeTupleStruct, Self::Error> {
         Ok(SeqSerializer {
+            options: self.options,
             result: Vec::with_capacity(len),
         })
     }
@@ -166,13 +238,15 @@ impl ser::Serializer for Serializer {
         len: usize,
     ) -> Result<Self::SerializeTupleVariant, Self::Error> {
         Ok(TupleVariantSerializer {
-            name: variant.to_string(),
+            options: self.options,
+            name: variant.into(),
             result: Vec::with_capacity(len),
         })
     }
 
     fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
         Ok(MapSerializer {
+            options: self.options,
             result: BTreeMap::new(),
             next_key: None,
         })
@@ -184,6 +258,7 @@ impl ser::Serializer for Serializer {
         _len: usize,
     ) -> Result<Self::SerializeStruct, Self::Error> {
         Ok(MapSerializer {
+            options: self.options,
             result: BTreeMap::new(),
             next_key: None,
         })
@@ -197,13 +272,15 @@ impl ser::Serializer for Serializer {
         _len: usize,
     ) -> Result<Self::SerializeStructVariant, Self::Error> {
         Ok(StructVariantSerializer {
-            name: variant.to_string(),
+            options: self.options,
+            name: variant.into(),
             result: BTreeMap::new(),
         })
     }
 }
 
 pub(super) struct SeqSerializer {
+    options: SerializeOptions,
     result: Vec<BencodeValue>,
 }
 
@@ -211,16 +288,17 @@ impl ser::SerializeSeq for SeqSerializer {
     type Ok = BencodeValue;
     type Error = Error;
 
-    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
-        self.result.push(value.serialize(Serializer)?);
+        self.result
+            .push(value.serialize(Serializer::new(self.options))?);
         Ok(())
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
-        Ok(BencodeValue::List(self.result.into_boxed_slice()))
+        Ok(BencodeValue::List(self.result))
     }
 }
 
@@ -228,9 +306,9 @@ impl ser::SerializeTuple for SeqSerializer {
     type Ok = BencodeValue;
     type Error = Error;
 
-    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
         ser::SerializeSeq::serialize_element(self, value)
     }
@@ -244,9 +322,9 @@ impl ser::SerializeTupleStruct for SeqSerializer {
     type Ok = BencodeValue;
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
         ser::SerializeSeq::serialize_element(self, value)
     }
@@ -257,7 +335,8 @@ impl ser::SerializeTupleStruct for SeqSerializer {
 }
 
 pub(super) struct TupleVariantSerializer {
-    name: String,
+    options: SerializeOptions,
+    name: BString,
     result: Vec<BencodeValue>,
 }
 
@@ -265,119 +344,115 @@ impl ser::SerializeTupleVariant for TupleVariantSerializer {
     type Ok = BencodeValue;
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
-        self.result.push(value.serialize(Serializer)?);
+        self.result
+            .push(value.serialize(Serializer::new(self.options))?);
         Ok(())
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
         let mut dict = BTreeMap::new();
-        dict.insert(
-            self.name,
-            BencodeValue::List(self.result.into_boxed_slice()),
-        );
+        dict.insert(self.name, BencodeValue::List(self.result));
         Ok(BencodeValue::Dict(dict))
     }
 }
 
 pub(super) struct MapSerializer {
-    result: BTreeMap<String, BencodeValue>,
-    next_key: Option<String>,
+    options: SerializeOptions,
+    result: BTreeMap<BString, BencodeValue>,
+    next_key: Option<BString>,
 }
 
 struct MapKeySerializer;
 
 impl ser::Serializer for MapKeySerializer {
-    type Ok = String;
+    type Ok = BString;
     type Error = Error;
 
-    type SerializeSeq = ser::Impossible<String, Error>;
-    type SerializeTuple = ser::Impossible<String, Error>;
-    type SerializeTupleStruct = ser::Impossible<String, Error>;
-    type SerializeTupleVariant = ser::Impossible<String, Error>;
-    type SerializeMap = ser::Impossible<String, Error>;
-    type SerializeStruct = ser::Impossible<String, Error>;
-    type SerializeStructVariant = ser::Impossible<String, Error>;
+    type SerializeSeq = ser::Impossible<BString, Error>;
+    type SerializeTuple = ser::Impossible<BString, Error>;
+    type SerializeTupleStruct = ser::Impossible<BString, Error>;
+    type SerializeTupleVariant = ser::Impossible<BString, Error>;
+    type SerializeMap = ser::Impossible<BString, Error>;
+    type SerializeStruct = ser::Impossible<BString, Error>;
+    type SerializeStructVariant = ser::Impossible<BString, Error>;
 
     fn serialize_bool(self, v: bool) -> Result<Self::Ok, Self::Error> {
-        Ok(v.to_string())
+        Ok(v.to_string().into())
     }
 
     fn serialize_i8(self, v: i8) -> Result<Self::Ok, Self::Error> {
-        Ok(v.to_string())
+        Ok(v.to_string().into())
     }
 
     fn serialize_i16(self, v: i16) -> Result<Self::Ok, Self::Error> {
-        Ok(v.to_string())
+        Ok(v.to_string().into())
     }
 
     fn serialize_i32(self, v: i32) -> Result<Self::Ok, Self::Error> {
-        Ok(v.to_string())
+        Ok(v.to_string().into())
     }
 
     fn serialize_i64(self, v: i64) -> Result<Self::Ok, Self::Error> {
-        Ok(v.to_string())
+        Ok(v.to_string().into())
     }
 
     fn serialize_u8(self, v: u8) -> Result<Self::Ok, Self::Error> {
-        Ok(v.to_string())
+        Ok(v.to_string().into())
     }
 
     fn serialize_u16(self, v: u16) -> Result<Self::Ok, Self::Error> {
-        Ok(v.to_string())
+        Ok(v.to_string().into())
     }
 
     fn serialize_u32(self, v: u32) -> Result<Self::Ok, Self::Error> {
-        Ok(v.to_string())
+        Ok(v.to_string().into())
     }
 
     fn serialize_u64(self, v: u64) -> Result<Self::Ok, Self::Error> {
-        Ok(v.to_string())
+        Ok(v.to_string().into())
     }
 
     fn serialize_f32(self, _v: f32) -> Result<Self::Ok, Self::Error> {
-        Err(anyhow!("invalid type: f32 not serializable to string").into())
+        Err(Error::unexpected_type("string", "f32"))
     }
 
     fn serialize_f64(self, _v: f64) -> Result<Self::Ok, Self::Error> {
-        Err(anyhow!("invalid type: f64 not serializable to string").into())
+        Err(Error::unexpected_type("string", "f64"))
     }
 
     fn serialize_char(self, v: char) -> Result<Self::Ok, Self::Error> {
-        Ok(v.to_string())
+        Ok(v.to_string().into())
     }
 
     fn serialize_str(self, v: &str) -> Result<Self::Ok, Self::Error> {
-        Ok(v.to_string())
+        Ok(v.into())
     }
 
     fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, Self::Error> {
-        match std::str::from_utf8(v).ok() {
-            Some(v) => Ok(v.to_string()),
-            None => Err(anyhow!("map bytes key are not valid utf-8").into()),
-        }
+        Ok(v.into())
     }
 
     fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
-        Err(anyhow!("invalid value: option none not serializable as string").into())
+        Err(Error::unexpected_type("string", "none"))
     }
 
-    fn serialize_some<T: ?Sized>(self, value: &T) -> Result<Self::Ok, Self::Error>
+    fn serialize_some<T>(self, value: &T) -> Result<Self::Ok, Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
         value.serialize(self)
     }
 
     fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
-        Err(anyhow!("invalid type: unit not serializable as string").into())
+        Err(Error::unexpected_type("string", "unit"))
     }
 
     fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Self::Error> {
-        Err(anyhow!("invalid type: unit struct not serializable as string").into())
+        Err(Error::unexpected_type("string", "unit struct"))
     }
 
     fn serialize_unit_variant(
@@ -386,21 +461,21 @@ impl ser::Serializer for MapKeySerializer {
         _variant_index: u32,
         variant: &'static str,
     ) -> Result<Self::Ok, Self::Error> {
-        Ok(variant.to_string())
+        Ok(variant.into())
     }
 
-    fn serialize_newtype_struct<T: ?Sized>(
+    fn serialize_newtype_struct<T>(
         self,
         _name: &'static str,
         value: &T,
     ) -> Result<Self::Ok, Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
         value.serialize(self)
     }
 
-    fn serialize_newtype_variant<T: ?Sized>(
+    fn serialize_newtype_variant<T>(
         self,
         _name: &'static str,
         _variant_index: u32,
@@ -408,17 +483,17 @@ impl ser::Serializer for MapKeySerializer {
         _value: &T,
     ) -> Result<Self::Ok, Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
-        Err(anyhow!("invalid type: newtype variant not serializable to string").into())
+        Err(Error::unexpected_type("string", "newtype variant"))
     }
 
     fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
-        Err(anyhow!("invalid type: sequence not serializable to string").into())
+        Err(Error::unexpected_type("string", "sequence"))
     }
 
     fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Self::Error> {
-        Err(anyhow!("invalid type: tuple not serializable to string").into())
+        Err(Error::unexpected_type("string", "tuple"))
     }
 
     fn serialize_tuple_struct(
@@ -426,7 +501,7 @@ impl ser::Serializer for MapKeySerializer {
         _name: &'static str,
         _len: usize,
     ) -> Result<Self::SerializeTupleStruct, Self::Error> {
-        Err(anyhow!("invalid type: tuple struct not serializable to string").into())
+        Err(Error::unexpected_type("string", "tuple struct"))
     }
 
     fn serialize_tuple_variant(
@@ -436,11 +511,11 @@ impl ser::Serializer for MapKeySerializer {
         _variant: &'static str,
         _len: usize,
     ) -> Result<Self::SerializeTupleVariant, Self::Error> {
-        Err(anyhow!("invalid type: tuple variant not serializable to string").into())
+        Err(Error::unexpected_type("string", "tuple variant"))
     }
 
     fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
-        Err(anyhow!("invalid type: map not serializable to string").into())
+        Err(Error::unexpected_type("string", "map"))
     }
 
     fn serialize_struct(
@@ -448,7 +523,7 @@ impl ser::Serializer for MapKeySerializer {
         _name: &'static str,
         _len: usize,
     ) -> Result<Self::SerializeStruct, Self::Error> {
-        Err(anyhow!("invalid type: struct not serializable to string").into())
+        Err(Error::unexpected_type("string", "struct"))
     }
 
     fn serialize_struct_variant(
@@ -458,7 +533,7 @@ impl ser::Serializer for MapKeySerializer {
         _variant: &'static str,
         _len: usize,
     ) -> Result<Self::SerializeStructVariant, Self::Error> {
-        Err(anyhow!("invalid type: struct variant not serializable to string").into())
+        Err(Error::unexpected_type("string", "struct variant"))
     }
 }
 
@@ -466,25 +541,23 @@ impl ser::SerializeMap for MapSerializer {
     type Ok = BencodeValue;
     type Error = Error;
 
-    fn serialize_key<T: ?Sized>(&mut self, key: &T) -> Result<(), Self::Error>
+    fn serialize_key<T>(&mut self, key: &T) -> Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
         self.next_key.replace(key.serialize(MapKeySerializer)?);
         Ok(())
     }
 
-    fn serialize_value<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
+    fn serialize_value<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
-        self.result.insert(
-            self.next_key
-                .take()
-                .expect("serialize_value called before serialize_key"),
-            value.serialize(Serializer)?,
-        );
-        Ok(())
+        let key = self
+            .next_key
+            .take()
+            .expect("serialize_value called before serialize_key");
+        insert_entry(&mut self.result, self.options, key, value)
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
@@ -501,19 +574,16 @@ impl ser::SerializeStruct for MapSerializer {
     type Ok = BencodeValue;
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(
-        &mut self,
-        key: &'static str,
-        value: &T,
-    ) -> Result<(), Self::Error>
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
-        self.result.insert(
+        insert_entry(
+            &mut self.result,
+            self.options,
             key.serialize(MapKeySerializer)?,
-            value.serialize(Serializer)?,
-        );
-        Ok(())
+            value,
+        )
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
@@ -522,27 +592,25 @@ impl ser::SerializeStruct for MapSerializer {
 }
 
 pub(super) struct StructVariantSerializer {
-    name: String,
-    result: BTreeMap<String, BencodeValue>,
+    options: SerializeOptions,
+    name: BString,
+    result: BTreeMap<BString, BencodeValue>,
 }
 
 impl ser::SerializeStructVariant for StructVariantSerializer {
     type Ok = BencodeValue;
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(
-        &mut self,
-        key: &'static str,
-        value: &T,
-    ) -> Result<(), Self::Error>
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
-        self.result.insert(
+        insert_entry(
+            &mut self.result,
+            self.options,
             key.serialize(MapKeySerializer)?,
-            value.serialize(Serializer)?,
-        );
-        Ok(())
+            value,
+        )
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
@@ -0,0 +1,568 @@
+use std::{cell::Cell, collections::BTreeMap, io::Write};
+
+use bstr::BString;
+use serde::{ser, Serialize};
+
+use super::MapKeySerializer;
+use crate::{error::Error, SerializeOptions};
+
+/// Serializer writing bencode directly into a byte buffer.
+pub(crate) struct BytesSerializer<'a> {
+    out: &'a mut Vec<u8>,
+    options: SerializeOptions,
+    /// Set when the value is a `None` that is left out of its dict.
+    skipped: Option<&'a Cell<bool>>,
+}
+
+impl<'a> BytesSerializer<'a> {
+    pub(crate) fn new(out: &'a mut Vec<u8>, options: SerializeOptions) -> Self {
+        Self {
+            out,
+            options,
+            skipped: None,
+        }
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, v: &[u8]) {
+    write!(out, "{}:", v.len()).expect("writing to a vec should not fail");
+    out.extend_from_slice(v);
+}
+
+fn write_integer(out: &mut Vec<u8>, v: impl std::fmt::Display) {
+    write!(out, "i{v}e").expect("writing to a vec should not fail");
+}
+
+impl<'a> ser::Serializer for BytesSerializer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    type SerializeSeq = ListSerializer<'a>;
+    type SerializeTuple = ListSerializer<'a>;
+    type SerializeTupleStruct = ListSerializer<'a>;
+    type SerializeTupleVariant = ListSerializer<'a>;
+    type SerializeMap = DictSerializer<'a>;
+    type SerializeStruct = DictSerializer<'a>;
+    type SerializeStructVariant = DictSerializer<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, Self::Error> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, Self::Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, Self::Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, Self::Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, Self::Error> {
+        write_integer(self.out, v);
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, Self::Error> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok, Self::Error> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok, Self::Error> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok, Self::Error> {
+        write_integer(self.out, v);
+        Ok(())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<Self::Ok, Self::Error> {
+        write_integer(self.out, v);
+        Ok(())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<Self::Ok, Self::Error> {
+        write_integer(self.out, v);
+        Ok(())
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<Self::Ok, Self::Error> {
+        Err(Error::unexpected_type("bencode value", "f32"))
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<Self::Ok, Self::Error> {
+        Err(Error::unexpected_type("bencode value", "f64"))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok, Self::Error> {
+        self.serialize_str(v.encode_utf8(&mut [0u8; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, Self::Error> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, Self::Error> {
+        write_string(self.out, v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
+        match self.skipped {
+            Some(skipped) => {
+                skipped.set(true);
+                Ok(())
+            }
+            None => Err(Error::unexpected_type("bencode value", "none")),
+        }
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<Self::Ok, Self::Error>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
+        Err(Error::unexpected_type("bencode value", "unit"))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Self::Error> {
+        Err(Error::unexpected_type("bencode value", "unit struct"))
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Self::Error> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        self.out.push(b'd');
+        write_string(self.out, variant.as_bytes());
+        value.serialize(BytesSerializer::new(self.out, self.options))?;
+        self.out.push(b'e');
+        Ok(())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        self.out.push(b'l');
+        Ok(ListSerializer {
+            out: self.out,
+            options: self.options,
+            in_variant: false,
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        self.out.push(b'd');
+        write_string(self.out, variant.as_bytes());
+        self.out.push(b'l');
+        Ok(ListSerializer {
+            out: self.out,
+            options: self.options,
+            in_variant: true,
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        Ok(DictSerializer::new(self.out, self.options, false))
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        Ok(DictSerializer::new(self.out, self.options, false))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        self.out.push(b'd');
+        write_string(self.out, variant.as_bytes());
+        Ok(DictSerializer::new(self.out, self.options, true))
+    }
+}
+
+pub(crate) struct ListSerializer<'a> {
+    out: &'a mut Vec<u8>,
+    options: SerializeOptions,
+    /// Whether the list is wrapped in a single-key dict naming an enum variant.
+    in_variant: bool,
+}
+
+impl ListSerializer<'_> {
+    fn finish(self) {
+        self.out.push(b'e');
+        if self.in_variant {
+            self.out.push(b'e');
+        }
+    }
+}
+
+impl ser::SerializeSeq for ListSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        value.serialize(BytesSerializer::new(self.out, self.options))
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        self.finish();
+        Ok(())
+    }
+}
+
+impl ser::SerializeTuple for ListSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for ListSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleVariant for ListSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+/// Buffers the encoded entries of a dict, as they have to be emitted sorted by key.
+pub(crate) struct DictSerializer<'a> {
+    out: &'a mut Vec<u8>,
+    options: SerializeOptions,
+    entries: BTreeMap<BString, Vec<u8>>,
+    next_key: Option<BString>,
+    /// Whether the dict is wrapped in a single-key dict naming an enum variant.
+    in_variant: bool,
+}
+
+impl<'a> DictSerializer<'a> {
+    fn new(out: &'a mut Vec<u8>, options: SerializeOptions, in_variant: bool) -> Self {
+        Self {
+            out,
+            options,
+            entries: BTreeMap::new(),
+            next_key: None,
+            in_variant,
+        }
+    }
+
+    fn insert<T>(&mut self, key: BString, value: &T) -> Result<(), Error>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        let mut buf = Vec::new();
+        let skipped = Cell::new(false);
+        value.serialize(BytesSerializer {
+            out: &mut buf,
+            options: self.options,
+            skipped: self.options.skip_none.then_some(&skipped),
+        })?;
+        if !skipped.get() {
+            self.entries.insert(key, buf);
+        }
+        Ok(())
+    }
+
+    fn finish(self) {
+        self.out.push(b'd');
+        for (k, v) in self.entries {
+            write_string(self.out, &k);
+            self.out.extend_from_slice(&v);
+        }
+        self.out.push(b'e');
+        if self.in_variant {
+            self.out.push(b'e');
+        }
+    }
+}
+
+impl ser::SerializeMap for DictSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T>(&mut self, key: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        self.next_key.replace(key.serialize(MapKeySerializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        let key = self
+            .next_key
+            .take()
+            .expect("serialize_value called before serialize_key");
+        self.insert(key, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        if self.next_key.is_some() {
+            panic!(
+                "serialize_key not matched with serialize_value before ending map serialization"
+            );
+        }
+        self.finish();
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for DictSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        self.insert(key.serialize(MapKeySerializer)?, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        self.finish();
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for DictSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        ser::SerializeStruct::serialize_field(self, key, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        ser::SerializeStruct::end(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bstr::{BString, B};
+    use serde::Serialize;
+
+    use crate::{to_bytes, to_bytes_with, BencodeValue, SerializeOptions};
+
+    #[derive(Serialize)]
+    struct Info {
+        name: String,
+        length: u64,
+        #[serde(rename = "piece length")]
+        piece_length: u32,
+        pieces: BString,
+    }
+
+    #[derive(Serialize)]
+    enum Event {
+        Started,
+        Peer { port: u16 },
+        Tags(Vec<String>),
+        Pair(u8, u8),
+    }
+
+    fn via_value<T: Serialize>(value: &T) -> BString {
+        BencodeValue::from_serialize(value)
+            .unwrap()
+            .to_byte_string()
+            .unwrap()
+    }
+
+    #[test]
+    fn serialize_struct() {
+        let info = Info {
+            name: "sample.txt".into(),
+            length: 92063,
+            piece_length: 32768,
+            pieces: "abc".into(),
+        };
+
+        assert_eq!(
+            B(&to_bytes(&info).unwrap()),
+            B(b"d6:lengthi92063e4:name10:sample.txt12:piece lengthi32768e6:pieces3:abce")
+        );
+        assert_eq!(to_bytes(&info).unwrap(), via_value(&info));
+    }
+
+    #[test]
+    fn serialize_collections() {
+        let value0 = vec![1, -2, 3];
+        let value1 = HashMap::from([("spam", vec!["a", "b"]), ("foo", vec![])]);
+        let value2 = (7u8, "foo");
+
+        assert_eq!(B(&to_bytes(&value0).unwrap()), B(b"li1ei-2ei3ee"));
+        assert_eq!(
+            B(&to_bytes(&value1).unwrap()),
+            B(b"d3:foole4:spaml1:a1:bee")
+        );
+        assert_eq!(B(&to_bytes(&value2).unwrap()), B(b"li7e3:fooe"));
+    }
+
+    #[test]
+    fn serialize_enum() {
+        let values = [
+            Event::Started,
+            Event::Peer { port: 6881 },
+            Event::Tags(vec!["a".into()]),
+            Event::Pair(1, 2),
+        ];
+
+        assert_eq!(B(&to_bytes(&values[0]).unwrap()), B(b"7:Started"));
+        assert_eq!(
+            B(&to_bytes(&values[1]).unwrap()),
+            B(b"d4:Peerd4:porti6881eee")
+        );
+        assert_eq!(B(&to_bytes(&values[2]).unwrap()), B(b"d4:Tagsl1:aee"));
+        assert_eq!(B(&to_bytes(&values[3]).unwrap()), B(b"d4:Pairli1ei2eee"));
+        for value in &values {
+            assert_eq!(to_bytes(value).unwrap(), via_value(value));
+        }
+    }
+
+    #[test]
+    fn serialize_unsupported() {
+        assert!(to_bytes(&1.5f64).is_err());
+        assert_eq!(to_bytes(&u64::MAX).unwrap(), b"i18446744073709551615e");
+        assert!(to_bytes(&Option::<u8>::None).is_err());
+    }
+
+    #[test]
+    fn skip_none() {
+        #[derive(Serialize)]
+        struct Torrent {
+            announce: Option<String>,
+            comment: Option<String>,
+            nodes: Option<Vec<Option<u8>>>,
+        }
+
+        let options = SerializeOptions::skip_none();
+        let torrent = Torrent {
+            announce: Some("url".into()),
+            comment: None,
+            nodes: None,
+        };
+        let expected = b"d8:announce3:urle";
+
+        assert!(to_bytes(&torrent).is_err());
+        assert_eq!(B(&to_bytes_with(&torrent, &options).unwrap()), B(expected));
+        assert_eq!(
+            BencodeValue::from_serialize_with(&torrent, &options)
+                .unwrap()
+                .to_byte_string()
+                .unwrap(),
+            B(expected)
+        );
+
+        // Only dict entries can be left out.
+        let torrent = Torrent {
+            nodes: Some(vec![None]),
+            ..torrent
+        };
+        assert!(to_bytes_with(&torrent, &options).is_err());
+        assert!(BencodeValue::from_serialize_with(&torrent, &options).is_err());
+        assert!(to_bytes_with(&Option::<u8>::None, &options).is_err());
+    }
+}
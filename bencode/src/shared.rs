@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+
+use bstr::BString;
+use bytes::Bytes;
+
+use super::{BencodeValue, BencodeValueRef, ParseError, ParseOptions};
+
+/// Owned bencode value whose strings are zero-copy slices of a shared [`Bytes`] buffer.
+///
+/// Unlike [`BencodeValueRef`] it is not tied to the lifetime of the input, which makes it a good
+/// fit for payloads received into `bytes` buffers (e.g. peer wire extension messages).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SharedValue {
+    String(Bytes),
+    Integer(i128),
+    List(Vec<SharedValue>),
+    Dict(BTreeMap<Bytes, SharedValue>),
+}
+
+impl SharedValue {
+    /// Attempts to parse the bytes into a [`SharedValue`] without copying any strings.
+    pub fn try_from_bytes(bytes: &Bytes) -> Result<Self, ParseError> {
+        Self::try_from_bytes_with(bytes, &ParseOptions::default())
+    }
+
+    /// Attempts to parse the bytes into a [`SharedValue`] using the given options.
+    pub fn try_from_bytes_with(bytes: &Bytes, options: &ParseOptions) -> Result<Self, ParseError> {
+        BencodeValueRef::try_from_bytes_with(bytes, options).map(|v| Self::from_ref(bytes, &v))
+    }
+
+    /// Parses the first value in the bytes, returning it together with the amount of bytes
+    /// consumed. Trailing bytes are ignored.
+    pub fn parse_prefix(bytes: &Bytes) -> Result<(Self, usize), ParseError> {
+        BencodeValueRef::parse_prefix(bytes)
+            .map(|(v, consumed)| (Self::from_ref(bytes, &v), consumed))
+    }
+
+    /// Copies the shared data into an owned [`BencodeValue`].
+    pub fn to_owned(&self) -> BencodeValue {
+        match self {
+            SharedValue::String(s) => BencodeValue::String(BString::from(&s[..])),
+            SharedValue::Integer(i) => BencodeValue::Integer(*i),
+            SharedValue::List(l) => {
+                BencodeValue::List(l.iter().map(SharedValue::to_owned).collect())
+            }
+            SharedValue::Dict(d) => BencodeValue::Dict(
+                d.iter()
+                    .map(|(k, v)| (BString::from(&k[..]), v.to_owned()))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Converts a value borrowed from `bytes` by slicing the buffer.
+    fn from_ref(bytes: &Bytes, value: &BencodeValueRef<'_>) -> Self {
+        match value {
+            BencodeValueRef::String(s) => SharedValue::String(bytes.slice_ref(s)),
+            BencodeValueRef::Integer(i) => SharedValue::Integer(*i),
+            BencodeValueRef::List(l) => {
+                SharedValue::List(l.iter().map(|v| Self::from_ref(bytes, v)).collect())
+            }
+            BencodeValueRef::Dict(d) => SharedValue::Dict(
+                d.iter()
+                    .map(|(k, v)| (bytes.slice_ref(k), Self::from_ref(bytes, v)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl From<SharedValue> for BencodeValue {
+    fn from(value: SharedValue) -> Self {
+        value.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use super::*;
+
+    #[test]
+    fn shares_input() {
+        let input = Bytes::from_static(b"d8:msg_typei1e5:piecei0e4:data3:abce");
+        let value = SharedValue::try_from_bytes(&input).unwrap();
+
+        let SharedValue::Dict(d) = &value else {
+            panic!("expected dict");
+        };
+        let SharedValue::String(data) = &d[&b"data"[..]] else {
+            panic!("expected string");
+        };
+        assert_eq!(data, "abc");
+        assert!(input.as_ptr_range().contains(&data.as_ptr()));
+        assert_eq!(
+            value.to_owned(),
+            BencodeValue::try_from_bytes(&input).unwrap()
+        );
+    }
+
+    #[test]
+    fn prefix_with_payload() {
+        let input = Bytes::from_static(b"d8:msg_typei1ee\x00\x01\x02");
+        let (value, consumed) = SharedValue::parse_prefix(&input).unwrap();
+
+        assert_eq!(
+            value,
+            SharedValue::Dict(BTreeMap::from([(
+                Bytes::from_static(b"msg_type"),
+                SharedValue::Integer(1)
+            )]))
+        );
+        assert_eq!(&input[consumed..], b"\x00\x01\x02");
+    }
+
+    #[test]
+    fn encode_into_bytes_mut() {
+        let input = b"d4:infod6:lengthi3e6:pieces3:\xff\x00\x01e4:listl0:lei0eee";
+        let value = BencodeValue::try_from_bytes(input).unwrap();
+
+        let mut buf = BytesMut::from(&b"header"[..]);
+        value.to_bytes_mut(&mut buf);
+        assert_eq!(&buf[..6], b"header");
+        assert_eq!(&buf[6..], input);
+    }
+}
@@ -0,0 +1,106 @@
+use std::{collections::BTreeMap, ops::Range};
+
+use bstr::BStr;
+
+use super::{error::ParseError, options::ParseOptions, parser::Parser, BencodeValueRef};
+
+/// Parsed value annotated with the byte range it was parsed from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpannedValue<'a> {
+    pub span: Range<usize>,
+    pub kind: SpannedKind<'a>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpannedKind<'a> {
+    String(&'a BStr),
+    Integer(i128),
+    List(Vec<SpannedValue<'a>>),
+    Dict(BTreeMap<&'a BStr, SpannedValue<'a>>),
+}
+
+impl<'a> SpannedValue<'a> {
+    /// Attempts to parse the bytes into a [`SpannedValue`].
+    pub fn try_from_bytes(bytes: &'a [u8]) -> Result<Self, ParseError> {
+        Self::try_from_bytes_with(bytes, &ParseOptions::default())
+    }
+
+    /// Attempts to parse the bytes into a [`SpannedValue`] using the given options.
+    pub fn try_from_bytes_with(
+        bytes: &'a [u8],
+        options: &ParseOptions,
+    ) -> Result<Self, ParseError> {
+        Parser::new(bytes, options).parse()
+    }
+
+    /// The exact bytes this value was parsed from, given the input passed to the parser.
+    pub fn raw<'i>(&self, input: &'i [u8]) -> &'i [u8] {
+        &input[self.span.clone()]
+    }
+
+    /// Looks up a key when this value is a dict.
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Option<&SpannedValue<'a>> {
+        match &self.kind {
+            SpannedKind::Dict(d) => d.get(BStr::new(key.as_ref())),
+            _ => None,
+        }
+    }
+
+    /// Strips the spans, leaving the plain borrowed value.
+    pub fn to_value_ref(&self) -> BencodeValueRef<'a> {
+        match &self.kind {
+            SpannedKind::String(s) => BencodeValueRef::String(s),
+            SpannedKind::Integer(i) => BencodeValueRef::Integer(*i),
+            SpannedKind::List(l) => {
+                BencodeValueRef::List(l.iter().map(SpannedValue::to_value_ref).collect())
+            }
+            SpannedKind::Dict(d) => {
+                BencodeValueRef::Dict(d.iter().map(|(k, v)| (*k, v.to_value_ref())).collect())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spans() {
+        let input = b"d8:announce3:url4:infod6:lengthi1e4:name1:aee";
+        let value = SpannedValue::try_from_bytes(input).unwrap();
+
+        assert_eq!(value.span, 0..input.len());
+        assert_eq!(value.get("announce").unwrap().span, 11..16);
+        assert_eq!(
+            value.get("info").unwrap().raw(input),
+            b"d6:lengthi1e4:name1:ae"
+        );
+        assert_eq!(
+            value.get("info").unwrap().get("length").unwrap().raw(input),
+            b"i1e"
+        );
+    }
+
+    #[test]
+    fn list_spans() {
+        let input = b"l3:fooli1ei22eee";
+        let value = SpannedValue::try_from_bytes(input).unwrap();
+
+        let SpannedKind::List(l) = &value.kind else {
+            panic!("expected list");
+        };
+        assert_eq!(l[0].span, 1..6);
+        assert_eq!(l[1].raw(input), b"li1ei22ee");
+    }
+
+    #[test]
+    fn to_value_ref() {
+        let input = b"d4:spaml3:fooi42ee4:eggs0:e";
+
+        assert_eq!(
+            SpannedValue::try_from_bytes(input).unwrap().to_value_ref(),
+            BencodeValueRef::try_from_bytes(input).unwrap()
+        );
+    }
+}
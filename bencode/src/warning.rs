@@ -0,0 +1,23 @@
+use derive_more::Display;
+
+/// Deviation from the bencode specification accepted while parsing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[display(fmt = "{kind} at byte offset {offset}")]
+pub struct Warning {
+    pub offset: usize,
+    pub kind: WarningKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum WarningKind {
+    #[display(fmt = "number with leading zeros")]
+    LeadingZeros,
+    #[display(fmt = "negative zero")]
+    NegativeZero,
+    #[display(fmt = "unsorted dict key")]
+    UnsortedKey,
+    #[display(fmt = "duplicate dict key")]
+    DuplicateKey,
+    #[display(fmt = "whitespace between values")]
+    Whitespace,
+}
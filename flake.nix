@@ -27,6 +27,7 @@
           buildInputs = with pkgs; [
             (rust-bin.stable."1.77.2".default.override {
               extensions = ["rust-analyzer" "rust-src"];
+              targets = ["wasm32-unknown-unknown"];
             })
             bacon
 
@@ -1,22 +1,166 @@
-use std::{net::SocketAddrV4, path::PathBuf};
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::{Context, Result};
-use bencode::BencodeValue;
+use anyhow::{bail, Context, Result};
+use bencode::{
+    json::{self, BinaryPolicy},
+    BencodeValue,
+};
 use bstr::BString;
 use clap::{Parser, Subcommand};
 
 use crate::{
+    dht::Dht,
     downloader::TorrentDownloader,
-    peer::{Peer, PieceDescriptor},
-    torrent::Torrent,
-    tracker::Tracker,
-    util::calculate_piece_length,
+    magnet::MagnetLink,
+    peer::{LocalBind, Peer, PeerTimeouts, PieceDescriptor, DEFAULT_PIPELINE_DEPTH},
+    storage::PathPolicy,
+    torrent::{fetch_torrent_file, MetaVersion, Torrent, TorrentBuilder, TorrentEditor},
+    tracker::{AnnounceEvent, RequestPolicy, Tracker, TrackerClient},
+    util::{calculate_piece_length, Sha1Hash},
+    validate::{self, Severity},
 };
 
 #[derive(Debug, Parser)]
+#[clap(rename_all = "snake_case")]
 pub struct Cli {
     #[command(subcommand)]
     pub command: Command,
+    /// Http(s) proxy to send tracker announces and torrent file downloads through.
+    #[arg(long, global = true)]
+    pub proxy: Option<String>,
+    /// Credentials for the proxy as `user:password`.
+    #[arg(long, global = true, requires = "proxy")]
+    pub proxy_auth: Option<String>,
+    /// `User-Agent` sent to trackers, some private trackers only accept known clients.
+    #[arg(long, global = true)]
+    pub user_agent: Option<String>,
+    /// Extra http header sent to trackers as `Name: value`.
+    #[arg(long, global = true)]
+    pub tracker_header: Vec<String>,
+    /// Seconds to wait for the connection to a tracker.
+    #[arg(long, global = true, default_value_t = 10)]
+    pub tracker_connect_timeout: u64,
+    /// Seconds a connected tracker may take to answer.
+    #[arg(long, global = true, default_value_t = 15)]
+    pub tracker_read_timeout: u64,
+    /// Further attempts at a tracker url that could not be reached.
+    #[arg(long, global = true, default_value_t = 1)]
+    pub tracker_retries: u32,
+    /// Seconds before the first retry of a tracker url, doubled on every further one.
+    #[arg(long, global = true, default_value_t = 1)]
+    pub tracker_retry_delay: u64,
+    /// `host:port` of a node to join the DHT through, replacing the well known routers.
+    #[arg(long, global = true)]
+    pub dht_bootstrap: Vec<String>,
+    /// Seconds to wait for the connection to a peer.
+    #[arg(long, global = true, default_value_t = 10)]
+    pub peer_connect_timeout: u64,
+    /// Seconds a connected peer may take to exchange handshakes.
+    #[arg(long, global = true, default_value_t = 10)]
+    pub peer_handshake_timeout: u64,
+    /// Seconds a peer may stay silent before the connection is closed.
+    #[arg(long, global = true, default_value_t = 150)]
+    pub peer_read_timeout: u64,
+    /// Seconds a peer may take to accept a message we send.
+    #[arg(long, global = true, default_value_t = 30)]
+    pub peer_write_timeout: u64,
+    /// Local address or network interface, such as a VPN, that peer connections are made and
+    /// accepted on, and that trackers, web seeds, torrent file urls and the DHT are reached
+    /// from. Connections are not made over other interfaces then.
+    #[arg(long, global = true, value_name = "ADDR_OR_INTERFACE")]
+    pub bind: Option<LocalBind>,
+}
+
+impl Cli {
+    pub async fn execute(self) -> Result<()> {
+        let policy = RequestPolicy {
+            connect_timeout: Duration::from_secs(self.tracker_connect_timeout),
+            read_timeout: Duration::from_secs(self.tracker_read_timeout),
+            retries: self.tracker_retries,
+            retry_delay: Duration::from_secs(self.tracker_retry_delay),
+        };
+        let mut tracker_client = TrackerClient::new(
+            policy,
+            self.proxy.as_deref(),
+            self.proxy_auth.as_deref(),
+            self.bind.as_ref(),
+        )?;
+        for header in &self.tracker_header {
+            let (name, value) = header
+                .split_once(':')
+                .with_context(|| format!("header `{header}` is not of the form `Name: value`"))?;
+            tracker_client = tracker_client.header(name.trim(), value.trim())?;
+        }
+        if let Some(user_agent) = &self.user_agent {
+            tracker_client = tracker_client.user_agent(user_agent)?;
+        }
+        let peer_timeouts = PeerTimeouts {
+            connect_timeout: Duration::from_secs(self.peer_connect_timeout),
+            handshake_timeout: Duration::from_secs(self.peer_handshake_timeout),
+            read_timeout: Duration::from_secs(self.peer_read_timeout),
+            write_timeout: Duration::from_secs(self.peer_write_timeout),
+        };
+        self.command
+            .execute(tracker_client, self.dht_bootstrap, peer_timeouts, self.bind)
+            .await
+    }
+}
+
+/// Where a torrent file is read from, either a local path or an http(s) url.
+#[derive(Debug, Clone)]
+pub enum TorrentLocation {
+    Path(PathBuf),
+    Url(String),
+}
+
+impl FromStr for TorrentLocation {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("http://") || s.starts_with("https://") {
+            Ok(Self::Url(s.to_owned()))
+        } else {
+            Ok(Self::Path(PathBuf::from(s)))
+        }
+    }
+}
+
+impl TorrentLocation {
+    /// Loads the torrent, fetching urls with the tracker client.
+    pub async fn load(&self, client: &TrackerClient) -> Result<Torrent> {
+        match self {
+            Self::Path(path) => {
+                Torrent::from_file_path(path).context("reading torrent from file path")
+            }
+            Self::Url(url) => Torrent::from_url(client, url)
+                .await
+                .context("reading torrent from url"),
+        }
+    }
+
+    /// Reads the raw contents of the torrent file.
+    pub async fn read(&self, client: &TrackerClient) -> Result<Vec<u8>> {
+        match self {
+            Self::Path(path) => std::fs::read(path).context("reading torrent file"),
+            Self::Url(url) => Ok(fetch_torrent_file(client, url).await?.to_vec()),
+        }
+    }
+}
+
+impl std::fmt::Display for TorrentLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Path(path) => write!(f, "{}", path.display()),
+            Self::Url(url) => write!(f, "{url}"),
+        }
+    }
 }
 
 #[derive(Debug, Subcommand)]
@@ -25,23 +169,63 @@ pub enum Command {
     /// Decode the given binary encoded value into a json value.
     Decode {
         value: BString,
+        /// Print an indented, human readable rendering instead of json.
+        #[arg(long)]
+        pretty: bool,
+        /// Representation of non utf-8 strings in json (hex, base64 or lossy).
+        #[arg(long, default_value = "hex")]
+        binary: BinaryPolicy,
     },
     Info {
-        path: PathBuf,
+        /// Path or http(s) url of the torrent file.
+        path: TorrentLocation,
+        /// Print the overview as json.
+        #[arg(long)]
+        json: bool,
+        /// Include the piece hashes in the json output.
+        #[arg(long, requires = "json")]
+        hashes: bool,
     },
     Peers {
-        path: PathBuf,
+        /// Path or http(s) url of the torrent file.
+        path: TorrentLocation,
+        /// Number of peers to ask the tracker for.
+        #[arg(long)]
+        numwant: Option<u32>,
+        /// Address to report to the tracker instead of the one the announce comes from.
+        #[arg(long)]
+        ip: Option<IpAddr>,
+        /// Announce to all tracker tiers at once instead of the first that answers. Ignored for
+        /// private torrents.
+        #[arg(long)]
+        all_tiers: bool,
+    },
+    /// Send a single announce to the trackers of a torrent and print the response.
+    Announce {
+        /// Path or http(s) url of the torrent file.
+        path: TorrentLocation,
+        /// Event to report, none for a regular announce.
+        #[arg(long, value_enum)]
+        event: Option<AnnounceEvent>,
+        /// Number of peers to ask the tracker for.
+        #[arg(long)]
+        numwant: Option<u32>,
+        /// Announce to all tracker tiers at once instead of the first that answers. Ignored for
+        /// private torrents.
+        #[arg(long)]
+        all_tiers: bool,
     },
     Handshake {
-        path: PathBuf,
-        peer: SocketAddrV4,
+        /// Path or http(s) url of the torrent file.
+        path: TorrentLocation,
+        peer: SocketAddr,
     },
     DownloadPiece {
         /// Path to download the piece to.
         #[arg(short)]
         output: PathBuf,
-        /// Path to the torrent file.
-        path: PathBuf,
+        /// Path or http(s) url of the torrent file.
+        path: TorrentLocation,
         /// Index of the piece to download.
         index: u32,
     },
@@ -49,59 +233,455 @@ pub enum Command {
         /// Path to download the file to.
         #[arg(short)]
         output: PathBuf,
+        /// Path or http(s) url of the torrent file.
+        path: TorrentLocation,
+        /// How to handle file paths that are unsafe to create on disk.
+        #[arg(long, value_enum, default_value_t)]
+        paths: PathPolicy,
+        /// Find peers through the DHT as well, unless the torrent is private.
+        #[arg(long)]
+        dht: bool,
+        /// Address to report to trackers instead of the one announces come from.
+        #[arg(long)]
+        ip: Option<IpAddr>,
+        /// Port to announce as the one we are reachable at.
+        #[arg(long, default_value_t = 6881)]
+        port: u16,
+        /// Announce to all tracker tiers at once instead of the first that answers. Ignored for
+        /// private torrents.
+        #[arg(long)]
+        all_tiers: bool,
+        /// Block requests kept outstanding per peer.
+        #[arg(long, default_value_t = DEFAULT_PIPELINE_DEPTH)]
+        pipeline_depth: usize,
+    },
+    /// Create a torrent file for a file or directory.
+    Create {
+        /// Path to write the torrent file to.
+        #[arg(short)]
+        output: PathBuf,
+        /// File or directory to create the torrent for.
+        path: PathBuf,
+        /// Tracker announce url.
+        #[arg(long)]
+        announce: String,
+        /// Size of the pieces in bytes, a power of two of at least 16 KiB. Picked from the
+        /// content size by default.
+        #[arg(long)]
+        piece_length: Option<u32>,
+        /// Mark the torrent as private, restricting peer discovery to its trackers.
+        #[arg(long)]
+        private: bool,
+        /// Free-form comment stored in the torrent.
+        #[arg(long)]
+        comment: Option<String>,
+        /// Source tag, giving cross-seeded content a distinct info hash per tracker.
+        #[arg(long)]
+        source: Option<String>,
+        /// Hex info hash of a torrent sharing files with this one.
+        #[arg(long, value_parser = parse_info_hash)]
+        similar: Vec<Sha1Hash>,
+        /// Collection the torrent belongs to.
+        #[arg(long)]
+        collection: Vec<String>,
+    },
+    /// Edit the trackers, comment or private flag of a torrent file.
+    Edit {
+        /// Path to write the edited torrent file to, defaults to overwriting the input.
+        #[arg(short)]
+        output: Option<PathBuf>,
         /// Path to the torrent file.
         path: PathBuf,
+        /// Tracker announce url to add as a new tier.
+        #[arg(long)]
+        add_tracker: Vec<String>,
+        /// Tracker announce url to remove from all tiers.
+        #[arg(long)]
+        remove_tracker: Vec<String>,
+        /// Set or clear the private flag. This changes the info hash.
+        #[arg(long)]
+        private: Option<bool>,
+        /// Replace the comment, an empty comment removes it.
+        #[arg(long)]
+        comment: Option<String>,
+    },
+    /// Check a torrent file for deviations from the specification.
+    Validate {
+        /// Path or http(s) url of the torrent file.
+        path: TorrentLocation,
+    },
+    /// Print a magnet link for a torrent file.
+    Magnet {
+        /// Path or http(s) url of the torrent file.
+        path: TorrentLocation,
+    },
+    MagnetParse {
+        link: MagnetLink,
+    },
+    /// Fetch the torrent metadata of a magnet link from peers.
+    MagnetInfo {
+        /// Path to save the fetched metadata to as .torrent file.
+        #[arg(short)]
+        output: Option<PathBuf>,
+        link: MagnetLink,
+        /// Find peers through the DHT as well.
+        #[arg(long)]
+        dht: bool,
+    },
+    MagnetDownload {
+        /// Path to download the file to.
+        #[arg(short)]
+        output: PathBuf,
+        link: MagnetLink,
+        /// How to handle file paths that are unsafe to create on disk.
+        #[arg(long, value_enum, default_value_t)]
+        paths: PathPolicy,
+        /// Find peers through the DHT as well.
+        #[arg(long)]
+        dht: bool,
+        /// Block requests kept outstanding per peer.
+        #[arg(long, default_value_t = DEFAULT_PIPELINE_DEPTH)]
+        pipeline_depth: usize,
+    },
+    /// Look up peers of a torrent in the DHT.
+    DhtPeers {
+        /// Hex info hash of the torrent.
+        #[arg(value_parser = parse_info_hash)]
+        info_hash: Sha1Hash,
     },
 }
 
 impl Command {
-    pub async fn execute(self) -> Result<()> {
+    pub async fn execute(
+        self,
+        tracker_client: TrackerClient,
+        dht_bootstrap: Vec<String>,
+        peer_timeouts: PeerTimeouts,
+        bind: Option<LocalBind>,
+    ) -> Result<()> {
         match self {
-            Command::Decode { value } => {
-                let decoded_value = serde_json::to_value(BencodeValue::try_from_bytes(&value)?)
-                    .context("serializing bencode value to json")?;
-                println!("{}", decoded_value);
+            Command::Decode {
+                value,
+                pretty,
+                binary,
+            } => {
+                let value = BencodeValue::try_from_bytes(&value)?;
+                if pretty {
+                    println!("{}", value.pretty());
+                    return Ok(());
+                }
+
+                println!("{}", json::to_json(&value, binary));
             }
-            Command::Info { path } => {
-                let torrent = Torrent::from_file_path(path).context("reading torrent from path")?;
+            Command::Info { path, json, hashes } => {
+                let torrent = path.load(&tracker_client).await?;
+                if json {
+                    println!("{}", torrent.overview().to_json(hashes));
+                    return Ok(());
+                }
+
                 println!("{}", torrent.overview());
             }
-            Command::Peers { path } => {
-                let torrent =
-                    Torrent::from_file_path(path).context("reading torrent from file path")?;
-                let tracker = Tracker::from(&torrent);
+            Command::Peers {
+                path,
+                numwant,
+                ip,
+                all_tiers,
+            } => {
+                let torrent = path.load(&tracker_client).await?;
+                let mut tracker = Tracker::from(&torrent)
+                    .client(tracker_client)
+                    .all_tiers(all_tiers);
+                if let Some(numwant) = numwant {
+                    tracker = tracker.numwant(numwant);
+                }
+                if let Some(ip) = ip {
+                    tracker = tracker.ip(ip);
+                }
 
                 let tracker_response = tracker.poll().await.context("polling tracker")?;
+                if let Some(warning) = &tracker_response.warning {
+                    eprintln!("Tracker warning: {warning}");
+                }
+                if let Some(external_ip) = tracker.external_ip() {
+                    eprintln!("External IP: {external_ip}");
+                }
+                // Kept off stdout, which lists nothing but peers.
+                if let Some(complete) = tracker_response.complete {
+                    eprintln!("Seeders: {complete}");
+                }
+                if let Some(incomplete) = tracker_response.incomplete {
+                    eprintln!("Leechers: {incomplete}");
+                }
+                if let Some(downloaded) = tracker_response.downloaded {
+                    eprintln!("Downloaded: {downloaded}");
+                }
                 println!("{}", tracker_response.peers);
             }
+            Command::Announce {
+                path,
+                event,
+                numwant,
+                all_tiers,
+            } => {
+                let torrent = path.load(&tracker_client).await?;
+                let mut tracker = Tracker::from(&torrent)
+                    .client(tracker_client)
+                    .all_tiers(all_tiers);
+                if let Some(numwant) = numwant {
+                    tracker = tracker.numwant(numwant);
+                }
+
+                let response = tracker.announce(event).await?;
+                println!("Interval: {}s", response.interval.as_secs());
+                if let Some(min_interval) = response.min_interval {
+                    println!("Min Interval: {}s", min_interval.as_secs());
+                }
+                if let Some(complete) = response.complete {
+                    println!("Seeders: {complete}");
+                }
+                if let Some(incomplete) = response.incomplete {
+                    println!("Leechers: {incomplete}");
+                }
+                if let Some(downloaded) = response.downloaded {
+                    println!("Downloaded: {downloaded}");
+                }
+                if let Some(tracker_id) = &response.tracker_id {
+                    println!("Tracker ID: {tracker_id}");
+                }
+                if let Some(external_ip) = response.external_ip {
+                    println!("External IP: {external_ip}");
+                }
+                if let Some(warning) = &response.warning {
+                    println!("Warning: {warning}");
+                }
+                println!("Peers: {}", response.peers.len());
+                print!("{}", response.peers);
+            }
             Command::Handshake { path, peer } => {
-                let torrent =
-                    Torrent::from_file_path(path).context("reading torrent from file path")?;
+                let torrent = path.load(&tracker_client).await?;
                 let tracker = Tracker::from(&torrent);
 
-                let peer = Peer::from_socket(peer)
-                    .handshake(*tracker.info_hash(), *tracker.peer_id())
+                let mut peer = Peer::from_socket(peer).timeouts(peer_timeouts);
+                if let Some(local) = bind {
+                    peer = peer.bind(local);
+                }
+                let peer = peer
+                    .handshake(
+                        *tracker.info_hash(),
+                        *tracker.peer_id(),
+                        torrent.piece_count() as u32,
+                    )
                     .await
                     .context("performing peer handshake")?;
-                println!("Peer ID: {}", hex::encode(peer.peer_id()))
+                println!("Peer ID: {}", hex::encode(peer.peer_id()));
+                let extensions = peer
+                    .extensions()
+                    .iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<_>>();
+                println!(
+                    "Extensions: {}",
+                    if extensions.is_empty() {
+                        "none".to_owned()
+                    } else {
+                        extensions.join(", ")
+                    }
+                );
+                println!(
+                    "Pieces: {}/{}",
+                    peer.pieces().count(),
+                    peer.pieces().piece_count()
+                );
             }
             Command::DownloadPiece {
                 output,
                 path,
                 index,
-            } => download_piece(output, path, index).await?,
-            Command::Download { output, path } => {
-                let torrent =
-                    Torrent::from_file_path(&path).context("reading torrent from file path")?;
+            } => download_piece(output, path, index, tracker_client, peer_timeouts, bind).await?,
+            Command::Download {
+                output,
+                path,
+                paths,
+                dht: use_dht,
+                ip,
+                port,
+                all_tiers,
+                pipeline_depth,
+            } => {
+                let torrent = path.load(&tracker_client).await?;
 
-                TorrentDownloader::new(torrent)
+                let mut downloader = TorrentDownloader::new(torrent)
                     .await
                     .context("initializing downloader")?
+                    .tracker_client(tracker_client)
+                    .path_policy(paths)
+                    .port(port)
+                    .all_tiers(all_tiers)
+                    .pipeline_depth(pipeline_depth)
+                    .peer_timeouts(peer_timeouts);
+                if use_dht {
+                    downloader = downloader.dht(join_dht(dht_bootstrap, bind.as_ref()).await?);
+                }
+                if let Some(ip) = ip {
+                    downloader = downloader.announce_ip(ip);
+                }
+                if let Some(local) = bind {
+                    downloader = downloader.bind(local);
+                }
+                downloader
                     .download_to_location(&output)
                     .await
                     .context("downloading torrent")?;
 
-                println!("Downloaded {} to {}", path.display(), output.display());
+                println!("Downloaded {path} to {}", output.display());
+            }
+            Command::Create {
+                output,
+                path,
+                announce,
+                piece_length,
+                private,
+                comment,
+                source,
+                similar,
+                collection,
+            } => {
+                let mut builder = TorrentBuilder::new(&path, announce).private(private);
+                if let Some(piece_length) = piece_length {
+                    builder = builder.piece_length(piece_length);
+                }
+                if let Some(comment) = comment {
+                    builder = builder.comment(comment);
+                }
+                if let Some(source) = source {
+                    builder = builder.source(source);
+                }
+                for info_hash in similar {
+                    builder = builder.similar(info_hash);
+                }
+                for name in collection {
+                    builder = builder.collection(name);
+                }
+                let metainfo = builder.build().context("building torrent")?;
+
+                std::fs::write(&output, metainfo.to_canonical_bytes())
+                    .context("writing torrent file")?;
+                println!("Created {} for {}", output.display(), path.display());
+            }
+            Command::Edit {
+                output,
+                path,
+                add_tracker,
+                remove_tracker,
+                private,
+                comment,
+            } => {
+                let contents = std::fs::read(&path).context("reading torrent file")?;
+                let mut editor =
+                    TorrentEditor::from_bytes(&contents).context("reading torrent for editing")?;
+
+                for url in &remove_tracker {
+                    editor.remove_tracker(url).context("removing tracker")?;
+                }
+                for url in add_tracker {
+                    editor.add_tracker(url).context("adding tracker")?;
+                }
+                if let Some(comment) = comment {
+                    editor.set_comment((!comment.is_empty()).then_some(comment));
+                }
+                if let Some(private) = private {
+                    editor
+                        .set_private(private)
+                        .context("setting private flag")?;
+                }
+
+                let output = output.unwrap_or(path);
+                std::fs::write(&output, editor.to_bytes()).context("writing torrent file")?;
+                println!("Edited {}", output.display());
+            }
+            Command::Validate { path } => {
+                let contents = path.read(&tracker_client).await?;
+                let findings = validate::validate(&contents);
+                for finding in &findings {
+                    println!("{finding}");
+                }
+
+                let errors = findings
+                    .iter()
+                    .filter(|finding| finding.severity == Severity::Error)
+                    .count();
+                if errors > 0 {
+                    bail!("{path} is invalid, found {errors} errors");
+                }
+                println!("{path} is valid");
+            }
+            Command::Magnet { path } => {
+                let torrent = path.load(&tracker_client).await?;
+                println!("{}", torrent.to_magnet());
+            }
+            Command::MagnetParse { link } => {
+                if let Some(name) = &link.display_name {
+                    println!("Name: {name}");
+                }
+                for tracker in &link.trackers {
+                    println!("Tracker URL: {tracker}");
+                }
+                println!("Info Hash: {}", hex::encode(link.info_hash));
+            }
+            Command::MagnetInfo {
+                output,
+                link,
+                dht: use_dht,
+            } => {
+                let dht = if use_dht {
+                    Some(join_dht(dht_bootstrap, bind.as_ref()).await?)
+                } else {
+                    None
+                };
+                let torrent = link
+                    .fetch_torrent(&tracker_client, dht.as_deref(), bind.as_ref())
+                    .await
+                    .context("fetching torrent metadata")?;
+                println!("{}", torrent.overview());
+                if let Some(output) = output {
+                    torrent.write_to(&output)?;
+                    println!("Saved torrent file to {}", output.display());
+                }
+            }
+            Command::MagnetDownload {
+                output,
+                link,
+                paths,
+                dht: use_dht,
+                pipeline_depth,
+            } => {
+                let dht = if use_dht {
+                    Some(join_dht(dht_bootstrap, bind.as_ref()).await?)
+                } else {
+                    None
+                };
+                TorrentDownloader::from_magnet(&link, tracker_client, dht, bind)
+                    .await
+                    .context("initializing downloader")?
+                    .path_policy(paths)
+                    .pipeline_depth(pipeline_depth)
+                    .peer_timeouts(peer_timeouts)
+                    .download_to_location(&output)
+                    .await
+                    .context("downloading torrent")?;
+
+                println!("Downloaded magnet link to {}", output.display());
+            }
+            Command::DhtPeers { info_hash } => {
+                let peers = join_dht(dht_bootstrap, bind.as_ref())
+                    .await?
+                    .get_peers(info_hash)
+                    .await
+                    .context("looking up peers in the dht")?;
+                for peer in peers {
+                    println!("{peer}");
+                }
             }
         }
 
@@ -109,39 +689,73 @@ impl Command {
     }
 }
 
-async fn download_piece(output: PathBuf, path: PathBuf, index: u32) -> Result<()> {
+/// Joins the DHT on a random port. Peers are only looked up, nobody connects to us.
+async fn join_dht(bootstrap_nodes: Vec<String>, bind: Option<&LocalBind>) -> Result<Arc<Dht>> {
+    Ok(Arc::new(Dht::bind(0, bootstrap_nodes, bind).await?))
+}
+
+fn parse_info_hash(hash: &str) -> Result<Sha1Hash> {
+    let mut info_hash = [0u8; 20];
+    hex::decode_to_slice(hash, &mut info_hash).context("decoding hex info hash")?;
+    Ok(info_hash)
+}
+
+async fn download_piece(
+    output: PathBuf,
+    path: TorrentLocation,
+    index: u32,
+    tracker_client: TrackerClient,
+    peer_timeouts: PeerTimeouts,
+    bind: Option<LocalBind>,
+) -> Result<()> {
     use std::io::Write;
 
-    let torrent = Torrent::from_file_path(path).context("reading torrent from file path")?;
-    let tracker = Tracker::from(&torrent);
+    let torrent = path.load(&tracker_client).await?;
+    let mut tracker = Tracker::from(&torrent).client(tracker_client);
 
     // Use first peer found.
-    let peer_socket_addr = *tracker
-        .poll()
-        .await
-        .context("polling tracker")?
-        .peers
-        .first()
-        .context("no peer found")?;
+    let peers = tracker.poll().await.context("polling tracker")?.peers;
+    let peer_socket_addr = *peers.first().context("no peer found")?;
 
-    let mut peer = Peer::from_socket(peer_socket_addr)
-        .handshake(*tracker.info_hash(), *tracker.peer_id())
+    let mut peer = Peer::from_socket(peer_socket_addr).timeouts(peer_timeouts);
+    if let Some(peer_id) = peers.peer_id(&peer_socket_addr) {
+        peer = peer.expect_peer_id(*peer_id);
+    }
+    if let Some(local) = bind {
+        peer = peer.bind(local);
+    }
+    let mut peer = peer
+        .handshake(
+            *tracker.info_hash(),
+            *tracker.peer_id(),
+            torrent.piece_count() as u32,
+        )
         .await
         .context("performing peer handshake")?;
 
     let piece_hash = torrent
         .info
-        .pieces
+        .v1_pieces()
         .get(index as usize)
         .context("piece index outside range")?;
     let piece = peer
         .download_piece(PieceDescriptor::new(
             index,
-            calculate_piece_length(torrent.info.piece_length, torrent.info.length, index),
+            calculate_piece_length(
+                torrent.info.piece_length,
+                torrent.info.total_length(),
+                index,
+            ),
             *piece_hash,
         ))
         .await
         .context("downloading a single piece")?;
+    // Hybrid torrents hash the piece for v2 as well, both hashes have to agree.
+    if torrent.version == MetaVersion::Hybrid {
+        torrent
+            .verify_piece_v2(index, &piece)
+            .context("checking piece against the v2 hashes")?;
+    }
 
     let mut file =
         std::fs::File::create(&output).context("creating file to download torrent piece to")?;
@@ -0,0 +1,665 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use tokio::{
+    net::UdpSocket,
+    sync::oneshot,
+    task::{AbortHandle, JoinSet},
+};
+
+use crate::{
+    discovery::{BoxFuture, PeerSender, PeerSource},
+    peer::LocalBind,
+    util::{hash_sha1, InfoHash},
+};
+
+use self::{
+    krpc::{Message, MessageKind, NodeId, NodeInfo, Query, Response},
+    routing::{distance, RoutingTable, K},
+};
+
+mod krpc;
+mod routing;
+
+/// Well known nodes to join the DHT through.
+pub const DEFAULT_BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Queries in flight during a lookup.
+const ALPHA: usize = 3;
+/// Tokens handed out in `get_peers` responses are renewed this often, the previous one staying
+/// valid for another round.
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+/// Peers announced to us are forgotten after this long without a new announce.
+const PEER_EXPIRY: Duration = Duration::from_secs(30 * 60);
+/// Stored peers are checked for expiry this often.
+const PEER_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+/// Swarms we store announced peers of, further swarms being ignored until some expire.
+const MAX_SWARMS: usize = 1024;
+/// Peers stored per swarm, the one announced longest ago making room for a new one.
+const MAX_SWARM_PEERS: usize = 256;
+/// Peers returned in a single `get_peers` response, keeping it within a UDP packet.
+const MAX_VALUES: usize = 50;
+/// Delay between lookups of a torrent run as peer source.
+const LOOKUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Node of the mainline DHT (BEP 5), finding peers of torrents without asking a tracker. It
+/// answers queries of other nodes for as long as it lives.
+pub struct Dht {
+    node: Arc<Node>,
+    receiver: AbortHandle,
+    /// Task forgetting expired peers.
+    expiry: AbortHandle,
+    /// `host:port` of nodes to join the DHT through.
+    bootstrap_nodes: Vec<String>,
+}
+
+/// State shared with the task receiving messages.
+struct Node {
+    id: NodeId,
+    socket: UdpSocket,
+    table: Mutex<RoutingTable>,
+    /// Queries awaiting a response, by transaction id.
+    pending: Mutex<HashMap<Vec<u8>, oneshot::Sender<Result<Response>>>>,
+    next_transaction: AtomicU16,
+    tokens: Mutex<Tokens>,
+    peers: Mutex<PeerStore>,
+}
+
+/// Peers announced to us, with the time of their last announce.
+#[derive(Default)]
+struct PeerStore {
+    swarms: HashMap<InfoHash, HashMap<SocketAddr, Instant>>,
+}
+
+/// Secrets tokens are derived from, so only nodes that asked for peers can announce.
+struct Tokens {
+    current: [u8; 20],
+    previous: [u8; 20],
+    rotated: Instant,
+}
+
+/// Outcome of a lookup of the nodes closest to a target.
+struct Lookup {
+    /// Peers returned by the nodes when looking up an info hash.
+    peers: Vec<SocketAddr>,
+    /// Closest nodes that responded, along with the token they handed out.
+    closest: Vec<(NodeInfo, Option<Vec<u8>>)>,
+}
+
+impl Dht {
+    /// Listens for DHT messages on the UDP port with a random node id, on the local address or
+    /// interface if given. An empty list of bootstrap nodes joins through
+    /// [`DEFAULT_BOOTSTRAP_NODES`].
+    pub async fn bind(
+        port: u16,
+        bootstrap_nodes: Vec<String>,
+        local: Option<&LocalBind>,
+    ) -> Result<Self> {
+        let socket = match local {
+            Some(local) => local.udp(port).await,
+            None => UdpSocket::bind(("0.0.0.0", port))
+                .await
+                .with_context(|| format!("binding dht socket to port {port}")),
+        }?;
+        let id = rand::random();
+        let node = Arc::new(Node {
+            id,
+            socket,
+            table: Mutex::new(RoutingTable::new(id)),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(rand::random()),
+            tokens: Mutex::new(Tokens {
+                current: rand::random(),
+                previous: rand::random(),
+                rotated: Instant::now(),
+            }),
+            peers: Mutex::new(PeerStore::default()),
+        });
+        let receiver = tokio::spawn(Arc::clone(&node).receive()).abort_handle();
+        let expiry = tokio::spawn(Arc::clone(&node).expire_peers()).abort_handle();
+        let bootstrap_nodes = if bootstrap_nodes.is_empty() {
+            DEFAULT_BOOTSTRAP_NODES
+                .iter()
+                .map(|node| node.to_string())
+                .collect()
+        } else {
+            bootstrap_nodes
+        };
+        Ok(Self {
+            node,
+            receiver,
+            expiry,
+            bootstrap_nodes,
+        })
+    }
+
+    /// Whether the routing table knows no node to ask.
+    pub fn is_empty(&self) -> bool {
+        self.node.table.lock().unwrap().is_empty()
+    }
+
+    /// UDP port the node listens on.
+    pub fn port(&self) -> Result<u16> {
+        let addr = self
+            .node
+            .socket
+            .local_addr()
+            .context("reading dht socket address")?;
+        Ok(addr.port())
+    }
+
+    /// Pings a node learned about outside the DHT, such as through the port message of a peer.
+    /// It ends up in the routing table when it answers.
+    pub fn add_node(&self, addr: SocketAddr) {
+        if !addr.is_ipv4() {
+            return;
+        }
+        let node = Arc::clone(&self.node);
+        tokio::spawn(async move {
+            if let Err(err) = node.query(addr, Query::Ping).await {
+                tracing::debug!(%addr, "Pinging dht node failed: {err:#}");
+            }
+        });
+    }
+
+    /// Joins the DHT through the bootstrap nodes, filling the routing table with the nodes
+    /// closest to our own id.
+    pub async fn bootstrap(&self) -> Result<()> {
+        let mut addrs = Vec::new();
+        for node in &self.bootstrap_nodes {
+            match tokio::net::lookup_host(node).await {
+                Ok(resolved) => addrs.extend(resolved.filter(SocketAddr::is_ipv4)),
+                Err(err) => tracing::debug!(node, "Resolving dht node failed: {err}"),
+            }
+        }
+
+        // The ids of bootstrap nodes are unknown, so they are asked directly. Responding nodes
+        // end up in the routing table.
+        let mut queries = JoinSet::new();
+        for addr in addrs {
+            let node = Arc::clone(&self.node);
+            queries.spawn(async move {
+                let target = node.id;
+                node.query(addr, Query::FindNode { target }).await
+            });
+        }
+        while let Some(result) = queries.join_next().await {
+            match result.context("dht query task failed")? {
+                Ok(response) => self.node.insert_all(&response.nodes),
+                Err(err) => tracing::debug!("Bootstrap query failed: {err:#}"),
+            }
+        }
+        if self.is_empty() {
+            bail!("no dht node responded");
+        }
+
+        self.node.lookup(self.node.id, false).await;
+        Ok(())
+    }
+
+    /// Looks up peers of the torrent, joining the DHT first when no node is known yet.
+    pub async fn get_peers(&self, info_hash: InfoHash) -> Result<Vec<SocketAddr>> {
+        if self.is_empty() {
+            self.bootstrap().await.context("joining the dht")?;
+        }
+        Ok(self.node.lookup(info_hash, true).await.peers)
+    }
+
+    /// Looks up peers of the torrent like [`Dht::get_peers`] and announces to the closest nodes
+    /// that we are a peer listening on the port.
+    pub async fn announce(&self, info_hash: InfoHash, port: u16) -> Result<Vec<SocketAddr>> {
+        if self.is_empty() {
+            self.bootstrap().await.context("joining the dht")?;
+        }
+        let lookup = self.node.lookup(info_hash, true).await;
+
+        let mut announces = JoinSet::new();
+        for (node, token) in lookup.closest {
+            let Some(token) = token else {
+                continue;
+            };
+            let dht = Arc::clone(&self.node);
+            announces.spawn(async move {
+                let query = Query::AnnouncePeer {
+                    info_hash,
+                    port,
+                    implied_port: false,
+                    token,
+                };
+                if let Err(err) = dht.query(SocketAddr::V4(node.addr), query).await {
+                    tracing::debug!(node = %node.addr, "Announcing to dht node failed: {err:#}");
+                }
+            });
+        }
+        while announces.join_next().await.is_some() {}
+
+        Ok(lookup.peers)
+    }
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        self.receiver.abort();
+        self.expiry.abort();
+    }
+}
+
+impl Node {
+    /// Sends a query and waits for the response of the node.
+    async fn query(&self, addr: SocketAddr, query: Query) -> Result<Response> {
+        let transaction_id = self
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+        let (tx, rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(transaction_id.clone(), tx);
+
+        let message = Message {
+            transaction_id: transaction_id.clone(),
+            kind: MessageKind::Query { id: self.id, query },
+        };
+        let result = match self.socket.send_to(&message.to_bytes(), addr).await {
+            Ok(_) => tokio::time::timeout(QUERY_TIMEOUT, rx)
+                .await
+                .map_err(|_| anyhow!("dht node {addr} did not respond"))
+                .and_then(|response| response.context("dht receiver stopped")?),
+            Err(err) => Err(anyhow::Error::from(err).context("sending dht query")),
+        };
+        self.pending.lock().unwrap().remove(&transaction_id);
+        result
+    }
+
+    /// Handles incoming messages until the socket fails.
+    async fn receive(self: Arc<Self>) {
+        let mut buffer = vec![0; 65536];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(err) => {
+                    tracing::error!("Receiving dht message failed: {err}");
+                    return;
+                }
+            };
+            let message = match Message::from_bytes(&buffer[..len]) {
+                Ok(message) => message,
+                Err(err) => {
+                    tracing::debug!(%from, "Ignoring invalid dht message: {err:#}");
+                    continue;
+                }
+            };
+
+            match message.kind {
+                MessageKind::Query { id, query } => {
+                    if let SocketAddr::V4(addr) = from {
+                        self.table.lock().unwrap().insert(NodeInfo { id, addr });
+                    }
+                    let reply = Message {
+                        transaction_id: message.transaction_id,
+                        kind: self.answer(query, from),
+                    };
+                    if let Err(err) = self.socket.send_to(&reply.to_bytes(), from).await {
+                        tracing::debug!(%from, "Answering dht query failed: {err}");
+                    }
+                }
+                MessageKind::Response(response) => {
+                    let Some(tx) = self.pending.lock().unwrap().remove(&message.transaction_id)
+                    else {
+                        continue;
+                    };
+                    if let SocketAddr::V4(addr) = from {
+                        let id = response.id;
+                        self.table.lock().unwrap().insert(NodeInfo { id, addr });
+                    }
+                    let _ = tx.send(Ok(response));
+                }
+                MessageKind::Error {
+                    code,
+                    message: text,
+                } => {
+                    if let Some(tx) = self.pending.lock().unwrap().remove(&message.transaction_id) {
+                        let _ = tx.send(Err(anyhow!(
+                            "dht node {from} returned error {code}: {text}"
+                        )));
+                    }
+                }
+            }
+        }
+    }
+
+    fn answer(&self, query: Query, from: SocketAddr) -> MessageKind {
+        let mut response = Response {
+            id: self.id,
+            ..Response::default()
+        };
+        match query {
+            Query::Ping => (),
+            Query::FindNode { target } => response.nodes = self.closest(&target),
+            Query::GetPeers { info_hash } => {
+                response.token = Some(self.token(from, false));
+                response.values = self.stored_peers(&info_hash);
+                if response.values.is_empty() {
+                    response.nodes = self.closest(&info_hash);
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                if token != self.token(from, false) && token != self.token(from, true) {
+                    return MessageKind::Error {
+                        code: 203,
+                        message: "bad token".to_owned(),
+                    };
+                }
+                let port = if implied_port { from.port() } else { port };
+                self.peers.lock().unwrap().insert(
+                    info_hash,
+                    SocketAddr::new(from.ip(), port),
+                    Instant::now(),
+                );
+            }
+        }
+        MessageKind::Response(response)
+    }
+
+    fn closest(&self, target: &NodeId) -> Vec<NodeInfo> {
+        self.table.lock().unwrap().closest(target, K)
+    }
+
+    fn insert_all(&self, nodes: &[NodeInfo]) {
+        let mut table = self.table.lock().unwrap();
+        for node in nodes {
+            table.insert(*node);
+        }
+    }
+
+    /// Token for the ip of a node, derived from the current or previous secret.
+    fn token(&self, node: SocketAddr, previous: bool) -> Vec<u8> {
+        let mut tokens = self.tokens.lock().unwrap();
+        if tokens.rotated.elapsed() >= TOKEN_ROTATION {
+            tokens.previous = tokens.current;
+            tokens.current = rand::random();
+            tokens.rotated = Instant::now();
+        }
+        let secret = if previous {
+            tokens.previous
+        } else {
+            tokens.current
+        };
+
+        let mut input = node.ip().to_string().into_bytes();
+        input.extend(secret);
+        hash_sha1(input).to_vec()
+    }
+
+    fn stored_peers(&self, info_hash: &InfoHash) -> Vec<SocketAddr> {
+        self.peers.lock().unwrap().get(info_hash, Instant::now())
+    }
+
+    /// Periodically forgets peers that did not announce again in time, so swarms nobody asks
+    /// for do not linger.
+    async fn expire_peers(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(PEER_EXPIRY_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.peers.lock().unwrap().expire(Instant::now());
+        }
+    }
+
+    /// Iteratively asks the closest known nodes for nodes closer to the target, until the `K`
+    /// closest nodes found responded. With `get_peers` the nodes are asked for peers of the
+    /// target as info hash on the way.
+    async fn lookup(self: &Arc<Self>, target: NodeId, get_peers: bool) -> Lookup {
+        let mut candidates = self
+            .closest(&target)
+            .into_iter()
+            .map(|node| (distance(&node.id, &target), node))
+            .collect::<BTreeMap<_, _>>();
+        let mut queried = HashSet::new();
+        let mut responded = BTreeMap::new();
+        let mut peers = HashSet::new();
+        let mut queries = JoinSet::new();
+
+        loop {
+            while queries.len() < ALPHA {
+                // Nodes farther than the k-th closest responding node cannot improve the result.
+                let bound = (responded.len() >= K).then(|| *responded.keys().nth(K - 1).unwrap());
+                let Some((&node_distance, &node)) = candidates
+                    .iter()
+                    .find(|(_, node)| !queried.contains(&node.id))
+                else {
+                    break;
+                };
+                if bound.is_some_and(|bound| node_distance > bound) {
+                    break;
+                }
+
+                queried.insert(node.id);
+                let dht = Arc::clone(self);
+                queries.spawn(async move {
+                    let query = if get_peers {
+                        Query::GetPeers { info_hash: target }
+                    } else {
+                        Query::FindNode { target }
+                    };
+                    (node, dht.query(SocketAddr::V4(node.addr), query).await)
+                });
+            }
+
+            let Some(result) = queries.join_next().await else {
+                break;
+            };
+            let Ok((node, result)) = result else {
+                continue;
+            };
+            match result {
+                Ok(response) => {
+                    for found in &response.nodes {
+                        candidates.insert(distance(&found.id, &target), *found);
+                    }
+                    peers.extend(response.values);
+                    responded.insert(distance(&node.id, &target), (node, response.token));
+                }
+                Err(err) => {
+                    tracing::trace!(node = %node.addr, "Dht lookup query failed: {err:#}");
+                    self.table.lock().unwrap().remove(&node.id);
+                }
+            }
+        }
+
+        Lookup {
+            peers: peers.into_iter().collect(),
+            closest: responded.into_values().take(K).collect(),
+        }
+    }
+}
+
+impl PeerStore {
+    /// Stores the peer of the swarm, unless the swarm is new and [`MAX_SWARMS`] are stored.
+    fn insert(&mut self, info_hash: InfoHash, peer: SocketAddr, now: Instant) {
+        if !self.swarms.contains_key(&info_hash) && self.swarms.len() >= MAX_SWARMS {
+            return;
+        }
+        let swarm = self.swarms.entry(info_hash).or_default();
+        if !swarm.contains_key(&peer) && swarm.len() >= MAX_SWARM_PEERS {
+            let oldest = swarm
+                .iter()
+                .min_by_key(|(_, announced)| **announced)
+                .map(|(peer, _)| *peer);
+            if let Some(oldest) = oldest {
+                swarm.remove(&oldest);
+            }
+        }
+        swarm.insert(peer, now);
+    }
+
+    /// Up to [`MAX_VALUES`] peers of the swarm that have not expired.
+    fn get(&self, info_hash: &InfoHash, now: Instant) -> Vec<SocketAddr> {
+        let Some(swarm) = self.swarms.get(info_hash) else {
+            return Vec::new();
+        };
+        swarm
+            .iter()
+            .filter(|(_, announced)| now.duration_since(**announced) < PEER_EXPIRY)
+            .map(|(peer, _)| *peer)
+            .take(MAX_VALUES)
+            .collect()
+    }
+
+    /// Forgets expired peers, and swarms left without peers.
+    fn expire(&mut self, now: Instant) {
+        self.swarms.retain(|_, swarm| {
+            swarm.retain(|_, announced| now.duration_since(*announced) < PEER_EXPIRY);
+            !swarm.is_empty()
+        });
+    }
+}
+
+/// Finds peers of a swarm through the DHT, announcing ourselves as peer on every lookup.
+pub struct DhtSource {
+    dht: Arc<Dht>,
+    info_hash: InfoHash,
+    port: u16,
+}
+
+impl DhtSource {
+    /// Announces that we are a peer of the swarm reachable at the port.
+    pub fn new(dht: Arc<Dht>, info_hash: InfoHash, port: u16) -> Self {
+        Self {
+            dht,
+            info_hash,
+            port,
+        }
+    }
+}
+
+impl PeerSource for DhtSource {
+    fn run<'a>(&'a mut self, peers: &'a PeerSender) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            loop {
+                tracing::debug!("Looking up peers in the dht");
+                match self.dht.announce(self.info_hash, self.port).await {
+                    Ok(found) => {
+                        tracing::debug!(count = found.len(), "Found peers in the dht");
+                        peers.send(found);
+                    }
+                    Err(err) => tracing::error!("Failed to look up peers in the dht: {err:#}"),
+                }
+                tokio::time::sleep(LOOKUP_INTERVAL).await;
+            }
+        })
+    }
+
+    fn is_decentralized(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn peer_store_limits() {
+        let mut store = PeerStore::default();
+        let start = Instant::now();
+
+        for port in 0..=MAX_SWARM_PEERS as u16 {
+            store.insert(
+                [0; 20],
+                peer(port),
+                start + Duration::from_secs(port.into()),
+            );
+        }
+        let swarm = &store.swarms[&[0; 20]];
+        assert_eq!(swarm.len(), MAX_SWARM_PEERS);
+        assert!(!swarm.contains_key(&peer(0)));
+
+        for swarm in 1..=MAX_SWARMS {
+            let mut info_hash = [0; 20];
+            info_hash[..8].copy_from_slice(&(swarm as u64).to_be_bytes());
+            store.insert(info_hash, peer(1), start);
+        }
+        assert_eq!(store.swarms.len(), MAX_SWARMS);
+    }
+
+    #[test]
+    fn peer_store_expiry() {
+        let mut store = PeerStore::default();
+        let start = Instant::now();
+
+        store.insert([0; 20], peer(1), start);
+        store.insert([0; 20], peer(2), start + PEER_EXPIRY);
+        store.insert([1; 20], peer(1), start);
+
+        let later = start + PEER_EXPIRY + Duration::from_secs(1);
+        assert_eq!(store.get(&[0; 20], later), vec![peer(2)]);
+        store.expire(later);
+        assert_eq!(store.swarms.len(), 1);
+        assert_eq!(store.swarms[&[0; 20]].len(), 1);
+    }
+
+    #[tokio::test]
+    async fn announce_peer_token() {
+        let dht = Dht::bind(0, vec!["127.0.0.1:1".to_owned()], None)
+            .await
+            .unwrap();
+        let announce = |token: Vec<u8>| Query::AnnouncePeer {
+            info_hash: [1; 20],
+            port: 6881,
+            implied_port: false,
+            token,
+        };
+
+        let from = peer(51413);
+        assert!(matches!(
+            dht.node.answer(announce(b"bad".to_vec()), from),
+            MessageKind::Error { code: 203, .. }
+        ));
+        assert!(dht.node.stored_peers(&[1; 20]).is_empty());
+
+        // The token handed out to another ip is no good either.
+        let MessageKind::Response(Response {
+            token: Some(token), ..
+        }) = dht
+            .node
+            .answer(Query::GetPeers { info_hash: [1; 20] }, from)
+        else {
+            panic!("get_peers answered without token");
+        };
+        let other = SocketAddr::from(([10, 0, 0, 2], 51413));
+        assert!(matches!(
+            dht.node.answer(announce(token.clone()), other),
+            MessageKind::Error { code: 203, .. }
+        ));
+
+        assert!(matches!(
+            dht.node.answer(announce(token), from),
+            MessageKind::Response(_)
+        ));
+        assert_eq!(dht.node.stored_peers(&[1; 20]), [peer(6881)]);
+    }
+}
@@ -0,0 +1,361 @@
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use anyhow::{bail, Context, Result};
+use bencode::{BencodeDict, BencodeValue};
+
+use crate::util::InfoHash;
+
+pub type NodeId = [u8; 20];
+
+/// DHT node as given in compact node info: the id followed by the IPv4 address and port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+}
+
+/// KRPC message exchanged between DHT nodes over UDP (BEP 5).
+#[derive(Debug, Clone)]
+pub struct Message {
+    /// Chosen by the querying node and echoed in the reply.
+    pub transaction_id: Vec<u8>,
+    pub kind: MessageKind,
+}
+
+#[derive(Debug, Clone)]
+pub enum MessageKind {
+    Query { id: NodeId, query: Query },
+    Response(Response),
+    Error { code: i64, message: String },
+}
+
+#[derive(Debug, Clone)]
+pub enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: InfoHash,
+    },
+    AnnouncePeer {
+        info_hash: InfoHash,
+        port: u16,
+        /// Use the source port of the UDP packet instead of `port`.
+        implied_port: bool,
+        /// Token received from the node with an earlier `get_peers` response.
+        token: Vec<u8>,
+    },
+}
+
+/// Arguments of a response. Which are present depends on the query answered.
+#[derive(Debug, Clone, Default)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+    /// Peers of the torrent asked for with `get_peers`.
+    pub values: Vec<SocketAddr>,
+    pub token: Option<Vec<u8>>,
+}
+
+impl Message {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let message = BencodeDict::new().with("t", self.transaction_id.clone());
+        let message = match &self.kind {
+            MessageKind::Query { id, query } => {
+                let (name, args) = query.encode(id);
+                message.with("y", "q").with("q", name).with("a", args)
+            }
+            MessageKind::Response(response) => message.with("y", "r").with("r", response.encode()),
+            MessageKind::Error {
+                code,
+                message: text,
+            } => message.with("y", "e").with(
+                "e",
+                vec![BencodeValue::from(*code), BencodeValue::from(text.as_str())],
+            ),
+        };
+        message.build().to_canonical_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let value = BencodeValue::try_from_bytes(bytes).context("decoding krpc message")?;
+        let transaction_id = value
+            .get("t")
+            .and_then(BencodeValue::as_bytes)
+            .context("krpc message has no transaction id")?
+            .to_vec();
+
+        let kind = match value.get("y").and_then(BencodeValue::as_bytes) {
+            Some(b"q") => {
+                let args = value.get("a").context("krpc query has no arguments")?;
+                let name = value
+                    .get("q")
+                    .and_then(BencodeValue::as_bytes)
+                    .context("krpc query has no name")?;
+                MessageKind::Query {
+                    id: node_id(args, "id")?,
+                    query: Query::decode(name, args)?,
+                }
+            }
+            Some(b"r") => MessageKind::Response(Response::decode(
+                value
+                    .get("r")
+                    .context("krpc response has no return values")?,
+            )?),
+            Some(b"e") => {
+                let error = value
+                    .get("e")
+                    .and_then(BencodeValue::as_list)
+                    .context("krpc error is not a list")?;
+                MessageKind::Error {
+                    code: error
+                        .first()
+                        .and_then(BencodeValue::as_int)
+                        .unwrap_or_default(),
+                    message: error
+                        .get(1)
+                        .and_then(BencodeValue::as_bytes)
+                        .map(|message| String::from_utf8_lossy(message).into_owned())
+                        .unwrap_or_default(),
+                }
+            }
+            _ => bail!("krpc message has unknown type"),
+        };
+
+        Ok(Self {
+            transaction_id,
+            kind,
+        })
+    }
+}
+
+impl Query {
+    fn encode(&self, id: &NodeId) -> (&'static str, BencodeValue) {
+        let args = BencodeDict::new().with("id", &id[..]);
+        let (name, args) = match self {
+            Query::Ping => ("ping", args),
+            Query::FindNode { target } => ("find_node", args.with("target", &target[..])),
+            Query::GetPeers { info_hash } => ("get_peers", args.with("info_hash", &info_hash[..])),
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => (
+                "announce_peer",
+                args.with("info_hash", &info_hash[..])
+                    .with("port", *port)
+                    .with("implied_port", *implied_port)
+                    .with("token", token.clone()),
+            ),
+        };
+        (name, args.build())
+    }
+
+    fn decode(name: &[u8], args: &BencodeValue) -> Result<Self> {
+        Ok(match name {
+            b"ping" => Query::Ping,
+            b"find_node" => Query::FindNode {
+                target: node_id(args, "target")?,
+            },
+            b"get_peers" => Query::GetPeers {
+                info_hash: node_id(args, "info_hash")?,
+            },
+            b"announce_peer" => Query::AnnouncePeer {
+                info_hash: node_id(args, "info_hash")?,
+                port: args
+                    .get("port")
+                    .and_then(BencodeValue::as_int)
+                    .and_then(|port| u16::try_from(port).ok())
+                    .context("announce_peer has no valid port")?,
+                implied_port: args.get("implied_port").and_then(BencodeValue::as_int) == Some(1),
+                token: args
+                    .get("token")
+                    .and_then(BencodeValue::as_bytes)
+                    .context("announce_peer has no token")?
+                    .to_vec(),
+            },
+            name => bail!("unknown krpc query `{}`", String::from_utf8_lossy(name)),
+        })
+    }
+}
+
+impl Response {
+    fn encode(&self) -> BencodeValue {
+        let nodes = self
+            .nodes
+            .iter()
+            .flat_map(|node| {
+                let mut compact = node.id.to_vec();
+                compact.extend(node.addr.ip().octets());
+                compact.extend(node.addr.port().to_be_bytes());
+                compact
+            })
+            .collect::<Vec<_>>();
+        let values = self
+            .values
+            .iter()
+            .filter_map(|peer| match peer {
+                SocketAddr::V4(peer) => {
+                    let mut compact = peer.ip().octets().to_vec();
+                    compact.extend(peer.port().to_be_bytes());
+                    Some(BencodeValue::from(compact))
+                }
+                SocketAddr::V6(_) => None,
+            })
+            .collect::<Vec<_>>();
+
+        BencodeDict::new()
+            .with("id", &self.id[..])
+            .with_opt("nodes", (!nodes.is_empty()).then_some(nodes))
+            .with_opt("values", (!values.is_empty()).then_some(values))
+            .with_opt("token", self.token.clone())
+            .build()
+    }
+
+    fn decode(values: &BencodeValue) -> Result<Self> {
+        let nodes = match values.get("nodes").and_then(BencodeValue::as_bytes) {
+            Some(nodes) => nodes
+                .chunks_exact(26)
+                .map(|node| NodeInfo {
+                    id: node[..20].try_into().expect("chunk holds a node id"),
+                    addr: compact_addr(&node[20..]),
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        let peers = values
+            .get("values")
+            .and_then(BencodeValue::as_list)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(BencodeValue::as_bytes)
+            .filter(|peer| peer.len() == 6)
+            .map(|peer| SocketAddr::V4(compact_addr(peer)))
+            .collect();
+
+        Ok(Self {
+            id: node_id(values, "id")?,
+            nodes,
+            values: peers,
+            token: values
+                .get("token")
+                .and_then(BencodeValue::as_bytes)
+                .map(<[u8]>::to_vec),
+        })
+    }
+}
+
+fn node_id(args: &BencodeValue, key: &str) -> Result<NodeId> {
+    args.get(key)
+        .and_then(BencodeValue::as_bytes)
+        .and_then(|id| id.try_into().ok())
+        .with_context(|| format!("krpc message has no valid `{key}`"))
+}
+
+/// Reads a 6 byte compact IPv4 address and port.
+fn compact_addr(bytes: &[u8]) -> SocketAddrV4 {
+    SocketAddrV4::new(
+        Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]),
+        u16::from_be_bytes([bytes[4], bytes[5]]),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes and decodes the message, checking the transaction id survives.
+    fn round_trip(kind: MessageKind) -> MessageKind {
+        let message = Message {
+            transaction_id: b"aa".to_vec(),
+            kind,
+        };
+        let decoded = Message::from_bytes(&message.to_bytes()).unwrap();
+        assert_eq!(decoded.transaction_id, b"aa");
+        assert_eq!(decoded.to_bytes(), message.to_bytes());
+        decoded.kind
+    }
+
+    fn query(query: Query) -> Query {
+        match round_trip(MessageKind::Query { id: [1; 20], query }) {
+            MessageKind::Query { id, query } => {
+                assert_eq!(id, [1; 20]);
+                query
+            }
+            kind => panic!("decoded {kind:?} instead of a query"),
+        }
+    }
+
+    #[test]
+    fn query_round_trip() {
+        assert!(matches!(query(Query::Ping), Query::Ping));
+        assert!(matches!(
+            query(Query::FindNode { target: [2; 20] }),
+            Query::FindNode { target } if target == [2; 20]
+        ));
+        assert!(matches!(
+            query(Query::GetPeers { info_hash: [3; 20] }),
+            Query::GetPeers { info_hash } if info_hash == [3; 20]
+        ));
+        assert!(matches!(
+            query(Query::AnnouncePeer {
+                info_hash: [3; 20],
+                port: 6881,
+                implied_port: true,
+                token: b"token".to_vec(),
+            }),
+            Query::AnnouncePeer {
+                info_hash,
+                port: 6881,
+                implied_port: true,
+                token,
+            } if info_hash == [3; 20] && token == b"token"
+        ));
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let node = NodeInfo {
+            id: [2; 20],
+            addr: "10.0.0.1:6881".parse().unwrap(),
+        };
+        let peer = "10.0.0.2:51413".parse().unwrap();
+        let response = Response {
+            id: [1; 20],
+            nodes: vec![node],
+            values: vec![peer],
+            token: Some(b"token".to_vec()),
+        };
+
+        let MessageKind::Response(decoded) = round_trip(MessageKind::Response(response)) else {
+            panic!("decoded no response");
+        };
+        assert_eq!(decoded.id, [1; 20]);
+        assert_eq!(decoded.nodes, [node]);
+        assert_eq!(decoded.values, [peer]);
+        assert_eq!(decoded.token.as_deref(), Some(&b"token"[..]));
+
+        // A bare response, as to a ping.
+        let MessageKind::Response(decoded) = round_trip(MessageKind::Response(Response {
+            id: [1; 20],
+            ..Response::default()
+        })) else {
+            panic!("decoded no response");
+        };
+        assert!(decoded.nodes.is_empty() && decoded.values.is_empty() && decoded.token.is_none());
+    }
+
+    #[test]
+    fn error_round_trip() {
+        let kind = round_trip(MessageKind::Error {
+            code: 203,
+            message: "bad token".to_owned(),
+        });
+        assert!(matches!(
+            kind,
+            MessageKind::Error { code: 203, message } if message == "bad token"
+        ));
+    }
+}
@@ -0,0 +1,155 @@
+use std::time::{Duration, Instant};
+
+use super::krpc::{NodeId, NodeInfo};
+
+/// Nodes kept per bucket.
+pub const K: usize = 8;
+/// Nodes not heard from for this long may be replaced by new ones.
+const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// Kademlia routing table, with a bucket for every length of the prefix a node id shares with our
+/// own. This is the table a fully split BEP 5 table would end up as.
+#[derive(Debug)]
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    node: NodeInfo,
+    last_seen: Instant,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    /// Records that the node is alive, adding it when its bucket has room.
+    pub fn insert(&mut self, node: NodeInfo) {
+        let Some(index) = self.bucket_index(&node.id) else {
+            return;
+        };
+        let bucket = &mut self.buckets[index];
+        let now = Instant::now();
+
+        if let Some(entry) = bucket.iter_mut().find(|entry| entry.node.id == node.id) {
+            *entry = Entry {
+                node,
+                last_seen: now,
+            };
+        } else if bucket.len() < K {
+            bucket.push(Entry {
+                node,
+                last_seen: now,
+            });
+        } else if let Some(stale) = bucket
+            .iter_mut()
+            .filter(|entry| now.duration_since(entry.last_seen) >= QUESTIONABLE_AFTER)
+            .min_by_key(|entry| entry.last_seen)
+        {
+            *stale = Entry {
+                node,
+                last_seen: now,
+            };
+        }
+    }
+
+    /// Drops a node that failed to answer.
+    pub fn remove(&mut self, id: &NodeId) {
+        if let Some(index) = self.bucket_index(id) {
+            self.buckets[index].retain(|entry| entry.node.id != *id);
+        }
+    }
+
+    /// Up to `count` known nodes closest to the target.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes = self
+            .buckets
+            .iter()
+            .flatten()
+            .map(|entry| entry.node)
+            .collect::<Vec<_>>();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.iter().all(Vec::is_empty)
+    }
+
+    /// Length of the prefix shared with our id, `None` for our own id.
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.id, id);
+        let index = distance.iter().position(|&byte| byte != 0)?;
+        Some(index * 8 + distance[index].leading_zeros() as usize)
+    }
+}
+
+/// XOR metric of Kademlia, compared as big-endian number.
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: NodeId) -> NodeInfo {
+        NodeInfo {
+            id,
+            addr: "10.0.0.1:6881".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn bucket_index() {
+        let table = RoutingTable::new([0; 20]);
+        assert_eq!(table.bucket_index(&[0; 20]), None);
+
+        let mut id = [0; 20];
+        id[0] = 0x80;
+        assert_eq!(table.bucket_index(&id), Some(0));
+        id[0] = 0x01;
+        assert_eq!(table.bucket_index(&id), Some(7));
+        id = [0; 20];
+        id[19] = 1;
+        assert_eq!(table.bucket_index(&id), Some(159));
+    }
+
+    #[test]
+    fn closest() {
+        let mut table = RoutingTable::new([0; 20]);
+        // Our own id is never stored.
+        table.insert(node([0; 20]));
+        assert!(table.is_empty());
+
+        for byte in [0x01, 0x10, 0x30, 0x80] {
+            table.insert(node([byte; 20]));
+        }
+        let closest = table
+            .closest(&[0x11; 20], 3)
+            .into_iter()
+            .map(|node| node.id[0])
+            .collect::<Vec<_>>();
+        assert_eq!(closest, [0x10, 0x01, 0x30]);
+
+        table.remove(&[0x10; 20]);
+        assert_eq!(table.closest(&[0x11; 20], 1)[0].id, [0x01; 20]);
+    }
+
+    #[test]
+    fn full_bucket() {
+        let mut table = RoutingTable::new([0; 20]);
+        // All in the bucket of ids starting with a one bit.
+        for byte in 0x80..=0x80 + K as u8 {
+            table.insert(node([byte; 20]));
+        }
+        assert_eq!(table.closest(&[0xff; 20], K + 1).len(), K);
+    }
+}
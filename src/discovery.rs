@@ -0,0 +1,112 @@
+use std::{future::Future, net::SocketAddr, pin::Pin};
+
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Mechanism finding peers of a torrent, such as a tracker. The downloader runs every source in
+/// its own task and merges what they find.
+pub trait PeerSource: Send + 'static {
+    /// Finds peers and reports them through `peers`. Runs until the download stops, at which
+    /// point the future is dropped.
+    fn run<'a>(&'a mut self, peers: &'a PeerSender) -> BoxFuture<'a, ()>;
+
+    /// Whether the source finds peers outside the control of the torrent's trackers, such as the
+    /// DHT, peer exchange or local discovery. BEP 27 forbids these for private torrents.
+    fn is_decentralized(&self) -> bool {
+        false
+    }
+
+    /// Called once when the download stops, e.g. to tell a tracker the client left the swarm.
+    /// `completed` tells whether all pieces were downloaded.
+    fn stop(&mut self, completed: bool) -> BoxFuture<'_, ()> {
+        let _ = completed;
+        Box::pin(async {})
+    }
+}
+
+/// Peers found by a source, tagged with the index of the swarm they were found in.
+pub type DiscoveredPeer = (SocketAddr, usize);
+
+/// Hands found peers to the downloader on behalf of a source.
+#[derive(Debug, Clone)]
+pub struct PeerSender {
+    swarm: usize,
+    tx: mpsc::UnboundedSender<DiscoveredPeer>,
+}
+
+impl PeerSender {
+    pub fn send(&self, peers: impl IntoIterator<Item = SocketAddr>) {
+        for peer in peers {
+            // The downloader is gone, nobody is interested in peers anymore.
+            if self.tx.send((peer, self.swarm)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Running peer source, stopped through [`SourceHandle::stop`].
+pub struct SourceHandle {
+    stop_tx: oneshot::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+/// Runs every source allowed for the torrent, each in its own task. Private torrents only get
+/// peers from their trackers, so decentralized sources are left out for them.
+pub fn spawn_sources(
+    sources: Vec<(usize, Box<dyn PeerSource>)>,
+    private: bool,
+    tx: &mpsc::UnboundedSender<DiscoveredPeer>,
+) -> Vec<SourceHandle> {
+    let (allowed, forbidden): (Vec<_>, Vec<_>) = sources
+        .into_iter()
+        .partition(|(_, source)| !private || !source.is_decentralized());
+    if !forbidden.is_empty() {
+        tracing::info!("Torrent is private, finding peers through its trackers only");
+    }
+    allowed
+        .into_iter()
+        .map(|(swarm, source)| spawn_source(source, swarm, tx.clone()))
+        .collect()
+}
+
+/// Runs the source in a task, sending its peers of the given swarm into `tx`.
+fn spawn_source(
+    mut source: Box<dyn PeerSource>,
+    swarm: usize,
+    tx: mpsc::UnboundedSender<DiscoveredPeer>,
+) -> SourceHandle {
+    let (stop_tx, stop_rx) = oneshot::channel();
+    let task = tokio::spawn(async move {
+        let sender = PeerSender { swarm, tx };
+        let mut stop_rx = stop_rx;
+        let stopped = tokio::select! {
+            () = source.run(&sender) => None,
+            completed = &mut stop_rx => Some(completed),
+        };
+        // Sources that are done early still get to wrap up when the download stops.
+        let Ok(completed) = (match stopped {
+            Some(completed) => completed,
+            None => stop_rx.await,
+        }) else {
+            return;
+        };
+        source.stop(completed).await;
+    });
+    SourceHandle { stop_tx, task }
+}
+
+impl SourceHandle {
+    /// Stops finding peers and waits for the source to wrap up.
+    pub async fn stop(self, completed: bool) {
+        if self.stop_tx.send(completed).is_ok() {
+            if let Err(err) = self.task.await {
+                tracing::warn!("Peer source task failed: {err}");
+            }
+        }
+    }
+}
@@ -1,33 +1,77 @@
 use std::{
-    collections::{HashMap, VecDeque},
-    io::{Seek, Write},
-    net::SocketAddrV4,
+    collections::{HashMap, HashSet, VecDeque},
+    io::{Read, Seek, SeekFrom, Write},
+    net::{IpAddr, SocketAddr},
     path::Path,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use reqwest::Client;
 use tokio::{
-    sync::watch,
-    task::{AbortHandle, JoinHandle, JoinSet},
+    sync::{mpsc, watch},
+    task::{AbortHandle, JoinSet},
 };
 
+use self::ban::BanList;
 use crate::{
-    peer::{Connected, Peer, PieceDescriptor},
-    torrent::Torrent,
-    tracker::{Peers, Tracker, TrackerResponse},
+    dht::{Dht, DhtSource},
+    discovery::{spawn_sources, PeerSource},
+    magnet::MagnetLink,
+    peer::{
+        Bitfield, Connected, Disconnected, LocalBind, Peer, PeerTimeouts, PieceDescriptor,
+        UploadRequest, DEFAULT_PIPELINE_DEPTH,
+    },
+    storage::{PathPolicy, Storage},
+    torrent::{MetaVersion, Torrent, TorrentInfo},
+    tracker::{Tracker, TrackerClient},
     util::Sha1Hash,
-    util::{calculate_piece_length, PeerId},
+    util::{calculate_piece_length, piece_offset, InfoHash, PeerId},
+    webseed::WebSeed,
 };
 
+mod ban;
+mod listener;
+
 const MAX_CONCURRENT_DOWNLOADS: usize = 20;
+/// Time a source may take to deliver a piece once it is connected and unchoked us.
 const PIECE_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct TorrentDownloader {
     piece_queue: VecDeque<PieceDescriptor>,
-    tracker: Tracker,
+    /// Info hash of every swarm the torrent is shared in.
+    info_hashes: Vec<InfoHash>,
+    /// One tracker per swarm, none for trackerless torrents.
+    trackers: Vec<Tracker>,
+    /// Finds peers of every swarm next to the trackers, unless the torrent is private.
+    dht: Option<Arc<Dht>>,
+    /// Restricts peer discovery to the trackers of the torrent (BEP 27).
+    private: bool,
+    /// Port announced as the one we are reachable at.
+    port: u16,
+    web_seeds: Vec<WebSeed>,
     client_peer_id: PeerId,
+    /// Block requests kept outstanding per peer.
+    pipeline_depth: usize,
+    peer_timeouts: PeerTimeouts,
+    /// Local address or interface peer connections are made and accepted on.
+    bind: Option<LocalBind>,
     torrent_piece_length: u32,
+    info: TorrentInfo,
+    path_policy: PathPolicy,
+}
+
+/// Where a piece is downloaded from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum DownloadSource {
+    Peer {
+        addr: SocketAddr,
+        /// Index of the swarm the peer was found in, telling which info hash to greet it with.
+        swarm: usize,
+    },
+    /// Index into the web seeds of the download.
+    WebSeed(usize),
 }
 
 fn generate_piece_queue(
@@ -59,105 +103,186 @@ fn generate_piece_queue(
     VecDeque::from_iter(piece_descriptors)
 }
 
-fn spawn_tracker_poller(
-    tracker: Tracker,
-    tracker_tx: watch::Sender<Option<Peers>>,
-) -> JoinHandle<()> {
-    tokio::spawn(async move {
-        let mut last_interval = None;
-
-        // Close this loop using task aborting.
-        loop {
-            tracing::debug!("Polling tracker");
-            let TrackerResponse { peers, interval } = match tracker.poll().await {
-                Ok(res) => res,
-                Err(err) => {
-                    tracing::error!("{}", err);
-
-                    if let Some(last_interval) = last_interval {
-                        tracing::error!("Failed to poll tracker");
-                        tokio::time::sleep(last_interval).await;
-                    }
-                    continue;
-                }
-            };
-
-            last_interval = Some(interval);
+/// Lists the sources without a download in progress. Web seeds never choke, so they come first
+/// and are available even before the tracker answered. Snubbing peers come last, banned ones and
+/// ones waiting to be retried not at all.
+/// Peers in several swarms are only used once.
+fn fetch_idle_sources(
+    active_peers: &HashMap<DownloadSource, PieceDownloadPending>,
+    retired: &HashSet<DownloadSource>,
+    web_seeds: usize,
+    known_peers: &HashMap<SocketAddr, usize>,
+    bans: &mut BanList,
+) -> Vec<DownloadSource> {
+    let busy_peers = active_peers
+        .keys()
+        .filter_map(DownloadSource::peer_addr)
+        .collect::<HashSet<_>>();
+
+    let mut peers = known_peers
+        .iter()
+        .filter(|(addr, _)| {
+            !busy_peers.contains(addr)
+                && bans.ban_reason(addr).is_none()
+                && !bans.is_backing_off(addr)
+        })
+        .map(|(&addr, &swarm)| DownloadSource::Peer { addr, swarm })
+        .collect::<Vec<_>>();
+    peers.sort_by_key(|source| {
+        source
+            .peer_addr()
+            .is_some_and(|addr| bans.is_snubbed(&addr))
+    });
+
+    (0..web_seeds)
+        .map(DownloadSource::WebSeed)
+        .filter(|s| !active_peers.contains_key(s))
+        .chain(peers)
+        .filter(|s| !retired.contains(s))
+        .collect()
+}
 
-            tracker_tx.send_replace(Some(peers));
-            tokio::time::sleep(interval).await;
+fn spawn_web_seed_download_task(
+    index: usize,
+    web_seed: WebSeed,
+    client: Client,
+    piece_des: PieceDescriptor,
+    handles: &mut JoinSet<PieceDownloadResult>,
+) -> AbortHandle {
+    handles.spawn(async move {
+        let source = DownloadSource::WebSeed(index);
+        match web_seed.download_piece(&client, piece_des.clone()).await {
+            Ok(piece_bytes) => PieceDownloadResult::Success {
+                source,
+                piece: (piece_des, piece_bytes),
+                peer: None,
+            },
+            Err(err) => {
+                tracing::warn!(url = web_seed.url(), "Web seed download failed: {err:#}");
+                PieceDownloadResult::Error {
+                    source,
+                    piece_des,
+                    error: format!("{err:#}"),
+                }
+            }
         }
     })
 }
 
-async fn fetch_new_peers<'a>(
-    active_peers: &'a HashMap<SocketAddrV4, PieceDownloadPending>,
-    tracker_rx: &mut watch::Receiver<Option<Peers>>,
-) -> Option<impl Iterator<Item = SocketAddrV4> + 'a> {
-    let Some(usable_peers) = tracker_rx.borrow_and_update().clone() else {
-        tokio::time::sleep(Duration::from_millis(100)).await;
-        return None;
-    };
-
-    Some(
-        usable_peers
-            .into_socket_addrs()
-            .into_iter()
-            .filter(|p| !active_peers.contains_key(p)),
-    )
-}
-
 fn spawn_piece_download_task(
-    peer_socket_addr: SocketAddrV4,
+    peer: PeerConnection,
+    swarm: usize,
     piece_des: PieceDescriptor,
-    info_hash: Sha1Hash,
-    client_peer_id: PeerId,
+    context: PeerContext,
     handles: &mut JoinSet<PieceDownloadResult>,
 ) -> AbortHandle {
     handles.spawn(async move {
-        let Ok(mut peer) = Peer::from_socket(peer_socket_addr)
-            .handshake(info_hash, client_peer_id)
-            .await
-        else {
-            return PieceDownloadResult::Error {
-                peer_socket_addr,
-                piece_des,
-            };
+        let mut peer = match peer {
+            PeerConnection::Idle(peer) => peer,
+            PeerConnection::New(peer) => {
+                let source = DownloadSource::Peer {
+                    addr: peer.socket_addr(),
+                    swarm,
+                };
+                let peer = match peer
+                    .handshake(
+                        context.info_hash,
+                        context.client_peer_id,
+                        context.piece_count,
+                    )
+                    .await
+                {
+                    Ok(peer) => peer,
+                    Err(err) => {
+                        return PieceDownloadResult::Error {
+                            source,
+                            piece_des,
+                            error: format!("{err:#}"),
+                        }
+                    }
+                };
+
+                // Nodes of peers in the swarm are likely to know about the torrent as well.
+                if let (Some(dht), Some(port)) = (&context.dht, peer.dht_port()) {
+                    dht.add_node(SocketAddr::new(peer.socket_addr().ip(), port));
+                }
+                peer
+            }
+        };
+        let source = DownloadSource::Peer {
+            addr: peer.socket_addr(),
+            swarm,
         };
 
-        let Ok(piece_bytes) = peer.download_piece(piece_des.clone()).await else {
-            return PieceDownloadResult::Error {
-                peer_socket_addr,
+        if !peer.pieces().has(piece_des.index) {
+            return PieceDownloadResult::Unavailable {
+                source,
                 piece_des,
+                peer,
             };
+        }
+
+        let piece_bytes = match peer.download_piece(piece_des.clone()).await {
+            Ok(piece_bytes) => piece_bytes,
+            Err(err) => {
+                return PieceDownloadResult::Error {
+                    source,
+                    piece_des,
+                    error: format!("{err:#}"),
+                }
+            }
         };
 
         PieceDownloadResult::Success {
-            peer,
+            source,
             piece: (piece_des, piece_bytes),
+            peer: Some(peer),
         }
     })
 }
 
-fn check_piece_download_timeout<'a>(
-    active_peers: impl IntoIterator<Item = &'a PieceDownloadPending>,
+/// Takes the first queued piece the source can provide. Peers we have not talked to yet are
+/// assumed to have every piece.
+fn take_piece(
+    piece_queue: &mut VecDeque<PieceDescriptor>,
+    pieces: Option<&Bitfield>,
+) -> Option<PieceDescriptor> {
+    let position = match pieces {
+        Some(pieces) => piece_queue
+            .iter()
+            .position(|piece_des| pieces.has(piece_des.index))?,
+        None => 0,
+    };
+    piece_queue.remove(position)
+}
+
+/// Aborts downloads that take too long, returning their sources.
+fn check_piece_download_timeout(
+    active_peers: &HashMap<DownloadSource, PieceDownloadPending>,
     piece_queue: &mut VecDeque<PieceDescriptor>,
-) {
+) -> Vec<DownloadSource> {
     let now = Instant::now();
-    for PieceDownloadPending {
-        started_at,
-        abort_handle,
-        piece_des,
-    } in active_peers.into_iter()
+    let mut timed_out = Vec::new();
+    for (
+        source,
+        PieceDownloadPending {
+            started_at,
+            timeout,
+            abort_handle,
+            piece_des,
+        },
+    ) in active_peers
     {
-        if now.duration_since(*started_at) < PIECE_DOWNLOAD_TIMEOUT {
+        if now.duration_since(*started_at) < *timeout {
             continue;
         }
 
         tracing::warn!("Piece download timeout occurs!");
         abort_handle.abort();
         piece_queue.push_back(piece_des.clone());
+        timed_out.push(*source);
     }
+    timed_out
 }
 
 fn write_piece_to_writer<W: Write + Seek>(
@@ -167,86 +292,413 @@ fn write_piece_to_writer<W: Write + Seek>(
     writer: &mut W,
 ) -> Result<()> {
     writer
-        .seek(std::io::SeekFrom::Start(u64::from(
-            piece_des.index * torrent_piece_length,
+        .seek(SeekFrom::Start(piece_offset(
+            torrent_piece_length,
+            piece_des.index,
         )))
         .context("seeking position in writer")?;
     writer.write_all(&piece).context("writing to writer")
 }
 
+/// Answers a request of a peer from the pieces we have, returning the number of bytes uploaded.
+/// Requests reaching past the end of their piece disconnect the peer.
+fn serve_upload<R: Read + Seek>(
+    request: UploadRequest,
+    completed: &Bitfield,
+    torrent_piece_length: u32,
+    total_length: u64,
+    reader: &mut R,
+) -> u64 {
+    if !completed.has(request.index) {
+        tracing::trace!(index = request.index, "Declining request for missing piece");
+        request.decline();
+        return 0;
+    }
+    let piece_length = calculate_piece_length(torrent_piece_length, total_length, request.index);
+    match request.begin.checked_add(request.length) {
+        Some(end) if end <= piece_length => (),
+        _ => {
+            let reason = format!(
+                "block at {} of {} bytes is out of range for piece of {piece_length} bytes",
+                request.begin, request.length
+            );
+            request.reject(reason);
+            return 0;
+        }
+    }
+
+    match read_block_from_reader(&request, torrent_piece_length, reader) {
+        Ok(block) => {
+            let length = block.len() as u64;
+            request.respond(block.into());
+            length
+        }
+        Err(err) => {
+            tracing::warn!(
+                index = request.index,
+                "Reading block to upload failed: {err:#}"
+            );
+            request.decline();
+            0
+        }
+    }
+}
+
+fn read_block_from_reader<R: Read + Seek>(
+    request: &UploadRequest,
+    torrent_piece_length: u32,
+    reader: &mut R,
+) -> Result<Vec<u8>> {
+    reader
+        .seek(SeekFrom::Start(
+            piece_offset(torrent_piece_length, request.index) + u64::from(request.begin),
+        ))
+        .context("seeking position in reader")?;
+    let mut block = vec![0; request.length as usize];
+    reader
+        .read_exact(&mut block)
+        .context("reading from reader")?;
+    Ok(block)
+}
+
 impl TorrentDownloader {
     pub async fn new(
         torrent: Torrent,
-        // peer_socket_addresses: impl IntoIterator<Item = SocketAddrV4>,
+        // peer_socket_addresses: impl IntoIterator<Item = SocketAddr>,
         // client_peer_id: PeerId,
     ) -> Result<Self> {
         let tracker = Tracker::from(&torrent);
-
         let client_peer_id = *tracker.peer_id();
+        let info_hashes = torrent.swarm_info_hashes();
+        let trackers = if torrent.announce_tiers().next().is_some() {
+            info_hashes
+                .iter()
+                .map(|info_hash| tracker.with_info_hash(*info_hash))
+                .collect()
+        } else {
+            Vec::new()
+        };
 
-        let torrent_length = torrent.info.length;
+        if torrent.version == MetaVersion::V2 {
+            bail!("downloading v2-only torrents is not supported");
+        }
+
+        let torrent_length = torrent.total_size();
         let piece_length = torrent.info.piece_length;
-        let piece_hashes = torrent.info.pieces;
+        let piece_hashes = torrent.info.v1_pieces().to_vec();
 
         let piece_queue = generate_piece_queue(piece_hashes, piece_length, torrent_length);
+        let web_seeds = torrent
+            .url_list
+            .iter()
+            .map(|url| WebSeed::new(url, &torrent.info))
+            .collect();
 
         Ok(Self {
             piece_queue,
-            tracker,
+            info_hashes,
+            trackers,
+            dht: None,
+            private: torrent.is_private(),
+            port: 6881,
+            web_seeds,
             client_peer_id,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            peer_timeouts: PeerTimeouts::default(),
+            bind: None,
             torrent_piece_length: torrent.info.piece_length,
+            info: torrent.info,
+            path_policy: PathPolicy::default(),
         })
     }
 
+    /// Starts from a magnet link, fetching the torrent metadata from the swarm first.
+    pub async fn from_magnet(
+        magnet: &MagnetLink,
+        tracker_client: TrackerClient,
+        dht: Option<Arc<Dht>>,
+        bind: Option<LocalBind>,
+    ) -> Result<Self> {
+        let torrent = magnet
+            .fetch_torrent(&tracker_client, dht.as_deref(), bind.as_ref())
+            .await
+            .context("fetching torrent metadata")?;
+        let mut downloader = Self::new(torrent).await?.tracker_client(tracker_client);
+        if let Some(dht) = dht {
+            downloader = downloader.dht(dht);
+        }
+        if let Some(local) = bind {
+            downloader = downloader.bind(local);
+        }
+        Ok(downloader)
+    }
+
+    /// Sets the http client the trackers are announced to with.
+    pub fn tracker_client(mut self, client: TrackerClient) -> Self {
+        self.trackers = self
+            .trackers
+            .into_iter()
+            .map(|tracker| tracker.client(client.clone()))
+            .collect();
+        self
+    }
+
+    /// Announces the port to trackers and the DHT as the one we are reachable at.
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        for tracker in &self.trackers {
+            tracker.set_port(port);
+        }
+        self
+    }
+
+    /// Announces to all tracker tiers at once, unless the torrent is private.
+    pub fn all_tiers(mut self, all_tiers: bool) -> Self {
+        self.trackers = self
+            .trackers
+            .into_iter()
+            .map(|tracker| tracker.all_tiers(all_tiers))
+            .collect();
+        self
+    }
+
+    /// Reports the address to the trackers instead of the one announces come from.
+    pub fn announce_ip(mut self, ip: IpAddr) -> Self {
+        self.trackers = self
+            .trackers
+            .into_iter()
+            .map(|tracker| tracker.ip(ip))
+            .collect();
+        self
+    }
+
+    /// Looks for peers in the DHT as well. Ignored for private torrents, which only get their
+    /// peers from trackers (BEP 27).
+    pub fn dht(mut self, dht: Arc<Dht>) -> Self {
+        self.dht = Some(dht);
+        self
+    }
+
+    /// Sets how many block requests are kept outstanding per peer.
+    pub fn pipeline_depth(mut self, depth: usize) -> Self {
+        self.pipeline_depth = depth;
+        self
+    }
+
+    /// Sets how long connecting and talking to peers may take before they are given up.
+    pub fn peer_timeouts(mut self, timeouts: PeerTimeouts) -> Self {
+        self.peer_timeouts = timeouts;
+        self
+    }
+
+    /// Makes and accepts peer connections on the local address or interface only.
+    pub fn bind(mut self, local: LocalBind) -> Self {
+        self.bind = Some(local);
+        self
+    }
+
+    /// Sets how file paths that are unsafe to create on disk are handled.
+    pub fn path_policy(mut self, path_policy: PathPolicy) -> Self {
+        self.path_policy = path_policy;
+        self
+    }
+
+    /// Downloads a single file torrent to the location, or the files of a multi-file torrent
+    /// into the location as directory.
     pub async fn download_to_location(self, location: impl AsRef<Path>) -> Result<()> {
-        let mut file =
-            std::fs::File::create(location).context("creating file for downloading torrent")?;
-        self.download(&mut file).await
+        let mut storage = Storage::create(location.as_ref(), &self.info, self.path_policy)
+            .context("creating files for downloading torrent")?;
+        self.download(&mut storage).await?;
+        storage.finish().context("applying torrent file attributes")
     }
 
-    pub async fn download<W: Write + Seek>(mut self, writer: &mut W) -> Result<()> {
+    /// Downloads the torrent into `writer`, uploading the pieces written so far to peers that
+    /// ask for them.
+    pub async fn download<W: Read + Write + Seek>(mut self, writer: &mut W) -> Result<()> {
         let mut handles = JoinSet::new();
 
-        let info_hash = *self.tracker.info_hash();
+        let info_hashes = self.info_hashes.clone();
+        let piece_count =
+            u32::try_from(self.info.v1_pieces().len()).context("too many pieces in torrent")?;
 
-        let (tracker_tx, mut tracker_rx) = watch::channel(None);
         let mut active_peers = HashMap::new();
+        let mut retired = HashSet::new();
+        let mut bans = BanList::default();
+        // Requests of peers for the pieces we have.
+        let (uploads_tx, mut uploads_rx) = mpsc::unbounded_channel();
+        // Pieces we have, announced to every connected peer as they are completed.
+        let (completed, completed_rx) = watch::channel(Bitfield::new(piece_count));
+
+        // Sources of a swarm find peers to greet with the info hash of that swarm.
+        let (peers_tx, mut peers_rx) = mpsc::unbounded_channel();
+        let mut sources = self
+            .trackers
+            .iter()
+            .enumerate()
+            .map(|(swarm, tracker)| (swarm, Box::new(tracker.clone()) as Box<dyn PeerSource>))
+            .collect::<Vec<_>>();
+        if let Some(dht) = &self.dht {
+            for (swarm, info_hash) in info_hashes.iter().enumerate() {
+                sources.push((
+                    swarm,
+                    Box::new(DhtSource::new(dht.clone(), *info_hash, self.port)),
+                ));
+            }
+        }
+        let source_handles = spawn_sources(sources, self.private, &peers_tx);
+        // Peers of private torrents are not told about our DHT node either (BEP 27).
+        let dht = self.dht.clone().filter(|_| !self.private);
+        let dht_port = match &dht {
+            Some(dht) => Some(dht.port().context("reading dht port")?),
+            None => None,
+        };
+        let settings = PeerSettings {
+            pipeline_depth: self.pipeline_depth,
+            timeouts: self.peer_timeouts,
+            dht_port,
+            uploads: uploads_tx,
+            pieces: completed_rx,
+            bind: self.bind.clone(),
+        };
+        let web_seed_client = WebSeed::client(self.bind.as_ref())?;
+
+        // Peers connecting to us are greeted in the swarm they ask for.
+        let (inbound_tx, mut inbound_rx) = mpsc::unbounded_channel();
+        let listener = match listener::spawn_listener(
+            self.port,
+            info_hashes.clone(),
+            self.client_peer_id,
+            piece_count,
+            settings.clone(),
+            inbound_tx,
+        )
+        .await
+        {
+            Ok(handle) => Some(handle),
+            Err(err) => {
+                tracing::warn!("Not accepting peer connections: {err:#}");
+                None
+            }
+        };
 
-        let tracker_handle = spawn_tracker_poller(self.tracker, tracker_tx);
-
-        'main: loop {
-            let Some(new_peers) = fetch_new_peers(&active_peers, &mut tracker_rx).await else {
-                tokio::time::sleep(Duration::from_millis(100)).await;
-                continue;
-            };
+        let mut known_peers = HashMap::new();
+        // Peers that connected to us, from a port we cannot connect back to.
+        let mut inbound_peers = HashSet::new();
+        // Connections kept open to peers between pieces.
+        let mut idle_peers: HashMap<SocketAddr, Peer<Connected>> = HashMap::new();
+
+        let total_length = self.info.total_length();
+        let mut downloaded = 0;
+        let mut uploaded = 0;
+        let mut result = Ok(());
+        'download: loop {
+            while let Ok((addr, swarm)) = peers_rx.try_recv() {
+                if let Some(reason) = bans.ban_reason(&addr) {
+                    tracing::trace!(peer = %addr, "Ignoring banned peer: {reason}");
+                    continue;
+                }
+                known_peers.entry(addr).or_insert(swarm);
+            }
+            while let Ok((swarm, peer)) = inbound_rx.try_recv() {
+                let addr = peer.socket_addr();
+                if let Some(reason) = bans.ban_reason(&addr) {
+                    tracing::trace!(peer = %addr, "Dropping banned peer: {reason}");
+                    peer.shutdown();
+                    continue;
+                }
+                known_peers.entry(addr).or_insert(swarm);
+                inbound_peers.insert(addr);
+                if let Some(replaced) = idle_peers.insert(addr, peer) {
+                    replaced.shutdown();
+                }
+            }
+            let new_sources = fetch_idle_sources(
+                &active_peers,
+                &retired,
+                self.web_seeds.len(),
+                &known_peers,
+                &mut bans,
+            );
 
             let mut new_active_peers = HashMap::new();
             // Start a task for every peer that is inactive.
-            for peer in new_peers {
+            for source in new_sources {
                 if active_peers.len() + new_active_peers.len() >= MAX_CONCURRENT_DOWNLOADS {
                     tracing::debug!("Max concurrent downloads reached!");
                     break;
                 }
 
-                let piece_des = match self.piece_queue.pop_front() {
-                    Some(p) => p,
-                    None => break 'main,
+                if self.piece_queue.is_empty() {
+                    break;
+                }
+                let mut idle = source.peer_addr().and_then(|addr| idle_peers.remove(&addr));
+                if let Some(peer) = &mut idle {
+                    if let Err(err) = peer.refresh() {
+                        tracing::debug!(peer = %peer.socket_addr(), "Peer connection lost: {err:#}");
+                        if inbound_peers.remove(&peer.socket_addr()) {
+                            known_peers.remove(&peer.socket_addr());
+                            continue;
+                        }
+                        idle = None;
+                    }
+                }
+                // Peers that connected to us may not let us download yet.
+                if idle.as_ref().is_some_and(Peer::is_choked) {
+                    let peer = idle.take().expect("idle peer was just checked");
+                    idle_peers.insert(peer.socket_addr(), peer);
+                    continue;
+                }
+                // Pieces still in flight may fail and return to the queue.
+                let Some(piece_des) =
+                    take_piece(&mut self.piece_queue, idle.as_ref().map(Peer::pieces))
+                else {
+                    if let Some(peer) = idle {
+                        idle_peers.insert(peer.socket_addr(), peer);
+                    }
+                    continue;
                 };
 
                 tracing::trace!("Taking piece descriptor from queue");
 
-                let handle = spawn_piece_download_task(
-                    peer,
-                    piece_des.clone(),
-                    info_hash,
-                    self.client_peer_id,
-                    &mut handles,
-                );
+                // New connections are given up by the peer timeouts first, which are no snub.
+                let mut timeout = PIECE_DOWNLOAD_TIMEOUT;
+                let handle = match source {
+                    DownloadSource::Peer { addr, swarm } => {
+                        let peer = match idle {
+                            Some(peer) => PeerConnection::Idle(peer),
+                            None => {
+                                timeout += self.peer_timeouts.connect_timeout
+                                    + self.peer_timeouts.handshake_timeout;
+                                PeerConnection::New(settings.peer(addr))
+                            }
+                        };
+                        spawn_piece_download_task(
+                            peer,
+                            swarm,
+                            piece_des.clone(),
+                            PeerContext {
+                                info_hash: info_hashes[swarm],
+                                client_peer_id: self.client_peer_id,
+                                piece_count,
+                                dht: dht.clone(),
+                            },
+                            &mut handles,
+                        )
+                    }
+                    DownloadSource::WebSeed(index) => spawn_web_seed_download_task(
+                        index,
+                        self.web_seeds[index].clone(),
+                        web_seed_client.clone(),
+                        piece_des.clone(),
+                        &mut handles,
+                    ),
+                };
 
                 new_active_peers.insert(
-                    peer,
+                    source,
                     PieceDownloadPending {
                         started_at: Instant::now(),
+                        timeout,
                         abort_handle: handle,
                         piece_des,
                     },
@@ -255,57 +707,265 @@ impl TorrentDownloader {
 
             active_peers.extend(new_active_peers);
 
-            // Check for tasks/peers that have already completed.
-            while let Some(Ok(res)) = handles.try_join_next() {
+            // Check for tasks/peers that have already completed. Aborted ones were handled when
+            // timing out.
+            while let Some(joined) = handles.try_join_next() {
+                let Ok(res) = joined else {
+                    continue;
+                };
                 tracing::trace!("Piece download task finished");
+                // Results of downloads that timed out meanwhile are dropped, their piece is
+                // queued again already.
+                if active_peers.remove(&res.source()).is_none() {
+                    continue;
+                }
                 match res {
                     PieceDownloadResult::Success {
-                        peer,
                         piece: (piece_des, piece),
+                        peer,
+                        ..
                     } => {
-                        write_piece_to_writer(piece, piece_des, self.torrent_piece_length, writer)
-                            .context("writing piece to writer")?;
-
-                        assert!(active_peers.remove(&peer.socket_addr()).is_some());
+                        if let Some(peer) = peer {
+                            bans.delivered(&peer.socket_addr());
+                            idle_peers.insert(peer.socket_addr(), peer);
+                        }
+                        let length = piece.len() as u64;
+                        let index = piece_des.index;
+                        if let Err(err) = write_piece_to_writer(
+                            piece,
+                            piece_des,
+                            self.torrent_piece_length,
+                            writer,
+                        ) {
+                            result = Err(err.context("writing piece to writer"));
+                            break 'download;
+                        }
+                        completed.send_modify(|completed| {
+                            completed
+                                .set(index)
+                                .expect("queued piece index should be in range");
+                        });
+                        downloaded += length;
+                        for tracker in &self.trackers {
+                            tracker
+                                .set_progress(downloaded, total_length.saturating_sub(downloaded));
+                        }
                     }
                     PieceDownloadResult::Error {
-                        peer_socket_addr,
+                        source,
                         piece_des,
+                        error,
                     } => {
-                        assert!(active_peers.remove(&peer_socket_addr).is_some());
+                        tracing::debug!(?source, "Piece download failed: {error}");
                         self.piece_queue.push_back(piece_des);
+
+                        match source {
+                            // A failing web seed likely fails every piece, so stop asking it.
+                            DownloadSource::WebSeed(_) => {
+                                retired.insert(source);
+                            }
+                            DownloadSource::Peer { addr, .. } => {
+                                if inbound_peers.remove(&addr) {
+                                    known_peers.remove(&addr);
+                                }
+                                bans.failed(addr, error);
+                            }
+                        }
+                    }
+                    PieceDownloadResult::Unavailable {
+                        piece_des, peer, ..
+                    } => {
+                        self.piece_queue.push_front(piece_des);
+                        idle_peers.insert(peer.socket_addr(), peer);
                     }
                 }
             }
 
-            check_piece_download_timeout(active_peers.values(), &mut self.piece_queue);
+            for source in check_piece_download_timeout(&active_peers, &mut self.piece_queue) {
+                active_peers.remove(&source);
+                if let Some(addr) = source.peer_addr() {
+                    bans.snubbed(addr);
+                }
+            }
 
             if active_peers.is_empty() && self.piece_queue.is_empty() {
                 break;
             }
 
-            tokio::time::sleep(Duration::from_millis(300)).await;
+            // Serve uploads until the next round.
+            let next_round = tokio::time::sleep(Duration::from_millis(300));
+            tokio::pin!(next_round);
+            loop {
+                tokio::select! {
+                    () = &mut next_round => break,
+                    Some(request) = uploads_rx.recv() => {
+                        let length = serve_upload(
+                            request,
+                            &completed.borrow(),
+                            self.torrent_piece_length,
+                            total_length,
+                            writer,
+                        );
+                        if length > 0 {
+                            uploaded += length;
+                            for tracker in &self.trackers {
+                                tracker.set_uploaded(uploaded);
+                            }
+                        }
+                    }
+                }
+            }
         }
 
-        tracker_handle.abort();
+        for handle in active_peers.values() {
+            handle.abort_handle.abort();
+        }
+        for peer in idle_peers.into_values() {
+            peer.shutdown();
+        }
+        if let Some(listener) = listener {
+            listener.abort();
+        }
 
-        Ok(())
+        let completed = result.is_ok();
+        for source_handle in source_handles {
+            source_handle.stop(completed).await;
+        }
+
+        result
+    }
+}
+
+impl PieceDownloadResult {
+    fn source(&self) -> DownloadSource {
+        match self {
+            PieceDownloadResult::Success { source, .. }
+            | PieceDownloadResult::Error { source, .. }
+            | PieceDownloadResult::Unavailable { source, .. } => *source,
+        }
     }
 }
 
+impl DownloadSource {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            DownloadSource::Peer { addr, .. } => Some(*addr),
+            DownloadSource::WebSeed(_) => None,
+        }
+    }
+}
+
+/// Peer to download a piece from, greeted first unless we are still connected to it.
+enum PeerConnection {
+    Idle(Peer<Connected>),
+    New(Peer<Disconnected>),
+}
+
+/// How connections to peers are set up, whoever initiates them.
+#[derive(Clone)]
+struct PeerSettings {
+    pipeline_depth: usize,
+    timeouts: PeerTimeouts,
+    /// Port of our DHT node, advertised to peers.
+    dht_port: Option<u16>,
+    /// Where requests of peers for our pieces go.
+    uploads: mpsc::UnboundedSender<UploadRequest>,
+    /// Pieces we have, announced to peers.
+    pieces: watch::Receiver<Bitfield>,
+    /// Local address or interface connections are made and accepted on.
+    bind: Option<LocalBind>,
+}
+
+impl PeerSettings {
+    fn peer(&self, addr: SocketAddr) -> Peer<Disconnected> {
+        let peer = Peer::from_socket(addr)
+            .pipeline_depth(self.pipeline_depth)
+            .timeouts(self.timeouts)
+            .serve_uploads(self.uploads.clone())
+            .announce_pieces(self.pieces.clone());
+        let peer = match self.dht_port {
+            Some(port) => peer.dht_port(port),
+            None => peer,
+        };
+        match &self.bind {
+            Some(local) => peer.bind(local.clone()),
+            None => peer,
+        }
+    }
+}
+
+/// What a piece download task needs to know besides the peer and piece.
+struct PeerContext {
+    info_hash: InfoHash,
+    client_peer_id: PeerId,
+    piece_count: u32,
+    /// Learns about the DHT nodes of peers.
+    dht: Option<Arc<Dht>>,
+}
+
 struct PieceDownloadPending {
     started_at: Instant,
+    /// Time after which the download is aborted and the source counted as snubbing us.
+    timeout: Duration,
     abort_handle: AbortHandle,
     piece_des: PieceDescriptor,
 }
 
 enum PieceDownloadResult {
     Success {
-        peer: Peer<Connected>,
+        source: DownloadSource,
         piece: (PieceDescriptor, Vec<u8>),
+        /// Connection to reuse for the next piece, `None` for web seeds.
+        peer: Option<Peer<Connected>>,
     },
     Error {
-        peer_socket_addr: SocketAddrV4,
+        source: DownloadSource,
         piece_des: PieceDescriptor,
+        error: String,
+    },
+    /// The peer does not have the piece it was asked for.
+    Unavailable {
+        source: DownloadSource,
+        piece_des: PieceDescriptor,
+        peer: Peer<Connected>,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use bytes::Bytes;
+
+    use super::*;
+
+    #[test]
+    fn serve_upload_checks_requests() {
+        // Pieces of 4 bytes, the last one 2 bytes long.
+        let mut reader = Cursor::new((0..10).collect::<Vec<u8>>());
+        let mut completed = Bitfield::new(3);
+        completed.set(1).unwrap();
+        completed.set(2).unwrap();
+        let mut serve = |index, begin, length| {
+            let (request, mut replies) = UploadRequest::with_replies(index, begin, length);
+            let uploaded = serve_upload(request, &completed, 4, 10, &mut reader);
+            (uploaded, replies.try_recv().unwrap().outcome())
+        };
+
+        assert_eq!(
+            serve(1, 1, 3),
+            (3, Ok(Some(Bytes::from_static(&[5, 6, 7]))))
+        );
+        assert_eq!(serve(2, 0, 2), (2, Ok(Some(Bytes::from_static(&[8, 9])))));
+
+        // Past the end of the last piece, though within a full piece length.
+        let (uploaded, outcome) = serve(2, 1, 2);
+        assert_eq!(uploaded, 0);
+        assert!(outcome.is_err());
+        let (_, outcome) = serve(1, u32::MAX, 2);
+        assert!(outcome.is_err());
+
+        // Pieces we do not have are declined, without disconnecting the peer.
+        assert_eq!(serve(0, 0, 4), (0, Ok(None)));
+    }
+}
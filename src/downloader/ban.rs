@@ -0,0 +1,102 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// Failures after which a peer is banned.
+const MAX_STRIKES: u32 = 5;
+/// Wait before reconnecting to a peer after its first failure, doubled on every further one.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// How long a banned peer is left alone before it gets another chance.
+const BAN_DURATION: Duration = Duration::from_secs(10 * 60);
+
+/// Why a peer is not asked for pieces anymore.
+#[derive(Debug, Clone)]
+pub enum BanReason {
+    /// Took our requests but never delivered the blocks.
+    Snubbed,
+    /// Failed the last piece download with the error.
+    Failed(String),
+}
+
+impl fmt::Display for BanReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BanReason::Snubbed => write!(f, "snubbed our requests"),
+            BanReason::Failed(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+/// Peers that misbehaved during the download. Snubbing peers are asked last, failing peers are
+/// retried with exponential backoff, and peers that keep failing are banned for a while instead
+/// of being reconnected to on every tracker response.
+#[derive(Debug, Default)]
+pub struct BanList {
+    peers: HashMap<SocketAddr, Record>,
+}
+
+#[derive(Debug, Default)]
+struct Record {
+    strikes: u32,
+    snubbed: bool,
+    /// When the peer may be asked again after its last failure.
+    retry_at: Option<Instant>,
+    banned: Option<(Instant, BanReason)>,
+}
+
+impl BanList {
+    /// Why the peer is banned, if it is. Bans that ran out are lifted.
+    pub fn ban_reason(&mut self, addr: &SocketAddr) -> Option<&BanReason> {
+        if let Some((since, _)) = self.peers.get(addr)?.banned {
+            if since.elapsed() >= BAN_DURATION {
+                self.peers.remove(addr);
+                return None;
+            }
+        }
+        let (_, reason) = self.peers.get(addr)?.banned.as_ref()?;
+        Some(reason)
+    }
+
+    pub fn is_snubbed(&self, addr: &SocketAddr) -> bool {
+        self.peers.get(addr).is_some_and(|record| record.snubbed)
+    }
+
+    /// Whether the peer failed too recently to be asked again.
+    pub fn is_backing_off(&self, addr: &SocketAddr) -> bool {
+        self.peers
+            .get(addr)
+            .and_then(|record| record.retry_at)
+            .is_some_and(|retry_at| Instant::now() < retry_at)
+    }
+
+    /// Records that the peer delivered a piece, clearing earlier failures.
+    pub fn delivered(&mut self, addr: &SocketAddr) {
+        self.peers.remove(addr);
+    }
+
+    /// Records that the peer did not deliver a piece in time.
+    pub fn snubbed(&mut self, addr: SocketAddr) {
+        self.peers.entry(addr).or_default().snubbed = true;
+        self.strike(addr, BanReason::Snubbed);
+    }
+
+    /// Records that connecting to or downloading a piece from the peer failed.
+    pub fn failed(&mut self, addr: SocketAddr, reason: String) {
+        self.strike(addr, BanReason::Failed(reason));
+        let record = self.peers.entry(addr).or_default();
+        let delay = RECONNECT_DELAY * 2u32.pow(record.strikes - 1);
+        record.retry_at = Some(Instant::now() + delay);
+    }
+
+    fn strike(&mut self, addr: SocketAddr, reason: BanReason) {
+        let record = self.peers.entry(addr).or_default();
+        record.strikes += 1;
+        if record.strikes >= MAX_STRIKES && record.banned.is_none() {
+            tracing::info!(peer = %addr, "Banning peer: {reason}");
+            record.banned = Some((Instant::now(), reason));
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+use super::PeerSettings;
+use crate::{
+    peer::{Connected, Peer},
+    util::{InfoHash, PeerId},
+};
+
+/// Who peers connecting to us are greeted as.
+struct Greeting {
+    info_hashes: Vec<InfoHash>,
+    client_peer_id: PeerId,
+    piece_count: u32,
+    settings: PeerSettings,
+}
+
+/// Listens on the announced port for peers connecting to us. Peers that greet us with the info
+/// hash of one of our swarms are sent to `peers` with the index of that swarm, others are
+/// dropped.
+pub(super) async fn spawn_listener(
+    port: u16,
+    info_hashes: Vec<InfoHash>,
+    client_peer_id: PeerId,
+    piece_count: u32,
+    settings: PeerSettings,
+    peers: mpsc::UnboundedSender<(usize, Peer<Connected>)>,
+) -> Result<JoinHandle<()>> {
+    let listener = match &settings.bind {
+        Some(local) => local.listen(port),
+        None => Ok(TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await?),
+    }
+    .with_context(|| format!("binding peer listener to port {port}"))?;
+    match &settings.bind {
+        Some(local) => tracing::info!(port, %local, "Listening for peer connections"),
+        None => tracing::info!(port, "Listening for peer connections"),
+    }
+
+    let greeting = Arc::new(Greeting {
+        info_hashes,
+        client_peer_id,
+        piece_count,
+        settings,
+    });
+    Ok(tokio::spawn(async move {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(err) => {
+                    tracing::warn!("Accepting peer connection failed: {err}");
+                    // Running out of file descriptors does not resolve right away.
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            tokio::spawn(accept(stream, addr, greeting.clone(), peers.clone()));
+        }
+    }))
+}
+
+async fn accept(
+    stream: TcpStream,
+    addr: SocketAddr,
+    greeting: Arc<Greeting>,
+    peers: mpsc::UnboundedSender<(usize, Peer<Connected>)>,
+) {
+    let handshake = greeting.settings.peer(addr).accept(
+        stream,
+        &greeting.info_hashes,
+        greeting.client_peer_id,
+        greeting.piece_count,
+    );
+    match handshake.await {
+        Ok((swarm, peer)) => {
+            tracing::debug!(peer = %addr, swarm, "Accepted peer connection");
+            // The download may be over, closing the connection.
+            let _ = peers.send((swarm, peer));
+        }
+        Err(err) => tracing::debug!(peer = %addr, "Peer handshake failed: {err:#}"),
+    }
+}
@@ -0,0 +1,173 @@
+use std::{net::SocketAddr, str::FromStr, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+
+use crate::{
+    dht::Dht,
+    peer::{LocalBind, MetadataAssembler, Peer},
+    torrent::Torrent,
+    tracker::{Tracker, TrackerClient},
+    util::{PeerId, Sha1Hash},
+};
+
+const METADATA_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Parsed `magnet:` URI, identifying a torrent by its info hash only.
+#[derive(Debug, Clone)]
+pub struct MagnetLink {
+    pub info_hash: Sha1Hash,
+    pub display_name: Option<String>,
+    pub trackers: Vec<String>,
+}
+
+impl FromStr for MagnetLink {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let query = s
+            .strip_prefix("magnet:?")
+            .context("magnet link does not start with `magnet:?`")?;
+
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = Vec::new();
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            match &*key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_btih(hash)?);
+                    }
+                }
+                "dn" => display_name = Some(value.into_owned()),
+                "tr" => trackers.push(value.into_owned()),
+                _ => (),
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.context("magnet link does not contain a btih info hash")?,
+            display_name,
+            trackers,
+        })
+    }
+}
+
+impl MagnetLink {
+    /// Fetches the info dict from peers found through the trackers of the link, announcing with
+    /// the http client, and through the DHT when given. Peers are connected to from the local
+    /// address or interface, if given.
+    pub async fn fetch_torrent(
+        &self,
+        client: &TrackerClient,
+        dht: Option<&Dht>,
+        bind: Option<&LocalBind>,
+    ) -> Result<Torrent> {
+        if self.trackers.is_empty() && dht.is_none() {
+            bail!("magnet link has no trackers, peers can only be found through the dht");
+        }
+        // Peers fetch the metadata from one another, so any id does.
+        let peer_id = rand::random();
+        // Pieces fetched from one peer are kept when the next one is asked for the rest.
+        let mut assembler = MetadataAssembler::new(self.info_hash);
+
+        for announce in &self.trackers {
+            // The size is unknown until the metadata is fetched; trackers may not hand out seeders
+            // to peers reporting nothing left to download.
+            let mut tracker =
+                Tracker::new(announce.clone(), self.info_hash, 1).client(client.clone());
+            let peers = match tracker.poll().await {
+                Ok(response) => response.peers,
+                Err(err) => {
+                    tracing::debug!(announce, "Polling tracker failed: {err:#}");
+                    continue;
+                }
+            };
+
+            if let Some(metadata) = self
+                .fetch_metadata(peers.iter().copied(), peer_id, bind, &mut assembler)
+                .await
+            {
+                return Torrent::from_metadata(announce.clone(), &metadata)
+                    .context("reading torrent from fetched metadata");
+            }
+        }
+
+        if let Some(dht) = dht {
+            let peers = dht
+                .get_peers(self.info_hash)
+                .await
+                .context("looking up peers in the dht")?;
+            if let Some(metadata) = self
+                .fetch_metadata(peers, peer_id, bind, &mut assembler)
+                .await
+            {
+                // Keep the trackers of the link, if any.
+                let announce = self.trackers.first().cloned().unwrap_or_default();
+                return Torrent::from_metadata(announce, &metadata)
+                    .context("reading torrent from fetched metadata");
+            }
+        }
+
+        bail!("no peer provided the torrent metadata")
+    }
+
+    /// Asks the peers in turn for the metadata pieces still missing, until the assembled
+    /// metadata matches the info hash.
+    async fn fetch_metadata(
+        &self,
+        peers: impl IntoIterator<Item = SocketAddr>,
+        peer_id: PeerId,
+        bind: Option<&LocalBind>,
+        assembler: &mut MetadataAssembler,
+    ) -> Option<Bytes> {
+        for peer in peers {
+            let mut connection = Peer::from_socket(peer);
+            if let Some(local) = bind {
+                connection = connection.bind(local.clone());
+            }
+            let fetch = connection.fetch_metadata(peer_id, assembler);
+            match tokio::time::timeout(METADATA_FETCH_TIMEOUT, fetch).await {
+                Ok(Ok(())) => (),
+                Ok(Err(err)) => tracing::debug!(%peer, "Fetching metadata failed: {err:#}"),
+                Err(_) => tracing::debug!(%peer, "Fetching metadata timed out"),
+            }
+
+            if assembler.is_complete() {
+                match assembler.finish() {
+                    Ok(metadata) => return Some(metadata),
+                    Err(err) => tracing::debug!("Assembling metadata failed: {err:#}"),
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Parses a hex or base32 encoded info hash.
+fn parse_btih(hash: &str) -> Result<Sha1Hash> {
+    let mut info_hash = [0u8; 20];
+    match hash.len() {
+        40 => hex::decode_to_slice(hash, &mut info_hash).context("decoding hex info hash")?,
+        32 => {
+            const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+            let (mut buffer, mut bits, mut len) = (0u32, 0, 0);
+            for c in hash.bytes() {
+                let value = ALPHABET
+                    .iter()
+                    .position(|a| *a == c.to_ascii_uppercase())
+                    .context("invalid base32 character in info hash")?;
+                buffer = (buffer << 5 | value as u32) & 0xffff;
+                bits += 5;
+                if bits >= 8 {
+                    bits -= 8;
+                    info_hash[len] = (buffer >> bits) as u8;
+                    len += 1;
+                }
+            }
+        }
+        len => bail!("info hash has unexpected length {len}"),
+    }
+    Ok(info_hash)
+}
@@ -1,22 +1,44 @@
-use anyhow::Result;
+use std::process::ExitCode;
+
 use clap::Parser;
 use tracing_subscriber::EnvFilter;
 
-use crate::command::Cli;
+use crate::{command::Cli, torrent::TorrentError};
 
 mod command;
+mod dht;
+mod discovery;
 mod downloader;
+mod magnet;
 mod peer;
+mod storage;
 mod torrent;
 mod tracker;
 mod util;
+mod validate;
+mod webseed;
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> ExitCode {
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env())
         .init();
 
     let cli = Cli::parse();
-    cli.command.execute().await
+    match cli.execute().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            exit_code(&err)
+        }
+    }
+}
+
+/// Invalid torrents exit with `EX_DATAERR` from sysexits.h, other failures with 1.
+fn exit_code(err: &anyhow::Error) -> ExitCode {
+    if err.chain().any(|cause| cause.is::<TorrentError>()) {
+        ExitCode::from(65)
+    } else {
+        ExitCode::FAILURE
+    }
 }
@@ -1,109 +1,396 @@
-use std::net::SocketAddrV4;
+use std::{net::SocketAddr, time::Duration};
 
 use anyhow::{bail, Context, Result};
+use futures_util::{SinkExt, StreamExt};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
+    sync::{mpsc, watch},
 };
+use tokio_util::codec::Framed;
 
-use self::message::{PeerHandShakePacket, PeerMessage};
-use crate::util::{PeerId, Sha1Hash};
+use self::{
+    actor::{PeerCommand, PeerEvent},
+    codec::PeerMessageCodec,
+    message::{PeerHandShakePacket, PeerMessage},
+    upload::Uploads,
+};
+use crate::util::{InfoHash, PeerId};
 
+mod actor;
+mod bind;
+mod bitfield;
+mod codec;
+mod extensions;
 mod message;
+mod metadata;
 mod piece;
+mod upload;
+
+pub use self::{
+    bind::LocalBind,
+    bitfield::Bitfield,
+    extensions::{Extension, Extensions},
+    metadata::MetadataAssembler,
+    piece::{PieceDescriptor, DEFAULT_PIPELINE_DEPTH},
+    upload::UploadRequest,
+};
 
-pub use self::piece::PieceDescriptor;
+/// Byte stream a peer connection runs over, such as a TCP connection.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> Transport for S {}
 
 pub struct Peer<C> {
-    socket_addr: SocketAddrV4,
+    socket_addr: SocketAddr,
     connection: C,
 }
 
-pub struct Disconnected;
+/// How long talking to a peer may take before the connection is given up.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerTimeouts {
+    /// Establishing the TCP connection.
+    pub connect_timeout: Duration,
+    /// Exchanging handshakes once connected.
+    pub handshake_timeout: Duration,
+    /// Waiting for the next message. Peers send keep-alives every two minutes when idle.
+    pub read_timeout: Duration,
+    /// Sending a message.
+    pub write_timeout: Duration,
+}
+
+impl Default for PeerTimeouts {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            handshake_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(150),
+            write_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+pub struct Disconnected {
+    /// Id the peer must greet us with, as announced by the tracker.
+    expected_peer_id: Option<PeerId>,
+    pipeline_depth: usize,
+    /// UDP port of our DHT node, advertised to peers that run one as well.
+    dht_port: Option<u16>,
+    /// Where requests of the peer are forwarded to, if we upload.
+    uploads: Option<mpsc::UnboundedSender<UploadRequest>>,
+    /// Pieces we have, announced to the peer as they are completed.
+    pieces: Option<watch::Receiver<Bitfield>>,
+    /// Local address or interface the connection is made from.
+    bind: Option<LocalBind>,
+    timeouts: PeerTimeouts,
+}
+/// Connection to a peer exchanging messages after the handshake.
+type PeerStream<S> = Framed<S, PeerMessageCodec>;
+
+/// Handle to the task owning the connection to a peer, see [`actor::spawn`].
 pub struct Connected {
-    stream: TcpStream,
+    commands: mpsc::UnboundedSender<PeerCommand>,
+    events: mpsc::UnboundedReceiver<PeerEvent>,
     peer_id: PeerId,
+    /// Extensions the peer announced in its handshake.
+    extensions: Extensions,
+    /// Pieces the peer announced to have, from its bitfield and later `Have` messages.
+    pieces: Bitfield,
+    /// Whether the peer may still send its bitfield, which it only does before announcing
+    /// pieces otherwise.
+    awaiting_bitfield: bool,
+    /// Whether the peer refuses to answer our requests.
+    choked: bool,
+    /// Number of block requests kept outstanding while downloading a piece.
+    pipeline_depth: usize,
+    /// UDP port of the DHT node of the peer, as told by its port message.
+    dht_port: Option<u16>,
 }
 
-async fn read_bitfield(stream: &mut TcpStream) -> Result<()> {
-    let mut buf = prepare_buffer_with_length(stream).await?;
+impl Connected {
+    fn send(&self, command: PeerCommand) -> Result<()> {
+        self.commands
+            .send(command)
+            .ok()
+            .context("peer connection closed")
+    }
 
-    stream
-        .read_exact(&mut buf)
-        .await
-        .context("reading bitfield message")?;
-    match PeerMessage::parse(buf.into()) {
-        Ok(PeerMessage::Bitfield) => (),
-        Err(err) => return Err(err).context("parsing peer bitfield message"),
-        _ => bail!("unexpected peer message"),
+    /// Waits for the next event of the connection, failing once it is closed.
+    async fn next_event(&mut self) -> Result<PeerEvent> {
+        match self.events.recv().await {
+            Some(PeerEvent::Closed(err)) => Err(err),
+            Some(event) => Ok(event),
+            None => bail!("peer connection closed"),
+        }
     }
-    Ok(())
-}
 
-async fn read_unchoke(stream: &mut TcpStream) -> Result<()> {
-    let mut buf = prepare_buffer_with_length(stream).await?;
+    /// Waits until the peer unchokes us, recording the pieces it announces meanwhile.
+    async fn wait_for_unchoke(&mut self) -> Result<()> {
+        while self.choked {
+            let event = self
+                .next_event()
+                .await
+                .context("waiting for unchoke message")?;
+            self.handle(event)?;
+        }
+        Ok(())
+    }
 
-    stream
-        .read_exact(&mut buf)
-        .await
-        .context("reading unchoke message")?;
-    match PeerMessage::parse(buf.into()) {
-        Ok(PeerMessage::Unchoke) => (),
-        Err(err) => return Err(err).context("parsing unchoke message"),
-        _ => bail!("unexpected peer message"),
+    /// Records state the peer may announce at any time, failing on events that make no sense
+    /// at this point.
+    fn handle(&mut self, event: PeerEvent) -> Result<()> {
+        let announces_pieces = matches!(event, PeerEvent::Have(_) | PeerEvent::Bitfield(_));
+        match event {
+            PeerEvent::Bitfield(bitfield) if self.awaiting_bitfield => {
+                self.pieces = bitfield
+                    .with_piece_count(self.pieces.piece_count())
+                    .context("checking peer bitfield")?;
+            }
+            PeerEvent::Bitfield(_) => bail!("peer sent its bitfield after other messages"),
+            PeerEvent::Have(index) => self.pieces.set(index).context("handling have message")?,
+            PeerEvent::Choked => self.choked = true,
+            PeerEvent::Unchoked => self.choked = false,
+            PeerEvent::DhtPort(port) => self.dht_port = Some(port),
+            PeerEvent::Block { index, begin, .. } => {
+                self.check_block(index)?;
+                // Blocks of requests that were cancelled may still arrive.
+                tracing::trace!(index, begin, "Skipping piece block that is not wanted");
+            }
+            PeerEvent::Closed(err) => return Err(err),
+        }
+        // Pieces announced one by one are not overwritten by a bitfield anymore.
+        if announces_pieces {
+            self.awaiting_bitfield = false;
+        }
+        Ok(())
+    }
+
+    /// Fails on blocks that no request of ours could have asked for, the rest is left to the
+    /// caller to match with its requests.
+    fn check_block(&self, index: u32) -> Result<()> {
+        if index >= self.pieces.piece_count() {
+            bail!(
+                "peer sent block of piece {index} of a torrent with {} pieces",
+                self.pieces.piece_count()
+            );
+        }
+        Ok(())
+    }
+
+    /// Applies the events received while nobody was waiting for them.
+    fn handle_pending(&mut self) -> Result<()> {
+        while let Ok(event) = self.events.try_recv() {
+            self.handle(event)?;
+        }
+        Ok(())
     }
-    Ok(())
 }
 
 impl Peer<Disconnected> {
-    pub fn from_socket(socket: SocketAddrV4) -> Self {
+    pub fn from_socket(socket: SocketAddr) -> Self {
         Self {
             socket_addr: socket,
-            connection: Disconnected,
+            connection: Disconnected {
+                expected_peer_id: None,
+                pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+                dht_port: None,
+                uploads: None,
+                pieces: None,
+                bind: None,
+                timeouts: PeerTimeouts::default(),
+            },
+        }
+    }
+
+    /// Drops the connection when the peer greets us with another id than the one given.
+    pub fn expect_peer_id(mut self, peer_id: PeerId) -> Self {
+        self.connection.expected_peer_id = Some(peer_id);
+        self
+    }
+
+    /// Sets how many block requests are kept outstanding while downloading a piece.
+    pub fn pipeline_depth(mut self, depth: usize) -> Self {
+        self.connection.pipeline_depth = depth.max(1);
+        self
+    }
+
+    /// Advertises our DHT node listening on the UDP port to the peer (BEP 5).
+    pub fn dht_port(mut self, port: u16) -> Self {
+        self.connection.dht_port = Some(port);
+        self
+    }
+
+    /// Answers requests of the peer by forwarding them to `requests`, to be served from the
+    /// pieces we have.
+    pub fn serve_uploads(mut self, requests: mpsc::UnboundedSender<UploadRequest>) -> Self {
+        self.connection.uploads = Some(requests);
+        self
+    }
+
+    /// Tells the peer about the pieces we have, and about every piece we complete afterwards as
+    /// sent on `pieces`.
+    pub fn announce_pieces(mut self, pieces: watch::Receiver<Bitfield>) -> Self {
+        self.connection.pieces = Some(pieces);
+        self
+    }
+
+    /// Connects to the peer from the local address or interface only.
+    pub fn bind(mut self, local: LocalBind) -> Self {
+        self.connection.bind = Some(local);
+        self
+    }
+
+    pub fn timeouts(mut self, timeouts: PeerTimeouts) -> Self {
+        self.connection.timeouts = timeouts;
+        self
+    }
+
+    fn check_peer_id(&self, peer_id: &PeerId) -> Result<()> {
+        match self.connection.expected_peer_id {
+            Some(expected) if expected != *peer_id => bail!(
+                "peer id {} does not match the announced {}",
+                hex::encode(peer_id),
+                hex::encode(expected)
+            ),
+            _ => Ok(()),
         }
     }
 
     pub async fn handshake(
         self,
-        info_hash: Sha1Hash,
+        info_hash: InfoHash,
         client_peer_id: PeerId,
+        piece_count: u32,
     ) -> Result<Peer<Connected>> {
-        let mut stream = TcpStream::connect(self.socket_addr)
+        let stream = self.connect().await?;
+        self.handshake_over(stream, info_hash, client_peer_id, piece_count)
             .await
-            .context("connecting to peer")?;
+    }
 
-        stream
-            .write_all(&PeerHandShakePacket::new(info_hash, client_peer_id).into_bytes())
-            .await
-            .context("sending handshake packet")?;
+    /// Like [`Peer::handshake`], over a stream that is already connected to the peer.
+    pub async fn handshake_over<S: Transport>(
+        self,
+        mut stream: S,
+        info_hash: InfoHash,
+        client_peer_id: PeerId,
+        piece_count: u32,
+    ) -> Result<Peer<Connected>> {
+        let packet = self.handshake_packet(info_hash, client_peer_id);
+        let handshake_packet = tokio::time::timeout(
+            self.connection.timeouts.handshake_timeout,
+            exchange_handshake(&mut stream, packet),
+        )
+        .await
+        .context("timed out exchanging handshakes")??;
+        self.check_peer_id(&handshake_packet.peer_id)?;
 
-        let mut buf = Box::new([0u8; 68]) as Box<[u8]>;
-        stream
-            .read_exact(&mut buf)
-            .await
-            .context("reading handshake response packet")?;
-        let handshake_packet =
-            PeerHandShakePacket::parse(buf.into()).context("parsing peer handshake packet")?;
+        let mut peer = self.start(stream, &handshake_packet, piece_count)?;
+        peer.connection.wait_for_unchoke().await?;
+        Ok(peer)
+    }
 
-        if handshake_packet.info_hash != info_hash {
-            bail!("info hash received from handshake does not match");
-        }
+    /// Completes the handshake of a peer that connected to us, answering in the swarm of
+    /// whichever of the info hashes it greets us with. Returns the index of that info hash.
+    ///
+    /// Unlike [`Peer::handshake`], this does not wait for the peer to unchoke us.
+    pub async fn accept<S: Transport>(
+        self,
+        mut stream: S,
+        info_hashes: &[InfoHash],
+        client_peer_id: PeerId,
+        piece_count: u32,
+    ) -> Result<(usize, Peer<Connected>)> {
+        let handshake = async {
+            let handshake_packet = read_handshake(&mut stream).await?;
+            let Some(swarm) = info_hashes
+                .iter()
+                .position(|info_hash| *info_hash == handshake_packet.info_hash)
+            else {
+                bail!(
+                    "peer greeted us with unknown info hash {}",
+                    hex::encode(handshake_packet.info_hash)
+                );
+            };
+            self.check_peer_id(&handshake_packet.peer_id)?;
 
-        read_bitfield(&mut stream).await?;
+            let packet = self.handshake_packet(info_hashes[swarm], client_peer_id);
+            stream
+                .write_all(&packet.into_bytes())
+                .await
+                .context("sending handshake packet")?;
+            Ok((swarm, handshake_packet))
+        };
+        let (swarm, handshake_packet) =
+            tokio::time::timeout(self.connection.timeouts.handshake_timeout, handshake)
+                .await
+                .context("timed out exchanging handshakes")??;
 
-        stream
-            .write_all(&PeerMessage::Interested.into_bytes())
+        Ok((swarm, self.start(stream, &handshake_packet, piece_count)?))
+    }
+
+    async fn connect(&self) -> Result<TcpStream> {
+        let connect = async {
+            match &self.connection.bind {
+                Some(local) => local.connect(self.socket_addr).await,
+                None => Ok(TcpStream::connect(self.socket_addr).await?),
+            }
+        };
+        tokio::time::timeout(self.connection.timeouts.connect_timeout, connect)
             .await
-            .context("sending peer interested message")?;
+            .context("timed out connecting to peer")?
+            .context("connecting to peer")
+    }
+
+    /// Extensions we announce in the handshake.
+    fn extensions(&self) -> Extensions {
+        let extensions = Extensions::default();
+        if self.connection.dht_port.is_some() {
+            extensions.with(Extension::Dht)
+        } else {
+            extensions
+        }
+    }
 
-        read_unchoke(&mut stream).await?;
+    fn handshake_packet(&self, info_hash: InfoHash, client_peer_id: PeerId) -> PeerHandShakePacket {
+        PeerHandShakePacket::new(info_hash, client_peer_id, self.extensions())
+    }
+
+    /// Hands the connection to its task once the handshakes are exchanged, and tells the peer we
+    /// are interested.
+    fn start<S: Transport>(
+        self,
+        stream: S,
+        handshake_packet: &PeerHandShakePacket,
+        piece_count: u32,
+    ) -> Result<Peer<Connected>> {
+        let common = self.extensions().common(handshake_packet.extensions);
+        let (commands, events) = actor::spawn(
+            Framed::new(stream, PeerMessageCodec),
+            self.connection
+                .uploads
+                .map(|requests| Uploads::new(requests, piece_count)),
+            self.connection.pieces,
+            self.connection.timeouts,
+        );
+        let connection = Connected {
+            commands,
+            events,
+            peer_id: handshake_packet.peer_id,
+            extensions: handshake_packet.extensions,
+            pieces: Bitfield::new(piece_count),
+            awaiting_bitfield: true,
+            choked: true,
+            pipeline_depth: self.connection.pipeline_depth,
+            dht_port: None,
+        };
+        if let (Some(port), true) = (self.connection.dht_port, common.supports(Extension::Dht)) {
+            connection.send(PeerCommand::DhtPort(port))?;
+        }
+        connection.send(PeerCommand::Interested)?;
 
         Ok(Peer {
             socket_addr: self.socket_addr,
-            connection: Connected {
-                stream,
-                peer_id: handshake_packet.peer_id,
-            },
+            connection,
         })
     }
 }
@@ -112,21 +399,208 @@ impl Peer<Connected> {
     pub fn peer_id(&self) -> &PeerId {
         &self.connection.peer_id
     }
+
+    /// Extensions the peer supports, whether or not we do.
+    pub fn extensions(&self) -> Extensions {
+        self.connection.extensions
+    }
+
+    pub fn pieces(&self) -> &Bitfield {
+        &self.connection.pieces
+    }
+
+    /// UDP port of the DHT node of the peer, if it told us.
+    pub fn dht_port(&self) -> Option<u16> {
+        self.connection.dht_port
+    }
+
+    /// Catches up with what the peer announced since we last talked to it, failing when the
+    /// connection was closed meanwhile.
+    pub fn refresh(&mut self) -> Result<()> {
+        self.connection.handle_pending()
+    }
+
+    /// Stops or resumes answering the requests of the peer.
+    // Only sent once upload slots are limited, interested peers are unchoked right away.
+    #[allow(dead_code)]
+    pub fn choke(&self, choke: bool) -> Result<()> {
+        self.connection.send(if choke {
+            PeerCommand::Choke
+        } else {
+            PeerCommand::Unchoke
+        })
+    }
+
+    /// Whether the peer refuses to answer our requests, as of the last refresh.
+    pub fn is_choked(&self) -> bool {
+        self.connection.choked
+    }
+
+    /// Closes the connection.
+    pub fn shutdown(self) {
+        let _ = self.connection.send(PeerCommand::Shutdown);
+    }
 }
 
 impl<C> Peer<C> {
-    pub fn socket_addr(&self) -> SocketAddrV4 {
+    pub fn socket_addr(&self) -> SocketAddr {
         self.socket_addr
     }
 }
 
-async fn prepare_buffer_with_length(stream: &mut TcpStream) -> Result<Vec<u8>> {
-    let message_length = stream.read_u32().await.context("reading message length")?;
-    Ok(vec![0u8; message_length as usize])
+/// Sends our handshake and reads the peer's, checking that both are for the same torrent.
+async fn exchange_handshake<S: Transport>(
+    stream: &mut S,
+    packet: PeerHandShakePacket,
+) -> Result<PeerHandShakePacket> {
+    let info_hash = packet.info_hash;
+    stream
+        .write_all(&packet.into_bytes())
+        .await
+        .context("sending handshake packet")?;
+
+    let handshake_packet = read_handshake(stream).await?;
+    if handshake_packet.info_hash != info_hash {
+        bail!("info hash received from handshake does not match");
+    }
+    Ok(handshake_packet)
+}
+
+async fn read_handshake<S: Transport>(stream: &mut S) -> Result<PeerHandShakePacket> {
+    let mut buf = Box::new([0u8; 68]) as Box<[u8]>;
+    stream
+        .read_exact(&mut buf)
+        .await
+        .context("reading handshake packet")?;
+    PeerHandShakePacket::parse(buf.into()).context("parsing peer handshake packet")
 }
 
-impl From<SocketAddrV4> for Peer<Disconnected> {
-    fn from(value: SocketAddrV4) -> Self {
+/// Reads the next message, failing when the peer closed the connection.
+async fn read_message<S: Transport>(stream: &mut PeerStream<S>) -> Result<PeerMessage> {
+    stream
+        .next()
+        .await
+        .context("peer closed the connection")?
+        .context("reading peer message")
+}
+
+/// Like [`read_message`], failing as well when the peer sends nothing in time.
+async fn read_message_timeout<S: Transport>(
+    stream: &mut PeerStream<S>,
+    timeout: Duration,
+) -> Result<PeerMessage> {
+    tokio::time::timeout(timeout, read_message(stream))
+        .await
+        .context("timed out reading peer message")?
+}
+
+/// Sends the message, failing when the peer does not take it in time.
+async fn send_message<S: Transport>(
+    stream: &mut PeerStream<S>,
+    message: PeerMessage,
+    timeout: Duration,
+) -> Result<()> {
+    tokio::time::timeout(timeout, stream.send(message))
+        .await
+        .context("timed out")?
+}
+
+impl From<SocketAddr> for Peer<Disconnected> {
+    fn from(value: SocketAddr) -> Self {
         Self::from_socket(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio::io::DuplexStream;
+
+    use super::*;
+    use crate::util::hash_sha1;
+
+    const INFO_HASH: InfoHash = [1; 20];
+    const CLIENT_ID: PeerId = [2; 20];
+    const REMOTE_ID: PeerId = [3; 20];
+
+    fn peer() -> Peer<Disconnected> {
+        Peer::from_socket(SocketAddr::from(([127, 0, 0, 1], 6881)))
+    }
+
+    /// Answers the handshake in the swarm of `info_hash` as a peer having `data` as its only
+    /// piece, then serves every request for it.
+    async fn serve_piece(mut stream: DuplexStream, info_hash: InfoHash, data: Bytes) -> Result<()> {
+        read_handshake(&mut stream).await?;
+        let packet = PeerHandShakePacket::new(info_hash, REMOTE_ID, Extensions::default());
+        stream.write_all(&packet.into_bytes()).await?;
+
+        let mut stream = Framed::new(stream, PeerMessageCodec);
+        let mut pieces = Bitfield::new(1);
+        pieces.set(0)?;
+        stream.send(PeerMessage::Bitfield(pieces)).await?;
+        stream.send(PeerMessage::Unchoke).await?;
+        while let Some(message) = stream.next().await {
+            if let PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } = message?
+            {
+                let block = data.slice(begin as usize..(begin + length) as usize);
+                stream
+                    .send(PeerMessage::Piece {
+                        index,
+                        begin,
+                        block,
+                    })
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handshake() {
+        let (local, remote) = tokio::io::duplex(1 << 16);
+        tokio::spawn(serve_piece(remote, INFO_HASH, Bytes::new()));
+
+        let peer = peer()
+            .handshake_over(local, INFO_HASH, CLIENT_ID, 1)
+            .await
+            .unwrap();
+        assert_eq!(*peer.peer_id(), REMOTE_ID);
+        assert!(peer.pieces().has(0));
+        assert!(!peer.is_choked());
+    }
+
+    #[tokio::test]
+    async fn handshake_info_hash_mismatch() {
+        let (local, remote) = tokio::io::duplex(1 << 16);
+        tokio::spawn(serve_piece(remote, [9; 20], Bytes::new()));
+
+        let err = peer()
+            .handshake_over(local, INFO_HASH, CLIENT_ID, 1)
+            .await
+            .err()
+            .expect("handshake with another info hash should fail");
+        assert!(format!("{err:#}").contains("info hash"));
+    }
+
+    #[tokio::test]
+    async fn download_piece() {
+        // Spans a full and a partial block.
+        let data = Bytes::from_iter((0..20_000u32).map(|i| i as u8));
+        let (local, remote) = tokio::io::duplex(1 << 16);
+        tokio::spawn(serve_piece(remote, INFO_HASH, data.clone()));
+
+        let mut peer = peer()
+            .handshake_over(local, INFO_HASH, CLIENT_ID, 1)
+            .await
+            .unwrap();
+        let piece = peer
+            .download_piece(PieceDescriptor::new(0, 20_000, hash_sha1(&data)))
+            .await
+            .unwrap();
+        assert_eq!(piece, data);
+    }
+}
@@ -0,0 +1,236 @@
+use anyhow::{bail, Context, Error, Result};
+use bytes::Bytes;
+use futures_util::SinkExt;
+use tokio::{
+    sync::{mpsc, watch},
+    time::Instant,
+};
+
+use super::{
+    message::PeerMessage,
+    read_message, send_message,
+    upload::{UploadReply, Uploads},
+    Bitfield, PeerStream, PeerTimeouts, Transport,
+};
+
+/// What the connection task is asked to do.
+#[derive(Debug)]
+pub(super) enum PeerCommand {
+    Interested,
+    /// Advertise our DHT node listening on the UDP port.
+    DhtPort(u16),
+    RequestBlock {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Choke,
+    Unchoke,
+    /// Close the connection and stop the task.
+    Shutdown,
+}
+
+/// What the connection task learned from the peer.
+#[derive(Debug)]
+pub(super) enum PeerEvent {
+    Bitfield(Bitfield),
+    Have(u32),
+    Block {
+        index: u32,
+        begin: u32,
+        block: Bytes,
+    },
+    Choked,
+    Unchoked,
+    /// UDP port of the DHT node of the peer.
+    DhtPort(u16),
+    /// The connection failed or was closed by the peer, no events follow.
+    Closed(Error),
+}
+
+/// Spawns the task owning the connection. It reads messages as they arrive, so the peer is
+/// heard even while we are not asking anything, and sends messages on command. Requests of the
+/// peer are answered through `uploads`, if given, and the pieces we complete are announced as
+/// they are sent on `pieces`. The task stops when the connection fails or
+/// times out, on [`PeerCommand::Shutdown`], or when the command sender is dropped.
+pub(super) fn spawn<S: Transport>(
+    stream: PeerStream<S>,
+    uploads: Option<Uploads>,
+    pieces: Option<watch::Receiver<Bitfield>>,
+    timeouts: PeerTimeouts,
+) -> (
+    mpsc::UnboundedSender<PeerCommand>,
+    mpsc::UnboundedReceiver<PeerEvent>,
+) {
+    let (command_tx, command_rx) = mpsc::unbounded_channel();
+    let (event_tx, event_rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        if let Err(err) = run(stream, command_rx, &event_tx, uploads, pieces, timeouts).await {
+            let _ = event_tx.send(PeerEvent::Closed(err));
+        }
+    });
+    (command_tx, event_rx)
+}
+
+async fn run<S: Transport>(
+    mut stream: PeerStream<S>,
+    mut commands: mpsc::UnboundedReceiver<PeerCommand>,
+    events: &mpsc::UnboundedSender<PeerEvent>,
+    mut uploads: Option<Uploads>,
+    mut pieces: Option<watch::Receiver<Bitfield>>,
+    timeouts: PeerTimeouts,
+) -> Result<()> {
+    // The bitfield may only be sent before any other message.
+    let mut announced = match &mut pieces {
+        Some(pieces) => pieces.borrow_and_update().clone(),
+        None => Bitfield::new(0),
+    };
+    if announced.count() > 0 {
+        send_message(
+            &mut stream,
+            PeerMessage::Bitfield(announced.clone()),
+            timeouts.write_timeout,
+        )
+        .await
+        .context("sending bitfield")?;
+    }
+
+    // Reset on every message, unlike a timeout around reading that restarts on every command.
+    let read_deadline = tokio::time::sleep(timeouts.read_timeout);
+    tokio::pin!(read_deadline);
+    loop {
+        tokio::select! {
+            message = read_message(&mut stream) => {
+                let message = message?;
+                read_deadline.as_mut().reset(Instant::now() + timeouts.read_timeout);
+                let reply = match &mut uploads {
+                    Some(uploads) => uploads.handle(&message)?,
+                    None => None,
+                };
+                if let Some(reply) = reply {
+                    send_message(&mut stream, reply, timeouts.write_timeout)
+                        .await
+                        .context("sending peer message")?;
+                }
+                let Some(event) = into_event(message) else {
+                    continue;
+                };
+                if events.send(event).is_err() {
+                    // Nobody listens anymore.
+                    return Ok(());
+                }
+            }
+            reply = next_reply(&mut uploads) => {
+                let piece = match &mut uploads {
+                    Some(uploads) => uploads.take_reply(reply)?,
+                    None => None,
+                };
+                if let Some(piece) = piece {
+                    send_message(&mut stream, piece, timeouts.write_timeout)
+                        .await
+                        .context("sending piece block")?;
+                }
+            }
+            completed = next_completed(&mut pieces) => {
+                for index in 0..completed.piece_count() {
+                    if completed.has(index) && !announced.has(index) {
+                        send_message(&mut stream, PeerMessage::Have { index }, timeouts.write_timeout)
+                            .await
+                            .context("sending have")?;
+                    }
+                }
+                announced = completed;
+            }
+            command = commands.recv() => {
+                let message = match command {
+                    Some(PeerCommand::Shutdown) | None => {
+                        return stream.close().await.context("closing peer connection");
+                    }
+                    Some(command) => into_message(command),
+                };
+                if let Some(uploads) = &mut uploads {
+                    match message {
+                        PeerMessage::Choke => uploads.set_choking(true),
+                        PeerMessage::Unchoke => uploads.set_choking(false),
+                        _ => (),
+                    }
+                }
+                send_message(&mut stream, message, timeouts.write_timeout)
+                    .await
+                    .context("sending peer message")?;
+            }
+            () = &mut read_deadline => {
+                bail!("peer sent no message for {} seconds", timeouts.read_timeout.as_secs());
+            }
+        }
+    }
+}
+
+/// Waits for the next reply to a request of the peer, forever when we do not upload.
+async fn next_reply(uploads: &mut Option<Uploads>) -> UploadReply {
+    match uploads {
+        Some(uploads) => uploads.next_reply().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits until we complete more pieces, forever when we do not announce them or the download is
+/// over.
+async fn next_completed(pieces: &mut Option<watch::Receiver<Bitfield>>) -> Bitfield {
+    if let Some(pieces) = pieces {
+        if pieces.changed().await.is_ok() {
+            return pieces.borrow_and_update().clone();
+        }
+    }
+    std::future::pending().await
+}
+
+/// Turns what the peer tells us into an event, skipping messages we have no use for.
+fn into_event(message: PeerMessage) -> Option<PeerEvent> {
+    Some(match message {
+        PeerMessage::Choke => PeerEvent::Choked,
+        PeerMessage::Unchoke => PeerEvent::Unchoked,
+        PeerMessage::Have { index } => PeerEvent::Have(index),
+        PeerMessage::Bitfield(bitfield) => PeerEvent::Bitfield(bitfield),
+        PeerMessage::Piece {
+            index,
+            begin,
+            block,
+        } => PeerEvent::Block {
+            index,
+            begin,
+            block,
+        },
+        PeerMessage::Port { port } => PeerEvent::DhtPort(port),
+        // Interest and requests of the peer concern the upload side only.
+        PeerMessage::KeepAlive
+        | PeerMessage::Interested
+        | PeerMessage::NotInterested
+        | PeerMessage::Request { .. }
+        | PeerMessage::Cancel { .. }
+        | PeerMessage::Extended { .. } => return None,
+        PeerMessage::Unknown { id, .. } => {
+            tracing::trace!(id, "Skipping peer message with unknown id");
+            return None;
+        }
+    })
+}
+
+fn into_message(command: PeerCommand) -> PeerMessage {
+    match command {
+        PeerCommand::Interested => PeerMessage::Interested,
+        PeerCommand::DhtPort(port) => PeerMessage::Port { port },
+        PeerCommand::RequestBlock {
+            index,
+            begin,
+            length,
+        } => PeerMessage::Request {
+            index,
+            begin,
+            length,
+        },
+        PeerCommand::Choke => PeerMessage::Choke,
+        PeerCommand::Unchoke => PeerMessage::Unchoke,
+        PeerCommand::Shutdown => unreachable!("shutdown is handled by the task"),
+    }
+}
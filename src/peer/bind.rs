@@ -0,0 +1,155 @@
+use std::{
+    convert::Infallible,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+use anyhow::{Context, Result};
+use reqwest::ClientBuilder;
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+
+/// Local address or network interface that peer connections are bound to. This keeps them on
+/// that interface, such as a VPN, even when other routes exist.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LocalBind {
+    Addr(IpAddr),
+    /// Name of the interface, such as `wg0`.
+    Interface(String),
+}
+
+impl FromStr for LocalBind {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(ip) => Self::Addr(ip),
+            Err(_) => Self::Interface(s.to_owned()),
+        })
+    }
+}
+
+impl fmt::Display for LocalBind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LocalBind::Addr(ip) => write!(f, "{ip}"),
+            LocalBind::Interface(name) => write!(f, "interface {name}"),
+        }
+    }
+}
+
+impl LocalBind {
+    /// Connects to the peer from the local address or interface.
+    pub async fn connect(&self, peer: SocketAddr) -> Result<TcpStream> {
+        let socket = self.socket(peer.ip(), 0)?;
+        Ok(socket.connect(peer).await?)
+    }
+
+    /// Listens for peers on the port of the local address or interface.
+    pub fn listen(&self, port: u16) -> Result<TcpListener> {
+        let socket = self.socket(Ipv4Addr::UNSPECIFIED.into(), port)?;
+        socket.set_reuseaddr(true)?;
+        Ok(socket.listen(1024)?)
+    }
+
+    /// Binds a UDP socket, such as the one of the DHT, to the port of the local address or
+    /// interface.
+    pub async fn udp(&self, port: u16) -> Result<UdpSocket> {
+        match self {
+            LocalBind::Addr(ip) => UdpSocket::bind(SocketAddr::new(*ip, port))
+                .await
+                .with_context(|| format!("binding udp socket to local address {ip}")),
+            LocalBind::Interface(name) => {
+                let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))
+                    .await
+                    .with_context(|| format!("binding udp socket to port {port}"))?;
+                bind_udp_device(&socket, name)?;
+                Ok(socket)
+            }
+        }
+    }
+
+    /// Makes the connections of an http client, such as the one of the trackers, from the local
+    /// address or interface.
+    pub fn http(&self, builder: ClientBuilder) -> Result<ClientBuilder> {
+        match self {
+            LocalBind::Addr(ip) => Ok(builder.local_address(*ip)),
+            LocalBind::Interface(name) => http_interface(builder, name),
+        }
+    }
+
+    /// Creates a socket bound to the local address or interface, and to the port unless it is 0.
+    /// Sockets bound to an interface are of the address family of `family`.
+    fn socket(&self, family: IpAddr, port: u16) -> Result<TcpSocket> {
+        match self {
+            LocalBind::Addr(ip) => {
+                let socket = new_socket(*ip)?;
+                socket
+                    .bind(SocketAddr::new(*ip, port))
+                    .with_context(|| format!("binding to local address {ip}"))?;
+                Ok(socket)
+            }
+            LocalBind::Interface(name) => {
+                let socket = new_socket(family)?;
+                bind_device(&socket, name)?;
+                if port != 0 {
+                    let unspecified = match family {
+                        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+                    };
+                    socket
+                        .bind(SocketAddr::new(unspecified, port))
+                        .with_context(|| format!("binding to port {port}"))?;
+                }
+                Ok(socket)
+            }
+        }
+    }
+}
+
+fn new_socket(family: IpAddr) -> Result<TcpSocket> {
+    match family {
+        IpAddr::V4(_) => TcpSocket::new_v4(),
+        IpAddr::V6(_) => TcpSocket::new_v6(),
+    }
+    .context("creating socket")
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &TcpSocket, name: &str) -> Result<()> {
+    socket
+        .bind_device(Some(name.as_bytes()))
+        .with_context(|| format!("binding to interface `{name}`"))
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_udp_device(socket: &UdpSocket, name: &str) -> Result<()> {
+    socket
+        .bind_device(Some(name.as_bytes()))
+        .with_context(|| format!("binding udp socket to interface `{name}`"))
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn http_interface(builder: ClientBuilder, name: &str) -> Result<ClientBuilder> {
+    Ok(builder.interface(name))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_socket: &TcpSocket, name: &str) -> Result<()> {
+    unsupported_interface(name)
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_udp_device(_socket: &UdpSocket, name: &str) -> Result<()> {
+    unsupported_interface(name)
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn http_interface(_builder: ClientBuilder, name: &str) -> Result<ClientBuilder> {
+    unsupported_interface(name)
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn unsupported_interface<T>(name: &str) -> Result<T> {
+    anyhow::bail!("binding to interface `{name}` is not supported on this platform, bind to its address instead")
+}
@@ -0,0 +1,130 @@
+use anyhow::{bail, Result};
+use bytes::Bytes;
+
+/// Pieces a peer has, one bit per piece with the high bit of the first byte for piece 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bits: Vec<u8>,
+    /// Number of pieces of the torrent.
+    len: u32,
+}
+
+impl Bitfield {
+    /// Bitfield of a peer without any pieces.
+    pub fn new(len: u32) -> Self {
+        Self {
+            bits: vec![0; len.div_ceil(8) as usize],
+            len,
+        }
+    }
+
+    /// Takes the payload of a bitfield message as is, counting every bit as a piece until it is
+    /// checked with [`Bitfield::with_piece_count`].
+    pub(super) fn from_payload(bits: Bytes) -> Result<Self> {
+        let Ok(len) = u32::try_from(bits.len() * 8) else {
+            bail!("bitfield payload too large");
+        };
+        Ok(Self {
+            bits: bits.to_vec(),
+            len,
+        })
+    }
+
+    /// Checks that the bitfield holds a bit for every piece of the torrent, and that the spare
+    /// bits at the end are cleared.
+    pub(super) fn with_piece_count(mut self, len: u32) -> Result<Self> {
+        if self.bits.len() != len.div_ceil(8) as usize {
+            bail!(
+                "bitfield of {} bytes does not match the piece count of {len}",
+                self.bits.len()
+            );
+        }
+        if (len..self.bits.len() as u32 * 8).any(|index| self.bit(index)) {
+            bail!("bitfield has spare bits set");
+        }
+        self.len = len;
+        Ok(self)
+    }
+
+    pub fn has(&self, index: u32) -> bool {
+        index < self.len && self.bit(index)
+    }
+
+    /// Marks the piece as available, as announced by a `Have` message.
+    pub fn set(&mut self, index: u32) -> Result<()> {
+        if index >= self.len {
+            bail!("piece index {index} out of range for {} pieces", self.len);
+        }
+        let (byte, mask) = Self::position(index);
+        self.bits[byte] |= mask;
+        Ok(())
+    }
+
+    /// Payload of the bitfield message announcing the pieces.
+    pub(super) fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    /// Number of pieces of the torrent.
+    pub fn piece_count(&self) -> u32 {
+        self.len
+    }
+
+    /// Number of pieces available.
+    pub fn count(&self) -> u32 {
+        self.bits.iter().map(|byte| byte.count_ones()).sum()
+    }
+
+    fn bit(&self, index: u32) -> bool {
+        let (byte, mask) = Self::position(index);
+        self.bits[byte] & mask != 0
+    }
+
+    fn position(index: u32) -> (usize, u8) {
+        (index as usize / 8, 0x80 >> (index % 8))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn with_piece_count() {
+        let bitfield = Bitfield::from_payload(Bytes::from_static(&[0b1010_0000, 0b1000_0000]))
+            .unwrap()
+            .with_piece_count(9)
+            .unwrap();
+        assert_eq!(bitfield.piece_count(), 9);
+        assert_eq!(bitfield.count(), 3);
+        assert!(bitfield.has(0) && !bitfield.has(1) && bitfield.has(2) && bitfield.has(8));
+        assert!(!bitfield.has(9));
+    }
+
+    #[test]
+    fn reject_wrong_byte_length() {
+        for len in [8, 17] {
+            let bitfield = Bitfield::from_payload(Bytes::from_static(&[0, 0])).unwrap();
+            assert!(bitfield.with_piece_count(len).is_err());
+        }
+    }
+
+    #[test]
+    fn reject_spare_bits() {
+        let bitfield = Bitfield::from_payload(Bytes::from_static(&[0, 0b0100_0000])).unwrap();
+        assert!(bitfield.with_piece_count(9).is_err());
+    }
+
+    #[test]
+    fn set_in_range() {
+        let mut bitfield = Bitfield::new(9);
+        bitfield.set(8).unwrap();
+        assert!(bitfield.has(8));
+        assert_eq!(bitfield.as_bytes(), [0, 0b1000_0000]);
+
+        // Index 9 still has a spare bit in the last byte, but is no piece.
+        assert!(bitfield.set(9).is_err());
+        assert!(bitfield.set(u32::MAX).is_err());
+        assert_eq!(bitfield.count(), 1);
+    }
+}
@@ -0,0 +1,83 @@
+use anyhow::{bail, Error, Result};
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::message::PeerMessage;
+
+/// Largest frame accepted from a peer. Piece and metadata messages carry at most 16 KiB, only the
+/// bitfield of a torrent with millions of pieces comes close.
+const MAX_FRAME_LENGTH: usize = 1 << 20;
+
+/// Frames peer messages after the handshake, each prefixed with its length as big-endian `u32`.
+#[derive(Debug, Default)]
+pub(super) struct PeerMessageCodec;
+
+impl Decoder for PeerMessageCodec {
+    type Item = PeerMessage;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        let Some(prefix) = src.first_chunk::<4>() else {
+            return Ok(None);
+        };
+        let length = u32::from_be_bytes(*prefix) as usize;
+        if length > MAX_FRAME_LENGTH {
+            bail!("peer message of {length} bytes exceeds the maximum frame length");
+        }
+        if src.len() < 4 + length {
+            src.reserve(4 + length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(4);
+        PeerMessage::parse(src.split_to(length).freeze()).map(Some)
+    }
+}
+
+impl Encoder<PeerMessage> for PeerMessageCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: PeerMessage, dst: &mut BytesMut) -> Result<()> {
+        let length = item.byte_size();
+        dst.reserve(4 + length as usize);
+        dst.put_u32(length);
+        item.encode(dst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio_util::codec::FramedRead;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reject_oversized_frame() {
+        let (mut local, remote) = tokio::io::duplex(64);
+        let length = MAX_FRAME_LENGTH as u32 + 1;
+        local.write_all(&length.to_be_bytes()).await.unwrap();
+
+        let mut frames = FramedRead::new(remote, PeerMessageCodec);
+        let err = frames.next().await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("exceeds the maximum frame length"));
+    }
+
+    #[tokio::test]
+    async fn decode_frames() {
+        let (mut local, remote) = tokio::io::duplex(64);
+        local
+            .write_all(&[0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 5, 4, 0, 0, 0, 7])
+            .await
+            .unwrap();
+        drop(local);
+
+        let frames = FramedRead::new(remote, PeerMessageCodec)
+            .map(|frame| format!("{:?}", frame.unwrap()))
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(frames, ["KeepAlive", "Unchoke", "Have { index: 7 }"]);
+    }
+}
@@ -0,0 +1,73 @@
+use std::fmt;
+
+/// Protocol extension announced by a bit in the reserved bytes of the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Extension {
+    /// Extension protocol (BEP 10), carrying messages such as ut_metadata.
+    Extended,
+    /// Fast extension (BEP 6).
+    Fast,
+    /// DHT node whose port the port message tells (BEP 5).
+    Dht,
+}
+
+impl Extension {
+    const ALL: [Extension; 3] = [Extension::Extended, Extension::Fast, Extension::Dht];
+
+    /// Byte of the reserved bytes and mask of the bit within it.
+    fn position(self) -> (usize, u8) {
+        match self {
+            Extension::Extended => (5, 0x10),
+            Extension::Fast => (7, 0x04),
+            Extension::Dht => (7, 0x01),
+        }
+    }
+}
+
+impl fmt::Display for Extension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Extension::Extended => "extension protocol",
+            Extension::Fast => "fast",
+            Extension::Dht => "dht",
+        })
+    }
+}
+
+/// Reserved bytes of the handshake, telling which extensions a peer supports. Bits of extensions
+/// we do not know are kept as sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Extensions([u8; 8]);
+
+impl Extensions {
+    pub(super) fn from_bytes(bytes: [u8; 8]) -> Self {
+        Self(bytes)
+    }
+
+    pub(super) fn to_bytes(self) -> [u8; 8] {
+        self.0
+    }
+
+    pub fn with(mut self, extension: Extension) -> Self {
+        let (byte, mask) = extension.position();
+        self.0[byte] |= mask;
+        self
+    }
+
+    pub fn supports(&self, extension: Extension) -> bool {
+        let (byte, mask) = extension.position();
+        self.0[byte] & mask != 0
+    }
+
+    /// Extensions we know of that are supported.
+    pub fn iter(&self) -> impl Iterator<Item = Extension> + '_ {
+        Extension::ALL
+            .into_iter()
+            .filter(|extension| self.supports(*extension))
+    }
+
+    /// Extensions both sides support, the only ones that may be used on a connection.
+    pub fn common(self, other: Extensions) -> Self {
+        Self(std::array::from_fn(|i| self.0[i] & other.0[i]))
+    }
+}
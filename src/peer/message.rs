@@ -1,13 +1,22 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, ensure, Result};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 
-use crate::util::{PeerId, Sha1Hash};
+use super::{piece::PIECE_BLOCK_SIZE, Bitfield, Extensions};
+use crate::util::{InfoHash, PeerId};
 
 #[derive(Debug)]
 pub(super) enum PeerMessage {
+    /// Zero-length frame keeping an idle connection open.
+    KeepAlive,
+    Choke,
     Unchoke,
     Interested,
-    Bitfield,
+    NotInterested,
+    Have {
+        index: u32,
+    },
+    /// Pieces the peer has, to be checked against the piece count of the torrent.
+    Bitfield(Bitfield),
     Request {
         index: u32,
         begin: u32,
@@ -18,10 +27,30 @@ pub(super) enum PeerMessage {
         begin: u32,
         block: Bytes,
     },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// UDP port of the DHT node of the peer (BEP 5).
+    Port {
+        port: u16,
+    },
+    /// Message of the extension protocol (BEP 10); id 0 is the extension handshake.
+    Extended {
+        id: u8,
+        payload: Bytes,
+    },
+    /// Message with an id we do not know, left for the caller to skip or reject.
+    Unknown {
+        id: u8,
+        payload: Bytes,
+    },
 }
 
 pub(super) struct PeerHandShakePacket {
-    pub(super) info_hash: Sha1Hash,
+    pub(super) extensions: Extensions,
+    pub(super) info_hash: InfoHash,
     pub(super) peer_id: PeerId,
 }
 
@@ -32,31 +61,52 @@ fn parse_empty(input: Bytes) -> Result<()> {
     Ok(())
 }
 
-fn parse_ingore_payload(mut input: Bytes) -> Result<()> {
-    // Consume all remaining bytes.
-    input.advance(input.remaining());
-    Ok(())
+fn parse_have_payload(mut input: Bytes) -> Result<PeerMessage> {
+    if input.remaining() != 4 {
+        bail!("have payload should be 4 bytes");
+    }
+
+    Ok(PeerMessage::Have {
+        index: input.get_u32(),
+    })
+}
+
+fn parse_port_payload(mut input: Bytes) -> Result<PeerMessage> {
+    if input.remaining() != 2 {
+        bail!("port payload should be 2 bytes");
+    }
+
+    Ok(PeerMessage::Port {
+        port: input.get_u16(),
+    })
 }
 
-fn parse_request_payload(mut input: Bytes) -> Result<PeerMessage> {
+/// Reads the index, offset and length shared by request and cancel messages.
+fn parse_block_payload(mut input: Bytes) -> Result<(u32, u32, u32)> {
+    if input.remaining() != 12 {
+        bail!("request payload should be 12 bytes");
+    }
+
     let index = input.get_u32();
     let begin = input.get_u32();
     let length = input.get_u32();
 
-    if input.has_remaining() {
-        bail!("bytes remaining when parsing request payload");
-    }
-
-    Ok(PeerMessage::Request {
-        index,
-        begin,
-        length,
-    })
+    Ok((index, begin, length))
 }
 
 fn parse_piece_payload(mut input: Bytes) -> Result<PeerMessage> {
+    if input.remaining() < 8 {
+        bail!("piece payload should be at least 8 bytes");
+    }
+
     let index = input.get_u32();
     let begin = input.get_u32();
+    if input.remaining() > PIECE_BLOCK_SIZE as usize {
+        bail!(
+            "piece block of {} bytes is larger than any request",
+            input.remaining()
+        );
+    }
 
     Ok(PeerMessage::Piece {
         index,
@@ -67,9 +117,16 @@ fn parse_piece_payload(mut input: Bytes) -> Result<PeerMessage> {
 
 impl PeerMessage {
     pub(super) fn parse(mut input: Bytes) -> Result<Self> {
+        if !input.has_remaining() {
+            return Ok(PeerMessage::KeepAlive);
+        }
         let message_id = input.get_u8();
 
         Ok(match message_id {
+            0 => {
+                parse_empty(input)?;
+                PeerMessage::Choke
+            }
             1 => {
                 parse_empty(input)?;
                 PeerMessage::Unchoke
@@ -78,23 +135,62 @@ impl PeerMessage {
                 parse_empty(input)?;
                 PeerMessage::Interested
             }
-            5 => {
-                parse_ingore_payload(input)?;
-                PeerMessage::Bitfield
+            3 => {
+                parse_empty(input)?;
+                PeerMessage::NotInterested
+            }
+            4 => parse_have_payload(input)?,
+            5 => PeerMessage::Bitfield(Bitfield::from_payload(input)?),
+            6 => {
+                let (index, begin, length) = parse_block_payload(input)?;
+                if length == 0 || length > PIECE_BLOCK_SIZE {
+                    bail!("request of {length} bytes, blocks are at most {PIECE_BLOCK_SIZE} bytes");
+                }
+                PeerMessage::Request {
+                    index,
+                    begin,
+                    length,
+                }
             }
-            6 => parse_request_payload(input)?,
             7 => parse_piece_payload(input)?,
-            _ => bail!("unhandled message id: {}", message_id),
+            8 => {
+                let (index, begin, length) = parse_block_payload(input)?;
+                PeerMessage::Cancel {
+                    index,
+                    begin,
+                    length,
+                }
+            }
+            9 => parse_port_payload(input)?,
+            20 => {
+                if !input.has_remaining() {
+                    bail!("extended message has no id");
+                }
+                PeerMessage::Extended {
+                    id: input.get_u8(),
+                    payload: input,
+                }
+            }
+            id => PeerMessage::Unknown { id, payload: input },
         })
     }
 
-    pub(super) fn into_bytes(self) -> Bytes {
-        let mut buf = BytesMut::new();
-        buf.put_u32(self.byte_size());
-
+    /// Writes the message id and payload, without the length prefix.
+    pub(super) fn encode(self, buf: &mut BytesMut) {
         match self {
+            PeerMessage::KeepAlive => (),
+            PeerMessage::Choke => buf.put_u8(0),
             PeerMessage::Unchoke => buf.put_u8(1),
             PeerMessage::Interested => buf.put_u8(2),
+            PeerMessage::NotInterested => buf.put_u8(3),
+            PeerMessage::Have { index } => {
+                buf.put_u8(4);
+                buf.put_u32(index);
+            }
+            PeerMessage::Bitfield(bitfield) => {
+                buf.put_u8(5);
+                buf.put_slice(bitfield.as_bytes());
+            }
             PeerMessage::Request {
                 index,
                 begin,
@@ -105,52 +201,99 @@ impl PeerMessage {
                 buf.put_u32(begin);
                 buf.put_u32(length);
             }
-
-            PeerMessage::Piece { .. } | PeerMessage::Bitfield => {
-                unimplemented!("message unsupported for serialization")
+            PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                buf.put_u8(8);
+                buf.put_u32(index);
+                buf.put_u32(begin);
+                buf.put_u32(length);
+            }
+            PeerMessage::Port { port } => {
+                buf.put_u8(9);
+                buf.put_u16(port);
+            }
+            PeerMessage::Extended { id, payload } => {
+                buf.put_u8(20);
+                buf.put_u8(id);
+                buf.put(payload);
+            }
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                buf.put_u8(7);
+                buf.put_u32(index);
+                buf.put_u32(begin);
+                buf.put(block);
+            }
+            PeerMessage::Unknown { id, payload } => {
+                buf.put_u8(id);
+                buf.put(payload);
             }
         }
-
-        buf.freeze()
     }
 
-    fn byte_size(&self) -> u32 {
+    /// Length of the message as given in its length prefix.
+    pub(super) fn byte_size(&self) -> u32 {
         match self {
+            PeerMessage::KeepAlive => 0,
+            PeerMessage::Choke => 1,
             PeerMessage::Unchoke => 1,
             PeerMessage::Interested => 1,
-            PeerMessage::Request { .. } => 13,
-
-            PeerMessage::Piece { .. } | PeerMessage::Bitfield => {
-                unimplemented!("message unsupported for serialization")
+            PeerMessage::NotInterested => 1,
+            PeerMessage::Have { .. } => 5,
+            PeerMessage::Bitfield(bitfield) => {
+                1 + u32::try_from(bitfield.as_bytes().len()).expect("bitfield should fit in u32")
+            }
+            PeerMessage::Request { .. } | PeerMessage::Cancel { .. } => 13,
+            PeerMessage::Piece { block, .. } => {
+                9 + u32::try_from(block.len()).expect("piece block should fit in u32")
+            }
+            PeerMessage::Port { .. } => 3,
+            PeerMessage::Extended { payload, .. } => {
+                2 + u32::try_from(payload.len()).expect("extended payload should fit in u32")
+            }
+            PeerMessage::Unknown { payload, .. } => {
+                1 + u32::try_from(payload.len()).expect("message payload should fit in u32")
             }
         }
     }
 }
 
 impl PeerHandShakePacket {
-    pub(super) fn new(info_hash: Sha1Hash, peer_id: PeerId) -> Self {
-        Self { info_hash, peer_id }
+    pub(super) fn new(info_hash: InfoHash, peer_id: PeerId, extensions: Extensions) -> Self {
+        Self {
+            extensions,
+            info_hash,
+            peer_id,
+        }
     }
 
-    pub(super) fn parse(mut input: Bytes) -> Result<Self> {
-        let header_length = input.get_u8();
-        let header = input.copy_to_bytes(header_length as usize);
+    /// Parses the 68 bytes of a handshake, rejecting anything but the BitTorrent protocol.
+    pub(super) fn parse(input: Bytes) -> Result<Self> {
+        ensure!(
+            input.len() == 68,
+            "peer handshake packet of {} bytes, expected 68",
+            input.len()
+        );
+        ensure!(
+            input[0] == 19,
+            "peer handshake protocol name of {} bytes, expected 19",
+            input[0]
+        );
 
-        if header != b"BitTorrent protocol".as_slice() {
+        if &input[1..20] != b"BitTorrent protocol" {
             bail!("Unexpected peer handshake packet.");
         }
 
-        // Reserved zero-bytes.
-        input.get_u64();
-
-        let info_hash = input.copy_to_bytes(20);
-        let peer_id = input.copy_to_bytes(20);
-
         Ok(PeerHandShakePacket {
-            info_hash: *info_hash
-                .first_chunk()
-                .expect("info hash should be 20 bytes"),
-            peer_id: *peer_id.first_chunk().expect("peer id should be 20 bytes"),
+            extensions: Extensions::from_bytes(input[20..28].try_into()?),
+            info_hash: input[28..48].try_into()?,
+            peer_id: input[48..68].try_into()?,
         })
     }
 
@@ -161,7 +304,7 @@ impl PeerHandShakePacket {
             let mut buf = BytesMut::with_capacity(68);
             buf.put_u8(19);
             buf.write_str("BitTorrent protocol")?;
-            buf.put_u64(0);
+            buf.put_slice(&self.extensions.to_bytes());
             buf.extend(self.info_hash);
             buf.extend(self.peer_id);
 
@@ -171,3 +314,60 @@ impl PeerHandShakePacket {
         prepare().expect("prepared peer handshake buffer should not be empty")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake() -> BytesMut {
+        BytesMut::from(
+            &PeerHandShakePacket::new([1; 20], [2; 20], Extensions::default()).into_bytes()[..],
+        )
+    }
+
+    #[test]
+    fn parse_handshake() {
+        let packet = PeerHandShakePacket::parse(handshake().freeze()).unwrap();
+        assert_eq!(packet.info_hash, [1; 20]);
+        assert_eq!(packet.peer_id, [2; 20]);
+    }
+
+    #[test]
+    fn reject_malformed_handshake() {
+        // Protocol name longer than the packet, which used to panic.
+        let mut long_name = handshake();
+        long_name[0] = 200;
+        assert!(PeerHandShakePacket::parse(long_name.freeze()).is_err());
+
+        let mut other_protocol = handshake();
+        other_protocol[1] = b'b';
+        assert!(PeerHandShakePacket::parse(other_protocol.freeze()).is_err());
+
+        let truncated = handshake().split_to(40);
+        assert!(PeerHandShakePacket::parse(truncated.freeze()).is_err());
+        assert!(PeerHandShakePacket::parse(Bytes::new()).is_err());
+    }
+
+    #[test]
+    fn reject_request_lengths() {
+        let request = |length: u32| {
+            let mut payload = BytesMut::new();
+            payload.put_u8(6);
+            payload.put_u32(1);
+            payload.put_u32(0);
+            payload.put_u32(length);
+            PeerMessage::parse(payload.freeze())
+        };
+
+        assert!(matches!(
+            request(PIECE_BLOCK_SIZE),
+            Ok(PeerMessage::Request {
+                index: 1,
+                begin: 0,
+                length: PIECE_BLOCK_SIZE,
+            })
+        ));
+        assert!(request(0).is_err());
+        assert!(request(PIECE_BLOCK_SIZE + 1).is_err());
+    }
+}
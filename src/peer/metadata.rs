@@ -0,0 +1,388 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use bencode::{BencodeDict, BencodeValue};
+use bstr::BString;
+use bytes::{Bytes, BytesMut};
+use serde::Deserialize;
+use tokio_util::codec::Framed;
+
+use super::{
+    codec::PeerMessageCodec,
+    exchange_handshake,
+    message::{PeerHandShakePacket, PeerMessage},
+    read_message_timeout, send_message, Disconnected, Extension, Extensions, Peer, PeerStream,
+    Transport,
+};
+use crate::util::{hash_sha1, InfoHash, PeerId};
+
+/// Size of every metadata piece but the last (BEP 9).
+const METADATA_PIECE_SIZE: usize = 16 * 1024;
+/// Largest info dict we are willing to download.
+const MAX_METADATA_SIZE: usize = 16 << 20;
+/// Extended message id we ask peers to use for ut_metadata messages sent to us.
+const UT_METADATA_ID: u8 = 1;
+
+#[derive(Debug, Deserialize)]
+struct ExtensionHandshake {
+    #[serde(default)]
+    m: BTreeMap<BString, i64>,
+    metadata_size: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MetadataHeader {
+    msg_type: u8,
+    piece: usize,
+}
+
+/// Message of the ut_metadata extension (BEP 9).
+#[derive(Debug)]
+enum MetadataMessage {
+    Request {
+        piece: usize,
+    },
+    Data {
+        piece: usize,
+        data: Bytes,
+    },
+    /// The peer does not hand out the piece, for example because it does not have the metadata.
+    Reject {
+        piece: usize,
+    },
+}
+
+impl MetadataMessage {
+    /// Payload asking for a piece. Data messages are never sent, as we do not serve metadata.
+    fn request(piece: usize) -> BencodeValue {
+        Self::header(0, piece)
+    }
+
+    /// Payload declining a request of the peer.
+    fn reject(piece: usize) -> BencodeValue {
+        Self::header(2, piece)
+    }
+
+    fn header(msg_type: u8, piece: usize) -> BencodeValue {
+        BencodeDict::new()
+            .with("msg_type", msg_type)
+            .with("piece", piece as u64)
+            .build()
+    }
+
+    fn parse(payload: Bytes) -> Result<Self> {
+        let (header, header_length) =
+            BencodeValue::parse_prefix(&payload).context("parsing metadata message")?;
+        let header: MetadataHeader = header
+            .into_deserialize()
+            .context("parsing metadata message")?;
+        let piece = header.piece;
+
+        Ok(match header.msg_type {
+            0 => MetadataMessage::Request { piece },
+            1 => MetadataMessage::Data {
+                piece,
+                data: payload.slice(header_length..),
+            },
+            2 => MetadataMessage::Reject { piece },
+            msg_type => bail!("unexpected metadata message type {msg_type}"),
+        })
+    }
+}
+
+/// Collects the pieces of the info dict, possibly from several peers, until it is complete and
+/// matches the info hash.
+pub struct MetadataAssembler {
+    info_hash: InfoHash,
+    /// Size of the info dict as announced by the first peer that offered it.
+    size: Option<usize>,
+    pieces: Vec<Option<Bytes>>,
+}
+
+impl MetadataAssembler {
+    pub fn new(info_hash: InfoHash) -> Self {
+        Self {
+            info_hash,
+            size: None,
+            pieces: Vec::new(),
+        }
+    }
+
+    pub fn info_hash(&self) -> &InfoHash {
+        &self.info_hash
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.size.is_some() && self.pieces.iter().all(Option::is_some)
+    }
+
+    /// Joins the pieces and checks them against the info hash. On a mismatch every piece is
+    /// dropped, as there is no telling which peer sent a bad one.
+    pub fn finish(&mut self) -> Result<Bytes> {
+        ensure!(self.is_complete(), "metadata is incomplete");
+
+        let mut metadata = BytesMut::with_capacity(self.size.unwrap_or_default());
+        for piece in self.pieces.iter().flatten() {
+            metadata.extend_from_slice(piece);
+        }
+        if hash_sha1(&metadata) != self.info_hash {
+            self.pieces.fill(None);
+            bail!("metadata received from peers does not match the info hash");
+        }
+        Ok(metadata.freeze())
+    }
+
+    /// Takes the size a peer announces, which must agree with the one we already have.
+    fn set_size(&mut self, size: usize) -> Result<()> {
+        match self.size {
+            Some(known) => ensure!(
+                known == size,
+                "peer announced metadata size {size} instead of {known}"
+            ),
+            None => {
+                ensure!(
+                    (1..=MAX_METADATA_SIZE).contains(&size),
+                    "peer announced metadata size {size} outside the accepted range"
+                );
+                self.size = Some(size);
+                self.pieces = vec![None; size.div_ceil(METADATA_PIECE_SIZE)];
+            }
+        }
+        Ok(())
+    }
+
+    fn missing(&self) -> impl Iterator<Item = usize> + '_ {
+        self.pieces
+            .iter()
+            .enumerate()
+            .filter(|(_, piece)| piece.is_none())
+            .map(|(index, _)| index)
+    }
+
+    fn insert(&mut self, piece: usize, data: Bytes) -> Result<()> {
+        let size = self.size.context("metadata size is unknown")?;
+        let slot = self
+            .pieces
+            .get_mut(piece)
+            .with_context(|| format!("metadata piece {piece} out of range"))?;
+        let expected = (size - piece * METADATA_PIECE_SIZE).min(METADATA_PIECE_SIZE);
+        ensure!(
+            data.len() == expected,
+            "metadata piece {piece} has {} bytes, expected {expected}",
+            data.len()
+        );
+        *slot = Some(data);
+        Ok(())
+    }
+}
+
+/// Waits for the next extended message with the given id, skipping all other messages.
+/// Messages other than extended ones are irrelevant while fetching metadata.
+async fn read_extended<S: Transport>(
+    stream: &mut PeerStream<S>,
+    id: u8,
+    timeout: Duration,
+) -> Result<Bytes> {
+    loop {
+        match read_message_timeout(stream, timeout).await? {
+            PeerMessage::Extended {
+                id: message_id,
+                payload,
+            } if message_id == id => return Ok(payload),
+            _ => continue,
+        }
+    }
+}
+
+async fn send_extended<S: Transport>(
+    stream: &mut PeerStream<S>,
+    id: u8,
+    payload: BencodeValue,
+    timeout: Duration,
+) -> Result<()> {
+    let message = PeerMessage::Extended {
+        id,
+        payload: Bytes::from(payload.to_canonical_bytes()),
+    };
+    send_message(stream, message, timeout)
+        .await
+        .context("sending extended message")
+}
+
+impl Peer<Disconnected> {
+    /// Downloads the pieces of the info dict the assembler misses from the peer, using the
+    /// extension protocol (BEP 10) and ut_metadata (BEP 9). Pieces the peer rejects are left for
+    /// other peers.
+    pub async fn fetch_metadata(
+        self,
+        client_peer_id: PeerId,
+        assembler: &mut MetadataAssembler,
+    ) -> Result<()> {
+        let stream = self.connect().await?;
+        self.fetch_metadata_over(stream, client_peer_id, assembler)
+            .await
+    }
+
+    /// Like [`Peer::fetch_metadata`], over a stream that is already connected to the peer.
+    pub async fn fetch_metadata_over<S: Transport>(
+        self,
+        mut stream: S,
+        client_peer_id: PeerId,
+        assembler: &mut MetadataAssembler,
+    ) -> Result<()> {
+        let timeouts = self.connection.timeouts;
+
+        let extensions = Extensions::default().with(Extension::Extended);
+        let handshake_packet = tokio::time::timeout(
+            timeouts.handshake_timeout,
+            exchange_handshake(
+                &mut stream,
+                PeerHandShakePacket::new(*assembler.info_hash(), client_peer_id, extensions),
+            ),
+        )
+        .await
+        .context("timed out exchanging handshakes")??;
+        self.check_peer_id(&handshake_packet.peer_id)?;
+        ensure!(
+            extensions
+                .common(handshake_packet.extensions)
+                .supports(Extension::Extended),
+            "peer does not support the extension protocol"
+        );
+        let mut stream = Framed::new(stream, PeerMessageCodec);
+
+        send_extended(
+            &mut stream,
+            0,
+            BencodeDict::new()
+                .with("m", BencodeDict::new().with("ut_metadata", UT_METADATA_ID))
+                .build(),
+            timeouts.write_timeout,
+        )
+        .await
+        .context("sending extension handshake")?;
+
+        let handshake: ExtensionHandshake =
+            bencode::from_bytes(&read_extended(&mut stream, 0, timeouts.read_timeout).await?)
+                .context("parsing extension handshake")?;
+        let peer_ut_metadata_id = handshake
+            .m
+            .get(&BString::from("ut_metadata"))
+            .and_then(|id| u8::try_from(*id).ok())
+            .filter(|id| *id != 0)
+            .context("peer does not support ut_metadata")?;
+        assembler.set_size(
+            handshake
+                .metadata_size
+                .context("peer did not announce the metadata size")?,
+        )?;
+
+        // Every piece is requested up front, the peer answers each with data or a reject.
+        let mut requested = assembler.missing().collect::<BTreeSet<_>>();
+        for &piece in &requested {
+            send_extended(
+                &mut stream,
+                peer_ut_metadata_id,
+                MetadataMessage::request(piece),
+                timeouts.write_timeout,
+            )
+            .await
+            .context("requesting metadata piece")?;
+        }
+
+        while !requested.is_empty() {
+            let payload = read_extended(&mut stream, UT_METADATA_ID, timeouts.read_timeout).await?;
+            match MetadataMessage::parse(payload)? {
+                MetadataMessage::Data { piece, data } => {
+                    ensure!(
+                        requested.remove(&piece),
+                        "peer sent metadata piece {piece} that was not requested"
+                    );
+                    assembler.insert(piece, data)?;
+                }
+                MetadataMessage::Reject { piece } => {
+                    if requested.remove(&piece) {
+                        tracing::debug!(piece, "Peer rejected metadata request");
+                    }
+                }
+                MetadataMessage::Request { piece } => {
+                    // We do not serve metadata.
+                    send_extended(
+                        &mut stream,
+                        peer_ut_metadata_id,
+                        MetadataMessage::reject(piece),
+                        timeouts.write_timeout,
+                    )
+                    .await
+                    .context("rejecting metadata request")?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Assembler expecting metadata of `size` bytes, all of them `byte`.
+    fn assembler(size: usize, byte: u8) -> MetadataAssembler {
+        let mut assembler = MetadataAssembler::new(hash_sha1(vec![byte; size]));
+        assembler.set_size(size).unwrap();
+        assembler
+    }
+
+    #[test]
+    fn assemble_pieces() {
+        let size = METADATA_PIECE_SIZE + 10;
+        let mut assembler = assembler(size, 7);
+
+        assembler.insert(1, Bytes::from(vec![7; 10])).unwrap();
+        assert!(!assembler.is_complete());
+        assert_eq!(assembler.missing().collect::<Vec<_>>(), [0]);
+        assembler
+            .insert(0, Bytes::from(vec![7; METADATA_PIECE_SIZE]))
+            .unwrap();
+        assert_eq!(assembler.finish().unwrap(), vec![7; size]);
+    }
+
+    #[test]
+    fn reject_size_mismatch() {
+        let mut assembler = assembler(100, 7);
+
+        assert!(assembler.set_size(100).is_ok());
+        assert!(assembler.set_size(101).is_err());
+        assert!(MetadataAssembler::new([0; 20]).set_size(0).is_err());
+        assert!(MetadataAssembler::new([0; 20])
+            .set_size(MAX_METADATA_SIZE + 1)
+            .is_err());
+    }
+
+    #[test]
+    fn reject_wrong_piece_length() {
+        let mut assembler = assembler(METADATA_PIECE_SIZE + 10, 7);
+
+        assert!(assembler.insert(1, Bytes::from(vec![7; 11])).is_err());
+        assert!(assembler
+            .insert(1, Bytes::from(vec![7; METADATA_PIECE_SIZE]))
+            .is_err());
+        assert!(assembler.insert(2, Bytes::from(vec![7; 10])).is_err());
+        assert_eq!(assembler.missing().count(), 2);
+    }
+
+    #[test]
+    fn hash_mismatch_clears_pieces() {
+        let mut assembler = assembler(10, 7);
+
+        assembler.insert(0, Bytes::from(vec![8; 10])).unwrap();
+        assert!(assembler.is_complete());
+        assert!(assembler.finish().is_err());
+        assert!(!assembler.is_complete());
+        assert_eq!(assembler.missing().collect::<Vec<_>>(), [0]);
+    }
+}
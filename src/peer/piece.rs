@@ -1,11 +1,17 @@
+use std::collections::{HashMap, VecDeque};
+
 use anyhow::{bail, Context, Result};
 use bytes::Bytes;
-use tokio::net::TcpStream;
 
-use super::{message::PeerMessage, prepare_buffer_with_length, Connected, Peer};
-use crate::util::{hash_sha1, Sha1Hash};
+use super::{
+    actor::{PeerCommand, PeerEvent},
+    Connected, Peer,
+};
+use crate::util::{hash_piece, Sha1Hash};
 
-const PIECE_BLOCK_SIZE: u32 = 16 * 1024;
+pub(super) const PIECE_BLOCK_SIZE: u32 = 16 * 1024;
+/// Block requests kept outstanding per peer by default.
+pub const DEFAULT_PIPELINE_DEPTH: usize = 5;
 
 #[derive(Clone)]
 pub struct PieceDescriptor {
@@ -24,30 +30,6 @@ impl PieceDescriptor {
     }
 }
 
-async fn read_piece_block(stream: &mut TcpStream) -> Result<PieceBlockResponse> {
-    use tokio::io::AsyncReadExt;
-
-    let mut buf = prepare_buffer_with_length(stream).await?;
-
-    stream
-        .read_exact(&mut buf)
-        .await
-        .context("reading piece block message")?;
-    Ok(match PeerMessage::parse(buf.into()) {
-        Ok(PeerMessage::Piece {
-            index,
-            begin,
-            block,
-        }) => PieceBlockResponse {
-            index,
-            begin,
-            block,
-        },
-        Err(err) => return Err(err).context("parsing piece block message"),
-        _ => bail!("unexpected peer message"),
-    })
-}
-
 impl Peer<Connected> {
     pub async fn download_piece(
         &mut self,
@@ -57,33 +39,71 @@ impl Peer<Connected> {
             hash,
         }: PieceDescriptor,
     ) -> Result<Vec<u8>> {
-        use tokio::io::AsyncWriteExt;
-
-        let stream = &mut self.connection.stream;
+        let connection = &mut self.connection;
+        connection.handle_pending()?;
+        if !connection.pieces.has(index) {
+            bail!("peer does not have piece {index}");
+        }
 
-        // Request the piece.
         let mut buf = vec![0u8; length as usize];
-        for req_block in generate_piece_block_requests(index, length) {
-            // Request the block in the piece.
-            stream
-                .write_all(&req_block.to_message().into_bytes())
-                .await
-                .context("sending piece block request")?;
-
-            // Receive the block.
-            let rec_block = read_piece_block(stream)
+        let mut requests = generate_piece_block_requests(index, length).collect::<VecDeque<_>>();
+        // Requests sent but not answered yet, by piece index and offset.
+        let mut pending = HashMap::new();
+        while !(requests.is_empty() && pending.is_empty()) {
+            // Keep the pipeline filled so the peer never waits for our next request.
+            while !connection.choked && pending.len() < connection.pipeline_depth {
+                let Some(req_block) = requests.pop_front() else {
+                    break;
+                };
+                connection
+                    .send(req_block.to_command())
+                    .context("sending piece block request")?;
+                pending.insert((req_block.index, req_block.begin), req_block);
+            }
+
+            // Receive a block, in whatever order the peer answers.
+            match connection
+                .next_event()
                 .await
-                .context("reading piece block message")?;
-
-            check_block_validity(&req_block, &rec_block)?;
-
-            // Accumulate the values.
-            buf[rec_block.begin as usize..(rec_block.begin + req_block.length) as usize]
-                .copy_from_slice(&rec_block.block);
+                .context("waiting for piece block")?
+            {
+                PeerEvent::Block {
+                    index,
+                    begin,
+                    block,
+                } => {
+                    connection.check_block(index)?;
+                    let Some(req_block) = pending.remove(&(index, begin)) else {
+                        connection.handle(PeerEvent::Block {
+                            index,
+                            begin,
+                            block,
+                        })?;
+                        continue;
+                    };
+                    let rec_block = PieceBlockResponse { begin, block };
+                    check_block_validity(&req_block, &rec_block)?;
+
+                    // Accumulate the values.
+                    buf[rec_block.begin as usize..(rec_block.begin + req_block.length) as usize]
+                        .copy_from_slice(&rec_block.block);
+                }
+                PeerEvent::Choked => {
+                    // The peer drops our requests when choking, ask again once unchoked.
+                    connection.choked = true;
+                    let mut dropped = pending.drain().map(|(_, req)| req).collect::<Vec<_>>();
+                    dropped.sort_by_key(|req| req.begin);
+                    for req_block in dropped.into_iter().rev() {
+                        requests.push_front(req_block);
+                    }
+                }
+                event => connection.handle(event)?,
+            }
         }
 
         // Check the piece hash.
-        if hash != hash_sha1(&buf) {
+        let (buf, piece_hash) = hash_piece(buf).await;
+        if hash != piece_hash {
             bail!("piece hash does not match hash from torrent");
         }
 
@@ -98,7 +118,6 @@ struct PieceBlockRequest {
 }
 
 struct PieceBlockResponse {
-    index: u32,
     begin: u32,
     block: Bytes,
 }
@@ -123,8 +142,8 @@ fn generate_piece_block_requests(
 }
 
 impl PieceBlockRequest {
-    pub(super) fn to_message(&self) -> PeerMessage {
-        PeerMessage::Request {
+    fn to_command(&self) -> PeerCommand {
+        PeerCommand::RequestBlock {
             index: self.index,
             begin: self.begin,
             length: self.length,
@@ -133,11 +152,8 @@ impl PieceBlockRequest {
 }
 
 fn check_block_validity(req: &PieceBlockRequest, res: &PieceBlockResponse) -> Result<()> {
-    if res.index != req.index {
-        bail!("received block piece index does not match requested index");
-    }
-    if res.begin != req.begin {
-        bail!("received block piece offset does not match requested offset");
+    if res.block.len() != req.length as usize {
+        bail!("received block length does not match requested length");
     }
     Ok(())
 }
@@ -0,0 +1,230 @@
+use std::collections::HashSet;
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use tokio::sync::mpsc;
+
+use super::message::PeerMessage;
+
+/// Requests of a peer kept at most, further ones are dropped until some are answered.
+const MAX_QUEUED_REQUESTS: usize = 250;
+
+/// Block a peer asked us for, answered by the owner of the torrent content with
+/// [`UploadRequest::respond`], [`UploadRequest::decline`] or [`UploadRequest::reject`].
+#[derive(Debug)]
+pub struct UploadRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+    replies: mpsc::UnboundedSender<UploadReply>,
+}
+
+#[derive(Debug)]
+pub(crate) struct UploadReply {
+    index: u32,
+    begin: u32,
+    length: u32,
+    outcome: UploadOutcome,
+}
+
+#[derive(Debug)]
+enum UploadOutcome {
+    Block(Bytes),
+    Declined,
+    Rejected(String),
+}
+
+impl UploadRequest {
+    /// Sends the block to the peer, unless it cancelled the request or was choked meanwhile.
+    pub fn respond(self, block: Bytes) {
+        self.reply(UploadOutcome::Block(block));
+    }
+
+    /// Leaves the request unanswered, as for pieces we do not have.
+    pub fn decline(self) {
+        self.reply(UploadOutcome::Declined);
+    }
+
+    /// Disconnects the peer for asking for a block that cannot exist.
+    pub fn reject(self, reason: String) {
+        self.reply(UploadOutcome::Rejected(reason));
+    }
+
+    fn reply(self, outcome: UploadOutcome) {
+        // The connection may be closed by now, the reply is not needed anymore then.
+        let _ = self.replies.send(UploadReply {
+            index: self.index,
+            begin: self.begin,
+            length: self.length,
+            outcome,
+        });
+    }
+}
+
+#[cfg(test)]
+impl UploadRequest {
+    /// Request whose reply is sent to the returned receiver, for testing how it is answered.
+    pub(crate) fn with_replies(
+        index: u32,
+        begin: u32,
+        length: u32,
+    ) -> (Self, mpsc::UnboundedReceiver<UploadReply>) {
+        let (replies, replies_rx) = mpsc::unbounded_channel();
+        let request = Self {
+            index,
+            begin,
+            length,
+            replies,
+        };
+        (request, replies_rx)
+    }
+}
+
+#[cfg(test)]
+impl UploadReply {
+    /// The block sent, `None` when the request was declined or the reason it was rejected for.
+    pub(crate) fn outcome(self) -> Result<Option<Bytes>, String> {
+        match self.outcome {
+            UploadOutcome::Block(block) => Ok(Some(block)),
+            UploadOutcome::Declined => Ok(None),
+            UploadOutcome::Rejected(reason) => Err(reason),
+        }
+    }
+}
+
+/// Upload side of a connection: whether the peer may download from us, and which of its
+/// requests are still to be answered.
+pub(super) struct Uploads {
+    requests: mpsc::UnboundedSender<UploadRequest>,
+    replies_tx: mpsc::UnboundedSender<UploadReply>,
+    replies_rx: mpsc::UnboundedReceiver<UploadReply>,
+    piece_count: u32,
+    /// Index, offset and length of the requests forwarded but not answered yet.
+    pending: HashSet<(u32, u32, u32)>,
+    choking: bool,
+}
+
+impl Uploads {
+    /// Forwards the requests of the peer to `requests`. Peers are unchoked as soon as they are
+    /// interested.
+    pub(super) fn new(requests: mpsc::UnboundedSender<UploadRequest>, piece_count: u32) -> Self {
+        let (replies_tx, replies_rx) = mpsc::unbounded_channel();
+        Self {
+            requests,
+            replies_tx,
+            replies_rx,
+            piece_count,
+            pending: HashSet::new(),
+            choking: true,
+        }
+    }
+
+    /// Waits for the next reply to a request.
+    pub(super) async fn next_reply(&mut self) -> UploadReply {
+        self.replies_rx
+            .recv()
+            .await
+            .expect("reply sender should be kept alive by the uploads")
+    }
+
+    /// Turns a reply into the piece message to send, if the peer still wants it. Fails when the
+    /// peer is to be disconnected.
+    pub(super) fn take_reply(&mut self, reply: UploadReply) -> Result<Option<PeerMessage>> {
+        let UploadReply {
+            index,
+            begin,
+            length,
+            outcome,
+        } = reply;
+        if !self.pending.remove(&(index, begin, length)) {
+            tracing::trace!(
+                index,
+                begin,
+                "Dropping reply to request that is not wanted anymore"
+            );
+            return Ok(None);
+        }
+        match outcome {
+            UploadOutcome::Block(block) => Ok(Some(PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            })),
+            UploadOutcome::Declined => Ok(None),
+            UploadOutcome::Rejected(reason) => bail!("peer requested invalid block: {reason}"),
+        }
+    }
+
+    /// Updates the upload state on a message of the peer, returning the message to answer with.
+    /// Fails on requests for pieces the torrent does not have.
+    pub(super) fn handle(&mut self, message: &PeerMessage) -> Result<Option<PeerMessage>> {
+        match *message {
+            PeerMessage::Interested if self.choking => {
+                self.set_choking(false);
+                return Ok(Some(PeerMessage::Unchoke));
+            }
+            PeerMessage::NotInterested if !self.choking => {
+                self.set_choking(true);
+                return Ok(Some(PeerMessage::Choke));
+            }
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } => self.request(index, begin, length)?,
+            PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                self.pending.remove(&(index, begin, length));
+            }
+            _ => (),
+        }
+        Ok(None)
+    }
+
+    /// Stops or resumes answering requests. Requests of a choked peer are dropped.
+    pub(super) fn set_choking(&mut self, choking: bool) {
+        self.choking = choking;
+        if choking {
+            self.pending.clear();
+        }
+    }
+
+    fn request(&mut self, index: u32, begin: u32, length: u32) -> Result<()> {
+        if index >= self.piece_count {
+            bail!(
+                "peer requested piece {index} of a torrent with {} pieces",
+                self.piece_count
+            );
+        }
+        if self.choking {
+            tracing::trace!(index, begin, "Dropping request of choked peer");
+            return Ok(());
+        }
+        if self.pending.len() >= MAX_QUEUED_REQUESTS {
+            tracing::trace!(
+                index,
+                begin,
+                length,
+                "Dropping request beyond the queue limit"
+            );
+            return Ok(());
+        }
+        if !self.pending.insert((index, begin, length)) {
+            return Ok(());
+        }
+        let request = UploadRequest {
+            index,
+            begin,
+            length,
+            replies: self.replies_tx.clone(),
+        };
+        if self.requests.send(request).is_err() {
+            // Nobody serves uploads anymore.
+            self.pending.remove(&(index, begin, length));
+        }
+        Ok(())
+    }
+}
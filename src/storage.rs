@@ -0,0 +1,382 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use bstr::{BStr, BString, ByteSlice};
+
+use crate::torrent::{FileEntry, TorrentInfo};
+
+/// How to handle file paths of a torrent that are unsafe to create on disk, such as `..` or names
+/// reserved on Windows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PathPolicy {
+    /// Refuse to create any file of the torrent.
+    #[default]
+    Reject,
+    /// Replace offending characters and names, keeping every file below the root.
+    Sanitize,
+}
+
+/// Writes the content of a torrent to its files on disk and reads it back, as if they were one
+/// contiguous stream. Single file torrents are written to the location itself, multi-file
+/// torrents below it.
+pub struct Storage {
+    files: Vec<StorageFile>,
+    length: u64,
+    position: u64,
+}
+
+struct StorageFile {
+    entry: FileEntry,
+    path: PathBuf,
+    /// Absent for files without content on disk, such as padding files and symlinks.
+    handle: Option<File>,
+}
+
+impl Storage {
+    /// Creates the files of the torrent below `root`, sized to their final length. Empty files
+    /// are created as well, but no write ever lands in them.
+    pub fn create(root: &Path, info: &TorrentInfo, policy: PathPolicy) -> Result<Self> {
+        let mut files = Vec::new();
+        for entry in sanitize_entries(info, policy)? {
+            let path = entry
+                .path
+                .iter()
+                .fold(root.to_path_buf(), |path, component| {
+                    path.join(component.to_path_lossy())
+                });
+
+            let handle = if entry.is_padding() || entry.is_symlink() {
+                None
+            } else {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)
+                        .with_context(|| format!("creating directory `{}`", parent.display()))?;
+                }
+                // Read back to serve pieces to other peers.
+                let file = File::options()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&path)
+                    .with_context(|| format!("creating file `{}`", path.display()))?;
+                file.set_len(entry.length)
+                    .with_context(|| format!("allocating file `{}`", path.display()))?;
+                Some(file)
+            };
+
+            files.push(StorageFile {
+                entry,
+                path,
+                handle,
+            });
+        }
+
+        Ok(Self {
+            files,
+            length: info.total_length(),
+            position: 0,
+        })
+    }
+
+    /// Applies the file attributes once the content is complete, creating symlinks and marking
+    /// executables.
+    pub fn finish(mut self) -> Result<()> {
+        self.flush().context("flushing torrent files")?;
+
+        for file in &self.files {
+            if file.entry.is_symlink() {
+                let target =
+                    file.entry.symlink_path.as_ref().with_context(|| {
+                        format!("symlink `{}` has no target", file.path.display())
+                    })?;
+                create_symlink(file, target)?;
+            } else if file.entry.is_executable() {
+                set_executable(&file.path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks the paths of all files before any is created, so a rejected torrent leaves nothing
+/// behind.
+fn sanitize_entries(info: &TorrentInfo, policy: PathPolicy) -> Result<Vec<FileEntry>> {
+    let mut entries = Vec::new();
+    let mut seen = HashSet::new();
+    for mut entry in info.files() {
+        entry.path = sanitize_path(&entry.path, policy)?;
+        if let Some(target) = &entry.symlink_path {
+            entry.symlink_path = Some(sanitize_path(target, policy)?);
+        }
+        // Padding files are never created, so they commonly share a name.
+        if !entry.is_padding() && !seen.insert(entry.path.clone()) {
+            bail!(
+                "duplicate file path `{}` in torrent",
+                BString::from(entry.path.join(&b'/'))
+            );
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Checks the components of a torrent file path, so joining them to the root never leaves it.
+fn sanitize_path(path: &[BString], policy: PathPolicy) -> Result<Vec<BString>> {
+    path.iter()
+        .map(
+            |component| match (component_problem(component.as_bstr()), policy) {
+                (None, _) => Ok(component.clone()),
+                (Some(problem), PathPolicy::Reject) => bail!(
+                    "unsafe file path `{}` in torrent: {problem}",
+                    BString::from(path.join(&b'/'))
+                ),
+                (Some(_), PathPolicy::Sanitize) => Ok(sanitize_component(component.as_bstr())),
+            },
+        )
+        .collect()
+}
+
+/// Names Windows reserves for devices, with or without extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+fn is_reserved_name(component: &BStr) -> bool {
+    let stem = component.split_str(".").next().unwrap_or_default();
+    RESERVED_NAMES
+        .iter()
+        .any(|name| stem.eq_ignore_ascii_case(name.as_bytes()))
+}
+
+fn is_forbidden_byte(byte: u8) -> bool {
+    // Separators, the drive and stream delimiter of Windows, and the nul terminator.
+    matches!(byte, b'/' | b'\\' | b':' | 0)
+}
+
+/// Why the component of a file path is unsafe to create on disk, if it is. Shared with the
+/// validator, so it flags every path that downloading rejects.
+pub fn component_problem(component: &BStr) -> Option<&'static str> {
+    if component.is_empty() {
+        Some("path component is empty")
+    } else if component == "." || component == ".." {
+        Some("path traversal through `.` or `..`")
+    } else if component.iter().any(|&byte| is_forbidden_byte(byte)) {
+        Some("path component contains a separator, drive delimiter or nul byte")
+    } else if is_reserved_name(component) {
+        Some("path component is a name reserved on windows")
+    } else {
+        None
+    }
+}
+
+fn sanitize_component(component: &BStr) -> BString {
+    if component.is_empty() || component == "." || component == ".." {
+        return BString::from("_");
+    }
+    let mut sanitized = component
+        .iter()
+        .map(|&byte| if is_forbidden_byte(byte) { b'_' } else { byte })
+        .collect::<BString>();
+    if is_reserved_name(sanitized.as_bstr()) {
+        sanitized.insert(0, b'_');
+    }
+    sanitized
+}
+
+/// Links to the target relative to the directory of the link, keeping the content relocatable.
+#[cfg(unix)]
+fn create_symlink(file: &StorageFile, target: &[BString]) -> Result<()> {
+    let depth = file.entry.path.len().saturating_sub(1);
+    let target = std::iter::repeat(PathBuf::from(".."))
+        .take(depth)
+        .chain(target.iter().map(|c| c.to_path_lossy().into_owned()))
+        .collect::<PathBuf>();
+
+    if let Some(parent) = file.path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("creating directory `{}`", parent.display()))?;
+    }
+    std::os::unix::fs::symlink(target, &file.path)
+        .with_context(|| format!("creating symlink `{}`", file.path.display()))
+}
+
+#[cfg(not(unix))]
+fn create_symlink(file: &StorageFile, _target: &[BString]) -> Result<()> {
+    tracing::warn!(path = %file.path.display(), "Symlinks are not supported on this platform");
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = std::fs::metadata(path)
+        .with_context(|| format!("reading permissions of `{}`", path.display()))?
+        .permissions();
+    // Grant execution to everyone allowed to read the file.
+    permissions.set_mode(permissions.mode() | (permissions.mode() & 0o444) >> 2);
+    std::fs::set_permissions(path, permissions)
+        .with_context(|| format!("marking `{}` executable", path.display()))
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+impl Write for Storage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let position = self.position;
+        let Some(file) = self
+            .files
+            .iter_mut()
+            .find(|file| file.entry.range().contains(&position))
+        else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "writing past the end of the torrent content",
+            ));
+        };
+
+        let len = buf.len().min((file.entry.range().end - position) as usize);
+        // Bytes of files without content on disk are dropped.
+        if let Some(handle) = &mut file.handle {
+            handle.seek(SeekFrom::Start(position - file.entry.offset))?;
+            handle.write_all(&buf[..len])?;
+        }
+
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for handle in self
+            .files
+            .iter_mut()
+            .filter_map(|file| file.handle.as_mut())
+        {
+            handle.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for Storage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let position = self.position;
+        let Some(file) = self
+            .files
+            .iter_mut()
+            .find(|file| file.entry.range().contains(&position))
+        else {
+            // Past the end of the torrent content.
+            return Ok(0);
+        };
+
+        let len = buf.len().min((file.entry.range().end - position) as usize);
+        match &mut file.handle {
+            Some(handle) => {
+                handle.seek(SeekFrom::Start(position - file.entry.offset))?;
+                handle.read_exact(&mut buf[..len])?;
+            }
+            // Files without content on disk read as zeros, like the padding they are.
+            None => buf[..len].fill(0),
+        }
+
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for Storage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.length.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seeking before the start")
+        })?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::torrent::Torrent;
+
+    use super::*;
+
+    const UNSAFE: &[(&[u8], &str)] = &[
+        (b"..", "_"),
+        (b".", "_"),
+        (b"", "_"),
+        (b"a/b", "a_b"),
+        (b"a\\b", "a_b"),
+        (b"a\0b", "a_b"),
+        (b"c:b", "c_b"),
+        (b"CON.txt", "_CON.txt"),
+        (b"lpt1", "_lpt1"),
+    ];
+
+    fn path(components: &[&[u8]]) -> Vec<BString> {
+        components.iter().copied().map(BString::from).collect()
+    }
+
+    #[test]
+    fn reject_unsafe_components() {
+        for (component, _) in UNSAFE {
+            let unsafe_path = path(&[b"dir", component]);
+            assert!(
+                sanitize_path(&unsafe_path, PathPolicy::Reject).is_err(),
+                "{unsafe_path:?} was accepted"
+            );
+        }
+
+        let safe = path(&[b"dir", b"CONSOLE.txt", b".hidden"]);
+        assert_eq!(sanitize_path(&safe, PathPolicy::Reject).unwrap(), safe);
+    }
+
+    #[test]
+    fn sanitize_unsafe_components() {
+        for (component, sanitized) in UNSAFE {
+            assert_eq!(
+                sanitize_path(&path(&[b"dir", component]), PathPolicy::Sanitize).unwrap(),
+                path(&[b"dir", sanitized.as_bytes()])
+            );
+        }
+    }
+
+    #[test]
+    fn reject_duplicate_paths() {
+        let torrent = Torrent::from_bytes(
+            b"d8:announce0:4:infod5:filesld6:lengthi1e4:pathl1:aeed6:lengthi1e4:pathl1:aeee\
+            4:name4:root12:piece lengthi2e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+        )
+        .unwrap();
+        for policy in [PathPolicy::Reject, PathPolicy::Sanitize] {
+            assert!(sanitize_entries(&torrent.info, policy).is_err());
+        }
+
+        let padded = Torrent::from_bytes(
+            b"d8:announce0:4:infod5:filesld6:lengthi1e4:pathl1:aeed4:attr1:p6:lengthi1e4:pathl4:\
+            .pad1:1eed6:lengthi1e4:pathl1:beed4:attr1:p6:lengthi1e4:pathl4:.pad1:1eee\
+            4:name4:root12:piece lengthi2e6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbee",
+        )
+        .unwrap();
+        assert_eq!(
+            sanitize_entries(&padded.info, PathPolicy::Reject)
+                .unwrap()
+                .len(),
+            4
+        );
+    }
+}
@@ -1,113 +1,1353 @@
-use std::path::Path;
+use std::{
+    collections::BTreeMap,
+    io::Read,
+    ops::Range,
+    path::{Path, PathBuf},
+};
 
-use anyhow::{Context, Result};
-use bencode::BencodeValue;
+use anyhow::{bail, ensure, Context, Result};
+use bencode::{BencodeDict, BencodeValue, SpannedValue};
 use bstr::BString;
+use bytes::{Bytes, BytesMut};
 use serde::{Deserialize, Serialize};
-use serde_with::serde_as;
+use serde_json::json;
+use serde_with::{formats::PreferMany, serde_as, OneOrMany};
+use sha2::{Digest, Sha256};
 
-use crate::util::{hash_sha1, serde_with::ArrayChunksWithLength, Sha1Hash};
+use crate::{
+    tracker::TrackerClient,
+    util::{hash_sha1, serde_with::ArrayChunksWithLength, InfoHash, Sha1Hash, Sha256Hash},
+};
+
+/// Largest .torrent file downloaded from a url, room for the biggest info dictionaries fetched
+/// from peers plus the piece layers of a v2 torrent.
+const MAX_TORRENT_FILE_SIZE: usize = 32 << 20;
+
+/// Size of the blocks forming the leaves of v2 Merkle trees.
+const MERKLE_BLOCK_SIZE: usize = 16 * 1024;
 
 #[derive(Debug)]
 pub struct Torrent {
+    /// Tracker url, empty for trackerless torrents relying on the DHT.
     pub announce: String,
+    /// Tiers of tracker urls from `announce-list` (BEP 12), empty tiers left out.
+    pub announce_list: Vec<Vec<String>>,
+    /// Web seed urls from `url-list` (BEP 19).
+    pub url_list: Vec<String>,
+    /// Creation time in seconds since the unix epoch.
+    pub creation_date: Option<i64>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    /// Character encoding of the strings in the info dict.
+    pub encoding: Option<String>,
     pub info: TorrentInfo,
-    pub info_hash: Sha1Hash,
+    pub version: MetaVersion,
+    /// Hash identifying the torrent towards trackers and peers. For v2-only torrents this is the
+    /// truncated v2 info hash.
+    pub info_hash: InfoHash,
+    /// Full SHA-256 info hash of v2 and hybrid torrents.
+    pub info_hash_v2: Option<Sha256Hash>,
+    /// Merkle trees of the non-empty v2 files, checked against the `piece layers`.
+    pub file_trees: Vec<FileMerkleTree>,
+    /// Top-level keys not covered above, kept so the torrent can be written back faithfully.
+    pub extra: BTreeMap<BString, BencodeValue>,
+    /// Info dict exactly as read, written back unchanged to keep the info hash.
+    raw_info: Vec<u8>,
+}
+
+/// Reason a torrent could not be loaded, for callers that tell the user or pick an exit code
+/// depending on what is wrong.
+#[derive(Debug, thiserror::Error)]
+pub enum TorrentError {
+    #[error("torrent contents are not valid bencode")]
+    Decode(#[source] bencode::ParseError),
+    /// A required key is absent, given as path such as `info.name`.
+    #[error("torrent has no `{0}` field")]
+    MissingField(&'static str),
+    #[error("torrent contents do not match torrent specifications")]
+    Malformed(#[source] bencode::Error),
+    /// Zero, or not a power of two of at least 16 KiB for v2 metadata.
+    #[error("torrent piece length {0} is invalid")]
+    InvalidPieceLength(u32),
+    #[error("torrent info dict is incomplete")]
+    IncompleteInfo(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("torrent piece layers are invalid")]
+    InvalidPieceLayers(#[source] Box<dyn std::error::Error + Send + Sync>),
+    /// File lengths that overflow when summed, or v1 piece hashes not covering the content.
+    #[error("torrent content length is invalid: {0}")]
+    InvalidLength(String),
+}
+
+/// Layout of the metadata in the info dict.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetaVersion {
+    V1,
+    V2,
+    /// Both v1 and v2 metadata describing the same content (BEP 52).
+    Hybrid,
 }
 
 #[serde_as]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TorrentInfo {
-    pub length: u64,
     pub name: BString,
     #[serde(rename = "piece length")]
     pub piece_length: u32,
-    #[serde_as(as = "ArrayChunksWithLength<20>")]
-    pub pieces: Vec<Sha1Hash>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub length: Option<u64>,
+    /// File attribute flags of a single file torrent (BEP 47).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<BString>,
+    /// Files of a v1 multi-file torrent, in place of `length`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<InfoFile>>,
+    #[serde_as(as = "Option<ArrayChunksWithLength<20>>")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pieces: Option<Vec<Sha1Hash>>,
+    /// Set to 1 to restrict peer discovery to the trackers of the torrent (BEP 27).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<i64>,
+    /// Tag making the info hash unique to a tracker, for cross-seeding the same content.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// Info hashes of torrents sharing files with this one (BEP 38).
+    #[serde_as(as = "Option<Vec<serde_with::Bytes>>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub similar: Option<Vec<Sha1Hash>>,
+    /// Names of collections this torrent belongs to, whose torrents likely share files (BEP 38).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collections: Option<Vec<String>>,
+    #[serde(
+        rename = "meta version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub meta_version: Option<u64>,
+    #[serde(rename = "file tree", default, skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<BTreeMap<BString, FileTreeNode>>,
+    /// Keys not covered above, kept so the info dict can be re-encoded faithfully.
+    #[serde(flatten)]
+    pub extra: BTreeMap<BString, BencodeValue>,
+}
+
+/// Entry of the v1 `files` list.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InfoFile {
+    pub length: u64,
+    /// Path components relative to the torrent name.
+    pub path: Vec<BString>,
+    /// File attribute flags (BEP 47).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<BString>,
+    /// Target of a symlink as path components relative to the torrent name.
+    #[serde(
+        rename = "symlink path",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub symlink_path: Option<Vec<BString>>,
 }
 
-#[derive(Debug, Clone, Copy)]
+/// File of a torrent, mapped to its byte range in the torrent content.
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    /// Path components below the torrent name, empty for single file torrents.
+    pub path: Vec<BString>,
+    pub length: u64,
+    /// Offset of the first byte of the file in the concatenated content.
+    pub offset: u64,
+    /// File attribute flags (BEP 47), see [`FileEntry::is_executable`] and friends.
+    pub attr: Option<BString>,
+    /// Target of a symlink as path components relative to the torrent name.
+    pub symlink_path: Option<Vec<BString>>,
+}
+
+impl FileEntry {
+    /// Bytes of the torrent content belonging to the file.
+    pub fn range(&self) -> Range<u64> {
+        self.offset..self.offset + self.length
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.has_attr(b'x')
+    }
+
+    /// Symlinks have no content, their target is in [`FileEntry::symlink_path`].
+    pub fn is_symlink(&self) -> bool {
+        self.has_attr(b'l')
+    }
+
+    /// Padding files align the next file to a piece boundary and are not written to disk.
+    pub fn is_padding(&self) -> bool {
+        self.has_attr(b'p')
+    }
+
+    fn has_attr(&self, flag: u8) -> bool {
+        self.attr.as_ref().is_some_and(|attr| attr.contains(&flag))
+    }
+}
+
+/// Entry of a v2 `file tree`. Files are dicts with a single empty key holding their attributes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FileTreeNode {
+    File {
+        #[serde(rename = "")]
+        attributes: FileAttributes,
+    },
+    Directory(BTreeMap<BString, FileTreeNode>),
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileAttributes {
+    pub length: u64,
+    /// Root of the file's Merkle tree, absent for empty files.
+    #[serde_as(as = "Option<serde_with::Bytes>")]
+    #[serde(rename = "pieces root", skip_serializing_if = "Option::is_none")]
+    pub pieces_root: Option<Sha256Hash>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<BString>,
+    #[serde(
+        rename = "symlink path",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub symlink_path: Option<Vec<BString>>,
+}
+
+/// Downloads the raw contents of a .torrent file over http(s) with the tracker client, honouring
+/// its proxy, local bind and timeouts. Files larger than `MAX_TORRENT_FILE_SIZE` are rejected.
+pub async fn fetch_torrent_file(client: &TrackerClient, url: &str) -> Result<Bytes> {
+    let mut response = client
+        .request(url)
+        .send()
+        .await
+        .with_context(|| format!("requesting torrent file from `{url}`"))?
+        .error_for_status()
+        .context("torrent file request failed")?;
+
+    let mut contents = BytesMut::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .context("reading torrent file response")?
+    {
+        ensure!(
+            contents.len() + chunk.len() <= MAX_TORRENT_FILE_SIZE,
+            "torrent file is larger than {MAX_TORRENT_FILE_SIZE} bytes"
+        );
+        contents.extend_from_slice(&chunk);
+    }
+    Ok(contents.freeze())
+}
+
+/// Merkle tree of a v2 file, kept down to the piece layer for verifying downloaded pieces.
+#[derive(Debug, Clone)]
+pub struct FileMerkleTree {
+    pub length: u64,
+    pub pieces_root: Sha256Hash,
+    /// Hashes of the piece sized subtrees. Files of at most one piece have no piece layer, nor
+    /// do files of torrents loaded without `piece layers` (e.g. fetched from peers).
+    pub piece_layer: Option<Vec<Sha256Hash>>,
+    piece_length: u32,
+}
+
+#[derive(Debug, Clone)]
 pub struct TorrentOverview<'a> {
+    name: &'a BString,
     tracker_url: &'a str,
+    announce_list: &'a [Vec<String>],
+    url_list: &'a [String],
+    private: bool,
+    source: Option<&'a str>,
+    similar: &'a [Sha1Hash],
+    collections: &'a [String],
+    creation_date: Option<i64>,
+    comment: Option<&'a str>,
+    created_by: Option<&'a str>,
+    encoding: Option<&'a str>,
     length: usize,
     info_hash: &'a Sha1Hash,
+    info_hash_v2: Option<&'a Sha256Hash>,
+    piece_layers: usize,
     piece_length: usize,
+    piece_count: usize,
     pieces: &'a [Sha1Hash],
+    files: Vec<FileEntry>,
+}
+
+impl TorrentInfo {
+    /// Determines which metadata the info dict carries, checking that it is complete.
+    pub fn version(&self) -> Result<MetaVersion> {
+        let v1 = (self.length.is_some() || self.files.is_some()) && self.pieces.is_some();
+        let v2 = match (self.meta_version, &self.file_tree) {
+            (Some(2), Some(_)) => true,
+            (Some(2), None) => bail!("v2 info dict does not contain a file tree"),
+            (Some(version), _) => bail!("unsupported meta version {version}"),
+            (None, Some(_)) => bail!("info dict contains a file tree but no meta version"),
+            (None, None) => false,
+        };
+
+        Ok(match (v1, v2) {
+            (true, false) => MetaVersion::V1,
+            (false, true) => MetaVersion::V2,
+            (true, true) => MetaVersion::Hybrid,
+            (false, false) => bail!("info dict contains neither v1 nor v2 metadata"),
+        })
+    }
+
+    /// Total size of the content in bytes.
+    pub fn total_length(&self) -> u64 {
+        fn tree_length<'a>(nodes: impl IntoIterator<Item = &'a FileTreeNode>) -> u64 {
+            nodes
+                .into_iter()
+                .map(|node| match node {
+                    FileTreeNode::File { attributes } => attributes.length,
+                    FileTreeNode::Directory(children) => tree_length(children.values()),
+                })
+                .sum()
+        }
+
+        match (self.length, &self.files, &self.file_tree) {
+            (Some(length), _, _) => length,
+            (None, Some(files), _) => files.iter().map(|file| file.length).sum(),
+            (None, None, Some(tree)) => tree_length(tree.values()),
+            (None, None, None) => 0,
+        }
+    }
+
+    /// Checks that the file lengths sum up without overflowing, so [`TorrentInfo::total_length`]
+    /// and the offsets of [`TorrentInfo::files`] cannot overflow either. Returns the total.
+    fn checked_total_length(&self) -> Option<u64> {
+        fn checked_sum(lengths: impl IntoIterator<Item = Option<u64>>) -> Option<u64> {
+            lengths
+                .into_iter()
+                .try_fold(0u64, |total, length| total.checked_add(length?))
+        }
+        fn tree_length<'a>(nodes: impl IntoIterator<Item = &'a FileTreeNode>) -> Option<u64> {
+            checked_sum(nodes.into_iter().map(|node| match node {
+                FileTreeNode::File { attributes } => Some(attributes.length),
+                FileTreeNode::Directory(children) => tree_length(children.values()),
+            }))
+        }
+
+        // Hybrid torrents describe the content twice, both must be summable.
+        let tree_total = match &self.file_tree {
+            Some(tree) => Some(tree_length(tree.values())?),
+            None => None,
+        };
+        match (self.length, &self.files) {
+            (Some(length), _) => Some(length),
+            (None, Some(files)) => checked_sum(files.iter().map(|file| Some(file.length))),
+            (None, None) => Some(tree_total.unwrap_or(0)),
+        }
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    /// Files of the torrent in content order, each placed right after the previous one.
+    pub fn files(&self) -> impl Iterator<Item = FileEntry> {
+        fn tree_files(
+            tree: &BTreeMap<BString, FileTreeNode>,
+            path: &mut Vec<BString>,
+            files: &mut Vec<InfoFile>,
+        ) {
+            for (name, node) in tree {
+                path.push(name.clone());
+                match node {
+                    FileTreeNode::File { attributes } => files.push(InfoFile {
+                        length: attributes.length,
+                        path: path.clone(),
+                        attr: attributes.attr.clone(),
+                        symlink_path: attributes.symlink_path.clone(),
+                    }),
+                    FileTreeNode::Directory(children) => tree_files(children, path, files),
+                }
+                path.pop();
+            }
+        }
+
+        let files = match (self.length, &self.files, &self.file_tree) {
+            (Some(length), _, _) => vec![InfoFile {
+                length,
+                path: Vec::new(),
+                attr: self.attr.clone(),
+                symlink_path: None,
+            }],
+            (None, Some(files), _) => files.clone(),
+            (None, None, Some(tree)) => {
+                let mut files = Vec::new();
+                tree_files(tree, &mut Vec::new(), &mut files);
+                files
+            }
+            (None, None, None) => Vec::new(),
+        };
+
+        files.into_iter().scan(0, |offset, file| {
+            let entry = FileEntry {
+                path: file.path,
+                length: file.length,
+                offset: *offset,
+                attr: file.attr,
+                symlink_path: file.symlink_path,
+            };
+            *offset += file.length;
+            Some(entry)
+        })
+    }
+
+    /// SHA-1 piece hashes of the v1 metadata.
+    pub fn v1_pieces(&self) -> &[Sha1Hash] {
+        self.pieces.as_deref().unwrap_or_default()
+    }
 }
 
 impl Torrent {
     pub fn from_file_path(path: impl AsRef<Path>) -> Result<Self> {
+        fn read_file(path: impl AsRef<Path>) -> Result<Vec<u8>> {
+            let mut file = std::fs::File::open(&path)
+                .with_context(|| format!("opening torrent file from path `{:?}`", path.as_ref()))?;
+
+            let mut content_buf = match file.metadata() {
+                Ok(m) => Vec::with_capacity(m.len() as usize),
+                _ => Vec::new(),
+            };
+            file.read_to_end(&mut content_buf)
+                .context("reading contents of torrent file")?;
+            Ok(content_buf)
+        }
+
+        let contents = read_file(path)?;
+        Ok(Self::from_bytes(&contents)?)
+    }
+
+    /// Downloads a .torrent file over http(s).
+    pub async fn from_url(client: &TrackerClient, url: &str) -> Result<Self> {
+        Ok(Self::from_bytes(&fetch_torrent_file(client, url).await?)?)
+    }
+
+    /// Reads a torrent from the contents of a .torrent file.
+    pub fn from_bytes(contents: &[u8]) -> Result<Self, TorrentError> {
+        let parsed_contents =
+            SpannedValue::try_from_bytes(contents).map_err(TorrentError::Decode)?;
+        // The info hash covers the info dict exactly as stored, whatever its key order or contents.
+        let raw_info = parsed_contents
+            .get("info")
+            .ok_or(TorrentError::MissingField("info"))?
+            .raw(contents);
+        Self::from_value(&parsed_contents.to_value_ref().to_owned(), raw_info)
+    }
+
+    /// Builds a torrent from an info dict fetched from peers, e.g. for a magnet link.
+    pub fn from_metadata(announce: String, info: &[u8]) -> Result<Self, TorrentError> {
+        let parsed_info = BencodeValue::try_from_bytes(info).map_err(TorrentError::Decode)?;
+        let contents = BencodeDict::new()
+            .with("announce", announce)
+            .with("info", parsed_info)
+            .build();
+        Self::from_value(&contents, info)
+    }
+
+    fn from_value(contents: &BencodeValue, raw_info: &[u8]) -> Result<Self, TorrentError> {
+        #[serde_as]
         #[derive(Debug, Deserialize)]
         struct TorrentFile {
+            #[serde(default)]
             pub announce: String,
+            #[serde(rename = "announce-list", default)]
+            pub announce_list: Vec<Vec<String>>,
+            #[serde_as(as = "OneOrMany<_, PreferMany>")]
+            #[serde(rename = "url-list", default)]
+            pub url_list: Vec<String>,
+            #[serde(rename = "creation date")]
+            pub creation_date: Option<i64>,
+            pub comment: Option<String>,
+            #[serde(rename = "created by")]
+            pub created_by: Option<String>,
+            pub encoding: Option<String>,
             pub info: TorrentInfo,
+            #[serde_as(as = "BTreeMap<serde_with::Bytes, ArrayChunksWithLength<32>>")]
+            #[serde(rename = "piece layers", default)]
+            pub piece_layers: BTreeMap<Sha256Hash, Vec<Sha256Hash>>,
+            #[serde(flatten)]
+            pub extra: BTreeMap<BString, BencodeValue>,
         }
 
-        impl TorrentFile {
-            fn from_file_path(path: impl AsRef<Path>) -> Result<Self> {
-                use std::io::Read;
-
-                let mut file = std::fs::File::open(&path).with_context(|| {
-                    format!("opening torrent file from path `{:?}`", path.as_ref())
-                })?;
-
-                let contents = {
-                    let mut content_buf = match file.metadata() {
-                        Ok(m) => Vec::with_capacity(m.len() as usize),
-                        _ => Vec::new(),
-                    };
-                    file.read_to_end(&mut content_buf)
-                        .context("reading contents of torrent file")?;
-                    content_buf
-                };
+        /// Hashes the raw bytes of the info dict, including keys [`TorrentInfo`] does not know
+        /// about.
+        fn torrent_info_hashes(
+            raw_info: &[u8],
+            version: MetaVersion,
+        ) -> (InfoHash, Option<Sha256Hash>) {
+            let v2: Option<Sha256Hash> =
+                (version != MetaVersion::V1).then(|| Sha256::digest(raw_info).into());
 
-                let parsed_contents =
-                    BencodeValue::try_from_bytes(&contents).context("decoding torrent contents")?;
+            match (version, v2) {
+                (MetaVersion::V2, Some(v2)) => (
+                    *v2.first_chunk()
+                        .expect("sha256 hash is longer than 20 bytes"),
+                    Some(v2),
+                ),
+                _ => (hash_sha1(raw_info), v2),
+            }
+        }
 
-                parsed_contents
-                    .into_deserialize()
-                    .context("torrent contents do not match torrent specifications")
+        // Named before deserializing, which would only report the first missing key by name.
+        for (field, value) in [
+            ("info", contents.get("info")),
+            (
+                "info.name",
+                contents.get("info").and_then(|info| info.get("name")),
+            ),
+            (
+                "info.piece length",
+                contents
+                    .get("info")
+                    .and_then(|info| info.get("piece length")),
+            ),
+        ] {
+            if value.is_none() {
+                return Err(TorrentError::MissingField(field));
             }
+        }
 
-            fn torrent_info_hash(&self) -> Result<Sha1Hash> {
-                let torrent_info_bencode_bytes = &*BencodeValue::from_serialize(&self.info)
-                    .context("serializing torrent info")?
-                    .to_byte_string()
-                    .context("serializing bencode value as bytes")?;
+        let file = TorrentFile::deserialize(contents).map_err(TorrentError::Malformed)?;
+        let version = file
+            .info
+            .version()
+            .map_err(|err| TorrentError::IncompleteInfo(err.into()))?;
 
-                Ok(hash_sha1(torrent_info_bencode_bytes))
-            }
+        let piece_length = file.info.piece_length;
+        let valid_v2_piece_length =
+            piece_length.is_power_of_two() && piece_length as usize >= MERKLE_BLOCK_SIZE;
+        if piece_length == 0 || (version != MetaVersion::V1 && !valid_v2_piece_length) {
+            return Err(TorrentError::InvalidPieceLength(piece_length));
         }
 
-        let file = TorrentFile::from_file_path(path)?;
+        let total_length = file
+            .info
+            .checked_total_length()
+            .ok_or_else(|| TorrentError::InvalidLength("file lengths overflow".to_owned()))?;
+        if let Some(pieces) = &file.info.pieces {
+            let expected = total_length.div_ceil(u64::from(piece_length));
+            if pieces.len() as u64 != expected {
+                return Err(TorrentError::InvalidLength(format!(
+                    "{} piece hashes for {total_length} bytes, expected {expected}",
+                    pieces.len()
+                )));
+            }
+        }
 
-        let info_hash = file
-            .torrent_info_hash()
-            .context("calculating torrent info hash")?;
+        let (info_hash, info_hash_v2) = torrent_info_hashes(raw_info, version);
+        let file_trees = match &file.info.file_tree {
+            Some(tree) => FileMerkleTree::from_file_tree(tree, piece_length, file.piece_layers)
+                .map_err(|err| TorrentError::InvalidPieceLayers(err.into()))?,
+            None => Vec::new(),
+        };
 
         Ok(Self {
             announce: file.announce,
+            announce_list: file
+                .announce_list
+                .into_iter()
+                .filter(|tier| !tier.is_empty())
+                .collect(),
+            url_list: file
+                .url_list
+                .into_iter()
+                .filter(|url| !url.is_empty())
+                .collect(),
+            creation_date: file.creation_date,
+            comment: file.comment,
+            created_by: file.created_by,
+            encoding: file.encoding,
+            file_trees,
             info: file.info,
+            version,
             info_hash,
+            info_hash_v2,
+            extra: file.extra,
+            raw_info: raw_info.to_vec(),
         })
     }
 
-    pub fn overview(&self) -> TorrentOverview {
+    /// Encodes the torrent as .torrent file. The info dict is written as read, so the info hash
+    /// stays the same.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let piece_layers = self
+            .file_trees
+            .iter()
+            .filter_map(|tree| {
+                let layer = tree.piece_layer.as_ref()?;
+                let hashes = layer.iter().flatten().copied().collect::<Vec<_>>();
+                Some((BString::from(&tree.pieces_root[..]), hashes.into()))
+            })
+            .collect::<BTreeMap<_, BencodeValue>>();
+
+        let contents = BencodeDict::from(self.extra.clone())
+            .with_opt(
+                "announce",
+                (!self.announce.is_empty()).then_some(self.announce.as_str()),
+            )
+            .with_opt(
+                "announce-list",
+                (!self.announce_list.is_empty())
+                    .then(|| BencodeValue::from_serialize(&self.announce_list))
+                    .transpose()
+                    .context("encoding announce-list")?,
+            )
+            .with_opt(
+                "url-list",
+                match self.url_list.as_slice() {
+                    [] => None,
+                    // A single web seed is commonly stored as plain string.
+                    [url] => Some(BencodeValue::from(url.as_str())),
+                    urls => Some(BencodeValue::from_serialize(urls).context("encoding url-list")?),
+                },
+            )
+            .with_opt("creation date", self.creation_date)
+            .with_opt("comment", self.comment.as_deref())
+            .with_opt("created by", self.created_by.as_deref())
+            .with_opt("encoding", self.encoding.as_deref())
+            .with_opt(
+                "piece layers",
+                (!piece_layers.is_empty()).then_some(piece_layers),
+            );
+        Ok(encode_with_info(&contents, &self.raw_info))
+    }
+
+    /// Magnet URI identifying the torrent by its info hashes, with its name, trackers and web
+    /// seeds. The v2 info hash is given as multihash (`btmh`), v2-only torrents have no `btih`.
+    pub fn to_magnet(&self) -> String {
+        // The urns are left unescaped, as other clients do.
+        let mut topics = Vec::new();
+        if self.version != MetaVersion::V2 {
+            topics.push(format!("xt=urn:btih:{}", hex::encode(self.info_hash)));
+        }
+        if let Some(info_hash_v2) = self.info_hash_v2 {
+            // Multihash prefix of a 32 byte SHA-256 digest.
+            topics.push(format!("xt=urn:btmh:1220{}", hex::encode(info_hash_v2)));
+        }
+
+        let prefix = "magnet:?";
+        let mut query = form_urlencoded::Serializer::for_suffix(
+            prefix.to_owned() + &topics.join("&"),
+            prefix.len(),
+        );
+        query.append_pair("dn", &self.info.name.to_string());
+        let mut trackers = Vec::new();
+        for tracker in self.announce_tiers().flatten() {
+            if !tracker.is_empty() && !trackers.contains(&tracker) {
+                trackers.push(tracker);
+            }
+        }
+        for tracker in trackers {
+            query.append_pair("tr", tracker);
+        }
+        for url in &self.url_list {
+            query.append_pair("ws", url);
+        }
+        query.finish()
+    }
+
+    pub fn write_to(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_bytes()?)
+            .with_context(|| format!("writing torrent file `{}`", path.display()))
+    }
+
+    /// Tracker tiers in order of preference. Torrents without an `announce-list` have a single
+    /// tier holding the `announce` url.
+    pub fn announce_tiers(&self) -> impl Iterator<Item = &[String]> {
+        let fallback = (self.announce_list.is_empty() && !self.announce.is_empty())
+            .then(|| std::slice::from_ref(&self.announce));
+        self.announce_list.iter().map(Vec::as_slice).chain(fallback)
+    }
+
+    /// Info hashes of the swarms sharing the torrent. Hybrid torrents are shared in a v1 and a v2
+    /// swarm, the latter identified by the truncated v2 info hash.
+    pub fn swarm_info_hashes(&self) -> Vec<InfoHash> {
+        let v2 = match (self.version, &self.info_hash_v2) {
+            (MetaVersion::Hybrid, Some(v2)) => v2.first_chunk().copied(),
+            _ => None,
+        };
+        std::iter::once(self.info_hash).chain(v2).collect()
+    }
+
+    /// Whether peers may only be obtained from the trackers, not from DHT, PEX or similar.
+    pub fn is_private(&self) -> bool {
+        self.info.is_private()
+    }
+
+    /// Total size of the content in bytes.
+    pub fn total_size(&self) -> u64 {
+        self.info.total_length()
+    }
+
+    /// Checks a piece of a v2 or hybrid torrent against the Merkle tree of the file it lies in.
+    /// v2 pieces do not span file boundaries, so the padding ending a hybrid piece is ignored.
+    pub fn verify_piece_v2(&self, index: u32, piece: &[u8]) -> Result<()> {
+        let piece_length = u64::from(self.info.piece_length);
+        let mut index = u64::from(index);
+        for tree in &self.file_trees {
+            let pieces = tree.length.div_ceil(piece_length);
+            if index < pieces {
+                let length = piece_length.min(tree.length - index * piece_length) as usize;
+                let data = piece
+                    .get(..length)
+                    .context("piece is shorter than the file data it covers")?;
+                return tree.verify_piece(index as u32, data);
+            }
+            index -= pieces;
+        }
+        bail!("piece index outside v2 files")
+    }
+
+    pub fn piece_count(&self) -> usize {
+        match &self.info.pieces {
+            Some(pieces) => pieces.len(),
+            // v2 pieces do not span file boundaries.
+            None => self
+                .files()
+                .map(|file| file.length.div_ceil(u64::from(self.info.piece_length)) as usize)
+                .sum(),
+        }
+    }
+
+    /// Files of the torrent with their byte range in the content.
+    pub fn files(&self) -> impl Iterator<Item = FileEntry> {
+        self.info.files()
+    }
+
+    pub fn overview(&self) -> TorrentOverview<'_> {
         TorrentOverview {
+            name: &self.info.name,
             tracker_url: self.announce.as_ref(),
-            length: self.info.length as usize,
+            announce_list: &self.announce_list,
+            url_list: &self.url_list,
+            private: self.is_private(),
+            source: self.info.source.as_deref(),
+            similar: self.info.similar.as_deref().unwrap_or_default(),
+            collections: self.info.collections.as_deref().unwrap_or_default(),
+            creation_date: self.creation_date,
+            comment: self.comment.as_deref(),
+            created_by: self.created_by.as_deref(),
+            encoding: self.encoding.as_deref(),
+            length: self.total_size() as usize,
             info_hash: &self.info_hash,
+            info_hash_v2: self.info_hash_v2.as_ref(),
+            piece_layers: self
+                .file_trees
+                .iter()
+                .filter(|tree| tree.piece_layer.is_some())
+                .count(),
             piece_length: self.info.piece_length as usize,
-            pieces: &self.info.pieces,
+            piece_count: self.piece_count(),
+            pieces: self.info.v1_pieces(),
+            files: self.files().collect(),
+        }
+    }
+}
+
+impl FileMerkleTree {
+    /// Collects the trees of the files in the tree, checking every piece layer against the
+    /// pieces root of its file.
+    fn from_file_tree(
+        tree: &BTreeMap<BString, FileTreeNode>,
+        piece_length: u32,
+        mut piece_layers: BTreeMap<Sha256Hash, Vec<Sha256Hash>>,
+    ) -> Result<Vec<Self>> {
+        fn collect_files<'a>(
+            tree: &'a BTreeMap<BString, FileTreeNode>,
+            path: &mut Vec<BString>,
+            files: &mut Vec<(Vec<BString>, &'a FileAttributes)>,
+        ) {
+            for (name, node) in tree {
+                path.push(name.clone());
+                match node {
+                    FileTreeNode::File { attributes } => files.push((path.clone(), attributes)),
+                    FileTreeNode::Directory(children) => collect_files(children, path, files),
+                }
+                path.pop();
+            }
         }
+
+        let mut files = Vec::new();
+        collect_files(tree, &mut Vec::new(), &mut files);
+
+        let has_piece_layers = !piece_layers.is_empty();
+        let mut trees = Vec::new();
+        for (path, attributes) in files {
+            let Some(pieces_root) = attributes.pieces_root else {
+                ensure!(
+                    attributes.length == 0,
+                    "file `{}` has no pieces root",
+                    BString::from(path.join(&b'/'))
+                );
+                continue;
+            };
+
+            let piece_layer = match piece_layers.remove(&pieces_root) {
+                Some(layer) => {
+                    let pieces = attributes.length.div_ceil(u64::from(piece_length));
+                    ensure!(
+                        attributes.length > u64::from(piece_length) && layer.len() as u64 == pieces,
+                        "piece layer of file `{}` has {} hashes, expected {pieces}",
+                        BString::from(path.join(&b'/')),
+                        layer.len(),
+                    );
+                    ensure!(
+                        merkle_root(layer.clone(), pad_hash(piece_length)) == pieces_root,
+                        "piece layer of file `{}` does not match its pieces root",
+                        BString::from(path.join(&b'/'))
+                    );
+                    Some(layer)
+                }
+                None => {
+                    ensure!(
+                        !has_piece_layers || attributes.length <= u64::from(piece_length),
+                        "piece layers do not contain file `{}`",
+                        BString::from(path.join(&b'/'))
+                    );
+                    None
+                }
+            };
+
+            trees.push(Self {
+                length: attributes.length,
+                pieces_root,
+                piece_layer,
+                piece_length,
+            });
+        }
+
+        ensure!(
+            piece_layers.is_empty(),
+            "piece layers contain {} entries without a matching file",
+            piece_layers.len()
+        );
+        Ok(trees)
+    }
+
+    /// Checks the piece at `index` of the file against the Merkle tree.
+    pub fn verify_piece(&self, index: u32, piece: &[u8]) -> Result<()> {
+        let leaves = piece
+            .chunks(MERKLE_BLOCK_SIZE)
+            .map(|block| Sha256::digest(block).into())
+            .collect::<Vec<_>>();
+
+        let (root, expected) = if self.length <= u64::from(self.piece_length) {
+            ensure!(index == 0, "piece index outside file");
+            (merkle_root(leaves, [0; 32]), &self.pieces_root)
+        } else {
+            let layer = self
+                .piece_layer
+                .as_ref()
+                .context("piece layer of file is unknown")?;
+            let expected = layer
+                .get(index as usize)
+                .context("piece index outside file")?;
+            // The last piece is padded with zero blocks up to the full piece size.
+            let mut leaves = leaves;
+            leaves.resize(self.piece_length as usize / MERKLE_BLOCK_SIZE, [0; 32]);
+            (merkle_root(leaves, [0; 32]), expected)
+        };
+
+        if root != *expected {
+            bail!("piece hash does not match merkle tree from torrent");
+        }
+        Ok(())
+    }
+}
+
+/// Hashes the nodes up to the root, padding the layer to a power of two with `pad`.
+fn merkle_root(mut layer: Vec<Sha256Hash>, mut pad: Sha256Hash) -> Sha256Hash {
+    fn hash_pair(left: &Sha256Hash, right: &Sha256Hash) -> Sha256Hash {
+        Sha256::new()
+            .chain_update(left)
+            .chain_update(right)
+            .finalize()
+            .into()
+    }
+
+    layer.resize(layer.len().next_power_of_two(), pad);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks_exact(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+        pad = hash_pair(&pad, &pad);
+    }
+    layer.first().copied().unwrap_or(pad)
+}
+
+/// Root of a piece sized subtree of zero blocks, padding the piece layer.
+fn pad_hash(piece_length: u32) -> Sha256Hash {
+    let blocks = piece_length as usize / MERKLE_BLOCK_SIZE;
+    merkle_root(vec![[0; 32]; blocks], [0; 32])
+}
+
+/// Creates the metainfo of a new torrent from a file or directory on disk.
+#[derive(Debug, Clone)]
+pub struct TorrentBuilder {
+    path: PathBuf,
+    announce: String,
+    /// Picked from the content size when not set.
+    piece_length: Option<u32>,
+    private: bool,
+    comment: Option<String>,
+    source: Option<String>,
+    similar: Vec<Sha1Hash>,
+    collections: Vec<String>,
+}
+
+impl TorrentBuilder {
+    pub const MIN_PIECE_LENGTH: u32 = 16 * 1024;
+    pub const MAX_PIECE_LENGTH: u32 = 16 * 1024 * 1024;
+    /// Number of pieces the automatic piece length aims for, keeping the info dict small while
+    /// still sharing content in fine-grained pieces.
+    const TARGET_PIECE_COUNT: u64 = 1500;
+
+    pub fn new(path: impl Into<PathBuf>, announce: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            announce: announce.into(),
+            piece_length: None,
+            private: false,
+            comment: None,
+            source: None,
+            similar: Vec::new(),
+            collections: Vec::new(),
+        }
+    }
+
+    /// Overrides the automatically picked piece length.
+    pub fn piece_length(mut self, piece_length: u32) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    /// Smallest power of two splitting the content into at most [`Self::TARGET_PIECE_COUNT`]
+    /// pieces, within the allowed piece lengths.
+    fn auto_piece_length(total_length: u64) -> u32 {
+        let piece_length = total_length
+            .div_ceil(Self::TARGET_PIECE_COUNT)
+            .next_power_of_two()
+            .clamp(
+                u64::from(Self::MIN_PIECE_LENGTH),
+                u64::from(Self::MAX_PIECE_LENGTH),
+            );
+        u32::try_from(piece_length).expect("piece length is clamped to 32 bits")
+    }
+
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    /// Sets the `source` tag, giving the torrent a different info hash than the same content
+    /// created for another tracker.
+    pub fn source(mut self, source: impl Into<String>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    /// Adds the info hash of a torrent sharing files with this one (BEP 38).
+    pub fn similar(mut self, info_hash: Sha1Hash) -> Self {
+        self.similar.push(info_hash);
+        self
+    }
+
+    /// Adds a collection the torrent belongs to (BEP 38).
+    pub fn collection(mut self, name: impl Into<String>) -> Self {
+        self.collections.push(name.into());
+        self
+    }
+
+    /// Hashes the content and builds the metainfo dict, ready to be written as a .torrent file.
+    pub fn build(self) -> Result<BencodeValue> {
+        /// Collects the regular files below `dir` in a stable order, with their path components
+        /// relative to the root.
+        fn collect_files(
+            dir: &Path,
+            components: &mut Vec<BString>,
+            files: &mut Vec<(PathBuf, Vec<BString>)>,
+        ) -> Result<()> {
+            let mut entries = std::fs::read_dir(dir)
+                .with_context(|| format!("reading directory `{}`", dir.display()))?
+                .collect::<Result<Vec<_>, _>>()
+                .context("reading directory entry")?;
+            entries.sort_by_key(|entry| entry.file_name());
+
+            for entry in entries {
+                let path = entry.path();
+                components.push(BString::from(entry.file_name().as_encoded_bytes()));
+                if path.is_dir() {
+                    collect_files(&path, components, files)?;
+                } else if path.is_file() {
+                    files.push((path, components.clone()));
+                }
+                components.pop();
+            }
+            Ok(())
+        }
+
+        let name = self
+            .path
+            .file_name()
+            .context("torrent content path has no file name")?;
+        let multi_file = self.path.is_dir();
+        let mut files = Vec::new();
+        if multi_file {
+            collect_files(&self.path, &mut Vec::new(), &mut files)?;
+            ensure!(
+                !files.is_empty(),
+                "torrent content directory contains no files"
+            );
+        } else {
+            files.push((self.path.clone(), Vec::new()));
+        }
+
+        let piece_length = match self.piece_length {
+            Some(piece_length) => {
+                ensure!(
+                    piece_length.is_power_of_two() && piece_length >= Self::MIN_PIECE_LENGTH,
+                    "piece length {piece_length} is not a power of two of at least 16 KiB"
+                );
+                piece_length
+            }
+            None => {
+                let mut total_length = 0;
+                for (path, _) in &files {
+                    total_length += std::fs::metadata(path)
+                        .with_context(|| format!("reading metadata of `{}`", path.display()))?
+                        .len();
+                }
+                Self::auto_piece_length(total_length)
+            }
+        };
+
+        // Pieces span file boundaries, as if the files were concatenated.
+        let mut pieces = Vec::new();
+        let mut piece = vec![0u8; piece_length as usize];
+        let mut filled = 0;
+        let mut lengths = Vec::new();
+        for (path, _) in &files {
+            let mut file = std::fs::File::open(path)
+                .with_context(|| format!("opening `{}`", path.display()))?;
+            let mut length = 0u64;
+            loop {
+                let read = file
+                    .read(&mut piece[filled..])
+                    .with_context(|| format!("reading `{}`", path.display()))?;
+                if read == 0 {
+                    break;
+                }
+                length += read as u64;
+                filled += read;
+                if filled == piece.len() {
+                    pieces.extend(hash_sha1(&piece));
+                    filled = 0;
+                }
+            }
+            lengths.push(length);
+        }
+        if filled > 0 {
+            pieces.extend(hash_sha1(&piece[..filled]));
+        }
+
+        let info = BencodeDict::new()
+            .with("name", BString::from(name.as_encoded_bytes()))
+            .with("piece length", piece_length)
+            .with("pieces", pieces)
+            .with_opt("private", self.private.then_some(1))
+            .with_opt("source", self.source)
+            .with_opt(
+                "similar",
+                (!self.similar.is_empty()).then(|| {
+                    self.similar
+                        .iter()
+                        .map(|hash| BencodeValue::from(&hash[..]))
+                        .collect::<Vec<_>>()
+                }),
+            )
+            .with_opt(
+                "collections",
+                (!self.collections.is_empty()).then(|| {
+                    self.collections
+                        .into_iter()
+                        .map(BencodeValue::from)
+                        .collect::<Vec<_>>()
+                }),
+            );
+        let info = if multi_file {
+            let entries = files
+                .into_iter()
+                .zip(lengths)
+                .map(|((_, components), length)| {
+                    let path = components.into_iter().map(BencodeValue::from).collect();
+                    BencodeDict::new()
+                        .with("length", length)
+                        .with("path", BencodeValue::List(path))
+                        .build()
+                })
+                .collect::<Vec<_>>();
+            info.with("files", entries)
+        } else {
+            info.with("length", lengths[0])
+        };
+
+        Ok(BencodeDict::new()
+            .with("announce", self.announce)
+            .with("info", info)
+            .with_opt("comment", self.comment)
+            .build())
+    }
+}
+
+/// Edits the metainfo around the info dict of an existing torrent file. The info dict is kept
+/// byte for byte, so the info hash only changes when the private flag is toggled.
+#[derive(Debug, Clone)]
+pub struct TorrentEditor {
+    /// Metainfo dict without the info dict.
+    contents: BencodeValue,
+    info: Vec<u8>,
+}
+
+impl TorrentEditor {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let parsed = SpannedValue::try_from_bytes(bytes).context("decoding torrent contents")?;
+        let info = parsed
+            .get("info")
+            .context("torrent contents do not contain an info dict")?
+            .raw(bytes)
+            .to_vec();
+
+        let mut contents = parsed.to_value_ref().to_owned();
+        contents.remove("info");
+        Ok(Self { contents, info })
+    }
+
+    /// Adds the tracker as a new tier after the existing ones.
+    pub fn add_tracker(&mut self, url: impl Into<String>) -> Result<()> {
+        let url = url.into();
+        let mut tiers = self.announce_tiers()?;
+        if tiers.iter().flatten().any(|tracker| *tracker == url) {
+            return Ok(());
+        }
+        tiers.push(vec![url]);
+        self.set_announce_tiers(tiers)
+    }
+
+    /// Removes the tracker from every tier, dropping tiers left empty.
+    pub fn remove_tracker(&mut self, url: &str) -> Result<()> {
+        let mut tiers = self.announce_tiers()?;
+        for tier in &mut tiers {
+            tier.retain(|tracker| tracker != url);
+        }
+        tiers.retain(|tier| !tier.is_empty());
+        self.set_announce_tiers(tiers)
+    }
+
+    pub fn set_comment(&mut self, comment: Option<String>) {
+        match comment {
+            Some(comment) => {
+                self.contents.insert("comment", comment);
+            }
+            None => {
+                self.contents.remove("comment");
+            }
+        }
+    }
+
+    /// Sets the private flag. This rewrites the info dict and therefore changes the info hash.
+    pub fn set_private(&mut self, private: bool) -> Result<()> {
+        let mut info =
+            BencodeValue::try_from_bytes(&self.info).context("decoding torrent info dict")?;
+        let is_private = info.get("private").and_then(BencodeValue::as_int) == Some(1);
+        if is_private == private {
+            return Ok(());
+        }
+
+        if private {
+            info.insert("private", 1);
+        } else {
+            info.remove("private");
+        }
+        self.info = info.to_canonical_bytes();
+        Ok(())
+    }
+
+    /// Encodes the torrent file, with the info dict as read or last rewritten.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let contents = self
+            .contents
+            .as_dict()
+            .expect("torrent contents are a dict");
+        encode_with_info(contents, &self.info)
+    }
+
+    /// Tracker tiers, with the `announce` url as single tier when there is no `announce-list`.
+    fn announce_tiers(&self) -> Result<Vec<Vec<String>>> {
+        if let Some(list) = self.contents.get("announce-list") {
+            return list
+                .clone()
+                .into_deserialize()
+                .context("torrent announce-list is not a list of tiers");
+        }
+        Ok(self
+            .contents
+            .get("announce")
+            .and_then(BencodeValue::as_str)
+            .map(|announce| vec![vec![announce.to_owned()]])
+            .unwrap_or_default())
+    }
+
+    /// Stores the tiers as `announce-list`, pointing `announce` at the first tracker for clients
+    /// without BEP 12 support.
+    fn set_announce_tiers(&mut self, tiers: Vec<Vec<String>>) -> Result<()> {
+        match tiers.first().and_then(|tier| tier.first()) {
+            Some(first) => self.contents.insert("announce", first.as_str()),
+            None => self.contents.remove("announce"),
+        };
+
+        if tiers.is_empty() {
+            self.contents.remove("announce-list");
+        } else {
+            let list = BencodeValue::from_serialize(&tiers).context("encoding announce-list")?;
+            self.contents.insert("announce-list", list);
+        }
+        Ok(())
+    }
+}
+
+/// Encodes the metainfo dict with the already encoded info dict spliced in at its sorted place.
+fn encode_with_info(contents: &BTreeMap<BString, BencodeValue>, info: &[u8]) -> Vec<u8> {
+    let info_key = BString::from("info");
+
+    let mut bytes = vec![b'd'];
+    let mut info_written = false;
+    for (key, value) in contents {
+        if *key == info_key {
+            continue;
+        }
+        if !info_written && *key > info_key {
+            write_dict_entry(&mut bytes, &info_key, info);
+            info_written = true;
+        }
+        write_dict_entry(&mut bytes, key, &value.to_canonical_bytes());
+    }
+    if !info_written {
+        write_dict_entry(&mut bytes, &info_key, info);
+    }
+    bytes.push(b'e');
+    bytes
+}
+
+fn write_dict_entry(bytes: &mut Vec<u8>, key: &[u8], value: &[u8]) {
+    bytes.extend_from_slice(format!("{}:", key.len()).as_bytes());
+    bytes.extend_from_slice(key);
+    bytes.extend_from_slice(value);
+}
+
+impl TorrentOverview<'_> {
+    /// Machine readable form of the overview, optionally including the v1 piece hashes.
+    pub fn to_json(&self, with_hashes: bool) -> serde_json::Value {
+        let files = self
+            .files
+            .iter()
+            .map(|file| {
+                let path = std::iter::once(self.name)
+                    .chain(&file.path)
+                    .map(|component| component.to_string())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                json!({
+                    "path": path,
+                    "length": file.length,
+                    "offset": file.offset,
+                    "attr": file.attr.as_ref().map(ToString::to_string),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut overview = json!({
+            "name": self.name.to_string(),
+            "tracker_url": self.tracker_url,
+            "announce_list": self.announce_list,
+            "url_list": self.url_list,
+            "private": self.private,
+            "source": self.source,
+            "similar": self.similar.iter().map(hex::encode).collect::<Vec<_>>(),
+            "collections": self.collections,
+            "creation_date": self.creation_date,
+            "comment": self.comment,
+            "created_by": self.created_by,
+            "encoding": self.encoding,
+            "length": self.length,
+            "info_hash": hex::encode(self.info_hash),
+            "info_hash_v2": self.info_hash_v2.map(hex::encode),
+            "piece_length": self.piece_length,
+            "piece_count": self.piece_count,
+            "piece_layers": self.piece_layers,
+            "files": files,
+        });
+        if with_hashes {
+            overview["piece_hashes"] = self.pieces.iter().map(hex::encode).collect();
+        }
+        overview
     }
 }
 
 impl std::fmt::Display for TorrentOverview<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "Tracker URL: {}", self.tracker_url)?;
+        if !self.tracker_url.is_empty() {
+            writeln!(f, "Tracker URL: {}", self.tracker_url)?;
+        }
+        for (index, tier) in self.announce_list.iter().enumerate() {
+            writeln!(f, "Tracker Tier {index}: {}", tier.join(", "))?;
+        }
+        for url in self.url_list {
+            writeln!(f, "Web Seed: {url}")?;
+        }
         writeln!(f, "length: {}", self.length)?;
+        if self.private {
+            writeln!(f, "Private: yes")?;
+        }
+        if let Some(source) = self.source {
+            writeln!(f, "Source: {source}")?;
+        }
+        for info_hash in self.similar {
+            writeln!(f, "Similar: {}", hex::encode(info_hash))?;
+        }
+        for collection in self.collections {
+            writeln!(f, "Collection: {collection}")?;
+        }
+        if let Some(comment) = self.comment {
+            writeln!(f, "Comment: {comment}")?;
+        }
+        if let Some(created_by) = self.created_by {
+            writeln!(f, "Created By: {created_by}")?;
+        }
+        if let Some(creation_date) = self.creation_date {
+            writeln!(f, "Creation Date: {creation_date}")?;
+        }
+        if let Some(encoding) = self.encoding {
+            writeln!(f, "Encoding: {encoding}")?;
+        }
         writeln!(f, "Info Hash: {}", hex::encode(self.info_hash))?;
+        if let Some(info_hash_v2) = self.info_hash_v2 {
+            writeln!(f, "Info Hash v2: {}", hex::encode(info_hash_v2))?;
+            writeln!(f, "Piece Layers: {}", self.piece_layers)?;
+        }
         writeln!(f, "Piece Length: {}", self.piece_length)?;
         writeln!(f, "Piece Hashes:")?;
         for piece in self.pieces {
@@ -116,3 +1356,261 @@ impl std::fmt::Display for TorrentOverview<'_> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_overflowing_lengths() {
+        let result = Torrent::from_bytes(
+            b"d8:announce0:4:infod5:filesld6:lengthi9223372036854775808e4:pathl1:aeed6:lengthi\
+            9223372036854775808e4:pathl1:beee4:name4:root12:piece lengthi16384e6:pieces0:ee",
+        );
+        assert!(matches!(result, Err(TorrentError::InvalidLength(_))));
+    }
+
+    #[test]
+    fn reject_piece_count_mismatch() {
+        let torrent = |pieces: &str| {
+            Torrent::from_bytes(
+                format!(
+                    "d8:announce0:4:infod6:lengthi3e4:name1:a12:piece lengthi2e6:pieces{}:{pieces}ee",
+                    pieces.len()
+                )
+                .as_bytes(),
+            )
+        };
+
+        // Three bytes in pieces of two need two hashes.
+        assert!(torrent(&"a".repeat(40)).is_ok());
+        for count in [1, 3] {
+            assert!(matches!(
+                torrent(&"a".repeat(20 * count)),
+                Err(TorrentError::InvalidLength(_))
+            ));
+        }
+    }
+
+    /// Bencoded v2 torrent of a single file `a` in pieces of two blocks, with its piece layer.
+    fn v2_torrent(content: &[u8]) -> Vec<u8> {
+        const PIECE_LENGTH: u32 = 2 * MERKLE_BLOCK_SIZE as u32;
+        let layer = content
+            .chunks(PIECE_LENGTH as usize)
+            .map(|piece| {
+                let mut leaves = piece
+                    .chunks(MERKLE_BLOCK_SIZE)
+                    .map(|block| Sha256::digest(block).into())
+                    .collect::<Vec<_>>();
+                leaves.resize(2, [0; 32]);
+                merkle_root(leaves, [0; 32])
+            })
+            .collect::<Vec<_>>();
+        let pieces_root = merkle_root(layer.clone(), pad_hash(PIECE_LENGTH));
+
+        let attributes = BencodeDict::new()
+            .with("length", content.len() as i64)
+            .with("pieces root", &pieces_root[..]);
+        let info = BencodeDict::new()
+            .with(
+                "file tree",
+                BencodeDict::new().with("a", BencodeDict::new().with("", attributes)),
+            )
+            .with("meta version", 2)
+            .with("name", "a")
+            .with("piece length", i64::from(PIECE_LENGTH));
+        BencodeDict::new()
+            .with("info", info)
+            .with(
+                "piece layers",
+                BencodeDict::new().with(&pieces_root[..], layer.concat()),
+            )
+            .build()
+            .to_canonical_bytes()
+    }
+
+    #[test]
+    fn verify_v2_pieces() {
+        // Three pieces, the last one a single partial block.
+        let content = (0..5 * MERKLE_BLOCK_SIZE - 100)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        let torrent = Torrent::from_bytes(&v2_torrent(&content)).unwrap();
+        assert_eq!(torrent.version, MetaVersion::V2);
+        assert_eq!(torrent.piece_count(), 3);
+
+        let tree = &torrent.file_trees[0];
+        for (index, piece) in content.chunks(2 * MERKLE_BLOCK_SIZE).enumerate() {
+            tree.verify_piece(index as u32, piece).unwrap();
+            torrent.verify_piece_v2(index as u32, piece).unwrap();
+
+            let mut corrupted = piece.to_vec();
+            corrupted[0] ^= 1;
+            assert!(tree.verify_piece(index as u32, &corrupted).is_err());
+        }
+        assert!(torrent.verify_piece_v2(3, &content[..1]).is_err());
+    }
+
+    #[test]
+    fn reject_corrupted_piece_layer() {
+        let contents = v2_torrent(&[1; 5 * MERKLE_BLOCK_SIZE - 100]);
+        let torrent = Torrent::from_bytes(&contents).unwrap();
+        let tree = &torrent.file_trees[0];
+        let mut layer = tree.piece_layer.clone().unwrap();
+        layer[1][0] ^= 1;
+
+        let mut value = BencodeValue::try_from_bytes(&contents).unwrap();
+        value.insert(
+            "piece layers",
+            BencodeDict::new().with(&tree.pieces_root[..], layer.concat()),
+        );
+        assert!(matches!(
+            Torrent::from_bytes(&value.to_canonical_bytes()),
+            Err(TorrentError::InvalidPieceLayers(_))
+        ));
+    }
+
+    #[test]
+    fn build_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        let a = (0..20_000).map(|i| i as u8).collect::<Vec<_>>();
+        let b = (0..30_000).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+        std::fs::write(root.join("a"), &a).unwrap();
+        std::fs::write(root.join("sub").join("b"), &b).unwrap();
+
+        let contents = TorrentBuilder::new(&root, "http://tracker.example/announce")
+            .private(true)
+            .comment("test")
+            .build()
+            .unwrap()
+            .to_canonical_bytes();
+        let torrent = Torrent::from_bytes(&contents).unwrap();
+
+        assert_eq!(torrent.announce, "http://tracker.example/announce");
+        assert_eq!(torrent.comment.as_deref(), Some("test"));
+        assert_eq!(torrent.info.name, "root");
+        assert_eq!(torrent.info.private, Some(1));
+        assert_eq!(torrent.info.piece_length, TorrentBuilder::MIN_PIECE_LENGTH);
+        let files = torrent
+            .files()
+            .map(|file| (file.path, file.length, file.offset))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            [
+                (vec![BString::from("a")], 20_000, 0),
+                (
+                    vec![BString::from("sub"), BString::from("b")],
+                    30_000,
+                    20_000
+                ),
+            ]
+        );
+
+        // Pieces span the file boundary.
+        let content = [a, b].concat();
+        let pieces = content
+            .chunks(TorrentBuilder::MIN_PIECE_LENGTH as usize)
+            .map(hash_sha1)
+            .collect::<Vec<_>>();
+        assert_eq!(torrent.info.v1_pieces(), pieces);
+    }
+
+    #[test]
+    fn auto_piece_length() {
+        let target = TorrentBuilder::TARGET_PIECE_COUNT;
+        assert_eq!(
+            TorrentBuilder::auto_piece_length(0),
+            TorrentBuilder::MIN_PIECE_LENGTH
+        );
+        assert_eq!(
+            TorrentBuilder::auto_piece_length(target * (1 << 20)),
+            1 << 20
+        );
+        assert_eq!(
+            TorrentBuilder::auto_piece_length(target * (1 << 20) + 1),
+            2 << 20
+        );
+        assert_eq!(
+            TorrentBuilder::auto_piece_length(u64::MAX),
+            TorrentBuilder::MAX_PIECE_LENGTH
+        );
+    }
+
+    /// Single file torrent of three bytes in two pieces, with a key unknown to [`TorrentInfo`].
+    const V1_TORRENT: &[u8] = b"d8:announce20:http://a.example/ann4:infod6:lengthi3e4:name1:a\
+        12:piece lengthi2e6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbb7:unknowni1eee";
+
+    #[test]
+    fn edit_keeps_info_hash() {
+        let info_hash = Torrent::from_bytes(V1_TORRENT).unwrap().info_hash;
+
+        let mut editor = TorrentEditor::from_bytes(V1_TORRENT).unwrap();
+        editor.add_tracker("http://b.example/ann").unwrap();
+        editor.set_comment(Some("comment".to_owned()));
+        // Already public, the info dict stays as it is.
+        editor.set_private(false).unwrap();
+        let edited = Torrent::from_bytes(&editor.to_bytes()).unwrap();
+        assert_eq!(edited.info_hash, info_hash);
+        assert_eq!(edited.comment.as_deref(), Some("comment"));
+        assert_eq!(
+            edited.announce_list,
+            [["http://a.example/ann"], ["http://b.example/ann"]]
+        );
+
+        editor.remove_tracker("http://a.example/ann").unwrap();
+        let edited = Torrent::from_bytes(&editor.to_bytes()).unwrap();
+        assert_eq!(edited.announce, "http://b.example/ann");
+        assert_eq!(edited.info_hash, info_hash);
+
+        editor.set_private(true).unwrap();
+        let edited = Torrent::from_bytes(&editor.to_bytes()).unwrap();
+        assert_ne!(edited.info_hash, info_hash);
+        assert_eq!(edited.info.private, Some(1));
+        assert!(edited.info.extra.contains_key(&BString::from("unknown")));
+    }
+
+    #[test]
+    fn to_bytes_keeps_unknown_keys() {
+        let contents = [&V1_TORRENT[..V1_TORRENT.len() - 1], b"4:zkeyl1:aee"].concat();
+        let torrent = Torrent::from_bytes(&contents).unwrap();
+        assert!(torrent.extra.contains_key(&BString::from("zkey")));
+        assert_eq!(torrent.to_bytes().unwrap(), contents);
+
+        // Piece layers are written back as well.
+        let contents = v2_torrent(&[1; 5 * MERKLE_BLOCK_SIZE - 100]);
+        let torrent = Torrent::from_bytes(&contents).unwrap();
+        assert_eq!(torrent.to_bytes().unwrap(), contents);
+    }
+
+    #[test]
+    fn magnet_links() {
+        let torrent = Torrent::from_bytes(V1_TORRENT).unwrap();
+        assert_eq!(
+            torrent.to_magnet(),
+            format!(
+                "magnet:?xt=urn:btih:{}&dn=a&tr=http%3A%2F%2Fa.example%2Fann",
+                hex::encode(torrent.info_hash)
+            )
+        );
+
+        // The v2 torrent made hybrid by adding the v1 keys for the same content.
+        let content = [1; 5 * MERKLE_BLOCK_SIZE - 100];
+        let mut value = BencodeValue::try_from_bytes(&v2_torrent(&content)).unwrap();
+        let info = value.get_mut("info").unwrap();
+        info.insert("length", content.len() as i64);
+        info.insert("pieces", [0; 3 * 20].as_slice());
+        let torrent = Torrent::from_bytes(&value.to_canonical_bytes()).unwrap();
+        assert_eq!(torrent.version, MetaVersion::Hybrid);
+        assert_eq!(
+            torrent.to_magnet(),
+            format!(
+                "magnet:?xt=urn:btih:{}&xt=urn:btmh:1220{}&dn=a",
+                hex::encode(torrent.info_hash),
+                hex::encode(torrent.info_hash_v2.unwrap())
+            )
+        );
+    }
+}
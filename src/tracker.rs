@@ -1,25 +1,75 @@
-use std::{borrow::Cow, net::SocketAddrV4, time::Duration};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU16, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use bencode::BencodeValue;
 use bstr::BString;
+use bytes::Bytes;
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, USER_AGENT},
+    Client, Proxy, RequestBuilder,
+};
 use serde::Serialize;
 use serde_with::{serde_as, FromInto};
+use tokio::{sync::watch, task::JoinSet, time::Instant};
 
 use crate::{
+    discovery::{BoxFuture, PeerSender, PeerSource},
+    peer::LocalBind,
     torrent::Torrent,
-    util::{PeerId, Sha1Hash},
+    util::{client_fingerprint, InfoHash, PeerId},
 };
 
-#[derive(Debug)]
+/// Delay before retrying a failed announce, doubled on every consecutive failure.
+const RETRY_DELAY: Duration = Duration::from_secs(15);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+
+/// Trackers of a torrent, announced to in tiers as described by BEP 12.
+#[derive(Debug, Clone)]
 pub struct Tracker {
-    url: String,
-    info_hash: Sha1Hash,
+    /// Tiers of tracker urls, tried in order. Within a tier, the last working url comes first.
+    tiers: Vec<Vec<String>>,
+    info_hash: InfoHash,
     peer_id: PeerId,
-    port: u16,
-    uploaded: u64,
-    downloaded: u64,
-    left: u64,
+    /// Port we are reachable at, shared by clones.
+    port: Arc<AtomicU16>,
+    /// Wakes the announce loops of all clones to announce again before the interval is up.
+    wakeup: Arc<watch::Sender<()>>,
+    /// Shared by clones, so pollers announce the progress the downloader reports.
+    progress: Arc<Progress>,
+    /// Number of peers to ask for, left to the tracker when not set.
+    numwant: Option<u32>,
+    /// Random per session, letting trackers recognize the client after an ip change.
+    key: u32,
+    /// `tracker id` handed out by each tracker, sent back on later announces to it.
+    tracker_ids: HashMap<String, String>,
+    /// Trackers that only answered announces asking for the non-compact peer list.
+    non_compact: HashSet<String>,
+    /// Announce to every tier at once instead of only the first that answers.
+    all_tiers: bool,
+    /// Private torrents are announced tier by tier, as their trackers expect.
+    private: bool,
+    /// Address to report instead of the one the announce comes from, e.g. behind a proxy.
+    ip: Option<IpAddr>,
+    /// Last address trackers reported seeing us at, shared by clones.
+    external_ip: Arc<Mutex<Option<IpAddr>>>,
+    client: TrackerClient,
+}
+
+/// Transfer statistics reported with announces.
+#[derive(Debug, Default)]
+struct Progress {
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    left: AtomicU64,
 }
 
 #[serde_as]
@@ -35,51 +85,325 @@ struct TrackerRequest {
     left: u64,
     #[serde_as(as = "FromInto<u8>")]
     compact: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event: Option<AnnounceEvent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    numwant: Option<u32>,
+    key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trackerid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip: Option<IpAddr>,
+}
+
+/// Change in the state of the download, reported to the tracker along with an announce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AnnounceEvent {
+    /// First announce of a download.
+    Started,
+    /// The download finished, sent once.
+    Completed,
+    /// The client is leaving the swarm.
+    Stopped,
 }
 
 #[derive(Debug)]
 pub struct TrackerResponse {
     pub interval: Duration,
+    /// Announces should not be sent more often than this, even when asking for more peers.
+    pub min_interval: Option<Duration>,
     pub peers: Peers,
+    /// Number of seeders in the swarm.
+    pub complete: Option<u64>,
+    /// Number of leechers in the swarm.
+    pub incomplete: Option<u64>,
+    /// Number of times the torrent was downloaded completely.
+    pub downloaded: Option<u64>,
+    /// Message from a tracker that accepted the announce, but wants the user to know something.
+    pub warning: Option<String>,
+    pub tracker_id: Option<String>,
+    /// Our address as seen by the tracker (BEP 24).
+    pub external_ip: Option<IpAddr>,
 }
 
-#[derive(Debug, Clone)]
-pub struct Peers(pub Vec<SocketAddrV4>);
+impl TrackerResponse {
+    /// Combines the responses of trackers of different tiers, keeping every peer once and
+    /// announcing again when the first of them asks to.
+    fn merge(mut self, other: Self) -> Self {
+        self.interval = self.interval.min(other.interval);
+        self.min_interval = self.min_interval.max(other.min_interval);
+        self.peers.extend(other.peers);
+        self.complete = self.complete.max(other.complete);
+        self.incomplete = self.incomplete.max(other.incomplete);
+        self.downloaded = self.downloaded.max(other.downloaded);
+        self.warning = self.warning.or(other.warning);
+        self.tracker_id = self.tracker_id.or(other.tracker_id);
+        self.external_ip = self.external_ip.or(other.external_ip);
+        self
+    }
+}
+
+/// Announce rejected by the tracker, e.g. for an unregistered torrent or announcing too often.
+#[derive(Debug, thiserror::Error)]
+#[error("tracker refused announce: {reason}")]
+pub struct TrackerFailure {
+    /// The `failure reason` given by the tracker.
+    pub reason: String,
+}
+
+/// Announce response whose peer list could not be read, retried without `compact=1`.
+#[derive(Debug, thiserror::Error)]
+#[error("tracker sent an invalid peer list")]
+struct InvalidPeerList;
+
+#[derive(Debug, Clone, Default)]
+pub struct Peers {
+    addrs: Vec<SocketAddr>,
+    /// Ids of the peers the tracker listed them with, only sent in non-compact responses.
+    peer_ids: HashMap<SocketAddr, PeerId>,
+}
 
 impl From<&Torrent> for Tracker {
     fn from(value: &Torrent) -> Self {
-        Self::new(value.announce.clone(), value.info_hash, value.info.length)
+        use rand::seq::SliceRandom;
+
+        // Shuffled once, after which working trackers move to the front of their tier.
+        let mut rng = rand::thread_rng();
+        let tiers = value
+            .announce_tiers()
+            .map(|tier| {
+                let mut tier = tier.to_vec();
+                tier.shuffle(&mut rng);
+                tier
+            })
+            .collect();
+        Self {
+            private: value.is_private(),
+            ..Self::with_tiers(tiers, value.info_hash, value.info.total_length())
+        }
     }
 }
 
 impl Tracker {
-    pub fn new(announce: String, info_hash: Sha1Hash, size: u64) -> Self {
+    pub fn new(announce: String, info_hash: InfoHash, size: u64) -> Self {
+        Self::with_tiers(vec![vec![announce]], info_hash, size)
+    }
+
+    pub fn with_tiers(tiers: Vec<Vec<String>>, info_hash: InfoHash, size: u64) -> Self {
         Self {
-            url: announce,
+            tiers,
             info_hash,
             peer_id: rand::random(),
-            port: 6881,
-            uploaded: 0,
-            downloaded: 0,
-            left: size,
+            port: Arc::new(AtomicU16::new(6881)),
+            wakeup: Arc::new(watch::channel(()).0),
+            progress: Arc::new(Progress {
+                left: AtomicU64::new(size),
+                ..Progress::default()
+            }),
+            numwant: None,
+            key: rand::random(),
+            tracker_ids: HashMap::new(),
+            non_compact: HashSet::new(),
+            all_tiers: false,
+            private: false,
+            ip: None,
+            external_ip: Arc::default(),
+            client: TrackerClient::default(),
+        }
+    }
+
+    /// Sends announces with the client, e.g. one going through a proxy or with other timeouts.
+    pub fn client(mut self, client: TrackerClient) -> Self {
+        self.client = client;
+        self
+    }
+
+    pub fn numwant(mut self, numwant: u32) -> Self {
+        self.numwant = Some(numwant);
+        self
+    }
+
+    /// Reports the address to trackers, for when they cannot tell it from the connection.
+    pub fn ip(mut self, ip: IpAddr) -> Self {
+        self.ip = Some(ip);
+        self
+    }
+
+    /// Announces to all tiers concurrently and merges the peers they return, finding peers faster.
+    /// Has no effect on private torrents.
+    pub fn all_tiers(mut self, all_tiers: bool) -> Self {
+        self.all_tiers = all_tiers && !self.private;
+        self
+    }
+
+    /// Regular announce, asking for peers without reporting an event.
+    pub async fn poll(&mut self) -> Result<TrackerResponse> {
+        self.announce(None).await
+    }
+
+    /// Announces to the first tracker that answers, going through the tiers in order, or to the
+    /// first of every tier when announcing to all tiers.
+    pub async fn announce(&mut self, event: Option<AnnounceEvent>) -> Result<TrackerResponse> {
+        if self.all_tiers && self.tiers.len() > 1 {
+            self.announce_all_tiers(event).await
+        } else {
+            self.announce_in_order(event).await
         }
     }
 
-    pub async fn poll(&self) -> Result<TrackerResponse> {
-        let query = TrackerRequest {
+    async fn announce_all_tiers(
+        &mut self,
+        event: Option<AnnounceEvent>,
+    ) -> Result<TrackerResponse> {
+        let mut announces = JoinSet::new();
+        for (index, tier) in self.tiers.iter().enumerate() {
+            let mut tracker = Self {
+                tiers: vec![tier.clone()],
+                ..self.clone()
+            };
+            announces.spawn(async move {
+                let result = tracker.announce_in_order(event).await;
+                (index, tracker, result)
+            });
+        }
+
+        let mut merged: Option<TrackerResponse> = None;
+        let mut last_error = None;
+        while let Some(announce) = announces.join_next().await {
+            let (index, tracker, result) = announce.context("announce task failed")?;
+            // Keep what the tier learned about its trackers.
+            self.tiers[index] = tracker.tiers.into_iter().next().unwrap_or_default();
+            self.tracker_ids.extend(tracker.tracker_ids);
+            self.non_compact.extend(tracker.non_compact);
+            match result {
+                Ok(response) => {
+                    merged = Some(match merged {
+                        Some(merged) => merged.merge(response),
+                        None => response,
+                    })
+                }
+                Err(err) => last_error = Some(err),
+            }
+        }
+        merged.ok_or_else(|| last_error.unwrap_or_else(|| anyhow!("torrent has no trackers")))
+    }
+
+    async fn announce_in_order(&mut self, event: Option<AnnounceEvent>) -> Result<TrackerResponse> {
+        let mut query = TrackerRequest {
             info_hash: decode_iso_8859_1(&self.info_hash),
             peer_id: decode_iso_8859_1(&self.peer_id),
-            port: self.port,
-            uploaded: self.uploaded,
-            downloaded: self.downloaded,
-            left: self.left,
+            port: self.port.load(Ordering::Relaxed),
+            uploaded: self.progress.uploaded.load(Ordering::Relaxed),
+            downloaded: self.progress.downloaded.load(Ordering::Relaxed),
+            left: self.progress.left.load(Ordering::Relaxed),
             compact: true,
+            event,
+            numwant: self.numwant,
+            key: format!("{:08x}", self.key),
+            trackerid: None,
+            ip: self.ip,
         };
 
-        query.send(&self.url).await.context("polling tracker")
+        let mut last_error = None;
+        for tier in &mut self.tiers {
+            for index in 0..tier.len() {
+                let url = &tier[index];
+                let backend = match backend(&self.client, url) {
+                    Ok(backend) => backend,
+                    Err(err) => {
+                        tracing::debug!(url, "Skipping tracker: {err:#}");
+                        last_error = Some(err);
+                        continue;
+                    }
+                };
+                query.trackerid = self.tracker_ids.get(url).cloned();
+                query.compact = !self.non_compact.contains(url);
+                let mut result = backend.announce(&query, url).await;
+                // Old trackers answer compact announces with garbage.
+                if query.compact && result.as_ref().is_err_and(is_invalid_peer_list) {
+                    tracing::debug!(url, "Retrying announce without compact peer list");
+                    query.compact = false;
+                    if let Ok(response) = backend.announce(&query, url).await {
+                        self.non_compact.insert(url.clone());
+                        result = Ok(response);
+                    }
+                }
+
+                match result {
+                    Ok(response) => {
+                        if let Some(tracker_id) = &response.tracker_id {
+                            self.tracker_ids
+                                .insert(tier[index].clone(), tracker_id.clone());
+                        }
+                        if let Some(ip) = response.external_ip {
+                            let previous = self.external_ip.lock().unwrap().replace(ip);
+                            if previous != Some(ip) {
+                                tracing::info!(%ip, "Tracker reported external ip");
+                            }
+                            // Other trackers still know us by the old address.
+                            if previous.is_some_and(|previous| previous != ip) {
+                                self.wakeup.send_replace(());
+                            }
+                        }
+                        let url = tier.remove(index);
+                        tier.insert(0, url);
+                        return Ok(response);
+                    }
+                    Err(err) => {
+                        tracing::debug!(url = tier[index], "Announce failed: {err:#}");
+                        last_error = Some(err);
+                    }
+                }
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| anyhow!("torrent has no trackers"))
+            .context("polling tracker"))
+    }
+
+    /// Tracker of the same announce url for another swarm of the torrent, sharing the peer id.
+    pub fn with_info_hash(&self, info_hash: InfoHash) -> Self {
+        Self {
+            info_hash,
+            tracker_ids: HashMap::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Updates the transfer statistics sent with the next announces, also by clones of this
+    /// tracker.
+    pub fn set_progress(&self, downloaded: u64, left: u64) {
+        self.progress
+            .downloaded
+            .store(downloaded, Ordering::Relaxed);
+        self.progress.left.store(left, Ordering::Relaxed);
+    }
+
+    /// Sets the number of bytes uploaded to peers, reported with the next announce.
+    pub fn set_uploaded(&self, uploaded: u64) {
+        self.progress.uploaded.store(uploaded, Ordering::Relaxed);
+    }
+
+    /// Changes the port announced to trackers, announcing the new one right away when polling.
+    pub fn set_port(&self, port: u16) {
+        if self.port.swap(port, Ordering::Relaxed) != port {
+            self.reannounce();
+        }
+    }
+
+    /// Makes the announce loops of this tracker and its clones announce as soon as the minimum
+    /// interval allows, instead of waiting for the regular interval.
+    pub fn reannounce(&self) {
+        self.wakeup.send_replace(());
     }
 
-    pub fn info_hash(&self) -> &Sha1Hash {
+    /// Address the last tracker reporting one saw us at.
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        *self.external_ip.lock().unwrap()
+    }
+
+    pub fn info_hash(&self) -> &InfoHash {
         &self.info_hash
     }
 
@@ -88,102 +412,477 @@ impl Tracker {
     }
 }
 
-impl TrackerRequest {
-    pub async fn send(self, url: &str) -> Result<TrackerResponse> {
-        tracing::debug!("Sending request to tracker");
+impl PeerSource for Tracker {
+    /// Announces in the interval the tracker asks for, starting with the `started` event.
+    fn run<'a>(&'a mut self, peers: &'a PeerSender) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let mut failures = 0;
+            // Repeated until the tracker heard of the download.
+            let mut event = Some(AnnounceEvent::Started);
+            let mut wakeups = self.wakeup.subscribe();
+
+            loop {
+                tracing::debug!("Polling tracker");
+                let response = match self.announce(event).await {
+                    Ok(response) => {
+                        event = None;
+                        failures = 0;
+                        response
+                    }
+                    Err(err) => {
+                        let delay = retry_delay(failures);
+                        failures += 1;
+                        tracing::error!(?delay, "Failed to poll tracker: {err:#}");
+                        tokio::time::sleep(delay).await;
+                        continue;
+                    }
+                };
+
+                tracing::info!(
+                    seeders = response.complete,
+                    leechers = response.incomplete,
+                    downloaded = response.downloaded,
+                    peers = response.peers.len(),
+                    "Announced to tracker"
+                );
+                peers.send(response.peers.into_socket_addrs());
+                // This tracker just heard the current port and ip, only clones need to announce
+                // them.
+                wakeups.borrow_and_update();
+
+                let min_interval = response.min_interval.unwrap_or_default();
+                let earliest = Instant::now() + min_interval;
+                tokio::select! {
+                    () = tokio::time::sleep(response.interval.max(min_interval)) => (),
+                    Ok(()) = wakeups.changed() => {
+                        tracing::debug!("Announcing early after a change of port or ip");
+                        tokio::time::sleep_until(earliest).await;
+                    }
+                }
+            }
+        })
+    }
 
-        mod inner {
-            use std::{
-                net::{Ipv4Addr, SocketAddrV4},
-                time::Duration,
-            };
+    fn stop(&mut self, completed: bool) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            let events = completed
+                .then_some(AnnounceEvent::Completed)
+                .into_iter()
+                .chain([AnnounceEvent::Stopped]);
+            for event in events {
+                if let Err(err) = self.announce(Some(event)).await {
+                    tracing::warn!(?event, "Announcing to tracker failed: {err:#}");
+                }
+            }
+        })
+    }
+}
 
-            use anyhow::{bail, Result};
-            use bytes::Bytes;
-            use serde::Deserialize;
-            use serde_with::{serde_as, DurationSeconds};
+/// Protocol announces are sent over, picked by the scheme of the tracker url.
+trait Announce: Send + Sync {
+    fn announce<'a>(
+        &'a self,
+        request: &'a TrackerRequest,
+        url: &'a str,
+    ) -> BoxFuture<'a, Result<TrackerResponse>>;
+}
 
-            use super::Peers;
+impl Announce for TrackerClient {
+    fn announce<'a>(
+        &'a self,
+        request: &'a TrackerRequest,
+        url: &'a str,
+    ) -> BoxFuture<'a, Result<TrackerResponse>> {
+        Box::pin(request.send(self, url))
+    }
+}
 
-            #[serde_as]
-            #[derive(Debug, Deserialize)]
-            pub(super) struct TrackerResponse {
-                #[serde_as(as = "DurationSeconds")]
-                interval: Duration,
-                peers: Bytes,
-            }
+/// Backend announcing to the url, failing for schemes the client does not speak.
+fn backend<'a>(client: &'a TrackerClient, url: &str) -> Result<&'a dyn Announce> {
+    let (scheme, _) = url
+        .split_once("://")
+        .with_context(|| format!("tracker url `{url}` has no scheme"))?;
+    match scheme.to_ascii_lowercase().as_str() {
+        "http" | "https" => Ok(client),
+        "udp" => bail!("udp trackers (BEP 15) are not supported"),
+        "ws" | "wss" => bail!("websocket trackers are not supported"),
+        scheme => bail!("unknown tracker url scheme `{scheme}`"),
+    }
+}
 
-            impl TryFrom<TrackerResponse> for super::TrackerResponse {
-                type Error = anyhow::Error;
-
-                fn try_from(value: TrackerResponse) -> Result<Self> {
-                    let TrackerResponse { interval, peers } = value;
-                    let peers = peers
-                        .chunks(6)
-                        .map(|c| {
-                            let Some((ip_bytes, port_bytes)) = c
-                                .split_first_chunk::<4>()
-                                .and_then(|(ib, c)| c.first_chunk::<2>().map(|pb| (ib, pb)))
-                            else {
-                                bail!("peers array entry not of length 6 bytes");
-                            };
-
-                            Ok(SocketAddrV4::new(
-                                Ipv4Addr::from(*ip_bytes),
-                                u16::from_be_bytes(*port_bytes),
-                            ))
-                        })
-                        .collect::<Result<Vec<_>>>()?;
-
-                    Ok(Self {
-                        interval,
-                        peers: Peers(peers),
-                    })
+/// Whether the peer list of the tracker's response could not be read, rather than the tracker
+/// failing the announce or not being reached.
+fn is_invalid_peer_list(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<InvalidPeerList>().is_some()
+}
+
+/// Exponential backoff with up to a quarter of random jitter, so clients that failed together do
+/// not retry together.
+fn retry_delay(failures: u32) -> Duration {
+    let delay = RETRY_DELAY
+        .saturating_mul(2u32.saturating_pow(failures))
+        .min(MAX_RETRY_DELAY);
+    delay + delay.mul_f64(rand::random::<f64>() / 4.0)
+}
+
+/// Announce response as sent by the tracker.
+mod inner {
+    use std::{
+        net::{IpAddr, SocketAddr},
+        time::Duration,
+    };
+
+    use anyhow::{bail, Context, Result};
+    use bencode::BencodeValue;
+    use bytes::Bytes;
+    use serde::Deserialize;
+    use serde_with::{serde_as, DurationSeconds};
+
+    use super::{InvalidPeerList, PeerId, Peers};
+
+    #[serde_as]
+    #[derive(Debug, Deserialize)]
+    pub(super) struct TrackerResponse {
+        #[serde_as(as = "DurationSeconds")]
+        interval: Duration,
+        #[serde_as(as = "Option<DurationSeconds>")]
+        #[serde(rename = "min interval", default)]
+        min_interval: Option<Duration>,
+        /// Read as [`PeerList`] on its own, so a broken peer list can be told apart.
+        peers: BencodeValue,
+        complete: Option<u64>,
+        incomplete: Option<u64>,
+        downloaded: Option<u64>,
+        /// Compact IPv6 peers (BEP 7).
+        peers6: Option<Bytes>,
+        #[serde(rename = "warning message")]
+        warning_message: Option<String>,
+        #[serde(rename = "tracker id")]
+        tracker_id: Option<String>,
+        /// Address of the client as 4 or 16 bytes (BEP 24).
+        #[serde(rename = "external ip")]
+        external_ip: Option<Bytes>,
+    }
+
+    /// Trackers ignoring `compact=1` send a list of dicts instead of a byte string.
+    #[derive(Debug, Deserialize)]
+    #[serde(untagged)]
+    enum PeerList {
+        Compact(Bytes),
+        Dicts(Vec<PeerDict>),
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct PeerDict {
+        #[serde(rename = "peer id")]
+        peer_id: Option<Bytes>,
+        ip: String,
+        port: u16,
+    }
+
+    impl TryFrom<TrackerResponse> for super::TrackerResponse {
+        type Error = anyhow::Error;
+
+        fn try_from(value: TrackerResponse) -> Result<Self> {
+            let TrackerResponse {
+                interval,
+                min_interval,
+                peers: peers_list,
+                complete,
+                incomplete,
+                downloaded,
+                peers6,
+                warning_message,
+                tracker_id,
+                external_ip,
+            } = value;
+            let mut peers = Peers::default();
+            match peers_list.into_deserialize().context(InvalidPeerList)? {
+                PeerList::Compact(compact) => {
+                    peers.addrs = parse_compact::<4>(&compact).context(InvalidPeerList)?
+                }
+                PeerList::Dicts(dicts) => {
+                    for PeerDict { peer_id, ip, port } in dicts {
+                        // Host names are not resolved.
+                        let Ok(ip) = ip.parse::<IpAddr>() else {
+                            tracing::debug!(ip, "Skipping peer without ip address");
+                            continue;
+                        };
+                        let addr = SocketAddr::new(ip, port);
+                        peers.addrs.push(addr);
+                        if let Some(peer_id) = peer_id.and_then(|id| PeerId::try_from(&id[..]).ok())
+                        {
+                            peers.peer_ids.insert(addr, peer_id);
+                        }
+                    }
                 }
             }
+            if let Some(peers6) = peers6 {
+                peers.addrs.extend(parse_compact::<16>(&peers6)?);
+            }
+
+            Ok(Self {
+                interval,
+                min_interval,
+                peers,
+                complete,
+                incomplete,
+                downloaded,
+                warning: warning_message,
+                tracker_id,
+                external_ip: external_ip.and_then(|ip| match ip.len() {
+                    4 => Some(IpAddr::from(<[u8; 4]>::try_from(&ip[..]).unwrap())),
+                    16 => Some(IpAddr::from(<[u8; 16]>::try_from(&ip[..]).unwrap())),
+                    len => {
+                        tracing::debug!(len, "Ignoring external ip of invalid length");
+                        None
+                    }
+                }),
+            })
         }
+    }
 
-        let response_bytes = BString::from_iter(
-            reqwest::get(format!("{url}?{}", url_encode(self)?))
-                .await
-                .context("requesting tracker announce url")?
-                .bytes()
-                .await
-                .context("reading tracker announce response bytes")?,
-        );
+    /// Parses entries of an `N` byte address followed by a two byte port.
+    fn parse_compact<const N: usize>(peers: &[u8]) -> Result<Vec<SocketAddr>>
+    where
+        IpAddr: From<[u8; N]>,
+    {
+        peers
+            .chunks(N + 2)
+            .map(|c| {
+                let Some((ip_bytes, port_bytes)) = c
+                    .split_first_chunk::<N>()
+                    .and_then(|(ib, c)| c.first_chunk::<2>().map(|pb| (ib, pb)))
+                else {
+                    bail!("peers array entry not of length {} bytes", N + 2);
+                };
+
+                Ok(SocketAddr::new(
+                    IpAddr::from(*ip_bytes),
+                    u16::from_be_bytes(*port_bytes),
+                ))
+            })
+            .collect::<Result<Vec<_>>>()
+    }
+}
 
-        let response: inner::TrackerResponse = BencodeValue::try_from_bytes(&response_bytes)
-            .context("parsing tracker announce response as bencode value")?
+impl TrackerRequest {
+    pub async fn send(&self, client: &TrackerClient, url: &str) -> Result<TrackerResponse> {
+        tracing::debug!("Sending request to tracker");
+
+        let response_bytes =
+            BString::from_iter(client.get(&with_query(url, &url_encode(self)?)).await?);
+
+        let (response, warnings) = BencodeValue::try_from_bytes_lenient(&response_bytes)
+            .context("parsing tracker announce response as bencode value")?;
+        for warning in warnings {
+            tracing::warn!(%warning, "Tracker announce response is not valid bencode");
+        }
+        // A failed announce carries nothing but the reason.
+        if let Some(reason) = response.get("failure reason") {
+            let reason = String::from_utf8_lossy(reason.as_bytes().unwrap_or_default());
+            return Err(TrackerFailure {
+                reason: reason.into_owned(),
+            }
+            .into());
+        }
+        let response: inner::TrackerResponse = response
             .into_deserialize()
             .context("deserializing tracker announce response")?;
 
-        TrackerResponse::try_from(response)
+        let response = TrackerResponse::try_from(response)?;
+        if let Some(warning) = &response.warning {
+            tracing::warn!(url, "Tracker warning: {warning}");
+        }
+        Ok(response)
     }
 }
 
+/// Lists a peer per line, followed by the client it runs when the tracker sent its id.
 impl std::fmt::Display for Peers {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for peer in self.0.iter() {
-            writeln!(f, "{}", peer)?;
+        for peer in self.addrs.iter() {
+            match self.peer_ids.get(peer) {
+                Some(peer_id) => match client_fingerprint(peer_id) {
+                    Some(client) => writeln!(f, "{peer} {client}")?,
+                    None => writeln!(f, "{peer} {}", hex::encode(peer_id))?,
+                },
+                None => writeln!(f, "{peer}")?,
+            }
         }
         Ok(())
     }
 }
 
 impl std::ops::Deref for Peers {
-    type Target = [SocketAddrV4];
+    type Target = [SocketAddr];
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.addrs
     }
 }
 
 impl Peers {
-    pub fn into_socket_addrs(self) -> Vec<SocketAddrV4> {
-        self.0
+    /// Id the tracker listed the peer with.
+    pub fn peer_id(&self, peer: &SocketAddr) -> Option<&PeerId> {
+        self.peer_ids.get(peer)
+    }
+
+    pub fn into_socket_addrs(self) -> Vec<SocketAddr> {
+        self.addrs
+    }
+
+    /// Adds the peers not listed yet.
+    fn extend(&mut self, other: Self) {
+        for addr in other.addrs {
+            if !self.addrs.contains(&addr) {
+                self.addrs.push(addr);
+            }
+        }
+        for (addr, peer_id) in other.peer_ids {
+            self.peer_ids.entry(addr).or_insert(peer_id);
+        }
+    }
+}
+
+/// Timeouts and retries of tracker requests.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestPolicy {
+    /// Time to establish the connection to the tracker.
+    pub connect_timeout: Duration,
+    /// Time the tracker may stay silent once connected, so a hanging tracker is given up on.
+    pub read_timeout: Duration,
+    /// Further attempts at a tracker url that could not be reached, before moving on to the next
+    /// url of the tier.
+    pub retries: u32,
+    /// Delay before the first retry of a url, doubled on every further one.
+    pub retry_delay: Duration,
+}
+
+impl Default for RequestPolicy {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(15),
+            retries: 1,
+            retry_delay: Duration::from_secs(1),
+        }
     }
 }
 
+/// Http client announces are sent with, applying the request policy.
+#[derive(Debug, Clone)]
+pub struct TrackerClient {
+    client: Client,
+    policy: RequestPolicy,
+    /// Sent with every announce, e.g. a `User-Agent` a private tracker accepts.
+    headers: HeaderMap,
+}
+
+impl Default for TrackerClient {
+    fn default() -> Self {
+        Self::new(RequestPolicy::default(), None, None, None)
+            .expect("default http client should build")
+    }
+}
+
+impl TrackerClient {
+    /// Builds the http client for announces, sending every request through the proxy when given.
+    /// Credentials are taken from `proxy_auth` as `user:password`, or from the proxy url itself.
+    /// Connections are made from the local address or interface, if given.
+    pub fn new(
+        policy: RequestPolicy,
+        proxy: Option<&str>,
+        proxy_auth: Option<&str>,
+        bind: Option<&LocalBind>,
+    ) -> Result<Self> {
+        let mut builder = Client::builder()
+            .connect_timeout(policy.connect_timeout)
+            .read_timeout(policy.read_timeout);
+        if let Some(proxy) = proxy {
+            let mut proxy = Proxy::all(proxy).context("parsing proxy url")?;
+            if let Some(auth) = proxy_auth {
+                let (user, password) = auth
+                    .split_once(':')
+                    .context("proxy credentials are not of the form `user:password`")?;
+                proxy = proxy.basic_auth(user, password);
+            }
+            builder = builder.proxy(proxy);
+        }
+        if let Some(local) = bind {
+            builder = local.http(builder)?;
+        }
+        let client = builder.build().context("building http client")?;
+        Ok(Self {
+            client,
+            policy,
+            headers: HeaderMap::new(),
+        })
+    }
+
+    /// Sends the header with every announce, replacing an earlier value of the same name.
+    pub fn header(mut self, name: &str, value: &str) -> Result<Self> {
+        let name = HeaderName::try_from(name)
+            .with_context(|| format!("invalid http header name `{name}`"))?;
+        let value = HeaderValue::try_from(value)
+            .with_context(|| format!("invalid value for http header `{name}`"))?;
+        self.headers.insert(name, value);
+        Ok(self)
+    }
+
+    pub fn user_agent(self, user_agent: &str) -> Result<Self> {
+        self.header(USER_AGENT.as_str(), user_agent)
+    }
+
+    /// Starts a GET request through the proxy and local bind, carrying the configured headers.
+    pub fn request(&self, url: &str) -> RequestBuilder {
+        self.client.get(url).headers(self.headers.clone())
+    }
+
+    /// Fetches the url, retrying requests that failed to get a response.
+    async fn get(&self, url: &str) -> Result<Bytes> {
+        let mut attempt = 0;
+        loop {
+            let result = async {
+                self.request(url)
+                    .send()
+                    .await
+                    .context("requesting tracker announce url")?
+                    .bytes()
+                    .await
+                    .context("reading tracker announce response bytes")
+            }
+            .await;
+
+            match result {
+                Err(err) if attempt < self.policy.retries => {
+                    let delay = self
+                        .policy
+                        .retry_delay
+                        .saturating_mul(2u32.saturating_pow(attempt));
+                    attempt += 1;
+                    tracing::debug!(?delay, attempt, "Retrying tracker request: {err:#}");
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Appends the query to the url, after the query private trackers may already put in it (e.g. a
+/// passkey).
+fn with_query(url: &str, query: &str) -> String {
+    let separator = if !url.contains('?') {
+        "?"
+    } else if url.ends_with(['?', '&']) {
+        ""
+    } else {
+        "&"
+    };
+    format!("{url}{separator}{query}")
+}
+
 /// Adapted from [https://github.com/nox/serde_urlencoded/pull/60/files]
 fn url_encode(input: impl Serialize) -> Result<String> {
     use form_urlencoded::Serializer as UrlEncoder;
@@ -197,7 +896,7 @@ fn url_encode(input: impl Serialize) -> Result<String> {
     Ok(urlencoder.finish())
 }
 
-fn encode_iso_8859_1(input: &str) -> Cow<[u8]> {
+fn encode_iso_8859_1(input: &str) -> Cow<'_, [u8]> {
     input
         .chars()
         .map(|c| u8::try_from(u32::from(c)).expect("utf-8 character"))
@@ -207,3 +906,97 @@ fn encode_iso_8859_1(input: &str) -> Cow<[u8]> {
 fn decode_iso_8859_1(bytes: &[u8]) -> String {
     bytes.iter().map(|byte| char::from(*byte)).collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    fn parse_response(bytes: &[u8]) -> Result<TrackerResponse> {
+        let response: inner::TrackerResponse =
+            BencodeValue::try_from_bytes(bytes)?.into_deserialize()?;
+        TrackerResponse::try_from(response)
+    }
+
+    #[test]
+    fn parse_compact_peers() {
+        let response =
+            parse_response(b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe1e").unwrap();
+        assert_eq!(response.interval, Duration::from_secs(60));
+        assert_eq!(response.peers.addrs, ["127.0.0.1:6881".parse().unwrap()]);
+        assert!(response.peers.peer_ids.is_empty());
+    }
+
+    #[test]
+    fn parse_peer_dicts() {
+        let response = parse_response(
+            b"d8:intervali60e5:peersld2:ip9:127.0.0.17:peer id20:-XX0001-abcdefghijkl\
+            4:porti6881eed2:ip9:localhost4:porti6882eeee",
+        )
+        .unwrap();
+        let addr = "127.0.0.1:6881".parse().unwrap();
+        // Host names are skipped.
+        assert_eq!(response.peers.addrs, [addr]);
+        assert_eq!(response.peers.peer_id(&addr), Some(b"-XX0001-abcdefghijkl"));
+    }
+
+    #[test]
+    fn parse_ipv6_peers() {
+        let mut bytes = b"d8:intervali60e5:peers0:6:peers618:".to_vec();
+        bytes.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        bytes.extend_from_slice(&6881u16.to_be_bytes());
+        bytes.push(b'e');
+        let response = parse_response(&bytes).unwrap();
+        assert_eq!(response.peers.addrs, ["[::1]:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn parse_external_ip() {
+        let response =
+            parse_response(b"d11:external ip4:\x0a\x00\x00\x018:intervali60e5:peers0:e").unwrap();
+        assert_eq!(
+            response.external_ip,
+            Some(Ipv4Addr::new(10, 0, 0, 1).into())
+        );
+
+        let mut bytes = b"d11:external ip16:".to_vec();
+        bytes.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        bytes.extend_from_slice(b"8:intervali60e5:peers0:e");
+        let response = parse_response(&bytes).unwrap();
+        assert_eq!(response.external_ip, Some(Ipv6Addr::LOCALHOST.into()));
+
+        let response = parse_response(b"d11:external ip3:abc8:intervali60e5:peers0:e").unwrap();
+        assert_eq!(response.external_ip, None);
+    }
+
+    #[test]
+    fn reject_truncated_compact_peers() {
+        let err = parse_response(b"d8:intervali60e5:peers5:abcdee").unwrap_err();
+        assert!(is_invalid_peer_list(&err));
+    }
+
+    #[test]
+    fn join_announce_query() {
+        for (url, expected) in [
+            (
+                "http://t.example/announce",
+                "http://t.example/announce?port=1",
+            ),
+            (
+                "http://t.example/announce?passkey=a",
+                "http://t.example/announce?passkey=a&port=1",
+            ),
+            (
+                "http://t.example/announce?passkey=a&",
+                "http://t.example/announce?passkey=a&port=1",
+            ),
+            (
+                "http://t.example/announce?",
+                "http://t.example/announce?port=1",
+            ),
+        ] {
+            assert_eq!(with_query(url, "port=1"), expected);
+        }
+    }
+}
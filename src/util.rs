@@ -1,5 +1,9 @@
 pub type PeerId = [u8; 20];
 pub type Sha1Hash = [u8; 20];
+pub type Sha256Hash = [u8; 32];
+/// Identifies the swarm of a torrent towards trackers and peers: the SHA-1 hash of the info dict,
+/// or its SHA-256 hash truncated to 20 bytes for v2 torrents.
+pub type InfoHash = [u8; 20];
 
 pub mod serde_with {
     use std::marker::PhantomData;
@@ -46,6 +50,16 @@ pub mod serde_with {
     }
 }
 
+/// Client and version encoded in an Azureus-style peer id such as `-qB4630-`, the convention most
+/// clients follow.
+pub fn client_fingerprint(peer_id: &PeerId) -> Option<&str> {
+    let prefix = &peer_id[..8];
+    let valid = prefix[0] == b'-'
+        && prefix[7] == b'-'
+        && prefix[1..7].iter().all(u8::is_ascii_alphanumeric);
+    valid.then(|| std::str::from_utf8(prefix).expect("prefix is ascii"))
+}
+
 pub fn hash_sha1(value: impl AsRef<[u8]>) -> Sha1Hash {
     use sha1::{Digest, Sha1};
 
@@ -54,9 +68,24 @@ pub fn hash_sha1(value: impl AsRef<[u8]>) -> Sha1Hash {
     hasher.finalize().into()
 }
 
+/// Hashes the piece on the blocking thread pool, as hashing large pieces on the runtime would
+/// hold up every other connection. The piece is handed back along with its hash.
+pub async fn hash_piece(piece: Vec<u8>) -> (Vec<u8>, Sha1Hash) {
+    tokio::task::spawn_blocking(move || {
+        let hash = hash_sha1(&piece);
+        (piece, hash)
+    })
+    .await
+    .expect("hashing a piece should not panic")
+}
+
 pub fn calculate_piece_length(piece_length: u32, torrent_length: u64, piece_index: u32) -> u32 {
-    piece_length.min(
-        u32::try_from(torrent_length - u64::from(piece_index * piece_length))
-            .expect("piece length should fit in 32 bits"),
-    )
+    let remaining = torrent_length.saturating_sub(piece_offset(piece_length, piece_index));
+    u32::try_from(remaining.min(u64::from(piece_length)))
+        .expect("piece length should fit in 32 bits")
+}
+
+/// Offset of the first byte of the piece in the torrent content.
+pub fn piece_offset(piece_length: u32, piece_index: u32) -> u64 {
+    u64::from(piece_index) * u64::from(piece_length)
 }
@@ -0,0 +1,357 @@
+use std::collections::HashSet;
+
+use bencode::BencodeValue;
+use bstr::{BStr, ByteSlice};
+
+use crate::{storage::component_problem, torrent::Torrent};
+
+const MIN_PIECE_LENGTH: i64 = 16 * 1024;
+const MAX_PIECE_LENGTH: i64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Allowed, but likely to trip up other clients.
+    Warning,
+    /// The torrent does not conform to the specification.
+    Error,
+}
+
+/// Problem found in a torrent file.
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub severity: Severity,
+    /// Where the problem is, as a key path (`info.files[2].path`) or byte offset.
+    pub location: String,
+    pub message: String,
+}
+
+/// Checks the contents of a .torrent file for spec conformance, reporting every problem found.
+pub fn validate(contents: &[u8]) -> Vec<Finding> {
+    let mut findings = Findings::default();
+
+    let value = match BencodeValue::try_from_bytes_lenient(contents) {
+        Ok((value, warnings)) => {
+            for warning in warnings {
+                findings.warning(
+                    format!("byte offset {}", warning.offset),
+                    warning.kind.to_string(),
+                );
+            }
+            value
+        }
+        Err(err) => {
+            findings.error("", format!("not valid bencode: {err}"));
+            return findings.0;
+        }
+    };
+
+    if value.as_dict().is_none() {
+        findings.error("", "torrent is not a dict");
+        return findings.0;
+    }
+
+    check_trackers(&value, &mut findings);
+    match value.get("info") {
+        Some(info) if info.as_dict().is_some() => check_info(info, &mut findings),
+        Some(_) => findings.error("info", "info is not a dict"),
+        None => findings.error("info", "torrent has no info dict"),
+    }
+
+    // Loading catches what the checks above do not look at, such as v2 piece layers.
+    if !findings.has_errors() {
+        if let Err(err) = Torrent::from_bytes(contents) {
+            findings.error("", format!("{:#}", anyhow::Error::from(err)));
+        }
+    }
+
+    findings.0
+}
+
+#[derive(Default)]
+struct Findings(Vec<Finding>);
+
+impl Findings {
+    fn push(
+        &mut self,
+        severity: Severity,
+        location: impl Into<String>,
+        message: impl Into<String>,
+    ) {
+        self.0.push(Finding {
+            severity,
+            location: location.into(),
+            message: message.into(),
+        });
+    }
+
+    fn warning(&mut self, location: impl Into<String>, message: impl Into<String>) {
+        self.push(Severity::Warning, location, message);
+    }
+
+    fn error(&mut self, location: impl Into<String>, message: impl Into<String>) {
+        self.push(Severity::Error, location, message);
+    }
+
+    fn has_errors(&self) -> bool {
+        self.0.iter().any(|f| f.severity == Severity::Error)
+    }
+}
+
+fn check_trackers(value: &BencodeValue, findings: &mut Findings) {
+    let announce = value.get("announce");
+    if announce.is_some_and(|announce| announce.as_str().is_none()) {
+        findings.error("announce", "announce is not a utf-8 string");
+    }
+
+    let Some(announce_list) = value.get("announce-list") else {
+        if announce.is_none() {
+            findings.warning("announce", "torrent has no trackers");
+        }
+        return;
+    };
+    let Some(tiers) = announce_list.as_list() else {
+        findings.error("announce-list", "announce-list is not a list");
+        return;
+    };
+    for (index, tier) in tiers.iter().enumerate() {
+        let location = format!("announce-list[{index}]");
+        match tier.as_list() {
+            Some([]) => findings.warning(location, "tier is empty"),
+            Some(urls) if urls.iter().any(|url| url.as_str().is_none()) => {
+                findings.error(location, "tier contains a url that is not a utf-8 string")
+            }
+            Some(_) => (),
+            None => findings.error(location, "tier is not a list"),
+        }
+    }
+}
+
+fn check_info(info: &BencodeValue, findings: &mut Findings) {
+    match info.get("name").and_then(BencodeValue::as_bytes) {
+        Some(name) => check_path_component(BStr::new(name), "info.name", findings),
+        None => findings.error("info.name", "info dict has no name"),
+    }
+
+    let piece_length = info.get("piece length").and_then(BencodeValue::as_int);
+    match piece_length {
+        None => findings.error("info.piece length", "info dict has no piece length"),
+        Some(length) if length <= 0 => {
+            findings.error("info.piece length", "piece length is not positive")
+        }
+        Some(length) if !(length as u64).is_power_of_two() => {
+            findings.warning("info.piece length", "piece length is not a power of two")
+        }
+        Some(length) if !(MIN_PIECE_LENGTH..=MAX_PIECE_LENGTH).contains(&length) => findings
+            .warning(
+                "info.piece length",
+                format!("piece length {length} is outside of 16 KiB to 64 MiB"),
+            ),
+        Some(_) => (),
+    }
+
+    if let Some(private) = info.get("private") {
+        if !matches!(private.as_int(), Some(0 | 1)) {
+            findings.warning("info.private", "private flag is not 0 or 1");
+        }
+    }
+
+    let total_length = match (info.get("length"), info.get("files")) {
+        (Some(_), Some(_)) => {
+            findings.error("info", "info dict contains both length and files");
+            None
+        }
+        (Some(length), None) => match length.as_u64() {
+            Some(length) => Some(length),
+            None => {
+                findings.error("info.length", "length is not an unsigned 64-bit integer");
+                None
+            }
+        },
+        (None, Some(files)) => check_files(files, findings),
+        (None, None) => {
+            if info.get("file tree").is_none() {
+                findings.error(
+                    "info",
+                    "info dict contains neither length, files nor file tree",
+                );
+            }
+            None
+        }
+    };
+
+    if let Some(tree) = info.get("file tree") {
+        check_file_tree(tree, "info.file tree", findings);
+    }
+
+    match info.get("pieces") {
+        Some(pieces) => {
+            let Some(pieces) = pieces.as_bytes() else {
+                findings.error("info.pieces", "pieces is not a byte string");
+                return;
+            };
+            if pieces.len() % 20 != 0 {
+                findings.error("info.pieces", "pieces length is not divisible by 20");
+                return;
+            }
+            if let (Some(total_length), Some(piece_length @ 1..)) = (total_length, piece_length) {
+                let expected = total_length.div_ceil(piece_length as u64);
+                let count = pieces.len() as u64 / 20;
+                if count != expected {
+                    findings.error(
+                        "info.pieces",
+                        format!("{count} piece hashes for content of {expected} pieces"),
+                    );
+                }
+            }
+        }
+        None if total_length.is_some() => {
+            findings.error("info.pieces", "info dict has no piece hashes");
+        }
+        None => (),
+    }
+}
+
+/// Checks the entries of a v1 multi-file torrent, returning the total length when all are valid.
+fn check_files(files: &BencodeValue, findings: &mut Findings) -> Option<u64> {
+    let Some(files) = files.as_list() else {
+        findings.error("info.files", "files is not a list");
+        return None;
+    };
+    if files.is_empty() {
+        findings.error("info.files", "files is empty");
+    }
+
+    let mut total_length = Some(0u64);
+    let mut seen = HashSet::new();
+    for (index, file) in files.iter().enumerate() {
+        let location = format!("info.files[{index}]");
+        match file.get("length").and_then(BencodeValue::as_u64) {
+            Some(length) => {
+                total_length = match total_length.map(|total| total.checked_add(length)) {
+                    Some(None) => {
+                        findings.error(
+                            format!("{location}.length"),
+                            "total length of the files overflows",
+                        );
+                        None
+                    }
+                    total_length => total_length.flatten(),
+                }
+            }
+            _ => {
+                findings.error(
+                    format!("{location}.length"),
+                    "length is not an unsigned 64-bit integer",
+                );
+                total_length = None;
+            }
+        }
+
+        let path = file.get("path").and_then(BencodeValue::as_list);
+        let Some(components) = path.and_then(|path| {
+            path.iter()
+                .map(BencodeValue::as_bytes)
+                .collect::<Option<Vec<_>>>()
+        }) else {
+            findings.error(format!("{location}.path"), "path is not a list of strings");
+            continue;
+        };
+        if components.is_empty() {
+            findings.error(format!("{location}.path"), "path is empty");
+            continue;
+        }
+        for component in &components {
+            check_path_component(BStr::new(component), &format!("{location}.path"), findings);
+        }
+        // Padding files are never created, so they commonly share a name.
+        let padding = file
+            .get("attr")
+            .and_then(BencodeValue::as_bytes)
+            .is_some_and(|attr| attr.contains(&b'p'));
+        if !padding && !seen.insert(components.join(&b'/')) {
+            findings.error(format!("{location}.path"), "duplicate file path");
+        }
+    }
+    total_length
+}
+
+fn check_file_tree(tree: &BencodeValue, location: &str, findings: &mut Findings) {
+    let Some(entries) = tree.as_dict() else {
+        findings.error(location, "file tree node is not a dict");
+        return;
+    };
+    for (name, node) in entries {
+        // The empty key holds the attributes of a file.
+        if name.is_empty() {
+            continue;
+        }
+        let location = format!("{location}.{name}");
+        check_path_component(name.as_bstr(), &location, findings);
+        check_file_tree(node, &location, findings);
+    }
+}
+
+/// Flags names that would escape the download directory or are otherwise unusable as path.
+fn check_path_component(component: &BStr, location: &str, findings: &mut Findings) {
+    if let Some(problem) = component_problem(component) {
+        findings.error(location, problem);
+    }
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        match self.location.as_str() {
+            "" => write!(f, "{severity}: {}", self.message),
+            location => write!(f, "{severity}: {location}: {}", self.message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsafe_path_components() {
+        let findings = validate(
+            b"d8:announce0:4:infod5:filesld6:lengthi1e4:pathl7:CON.txteed6:lengthi1e4:pathl3:a:bee\
+            e4:name4:root12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+        );
+
+        let errors: Vec<_> = findings
+            .iter()
+            .filter(|finding| finding.severity == Severity::Error)
+            .map(|finding| finding.location.as_str())
+            .collect();
+        assert_eq!(errors, ["info.files[0].path", "info.files[1].path"]);
+    }
+
+    #[test]
+    fn lengths_beyond_i64() {
+        let locations = |contents: &[u8]| {
+            validate(contents)
+                .into_iter()
+                .map(|finding| finding.location)
+                .collect::<Vec<_>>()
+        };
+
+        // Valid as u64, which is how the torrent is read.
+        let findings = locations(
+            b"d8:announce0:4:infod6:lengthi9223372036854775808e4:name1:a\
+            12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+        );
+        assert!(!findings.iter().any(|location| location == "info.length"));
+
+        let findings = locations(
+            b"d8:announce0:4:infod5:filesld6:lengthi18446744073709551616e4:pathl1:aeee\
+            4:name4:root12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+        );
+        assert!(findings
+            .iter()
+            .any(|location| location == "info.files[0].length"));
+    }
+}
@@ -0,0 +1,232 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{bail, ensure, Context, Result};
+use bytes::{Bytes, BytesMut};
+use reqwest::{header, Client, Response, StatusCode};
+
+use crate::{
+    peer::{LocalBind, PieceDescriptor},
+    torrent::TorrentInfo,
+    util::{hash_piece, piece_offset, Sha1Hash},
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time the server may take to send the next part of a response.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// HTTP server hosting the content of a torrent (BEP 19). Pieces are fetched with range requests,
+/// so a web seed behaves like a peer that never chokes.
+#[derive(Debug, Clone)]
+pub struct WebSeed {
+    url: String,
+    /// Urls of the files in the order they are concatenated into pieces.
+    files: Arc<[WebSeedFile]>,
+    piece_length: u32,
+}
+
+#[derive(Debug)]
+struct WebSeedFile {
+    /// `None` for padding files (BEP 47), which are all zeros and not hosted by the server.
+    url: Option<String>,
+    length: u64,
+}
+
+impl WebSeed {
+    pub fn new(url: &str, info: &TorrentInfo) -> Self {
+        let name = percent_encode(&info.name);
+        let files = match &info.files {
+            Some(_) => {
+                let root = format!("{}/{name}", url.trim_end_matches('/'));
+                info.files()
+                    .map(|file| WebSeedFile {
+                        url: (!file.is_padding()).then(|| {
+                            file.path.iter().fold(root.clone(), |url, component| {
+                                url + "/" + &percent_encode(component)
+                            })
+                        }),
+                        length: file.length,
+                    })
+                    .collect()
+            }
+            // Urls ending in a slash point to the directory holding the file.
+            None => vec![WebSeedFile {
+                url: Some(if url.ends_with('/') {
+                    format!("{url}{name}")
+                } else {
+                    url.to_owned()
+                }),
+                length: info.total_length(),
+            }],
+        };
+
+        Self {
+            url: url.to_owned(),
+            files: files.into(),
+            piece_length: info.piece_length,
+        }
+    }
+
+    /// Builds the http client web seeds are downloaded with, connecting from the local address or
+    /// interface if given.
+    pub fn client(bind: Option<&LocalBind>) -> Result<Client> {
+        let mut builder = Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .read_timeout(READ_TIMEOUT);
+        if let Some(local) = bind {
+            builder = local.http(builder)?;
+        }
+        builder.build().context("building web seed http client")
+    }
+
+    pub async fn download_piece(
+        &self,
+        client: &Client,
+        PieceDescriptor {
+            index,
+            length,
+            hash,
+        }: PieceDescriptor,
+    ) -> Result<Vec<u8>> {
+        let piece_start = piece_offset(self.piece_length, index);
+        let piece_end = piece_start + u64::from(length);
+
+        // A piece may span several files, each needing its own request.
+        let mut buf = Vec::with_capacity(length as usize);
+        let mut file_start = 0;
+        for file in self.files.iter() {
+            let file_end = file_start + file.length;
+            let (start, end) = (piece_start.max(file_start), piece_end.min(file_end));
+            if start < end {
+                match &file.url {
+                    Some(url) => {
+                        let range = fetch_range(client, url, start - file_start, end - file_start);
+                        buf.extend_from_slice(&range.await?);
+                    }
+                    None => buf.resize(buf.len() + (end - start) as usize, 0),
+                }
+            }
+            file_start = file_end;
+        }
+
+        check_piece(buf, length, &hash).await
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+}
+
+/// Fetches the bytes in `start..end` of the file at the url.
+async fn fetch_range(client: &Client, url: &str, start: u64, end: u64) -> Result<Bytes> {
+    let response = client
+        .get(url)
+        .header(header::RANGE, format!("bytes={start}-{}", end - 1))
+        .send()
+        .await
+        .with_context(|| format!("requesting range of web seed file `{url}`"))?
+        .error_for_status()
+        .context("web seed responded with an error")?;
+
+    let length = end - start;
+    match response.status() {
+        StatusCode::PARTIAL_CONTENT => read_body(response, length).await,
+        // Servers without range support send the whole file, which is only worth it when the
+        // range is the whole file. Otherwise every piece would load the file up to its end.
+        StatusCode::OK if start == 0 && response.content_length() == Some(end) => {
+            read_body(response, length).await
+        }
+        StatusCode::OK => bail!("web seed does not support range requests"),
+        status => bail!("unexpected web seed response status {status}"),
+    }
+}
+
+/// Reads the body of the response, which should be `length` bytes long. Longer bodies are not
+/// read any further than that.
+async fn read_body(mut response: Response, length: u64) -> Result<Bytes> {
+    let mut body = BytesMut::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .context("reading web seed response body")?
+    {
+        ensure!(
+            (body.len() + chunk.len()) as u64 <= length,
+            "web seed returned more than the {length} bytes of the range"
+        );
+        body.extend_from_slice(&chunk);
+    }
+    ensure!(
+        body.len() as u64 == length,
+        "web seed returned {} bytes for a range of {length}",
+        body.len()
+    );
+    Ok(body.freeze())
+}
+
+async fn check_piece(buf: Vec<u8>, length: u32, hash: &Sha1Hash) -> Result<Vec<u8>> {
+    if buf.len() != length as usize {
+        bail!("web seed files are shorter than the torrent content");
+    }
+    let (buf, piece_hash) = hash_piece(buf).await;
+    if *hash != piece_hash {
+        bail!("piece hash does not match hash from torrent");
+    }
+    Ok(buf)
+}
+
+/// Escapes a path component for use in a url.
+fn percent_encode(component: &[u8]) -> String {
+    let mut encoded = String::with_capacity(component.len());
+    for &byte in component {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(char::from(byte))
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{torrent::Torrent, util::hash_sha1};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn padding_files() {
+        let torrent = Torrent::from_bytes(
+            b"d8:announce0:4:infod5:filesld6:lengthi4e4:pathl1:aeed4:attr1:p6:lengthi4e4:pathl4:\
+            .pad1:4eed6:lengthi4e4:pathl1:beee4:name4:root12:piece lengthi4e6:pieces60:\
+            aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbcccccccccccccccccccc\
+            ee",
+        )
+        .unwrap();
+        let seed = WebSeed::new("http://seed.invalid/files/", &torrent.info);
+
+        let urls: Vec<_> = seed.files.iter().map(|file| file.url.as_deref()).collect();
+        assert_eq!(
+            urls,
+            [
+                Some("http://seed.invalid/files/root/a"),
+                None,
+                Some("http://seed.invalid/files/root/b")
+            ]
+        );
+
+        // The piece covering only padding is never requested.
+        let piece = seed
+            .download_piece(
+                &Client::new(),
+                PieceDescriptor {
+                    index: 1,
+                    length: 4,
+                    hash: hash_sha1([0; 4]),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(piece, [0; 4]);
+    }
+}